@@ -0,0 +1,59 @@
+//! Throughput of converting a 4K wallpaper to Xrgb8888,
+//! run with: cargo bench --bench swizzle
+
+#[path = "../src/swizzle.rs"]
+mod swizzle;
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+const WIDTH: usize = 3840;
+const HEIGHT: usize = 2160;
+const ROUNDS: u32 = 50;
+
+fn main() {
+    let src = (0..WIDTH * HEIGHT * 3)
+        .map(|index| (index * 7 % 251) as u8)
+        .collect::<Vec<_>>();
+    let mut scalar = vec![0u8; WIDTH * HEIGHT * 4];
+    let mut simd = vec![0u8; WIDTH * HEIGHT * 4];
+
+    let scalar_time = measure(|| {
+        swizzle::bgrx_from_rgb_scalar(black_box(&src), black_box(&mut scalar))
+    });
+    let simd_time = measure(|| {
+        swizzle::bgrx_from_rgb(black_box(&src), black_box(&mut simd))
+    });
+    assert!(scalar == simd, "SIMD and scalar results differ");
+
+    report("scalar", scalar_time);
+    report("simd", simd_time);
+    println!(
+        "speedup: {:.2}x",
+        scalar_time.as_secs_f64() / simd_time.as_secs_f64()
+    );
+}
+
+/// Best of the rounds
+fn measure(mut f: impl FnMut()) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, time: Duration) {
+    let megapixels = (WIDTH * HEIGHT) as f64 / 1e6;
+    println!(
+        "{:>6}: {:>8.3} ms, {:>7.1} Mpx/s",
+        name,
+        time.as_secs_f64() * 1e3,
+        megapixels / time.as_secs_f64()
+    );
+}
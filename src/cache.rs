@@ -0,0 +1,215 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    thread::spawn,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, warn};
+use smithay_client_toolkit::shm::slot::{Buffer, SlotPool};
+use xxhash_rust::xxh3::{Xxh3, xxh3_64};
+
+use crate::store::WallpaperKey;
+
+const MAGIC: &[u8; 4] = b"MBGC";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 32;
+/// Entries not loaded for this long are removed, eg. the ones of
+/// wallpapers deleted or made with other settings or another version
+const MAX_UNUSED_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Wallpapers already decoded, processed and converted to their buffer
+/// format on disk, loading them is only a copy into the shm buffer
+pub struct DiskCache {
+    dir: PathBuf,
+    /// Hash of the settings affecting the pixels of wallpapers,
+    /// entries made with other settings are not used
+    fingerprint: u64,
+    verify: bool,
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: u32,
+    checksum: u64,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, settings: &str, verify: bool) -> DiskCache {
+        DiskCache { dir, fingerprint: xxh3_64(settings.as_bytes()), verify }
+    }
+
+    fn entry_path(&self, key: &WallpaperKey) -> PathBuf {
+        let mut hasher = Xxh3::new();
+        hasher.update(&self.fingerprint.to_le_bytes());
+        key.hash_stable(&mut |bytes| hasher.update(bytes));
+        self.dir.join(format!("{:016x}.bin", hasher.digest()))
+    }
+
+    /// Whether there is an entry, maybe invalid or corrupt
+    pub fn contains(&self, key: &WallpaperKey) -> bool {
+        self.entry_path(key).is_file()
+    }
+
+    /// Returns None on a miss, also if the entry is invalid or corrupt,
+    /// then the wallpaper is loaded as usual and the entry is overwritten
+    pub fn load(
+        &self,
+        key: &WallpaperKey,
+        slot_pool: &mut SlotPool,
+    ) -> Option<Buffer> {
+        let path = self.entry_path(key);
+        let mut file = File::open(&path).ok()?;
+        match self.read_entry(&mut file, key, slot_pool) {
+            Ok(buffer) => {
+                debug!("Loaded wallpaper from disk cache {:?}", path);
+                if let Err(e) = file.set_modified(SystemTime::now()) {
+                    debug!("Failed to mark {:?} used: {}", path, e);
+                }
+                Some(buffer)
+            }
+            Err(e) => {
+                warn!("Regenerating disk cache entry {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn read_entry(
+        &self,
+        file: &mut File,
+        key: &WallpaperKey,
+        slot_pool: &mut SlotPool,
+    ) -> Result<Buffer, String> {
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)
+            .map_err(|e| format!("Failed to read header: {}", e))?;
+        let header = Header::parse(&header)?;
+        if (header.width, header.height, header.format)
+            != (key.width(), key.height(), key.format() as u32)
+        {
+            return Err("Entry does not match the wallpaper".to_string());
+        }
+        let (buffer, canvas) = slot_pool
+            .create_buffer(
+                header.width as i32,
+                header.height as i32,
+                header.stride as i32,
+                key.format(),
+            )
+            .map_err(|e| format!("Failed to create buffer: {}", e))?;
+        let len = header.stride as usize * header.height as usize;
+        let canvas = canvas.get_mut(..len)
+            .ok_or("Entry is larger than its buffer")?;
+        file.read_exact(canvas)
+            .map_err(|e| format!("Failed to read pixels: {}", e))?;
+        if self.verify && xxh3_64(canvas) != header.checksum {
+            return Err("Checksum mismatch".to_string());
+        }
+        Ok(buffer)
+    }
+
+    pub fn store(
+        &self,
+        key: &WallpaperKey,
+        buffer: &Buffer,
+        slot_pool: &mut SlotPool,
+    ) {
+        let Some(canvas) = buffer.canvas(slot_pool) else { return };
+        let len = buffer.stride() as usize * buffer.height() as usize;
+        self.store_pixels(key, buffer.stride() as u32, &canvas[..len]);
+    }
+
+    /// The pixels in the buffer format of the key, row by row
+    pub fn store_pixels(&self, key: &WallpaperKey, stride: u32, pixels: &[u8]) {
+        let header = Header {
+            width: key.width(),
+            height: key.height(),
+            stride,
+            format: key.format() as u32,
+            checksum: xxh3_64(pixels),
+        };
+        let path = self.entry_path(key);
+        if let Err(e) = write_entry(&path, &header, pixels) {
+            warn!("Failed to write disk cache entry {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Remove the entries in the dir unused for long in the background,
+/// loading an entry marks it used by its modification time
+pub fn spawn_prune(dir: PathBuf) {
+    spawn(move || prune(&dir, MAX_UNUSED_AGE));
+}
+
+fn prune(dir: &Path, max_age: Duration) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Also temporary files left by a crash while writing
+        let unused = entry.metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| now.duration_since(modified)
+                .is_ok_and(|age| age > max_age)
+            );
+        if !unused || !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!(
+                "Failed to remove unused disk cache entry {:?}: {}", path, e
+            ),
+        }
+    }
+    if removed > 0 {
+        debug!("Removed {} unused disk cache entries", removed);
+    }
+}
+
+// Write to a temporary file and rename so readers never see partial entries
+fn write_entry(path: &Path, header: &Header, pixels: &[u8])
+    -> std::io::Result<()>
+{
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&header.to_bytes())?;
+    writer.write_all(pixels)?;
+    writer.into_inner()?.sync_data()?;
+    fs::rename(&tmp_path, path)
+}
+
+impl Header {
+    fn parse(bytes: &[u8; HEADER_LEN]) -> Result<Header, String> {
+        let u32_at = |i: usize|
+            u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if &bytes[..4] != MAGIC || u32_at(4) != VERSION {
+            return Err("Unknown entry format".to_string());
+        }
+        Ok(Header {
+            width: u32_at(8),
+            height: u32_at(12),
+            stride: u32_at(16),
+            format: u32_at(20),
+            checksum: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.width.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.height.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.stride.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.format.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+}
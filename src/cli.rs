@@ -1,4 +1,11 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use smithay_client_toolkit::shell::wlr_layer::Anchor;
+
+use crate::{
+    clock::TimeRange,
+    decoder::Format,
+    glob::glob_match,
+};
 
 #[derive(Parser)]
 #[command(author, version, long_about = None, about = "\
@@ -29,6 +36,10 @@ In more detail:
 
         $ swaymsg -t get_outputs
 
+    - Can also be named by the output identifier used in the sway config:
+      make, model and serial separated by spaces, or just make and model,
+      these take precedence over the connector name
+
 - workspace_name: The name of the sway workspace,
     by sway defaults: 1, 2, 3, ..., 10
 
@@ -68,24 +79,866 @@ Nevertheless the contrast and brightness might be adjusted here:
 
     $ multibg-sway --contrast=-25 --brightness=-60 ~/my_wallpapers
 
+Or muted to sit behind terminals:
+
+    $ multibg-sway --saturation=-50 --gamma=0.8 ~/my_wallpapers
+
 In case of errors multibg-sway logs to stderr and tries to continue.
 One may wish to redirect stderr if multibg-sway is being run as a daemon.")]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Cli {
-    /// adjust contrast, eg. -c=-25 (default: 0)
-    #[arg(short, long)]
-    pub contrast: Option<f32>,
-    /// adjust brightness, eg. -b=-60 (default: 0)
-    #[arg(short, long)]
-    pub brightness: Option<i32>,
-    /// wl_buffer pixel format (default: auto)
-    #[arg(long)]
-    pub pixelformat: Option<PixelFormat>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// adjust contrast, eg. -c=-25
+    #[arg(short, long, default_value_t = 0.0)]
+    pub contrast: f32,
+    /// adjust brightness, eg. -b=-60
+    #[arg(short, long, default_value_t = 0)]
+    pub brightness: i32,
+    /// adjust saturation in percent, -100 makes wallpapers grayscale,
+    /// eg. --saturation=-40
+    #[arg(long, default_value_t = 0.0)]
+    pub saturation: f32,
+    /// rotate the hue of the colors by degrees, eg. --hue-rotate=180
+    #[arg(long, default_value_t = 0)]
+    pub hue_rotate: i32,
+    /// adjust gamma, below 1 darkens the darker tones, eg. --gamma=0.8
+    #[arg(long, value_parser = parse_gamma, default_value = "1")]
+    pub gamma: f32,
+    /// wl_buffer pixel format
+    #[arg(long, value_enum, default_value_t = PixelFormat::Auto)]
+    pub pixelformat: PixelFormat,
+    /// make wallpapers at the logical size of scaled outputs and let the
+    /// compositor upscale them, less memory for less detail
+    #[arg(long, value_enum, default_value_t = RenderSize::Physical)]
+    pub render_size: RenderSize,
+    /// how images are fit to the output: fill crops them to cover it,
+    /// fit shows them whole, center doesn't resize them
+    #[arg(long, value_enum, default_value_t = Scaling::Fill)]
+    pub scaling: Scaling,
+    /// color around images not covering the output with --scaling=fit
+    /// or center, eg. --background-color=202020
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color,
+        default_value = "000000")]
+    pub background_color: [u8; 3],
+    /// the part of images kept when --scaling=fill crops them:
+    /// center, top, bottom, left, right, golden or X%,Y%, can be set
+    /// per image in a sidecar file, eg. 1.jpg.gravity
+    #[arg(long, value_name = "GRAVITY", value_parser = parse_gravity,
+        default_value = "center")]
+    pub crop_gravity: Gravity,
+    /// darken wallpapers by PERCENT, like a translucent black overlay,
+    /// eg. --dim=30
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    pub dim: Option<u8>,
+    /// darken the corners of wallpapers by up to PERCENT, eg. --vignette=60
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    pub vignette: Option<u8>,
+    /// darken a strip at an edge of wallpapers below a status bar for its
+    /// text to stay readable, fading out over as much again,
+    /// eg. --bar-shade=top,32,40 for 32 pixels at 40%
+    #[arg(long, value_name = "EDGE,PIXELS,PERCENT",
+        value_parser = parse_bar_shade)]
+    pub bar_shade: Vec<BarShade>,
+    /// color filter for the wallpapers of the matching workspaces,
+    /// eg. --workspace-filter='work*=grayscale', the file name can set it
+    /// too, eg. 1+sepia.jpg (last match wins)
+    #[arg(long, value_name = "WORKSPACE=FILTER",
+        value_parser = parse_workspace_filter)]
+    pub workspace_filter: Vec<WorkspaceFilter>,
+    /// slowly pan and zoom over the wallpapers of the matching workspaces,
+    /// excluding with a ! prefix, eg. --ken-burns='*' --ken-burns='!3'
+    /// (last match wins)
+    #[arg(long, value_name = "WORKSPACE")]
+    pub ken_burns: Vec<String>,
+    /// fill around images not covering the output with a heavily blurred
+    /// copy of the image instead of the background color
+    #[arg(long)]
+    pub blur_fill: bool,
+    /// animate shader wallpapers at this frame rate while they are visible,
+    /// 0 renders them once, needs the shader feature
+    #[arg(long, value_name = "FPS", default_value_t = 0)]
+    pub shader_fps: u32,
+    /// filter for resizing images to the output, the ones first in the list
+    /// are faster but lower quality (default: lanczos3)
+    #[arg(long)]
+    pub resize_filter: Option<ResizeFilter>,
+    /// start faster on slow CPUs at some loss of quality,
+    /// for now the same as --resize-filter=bilinear
+    #[arg(long, conflicts_with = "resize_filter")]
+    pub fast_start: bool,
+    /// how the unbounded brightness of floating point images,
+    /// eg. OpenEXR or Radiance HDR, is mapped to the display
+    #[arg(long, value_enum, default_value_t = ToneMap::Reinhard)]
+    pub tone_map: ToneMap,
+    /// exposure adjustment of floating point images in stops,
+    /// eg. --exposure=-1.5
+    #[arg(long, default_value_t = 0.0)]
+    pub exposure: f32,
+    /// dither wallpapers when reducing their color depth, avoids banding
+    /// on gradients of 16 bit images and with --pixelformat=rgb565
+    #[arg(long)]
+    pub dither: bool,
+    /// keep the transparency of images with an alpha channel,
+    /// the compositor blends them with what is below (default: discard)
+    #[arg(long)]
+    pub alpha: bool,
+    /// decode a format with a shell command getting the image path as $1
+    /// and writing eg. png to stdout,
+    /// eg. --decoder='jxl=djxl "$1" - --output_format=png'
+    #[arg(long, value_name = "FORMAT=COMMAND", value_parser = parse_decoder)]
+    pub decoder: Vec<DecoderCommand>,
+    /// like --decoder but used for any image the other decoders fail on,
+    /// eg. --decoder-fallback='magick "$1" png:-'
+    #[arg(long, value_name = "COMMAND")]
+    pub decoder_fallback: Option<String>,
+    /// refuse to decode images wider or taller than this, jpeg images
+    /// are decoded at a reduced size instead if possible
+    #[arg(long, value_name = "PIXELS", default_value_t = 16384)]
+    pub max_image_size: u32,
+    /// refuse to decode images taking more memory than this, or animations
+    /// with all their frames at the size of the output, jpeg images
+    /// are decoded at a reduced size instead if possible
+    #[arg(long, value_name = "MEGABYTES", default_value_t = 512)]
+    pub max_image_memory: u64,
+    /// use wallpaper_dir/NAME/ instead of wallpaper_dir while all the given
+    /// outputs are connected, eg. --profile=docked=HDMI-A-1,DP-2
+    /// (first match wins, outputs by connector name or by "make model")
+    #[arg(long, value_name = "NAME=OUTPUT,...",
+        value_parser = parse_output_group)]
+    pub profile: Vec<OutputGroup>,
+    /// use wallpaper_dir/NAME/ instead of wallpaper_dir during the daily
+    /// time range, eg. --time-profile=night=19:00-07:00 (first match wins,
+    /// --profile directories are looked up inside it)
+    #[arg(long, value_name = "NAME=HH:MM-HH:MM",
+        value_parser = parse_time_profile)]
+    pub time_profile: Vec<TimeProfile>,
+    /// use wallpaper_dir/light/ or wallpaper_dir/dark/ following the color
+    /// scheme preference of the desktop settings portal, eg. set by darkman
+    /// (inside the time profile directory, --profile directories inside it)
+    #[arg(long)]
+    pub follow_color_scheme: bool,
+    /// show all the images of a workspace directory
+    /// wallpaper_dir/output/workspace_name/ one after the other, advancing
+    /// every SECONDS while the workspace is visible, instead of only the one
+    /// closest to the aspect ratio of the output
+    #[arg(long, value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..))]
+    pub slideshow: Option<u64>,
+    /// show a random image of a workspace directory instead of the one
+    /// closest to the aspect ratio of the output, picked once or each time
+    /// the workspace is shown, --slideshow advances to random images too
+    #[arg(long, value_name = "WHEN")]
+    pub random: Option<RandomPick>,
+    /// span wallpapers from wallpaper_dir/NAME/ across the given outputs
+    /// by their layout position, eg. --span=wall=DP-1,DP-2,DP-3
+    #[arg(long, value_name = "NAME=OUTPUT,...",
+        value_parser = parse_output_group)]
+    pub span: Vec<OutputGroup>,
+    /// dim wallpapers by PERCENT during the daily time range,
+    /// eg. --night-dim=22:00-07:00=40
+    #[arg(long, value_name = "HH:MM-HH:MM=PERCENT",
+        value_parser = parse_night_dim)]
+    pub night_dim: Option<NightDim>,
+    /// also tint wallpapers by multiplying their colors during --night-dim,
+    /// eg. --night-tint=ffc080 for warmer colors
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color,
+        default_value = "ffffff", requires = "night_dim")]
+    pub night_tint: [u8; 3],
+    /// keep the wallpapers of a disconnected output or of the previous mode
+    /// of an output for this long to show them without loading again
+    /// if it comes back, eg. on redocking
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub keep_wallpapers: u64,
+    /// evict the least recently shown wallpapers when the wallpapers of all
+    /// outputs take more memory than this, loading them again when shown,
+    /// implies --lazy
+    #[arg(long, value_name = "MEGABYTES")]
+    pub max_memory: Option<usize>,
+    /// redraw an output at most once per this many milliseconds,
+    /// the last workspace switched to is always drawn
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    pub min_redraw_interval: u64,
+    /// only put wallpapers on these outputs, by connector name or by
+    /// "make model", may contain * and ? wildcards, eg. --only-output='DP-*'
+    #[arg(long, value_name = "OUTPUT")]
+    pub only_output: Vec<String>,
+    /// leave these outputs to something else, eg. a projector running
+    /// mpvpaper, matched like --only-output
+    #[arg(long, value_name = "OUTPUT")]
+    pub skip_output: Vec<String>,
+    /// prefer wallpapers named by the layout of the workspace,
+    /// eg. 1@tabbed.jpg over 1.jpg, also _default@stacked.jpg
+    #[arg(long)]
+    pub layout_wallpapers: bool,
+    /// only tell the compositor about the parts of the output that differ
+    /// between the old and new wallpaper, less work on large outputs
+    /// for wallpapers that differ only in parts
+    #[arg(long)]
+    pub damage_tracking: bool,
+    /// anchor wallpapers to these edges of the output, optionally only on
+    /// the given output, eg. --anchor=eDP-1=top,left (default: all edges)
+    #[arg(long, value_name = "[OUTPUT=]EDGE,...", value_parser = parse_anchor)]
+    pub anchor: Vec<PerOutput<Anchor>>,
+    /// leave space around the wallpaper, optionally only on the given output,
+    /// eg. --margin=30,0,0,0 or --margin=eDP-1=10 (default: 0)
+    #[arg(long, value_name = "[OUTPUT=]TOP,RIGHT,BOTTOM,LEFT",
+        value_parser = parse_margin)]
+    pub margin: Vec<PerOutput<Margin>>,
+    /// whether the wallpaper avoids the exclusive zones of status bars,
+    /// optionally only on the given output (default: ignore)
+    #[arg(long, value_name = "[OUTPUT=]ignore|respect",
+        value_parser = parse_exclusive_zone)]
+    pub exclusive_zone: Vec<PerOutput<ExclusiveZone>>,
+    /// animate switching workspaces, slides and wipes moving towards
+    /// the workspace with the higher number, optionally only on the given
+    /// output (default: none)
+    #[arg(long, value_name = "[OUTPUT=]STYLE",
+        value_parser = parse_transition)]
+    pub transition: Vec<PerOutput<TransitionStyle>>,
+    /// length of the --transition animation
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 300)]
+    pub transition_duration: u64,
+    /// fade the first wallpaper of each output in from black
+    /// over this long
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    pub fade_in: u64,
+    /// fade the wallpapers out over this long when asked to exit
+    /// by SIGTERM or SIGINT
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    pub fade_out: u64,
+    /// what the wallpapers fade out to
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color,
+        default_value = "000000")]
+    pub fade_out_color: [u8; 3],
+    /// limit the frame rate of animated wallpapers, videos
+    /// and transitions, 0 for unlimited
+    #[arg(long, value_name = "FPS", default_value_t = 0)]
+    pub max_fps: u32,
+    /// halve the frame rate of animations while on battery,
+    /// and again on outputs without the focused workspace
+    #[arg(long)]
+    pub adaptive_fps: bool,
+    /// run CMD with sh -c when an output shows another wallpaper, with
+    /// MULTIBG_OUTPUT, MULTIBG_WORKSPACE and MULTIBG_IMAGE in its
+    /// environment, at most once a second
+    #[arg(long, value_name = "CMD")]
+    pub on_change: Option<String>,
+    /// detach from the terminal once the wallpapers are shown, logging to
+    /// multibg-sway.log in the state directory from then on and writing
+    /// the pid to multibg-sway.pid in the runtime directory
+    #[arg(long)]
+    pub daemon: bool,
+    /// reload the wallpaper directory on SIGHUP like on SIGUSR1
+    /// instead of exiting
+    #[arg(long)]
+    pub reload_on_sighup: bool,
+    /// write workspace and output events with timestamps to FILE
+    /// as JSON lines, eg. to attach to bug reports
+    #[arg(long, value_name = "FILE")]
+    pub record_events: Option<String>,
+    /// show the workspaces from a FILE written by --record-events
+    /// with their original timing instead of following sway
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<String>,
+    /// keep processed wallpapers in the cache dir to start faster,
+    /// uses as much disk space as the wallpapers use memory
+    #[arg(long)]
+    pub disk_cache: bool,
+    /// check the disk cache entries against their checksum when loading,
+    /// corrupt entries are regenerated
+    #[arg(long, requires = "disk_cache")]
+    pub verify_cache: bool,
+    /// load wallpapers when their workspace is first shown instead of at
+    /// startup, besides _default, for a fast start and less memory use
+    #[arg(long)]
+    pub lazy: bool,
+    /// with --lazy or --max-memory, load the wallpapers not loaded yet
+    /// in the background once idle for SECONDS, so switching to workspaces
+    /// with slow to process wallpapers, eg. blurred or large, is smooth
+    #[arg(long, value_name = "SECONDS")]
+    pub idle_precompute: Option<u64>,
+    /// write the dominant and accent colors of the wallpaper shown on each
+    /// output to runtime_dir/palette/OUTPUT.json for theming other programs
+    #[arg(long)]
+    pub palette: bool,
+    /// draw a text into a corner of the wallpapers, with {workspace},
+    /// {output} and {hostname} replaced, eg. --overlay='{workspace}'
+    #[arg(long, value_name = "TEMPLATE")]
+    pub overlay: Option<String>,
+    /// the corner of the --overlay text
+    #[arg(long, value_enum, default_value_t = Corner::BottomRight,
+        requires = "overlay")]
+    pub overlay_corner: Corner,
+    /// font file of the --overlay text (default: sans-serif by fc-match)
+    #[arg(long, value_name = "PATH", requires = "overlay")]
+    pub overlay_font: Option<String>,
+    /// height of the --overlay text in pixels of the wallpaper
+    #[arg(long, value_name = "PIXELS", default_value_t = 24.0,
+        requires = "overlay")]
+    pub overlay_size: f32,
+    /// directory for cached data (default: $XDG_CACHE_HOME/multibg-sway)
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<String>,
+    /// directory for data kept across restarts
+    /// (default: $XDG_STATE_HOME/multibg-sway)
+    #[arg(long, value_name = "DIR")]
+    pub state_dir: Option<String>,
+    /// directory for sockets and other files only valid while running,
+    /// has to be accessible only by the user
+    /// (default: $XDG_RUNTIME_DIR/multibg-sway)
+    #[arg(long, value_name = "DIR")]
+    pub runtime_dir: Option<String>,
+    /// wayland display to connect to, eg. wayland-1 for a nested compositor
+    /// or an absolute socket path (default: $WAYLAND_DISPLAY)
+    #[arg(long, value_name = "WAYLAND_DISPLAY")]
+    pub display: Option<String>,
+    /// print the outputs of the compositor as JSON and exit
+    #[arg(long)]
+    pub list_outputs: bool,
+    /// print the options multibg-sway runs with, including the defaults
+    /// of those not given, and the directories it uses as JSON and exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// print how much memory the wallpapers of the connected outputs
+    /// would take with the other options, without loading them, and exit
+    #[arg(long)]
+    pub estimate: bool,
     /// directory with: wallpaper_dir/output/workspace_name.{jpg|png|...}
-    pub wallpaper_dir: String,
+    #[arg(required_unless_present = "list_outputs")]
+    pub wallpaper_dir: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Mirror a remote wallpaper directory into the local one with rsync,
+    /// only transferring changes, eg. sync user@host:wallpapers ~/wallpapers
+    Sync {
+        /// rsync source: a local path, [user@]host:path or rsync://host/path
+        source: String,
+        /// local wallpaper_dir to update, files missing from the source
+        /// are deleted
+        wallpaper_dir: String,
+        /// extra arguments for rsync, eg. --rsync-arg=--bwlimit=1m
+        #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+        rsync_arg: Vec<String>,
+    },
+    /// Decode and resize the wallpapers into the disk cache ahead of time
+    /// for the connected outputs, so starting with --disk-cache only
+    /// copies them, eg. prepare ~/wallpapers -- --brightness=-20
+    Prepare {
+        /// the wallpaper_dir multibg-sway is started with
+        wallpaper_dir: String,
+        /// prepare every output directory at this size instead
+        /// of connecting to the compositor, can be repeated
+        #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+        size: Vec<(u32, u32)>,
+        /// the other options multibg-sway is started with after --,
+        /// the cache is only used with the same image options
+        #[arg(last = true)]
+        options: Vec<String>,
+    },
+    /// Render the wallpaper of a workspace on an output to a PNG file like
+    /// multibg-sway would show it, without showing it, eg.
+    /// preview ~/wallpapers eDP-1 3 -o 3.png -- --contrast=20
+    Preview {
+        /// the wallpaper_dir multibg-sway is started with
+        wallpaper_dir: String,
+        output: String,
+        workspace: String,
+        /// PNG file to write
+        #[arg(short = 'o', long = "out", value_name = "FILE")]
+        out: String,
+        /// render at this size instead of the size of the output,
+        /// without connecting to the compositor
+        #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+        size: Option<(u32, u32)>,
+        /// the other options multibg-sway is started with after --
+        #[arg(last = true)]
+        options: Vec<String>,
+    },
+    /// Check the wallpaper directory for unreadable, unsupported or
+    /// duplicate wallpapers, broken symlinks and wrong sizes without
+    /// connecting to the compositor, failing if there are any problems
+    Validate {
+        wallpaper_dir: String,
+        /// report wallpapers upscaled to cover this size, can be repeated
+        #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+        size: Vec<(u32, u32)>,
+    },
+    /// Check the environment: the sway IPC, the wayland globals and
+    /// shm formats of the compositor and the outputs, with advice
+    Doctor,
+    /// Control the running multibg-sway through its socket in the runtime
+    /// directory, eg. ctl set eDP-1 3 ~/Pictures/mountains.jpg
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CtlCommand {
+    /// Rescan the wallpaper directory and redraw, like SIGUSR1
+    Reload,
+    /// Print the outputs, their workspaces and wallpapers and memory use
+    Status {
+        /// print the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the next wallpaper of the focused workspace, from its
+    /// slideshow or the files next to it
+    Next,
+    /// Show the previous wallpaper of the focused workspace
+    Prev,
+    /// Undo the last wallpaper change of set, next or prev
+    Back,
+    /// Print counters and timings of decoding, switching and memory use
+    Stats {
+        /// print the stats as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Stop following workspace changes and pause slideshows and
+    /// animations, eg. while recording the screen
+    Pause,
+    /// Follow workspace changes again, showing the wallpapers of the
+    /// workspaces visible now, and continue slideshows and animations
+    Resume,
+    /// Show black or a color on the output instead of its wallpapers,
+    /// eg. blank eDP-1 202020, until unblanked or exiting
+    Blank {
+        output: String,
+        /// RRGGBB (default: 000000)
+        #[arg(value_parser = parse_color)]
+        color: Option<[u8; 3]>,
+    },
+    /// Show the wallpapers on the blanked output again
+    Unblank {
+        output: String,
+    },
+    /// Print the schema version of the JSON replies and the commands
+    /// and events supported by the running daemon as JSON
+    Capabilities,
+    /// Print a JSON line on every wallpaper change until the daemon exits
+    Subscribe,
+    /// Show an image as the wallpaper of a workspace instead of the one
+    /// in the wallpaper directory, until exiting unless persisted
+    Set {
+        output: String,
+        workspace: String,
+        image: String,
+        /// keep it after restarting too, in the state directory
+        #[arg(long)]
+        persist: bool,
+    },
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
 pub enum PixelFormat {
     Auto,
     Baseline,
+    /// half the memory of the others at reduced color depth
+    Rgb565,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum RandomPick {
+    /// once for each workspace
+    Once,
+    /// again each time the workspace is shown
+    Show,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Scaling {
+    Fill,
+    Fit,
+    Center,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    #[value(name = "catmullrom")]
+    CatmullRom,
+    Lanczos3,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ToneMap {
+    /// cut off everything brighter than white
+    Clip,
+    /// compress highlights smoothly
+    Reinhard,
+    /// filmic curve with more contrast
+    Aces,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default, ValueEnum)]
+pub enum ColorFilter {
+    #[default]
+    None,
+    Grayscale,
+    Sepia,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum RenderSize {
+    /// the resolution of the output
+    Physical,
+    /// the resolution the output would have at scale 1
+    Logical,
+}
+
+#[derive(Clone)]
+pub struct DecoderCommand {
+    pub format: Format,
+    pub command: String,
+}
+
+fn parse_decoder(s: &str) -> Result<DecoderCommand, String> {
+    let (format_name, command) = s.split_once('=')
+        .ok_or("expected FORMAT=COMMAND")?;
+    let format = Format::from_name(format_name.trim())
+        .ok_or_else(|| format!("unknown image format '{}'", format_name))?;
+    if command.trim().is_empty() {
+        return Err("empty command".to_string());
+    }
+    Ok(DecoderCommand { format, command: command.to_string() })
+}
+
+/// Named set of outputs matched by connector name or by "make model"
+#[derive(Clone)]
+pub struct OutputGroup {
+    pub name: String,
+    pub outputs: Vec<String>,
+}
+
+impl OutputGroup {
+    pub fn contains(&self, output_name: &str, make: &str, model: &str)
+        -> bool
+    {
+        self.outputs.iter()
+            .any(|output| output_matches(output, output_name, make, model))
+    }
+}
+
+pub fn output_matches(
+    pattern: &str,
+    output_name: &str,
+    make: &str,
+    model: &str,
+) -> bool {
+    glob_match(pattern, output_name)
+        || glob_match(pattern, &[make, model].join(" "))
+}
+
+fn parse_output_group(s: &str) -> Result<OutputGroup, String> {
+    let (name, outputs) = s.split_once('=')
+        .ok_or("expected NAME=OUTPUT,...")?;
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("invalid name '{}'", name));
+    }
+    let outputs = outputs.split(',')
+        .map(str::trim)
+        .filter(|output| !output.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if outputs.is_empty() {
+        return Err("expected at least one output".to_string());
+    }
+    Ok(OutputGroup { name: name.to_string(), outputs })
+}
+
+/// Wallpaper directory used during a daily time range
+#[derive(Clone)]
+pub struct TimeProfile {
+    pub name: String,
+    pub time_range: TimeRange,
+}
+
+fn parse_time_profile(s: &str) -> Result<TimeProfile, String> {
+    let (name, time_range) = s.split_once('=')
+        .ok_or("expected NAME=HH:MM-HH:MM")?;
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("invalid name '{}'", name));
+    }
+    Ok(TimeProfile {
+        name: name.to_string(),
+        time_range: time_range.parse()?,
+    })
+}
+
+/// Color filter for the workspaces matching the name pattern
+#[derive(Clone)]
+pub struct WorkspaceFilter {
+    pub workspace: String,
+    pub filter: ColorFilter,
+}
+
+fn parse_workspace_filter(s: &str) -> Result<WorkspaceFilter, String> {
+    let (workspace, filter) = s.rsplit_once('=')
+        .ok_or("expected WORKSPACE=FILTER")?;
+    let filter = ColorFilter::from_str(filter.trim(), true)?;
+    Ok(WorkspaceFilter { workspace: workspace.to_string(), filter })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BarShade {
+    /// A single edge
+    pub edge: Anchor,
+    pub pixels: u32,
+    pub percent: u8,
+}
+
+fn parse_bar_shade(s: &str) -> Result<BarShade, String> {
+    let error = || format!("invalid bar shade '{}', expected \
+EDGE,PIXELS,PERCENT", s);
+    let [edge, pixels, percent] = s.split(',').collect::<Vec<_>>()[..]
+    else {
+        return Err(error());
+    };
+    let edge = match edge.trim() {
+        "top" => Anchor::TOP,
+        "bottom" => Anchor::BOTTOM,
+        "left" => Anchor::LEFT,
+        "right" => Anchor::RIGHT,
+        _ => return Err(format!(
+            "unknown edge '{}', expected top, bottom, left or right", edge
+        )),
+    };
+    Ok(BarShade {
+        edge,
+        pixels: pixels.trim().parse().ok()
+            .filter(|pixels| *pixels > 0)
+            .ok_or_else(error)?,
+        percent: parse_percent(percent)?,
+    })
+}
+
+#[derive(Clone)]
+pub struct NightDim {
+    pub time_range: TimeRange,
+    pub percent: u8,
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('x')
+        .and_then(|(width, height)| Some((
+            width.trim().parse().ok().filter(|width| *width > 0)?,
+            height.trim().parse().ok().filter(|height| *height > 0)?,
+        )))
+        .ok_or_else(|| format!("invalid size '{}', expected WIDTHxHEIGHT", s))
+}
+
+fn parse_night_dim(s: &str) -> Result<NightDim, String> {
+    let (time_range, percent) = s.split_once('=')
+        .ok_or("expected HH:MM-HH:MM=PERCENT")?;
+    Ok(NightDim {
+        time_range: time_range.parse()?,
+        percent: parse_percent(percent)?,
+    })
+}
+
+fn parse_percent(s: &str) -> Result<u8, String> {
+    s.trim().trim_end_matches('%').parse::<u8>().ok()
+        .filter(|percent| *percent <= 100)
+        .ok_or_else(|| format!("invalid percent '{}'", s))
+}
+
+fn parse_gamma(s: &str) -> Result<f32, String> {
+    s.trim().parse::<f32>().ok()
+        .filter(|gamma| gamma.is_finite() && *gamma > 0.0)
+        .ok_or_else(|| format!("invalid gamma '{}', expected above 0", s))
+}
+
+pub fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim().trim_start_matches('#');
+    let error = || format!("invalid color '{}', expected RRGGBB", s);
+    if !hex.is_ascii() {
+        return Err(error());
+    }
+    let hex = match hex.len() {
+        6 => hex.to_string(),
+        // Short form, eg. 333 for 333333
+        3 => hex.chars().flat_map(|digit| [digit, digit]).collect(),
+        _ => return Err(error()),
+    };
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16)
+        .map_err(|_| error());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Point of the image kept in place when it is cropped, as fractions
+/// of the width and height cropped off on the left and top
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Gravity {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Default for Gravity {
+    fn default() -> Gravity {
+        Gravity { x: 0.5, y: 0.5 }
+    }
+}
+
+pub fn parse_gravity(s: &str) -> Result<Gravity, String> {
+    // The larger part of the golden ratio below the subject
+    let golden = 1.0 - 1.0 / 1.618_034;
+    let (x, y) = match s.trim() {
+        "center" => (0.5, 0.5),
+        "top" => (0.5, 0.0),
+        "bottom" => (0.5, 1.0),
+        "left" => (0.0, 0.5),
+        "right" => (1.0, 0.5),
+        "golden" | "golden-ratio" => (0.5, golden),
+        value => {
+            let (x, y) = value.split_once(',')
+                .ok_or_else(|| format!(
+                    "invalid gravity '{}', expected center, top, bottom, \
+left, right, golden or X%,Y%", s
+                ))?;
+            let percent = |value: &str| value.trim().trim_end_matches('%')
+                .parse::<f64>().ok()
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .map(|percent| percent / 100.0)
+                .ok_or_else(|| format!("invalid percent '{}'", value));
+            (percent(x)?, percent(y)?)
+        }
+    };
+    Ok(Gravity { x, y })
+}
+
+/// A value for all outputs or, prefixed with OUTPUT=, for a single output
+#[derive(Clone)]
+pub struct PerOutput<T> {
+    pub output: Option<String>,
+    pub value: T,
+}
+
+impl<T> PerOutput<T> {
+    fn parse(
+        s: &str,
+        parse_value: impl Fn(&str) -> Result<T, String>
+    ) -> Result<PerOutput<T>, String> {
+        match s.split_once('=') {
+            Some((output, value)) => Ok(PerOutput {
+                output: Some(output.to_string()),
+                value: parse_value(value)?,
+            }),
+            None => Ok(PerOutput { output: None, value: parse_value(s)? }),
+        }
+    }
+}
+
+/// Output specific values take precedence over the ones for all outputs,
+/// among those the last one given wins
+pub fn per_output<'a, T>(
+    values: &'a [PerOutput<T>],
+    output_name: &str,
+    make: &str,
+    model: &str,
+) -> Option<&'a T> {
+    values.iter().rev()
+        .find(|per_output| per_output.output.as_deref()
+            .is_some_and(|output|
+                output_matches(output, output_name, make, model)
+            )
+        )
+        .or_else(|| values.iter().rev()
+            .find(|per_output| per_output.output.is_none())
+        )
+        .map(|per_output| &per_output.value)
+}
+
+fn parse_anchor(s: &str) -> Result<PerOutput<Anchor>, String> {
+    PerOutput::parse(s, |value| {
+        let mut anchor = Anchor::empty();
+        for edge in value.split(',') {
+            anchor |= match edge.trim() {
+                "top" => Anchor::TOP,
+                "bottom" => Anchor::BOTTOM,
+                "left" => Anchor::LEFT,
+                "right" => Anchor::RIGHT,
+                _ => return Err(format!(
+                    "unknown edge '{}', expected top, bottom, left or right",
+                    edge
+                )),
+            };
+        }
+        Ok(anchor)
+    })
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Margin {
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    pub left: i32,
+}
+
+fn parse_margin(s: &str) -> Result<PerOutput<Margin>, String> {
+    PerOutput::parse(s, |value| {
+        let values = value.split(',')
+            .map(|v| v.trim().parse::<i32>()
+                .ok()
+                .filter(|v| !v.is_negative())
+                .ok_or_else(|| format!("invalid margin '{}'", v))
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        match values[..] {
+            [all] => Ok(Margin {
+                top: all, right: all, bottom: all, left: all
+            }),
+            [top, right, bottom, left] => Ok(Margin {
+                top, right, bottom, left
+            }),
+            _ => Err("expected 1 or 4 margin values".to_string()),
+        }
+    })
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ExclusiveZone {
+    Ignore,
+    Respect,
+}
+
+fn parse_exclusive_zone(s: &str) -> Result<PerOutput<ExclusiveZone>, String> {
+    PerOutput::parse(s, |value| ExclusiveZone::from_str(value, true))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum TransitionStyle {
+    None,
+    /// the new wallpaper pushes the old one out sideways
+    Slide,
+    /// up and down for vertically stacked workspaces, eg. on niri
+    SlideVertical,
+    /// the new wallpaper is uncovered over the old one sideways
+    Wipe,
+    WipeVertical,
+    /// the old wallpaper fades into the new one
+    Crossfade,
+    /// the old wallpaper zooms in a little while fading into the new one
+    Zoom,
+    /// the old wallpaper fades to black, then the new one fades in
+    FadeThroughBlack,
+}
+
+fn parse_transition(s: &str) -> Result<PerOutput<TransitionStyle>, String> {
+    PerOutput::parse(s, |value| TransitionStyle::from_str(value, true))
 }
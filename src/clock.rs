@@ -0,0 +1,69 @@
+use std::{
+    str::FromStr,
+    time::Duration,
+};
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Seconds since the local midnight
+pub fn local_time_of_day() -> u32 {
+    // SAFETY: localtime_r only writes the provided tm struct
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return (now.rem_euclid(SECONDS_PER_DAY as libc::time_t)) as u32;
+        }
+        (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+    }
+}
+
+/// Daily time range like 22:00-07:00, may wrap around midnight
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimeRange {
+    start: u32,
+    end: u32,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time_of_day: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            time_of_day >= self.start || time_of_day < self.end
+        }
+    }
+
+    /// Time until the range starts or ends, whichever comes first
+    pub fn next_change(&self, time_of_day: u32) -> Duration {
+        let until = |time: u32|
+            (time + SECONDS_PER_DAY - time_of_day) % SECONDS_PER_DAY;
+        let seconds = [until(self.start), until(self.end)].into_iter()
+            .filter(|seconds| *seconds > 0)
+            .min()
+            .unwrap_or(SECONDS_PER_DAY);
+        Duration::from_secs(seconds as u64)
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeRange, String> {
+        let (start, end) = s.split_once('-')
+            .ok_or("expected a time range HH:MM-HH:MM")?;
+        Ok(TimeRange { start: parse_time(start)?, end: parse_time(end)? })
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let error = || format!("invalid time '{}', expected HH:MM", s);
+    let (hours, minutes) = s.trim().split_once(':').ok_or_else(error)?;
+    let hours = hours.parse::<u32>().ok()
+        .filter(|hours| *hours <= 24)
+        .ok_or_else(error)?;
+    let minutes = minutes.parse::<u32>().ok()
+        .filter(|minutes| *minutes < 60)
+        .ok_or_else(error)?;
+    Ok((hours * 3600 + minutes * 60) % SECONDS_PER_DAY)
+}
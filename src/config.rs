@@ -0,0 +1,95 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, CommandFactory, ValueEnum, parser::ValueSource};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    resize_filter,
+    cli::Cli,
+    json::to_json,
+    paths::Paths,
+};
+
+#[derive(Serialize)]
+struct Config<'a> {
+    wallpaper_dir: &'a Path,
+    /// By their long names, with the defaults of those not given,
+    /// null or empty for those off or not set by default
+    options: BTreeMap<String, Value>,
+    /// Given on the command line, not defaults
+    given: Vec<String>,
+    paths: ConfigPaths<'a>,
+}
+
+#[derive(Serialize)]
+struct ConfigPaths<'a> {
+    cache_dir: &'a Path,
+    state_dir: &'a Path,
+    runtime_dir: &'a Path,
+    config_dir: &'a Path,
+    control_socket: PathBuf,
+}
+
+/// Prints the options multibg-sway runs with as JSON, all of them from
+/// the command line or their defaults, with the directories it uses
+pub fn print_config(cli: &Cli, wallpaper_dir: &Path, paths: &Paths) {
+    let command = Cli::command();
+    let matches = command.clone().get_matches();
+
+    let mut options = BTreeMap::new();
+    let mut given = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else { continue };
+        // Not how it runs, or resolved in the paths
+        if matches!(
+            long,
+            "help" | "version" | "print-config" | "list-outputs" | "estimate"
+                | "cache-dir" | "state-dir" | "runtime-dir"
+        ) {
+            continue;
+        }
+        if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            given.push(long.to_string());
+        }
+        let values = matches.get_raw(id)
+            .map(|values| values
+                .map(|value| Value::from(value.to_string_lossy()))
+                .collect::<Vec<_>>()
+            );
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => Value::from(matches.get_flag(id)),
+            ArgAction::Append => Value::from(values.unwrap_or_default()),
+            _ => values.and_then(|values| values.into_iter().next())
+                .unwrap_or(Value::Null),
+        };
+        options.insert(long.to_string(), value);
+    }
+
+    // Defaults depending on other options or the environment
+    let resize_filter = resize_filter(cli).to_possible_value().unwrap();
+    options.insert(
+        "resize-filter".to_string(), Value::from(resize_filter.get_name())
+    );
+    let display = cli.display.clone()
+        .or_else(|| env::var("WAYLAND_DISPLAY").ok());
+    options.insert("display".to_string(), Value::from(display));
+
+    println!("{}", to_json(&Config {
+        wallpaper_dir,
+        options,
+        given,
+        paths: ConfigPaths {
+            cache_dir: &paths.cache_dir,
+            state_dir: &paths.state_dir,
+            runtime_dir: &paths.runtime_dir,
+            config_dir: &paths.config_dir,
+            control_socket: paths.control_socket(),
+        },
+    }));
+}
@@ -0,0 +1,483 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::CtlCommand,
+    json::to_json,
+    stats::DecodeStats,
+    store::StoreStats,
+};
+
+/// How long a client may take to send its request,
+/// the main event loop waits for it meanwhile
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for the reply, eg. while reconnecting to the compositor
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The commands of the requests understood, for the capabilities request
+const COMMANDS: &[&str] = &[
+    "reload", "status", "next", "prev", "back", "stats", "pause", "resume",
+    "subscribe", "set", "blank", "unblank", "capabilities",
+];
+/// The events sent to subscribed clients
+const WALLPAPER_CHANGED: &str = "wallpaper-changed";
+const EVENTS: &[&str] = &[WALLPAPER_CHANGED];
+
+/// A request to the running daemon, one JSON line
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Reload,
+    Status,
+    Next,
+    Prev,
+    Back,
+    Stats,
+    Pause,
+    Resume,
+    /// Keep the connection open for a line about every wallpaper change
+    Subscribe,
+    Set {
+        output: String,
+        workspace: String,
+        path: PathBuf,
+        /// Also after restarting
+        #[serde(default)]
+        persist: bool,
+    },
+    Blank {
+        output: String,
+        color: [u8; 3],
+    },
+    Unblank {
+        output: String,
+    },
+    /// What this version of the daemon supports
+    Capabilities,
+}
+
+/// Content of the reply to the status request
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    pub pid: u32,
+    /// Not following workspace changes, no slideshows and animations
+    #[serde(default)]
+    pub paused: bool,
+    pub outputs: Vec<OutputStatus>,
+    /// Of the wallpapers of all outputs
+    pub memory: StoreStats,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutputStatus {
+    pub name: String,
+    /// Resolution of the wallpapers in pixels
+    pub width: i32,
+    pub height: i32,
+    pub logical_width: i32,
+    pub logical_height: i32,
+    /// Maybe fractional
+    pub scale: f64,
+    /// The workspace shown, None before the first one
+    pub workspace: Option<String>,
+    /// The wallpaper file shown, None before it is loaded
+    pub wallpaper: Option<PathBuf>,
+    /// The color shown instead while blanked, eg. 000000
+    #[serde(default)]
+    pub blank: Option<String>,
+    /// With their own wallpaper, slides of slideshows
+    /// are listed by the name of their workspace
+    pub workspaces: Vec<WorkspaceStatus>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WorkspaceStatus {
+    pub name: String,
+    pub path: PathBuf,
+    /// Not yet shown with lazy loading, or evicted over --max-memory
+    pub loaded: bool,
+    /// Shared with other outputs using the same wallpaper
+    pub memory_bytes: usize,
+}
+
+/// Content of the reply to the capabilities request, for integrations
+/// to detect features. The schema version is in every reply
+#[derive(Serialize, Deserialize)]
+pub struct Capabilities {
+    pub commands: Vec<String>,
+    pub events: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        Capabilities {
+            commands: COMMANDS.iter().map(|s| s.to_string()).collect(),
+            events: EVENTS.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// Content of the reply to the stats request, counted since startup
+#[derive(Serialize, Deserialize)]
+pub struct Stats {
+    pub decoding: DecodeStats,
+    pub memory: StoreStats,
+    pub outputs: Vec<OutputStats>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutputStats {
+    pub name: String,
+    /// Times it showed another wallpaper
+    pub switches: u64,
+    /// Loaded for its workspaces
+    pub wallpapers: usize,
+    /// Of the loaded wallpapers, some may be shared with other outputs
+    pub memory_bytes: usize,
+}
+
+/// Sent to the subscribed clients when a wallpaper is shown
+#[derive(Serialize, Deserialize)]
+pub struct WallpaperChange {
+    pub output: String,
+    pub workspace: String,
+    pub path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    change: &'a WallpaperChange,
+}
+
+/// The reply to a request, one JSON line
+#[derive(Serialize)]
+struct Reply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    content: Option<serde_json::Value>,
+}
+
+/// Listens for the requests of `multibg-sway ctl`,
+/// readable when a client connected
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Connections of the clients waiting for wallpaper changes
+    subscribers: Vec<UnixStream>,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> Result<ControlSocket, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!(
+                "Failed to create directory {:?}: {}", dir, e
+            ))?;
+        }
+        // Left behind by a crash unless another instance is listening
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!(
+                    "Another instance is listening on {:?}", path
+                ));
+            }
+            fs::remove_file(path).map_err(|e| format!(
+                "Failed to remove stale control socket {:?}: {}", path, e
+            ))?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| format!(
+            "Failed to bind control socket {:?}: {}", path, e
+        ))?;
+        listener.set_nonblocking(true).unwrap();
+        debug!("Listening for control requests on {:?}", path);
+        Ok(ControlSocket {
+            listener,
+            path: path.to_path_buf(),
+            subscribers: Vec::new(),
+        })
+    }
+
+    fn subscribe(&mut self, mut stream: UnixStream) {
+        let reply = to_json(&Reply { ok: true, error: None, content: None });
+        // Never block the main event loop on a client not reading,
+        // it loses its subscription instead once its buffer is full
+        match stream.write_all((reply + "\n").as_bytes())
+            .and_then(|_| stream.set_nonblocking(true))
+        {
+            Ok(()) => self.subscribers.push(stream),
+            Err(e) => debug!("Failed to subscribe control client: {}", e),
+        }
+    }
+
+    /// Tell the subscribed clients, forgetting the ones gone
+    pub fn notify(&mut self, changes: &[WallpaperChange]) {
+        if self.subscribers.is_empty() || changes.is_empty() {
+            return;
+        }
+        let lines = changes.iter()
+            .map(|change| to_json(&Event {
+                event: WALLPAPER_CHANGED,
+                change,
+            }) + "\n")
+            .collect::<String>();
+        self.subscribers.retain_mut(|stream|
+            match stream.write_all(lines.as_bytes()) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Dropping control subscriber: {}", e);
+                    false
+                }
+            }
+        );
+    }
+
+    /// The requests of the clients connected since the last call
+    /// with the connections to reply on, except for subscriptions
+    pub fn requests(&mut self)
+        -> Vec<(UnixStream, Result<Request, String>)>
+    {
+        let mut requests = Vec::new();
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return requests;
+                }
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                    return requests;
+                }
+            };
+            match read_request(&stream) {
+                Ok(Request::Subscribe) => self.subscribe(stream),
+                request => requests.push((stream, request)),
+            }
+        }
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove control socket {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn read_request(stream: &UnixStream) -> Result<Request, String> {
+    stream.set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(REQUEST_TIMEOUT)))
+        .map_err(|e| format!("Failed to set up connection: {}", e))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)
+        .map_err(|e| format!("Failed to read request: {}", e))?;
+    serde_json::from_str(&line)
+        .map_err(|e| format!("Invalid request: {}", e))
+}
+
+/// Replies with the content of the result or its error,
+/// a client gone meanwhile is not an error
+pub fn reply(
+    mut stream: UnixStream,
+    result: Result<Option<serde_json::Value>, String>,
+) {
+    let reply = match result {
+        Ok(content) => Reply { ok: true, error: None, content },
+        Err(e) => Reply { ok: false, error: Some(e), content: None },
+    };
+    if let Err(e) = writeln!(stream, "{}", to_json(&reply)) {
+        debug!("Failed to reply to control client: {}", e);
+    }
+}
+
+/// Sends the command of `multibg-sway ctl` to the running daemon
+/// and prints its reply
+pub fn ctl(socket_path: &Path, command: &CtlCommand) -> Result<(), String> {
+    let request = match command {
+        CtlCommand::Reload => Request::Reload,
+        CtlCommand::Status { .. } => Request::Status,
+        CtlCommand::Next => Request::Next,
+        CtlCommand::Prev => Request::Prev,
+        CtlCommand::Back => Request::Back,
+        CtlCommand::Stats { .. } => Request::Stats,
+        CtlCommand::Pause => Request::Pause,
+        CtlCommand::Resume => Request::Resume,
+        CtlCommand::Subscribe => Request::Subscribe,
+        CtlCommand::Set {
+            output, workspace, image, persist
+        } => Request::Set {
+            output: output.clone(),
+            workspace: workspace.clone(),
+            // The daemon runs in another working directory
+            path: Path::new(image).canonicalize().map_err(|e|
+                format!("Failed to find {:?}: {}", image, e)
+            )?,
+            persist: *persist,
+        },
+        CtlCommand::Blank { output, color } => Request::Blank {
+            output: output.clone(),
+            color: color.unwrap_or_default(),
+        },
+        CtlCommand::Unblank { output } => Request::Unblank {
+            output: output.clone(),
+        },
+        CtlCommand::Capabilities => Request::Capabilities,
+    };
+
+    let stream = UnixStream::connect(socket_path).map_err(|e| format!(
+        "Failed to connect to {:?}, is multibg-sway running? {}",
+        socket_path, e
+    ))?;
+    let (line, reply) = send(&stream, &request)?;
+    if let CtlCommand::Subscribe = command {
+        // Until the daemon exits
+        stream.set_read_timeout(None).unwrap();
+        for line in BufReader::new(&stream).lines() {
+            let line = line
+                .map_err(|e| format!("Failed to read event: {}", e))?;
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    match command {
+        CtlCommand::Status { json: true }
+            | CtlCommand::Stats { json: true }
+            | CtlCommand::Capabilities => print!("{}", line),
+        CtlCommand::Status { json: false } => {
+            let status: Status = serde_json::from_value(reply)
+                .map_err(|e| format!("Invalid status: {}", e))?;
+            print_status(&status);
+        }
+        CtlCommand::Stats { json: false } => {
+            let stats: Stats = serde_json::from_value(reply)
+                .map_err(|e| format!("Invalid stats: {}", e))?;
+            print_stats(&stats);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Asks the running daemon to reload its wallpapers,
+/// returns false if it is not running
+pub fn request_reload(socket_path: &Path) -> Result<bool, String> {
+    let stream = match UnixStream::connect(socket_path) {
+        Ok(stream) => stream,
+        Err(e) if matches!(
+            e.kind(),
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+        ) => return Ok(false),
+        Err(e) => return Err(format!(
+            "Failed to connect to {:?}: {}", socket_path, e
+        )),
+    };
+    send(&stream, &Request::Reload)?;
+    Ok(true)
+}
+
+/// Sends the request and reads the reply line, failing if it is an error
+fn send(
+    mut stream: &UnixStream,
+    request: &Request,
+) -> Result<(String, serde_json::Value), String> {
+    stream.set_read_timeout(Some(REPLY_TIMEOUT)).unwrap();
+    let request_line = serde_json::to_string(request).unwrap() + "\n";
+    stream.write_all(request_line.as_bytes())
+        .map_err(|e| format!("Failed to send request: {}", e))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => String::from(
+            "No reply, multibg-sway is not connected to the compositor"
+        ),
+        _ => format!("Failed to read reply: {}", e),
+    })?;
+
+    let reply: serde_json::Value = serde_json::from_str(&line)
+        .map_err(|e| format!("Invalid reply: {}", e))?;
+    if reply["ok"] != true {
+        return Err(reply["error"].as_str()
+            .unwrap_or("Request failed")
+            .to_string());
+    }
+    Ok((line, reply))
+}
+
+fn print_stats(stats: &Stats) {
+    let decoding = &stats.decoding;
+    println!(
+        "decoded {} wallpapers in {} ms, {} ms of it resizing",
+        decoding.decoded, decoding.decode_ms, decoding.resize_ms,
+    );
+    println!(
+        "disk cache: {} hits, {} misses",
+        decoding.disk_cache_hits, decoding.disk_cache_misses,
+    );
+    let memory = &stats.memory;
+    println!(
+        "wallpapers: {} loaded, {} reused, {} released, {} held",
+        memory.loaded, memory.reused, memory.released, memory.wallpapers,
+    );
+    println!(
+        "shm pool: {} KiB, {} KiB of it used",
+        memory.pool_bytes / 1024, memory.used_bytes / 1024,
+    );
+    for output in &stats.outputs {
+        println!(
+            "{}: {} switches, {} wallpapers in {} KiB",
+            output.name, output.switches, output.wallpapers,
+            output.memory_bytes / 1024,
+        );
+    }
+}
+
+fn print_status(status: &Status) {
+    println!(
+        "pid: {}{}", status.pid, if status.paused { ", paused" } else { "" }
+    );
+    for output in &status.outputs {
+        println!(
+            "{}: {}x{} at scale {}, workspace {}, wallpaper {}",
+            output.name, output.width, output.height, output.scale,
+            output.workspace.as_deref().unwrap_or("-"),
+            output.wallpaper.as_ref()
+                .map_or("-".into(), |path| path.to_string_lossy()),
+        );
+        if let Some(color) = &output.blank {
+            println!("    blanked to {}", color);
+        }
+        let loaded = output.workspaces.iter()
+            .filter(|workspace| workspace.loaded);
+        println!(
+            "    {} of {} workspace wallpapers loaded, {} KiB",
+            loaded.clone().count(), output.workspaces.len(),
+            loaded.map(|workspace| workspace.memory_bytes).sum::<usize>()
+                / 1024,
+        );
+    }
+    let memory = &status.memory;
+    println!(
+        "shm pool: {} KiB, {} KiB of it used by {} wallpapers",
+        memory.pool_bytes / 1024, memory.used_bytes / 1024,
+        memory.wallpapers,
+    );
+}
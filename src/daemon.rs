@@ -0,0 +1,133 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    process,
+};
+
+use log::{debug, warn};
+
+/// Tells the launching process that the daemon is ready,
+/// it waits for that to exit with the status of the startup
+pub struct Launcher {
+    pipe: File,
+    log_path: PathBuf,
+}
+
+/// Detach from the terminal with --daemon by forking twice. Must happen
+/// before any threads are spawned, only the forking one survives. Until
+/// ready, the daemon still reports errors to the terminal, the launching
+/// process waiting for it exits with an error if it exits before that.
+pub fn daemonize(log_path: &Path) -> Result<Launcher, String> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe2 only writes the two fds
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(format!(
+            "Failed to create daemon pipe: {}", io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: both were just created and are owned by nothing else
+    let (mut read, write) = unsafe {
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+
+    match fork()? {
+        0 => {}
+        child => {
+            drop(write);
+            // SAFETY: waits only for the child just forked
+            unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+            let mut pid = String::new();
+            let _ = read.read_to_string(&mut pid);
+            if pid.is_empty() {
+                eprintln!("multibg-sway exited before it was ready");
+                process::exit(1);
+            }
+            process::exit(0);
+        }
+    }
+    drop(read);
+
+    // Leave the session of the terminal, then fork again
+    // to never acquire a controlling terminal
+    // SAFETY: setsid has no preconditions
+    if unsafe { libc::setsid() } < 0 {
+        return Err(format!(
+            "Failed to create session: {}", io::Error::last_os_error()
+        ));
+    }
+    if fork()? != 0 {
+        process::exit(0);
+    }
+
+    let null = File::open("/dev/null")
+        .map_err(|e| format!("Failed to open /dev/null: {}", e))?;
+    redirect(&null, libc::STDIN_FILENO)
+        .and_then(|_| redirect(&null, libc::STDOUT_FILENO))
+        .map_err(|e| format!("Failed to redirect stdio: {}", e))?;
+
+    Ok(Launcher { pipe: write, log_path: log_path.to_path_buf() })
+}
+
+impl Launcher {
+    /// Errors are logged to the log file from now on
+    pub fn ready(mut self) {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .and_then(|log| redirect(&log, libc::STDERR_FILENO));
+        match log {
+            Ok(()) => debug!("Logging to {:?}", self.log_path),
+            Err(e) => warn!(
+                "Failed to log to {:?}, no more logs: {}", self.log_path, e
+            ),
+        }
+        let _ = writeln!(self.pipe, "{}", process::id());
+    }
+}
+
+/// The pid of the daemon for scripts, removed on exit
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<PidFile, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!(
+                "Failed to create directory {:?}: {}", dir, e
+            ))?;
+        }
+        fs::write(path, format!("{}\n", process::id())).map_err(|e|
+            format!("Failed to write pidfile {:?}: {}", path, e)
+        )?;
+        Ok(PidFile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove pidfile {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// The pid of the child in the parent, zero in the child
+fn fork() -> Result<libc::pid_t, String> {
+    // SAFETY: no other threads are running yet
+    match unsafe { libc::fork() } {
+        -1 => Err(format!("Failed to fork: {}", io::Error::last_os_error())),
+        pid => Ok(pid),
+    }
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> io::Result<()> {
+    // SAFETY: both fds are open
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Side of the square tiles in pixels that wallpapers are compared by
+const TILE_SIZE: usize = 64;
+
+/// Above this many rectangles damaging the whole surface is cheaper
+const MAX_DAMAGE_RECTS: usize = 32;
+
+/// Hashes of the tiles of a wallpaper to find the parts that differ between
+/// two wallpapers without keeping or comparing their pixels
+pub struct TileHashes {
+    height: usize,
+    stride: usize,
+    columns: usize,
+    rows: usize,
+    hashes: Vec<u64>,
+}
+
+impl TileHashes {
+    pub fn new(
+        canvas: &[u8],
+        height: usize,
+        stride: usize,
+        bytes_per_pixel: usize,
+    ) -> TileHashes {
+        let tile_bytes = TILE_SIZE * bytes_per_pixel;
+        let columns = stride.div_ceil(tile_bytes);
+        let rows = height.div_ceil(TILE_SIZE);
+        let mut hashers = vec![DefaultHasher::new(); columns * rows];
+        for (y, line) in canvas.chunks_exact(stride).take(height).enumerate() {
+            let row = y / TILE_SIZE;
+            for (column, part) in line.chunks(tile_bytes).enumerate() {
+                part.hash(&mut hashers[row * columns + column]);
+            }
+        }
+        TileHashes {
+            height,
+            stride,
+            columns,
+            rows,
+            hashes: hashers.iter().map(Hasher::finish).collect(),
+        }
+    }
+
+    /// Rectangles in buffer pixels as x, y, width, height where the other
+    /// wallpaper differs from this one, None if the whole surface should be
+    /// damaged instead. Neighboring tiles in a row are merged
+    pub fn damage(&self, other: &TileHashes)
+        -> Option<Vec<(i32, i32, i32, i32)>>
+    {
+        if (self.height, self.stride) != (other.height, other.stride) {
+            return None;
+        }
+        let mut rects = Vec::new();
+        for row in 0..self.rows {
+            let mut run_start = None;
+            for column in 0..=self.columns {
+                let index = row * self.columns + column;
+                let differs = column < self.columns
+                    && self.hashes[index] != other.hashes[index];
+                match (differs, run_start) {
+                    (true, None) => run_start = Some(column),
+                    (false, Some(start)) => {
+                        rects.push((
+                            (start * TILE_SIZE) as i32,
+                            (row * TILE_SIZE) as i32,
+                            ((column - start) * TILE_SIZE) as i32,
+                            TILE_SIZE as i32,
+                        ));
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if rects.len() > MAX_DAMAGE_RECTS {
+                return None;
+            }
+        }
+        Some(rects)
+    }
+}
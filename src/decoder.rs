@@ -0,0 +1,897 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufReader, Cursor, Read},
+    path::Path,
+    process::Command,
+    time::Duration,
+};
+
+use image::{
+    AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageError,
+    ImageFormat, ImageReader, ImageResult, Limits,
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+};
+use log::{debug, warn};
+
+use crate::{
+    cli::{DecoderCommand, parse_color},
+    icc::IccTransform,
+};
+
+// Enough to recognize all the supported magic bytes,
+// also the xml prolog and comments before an svg root element
+const SNIFF_LEN: usize = 512;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Image(ImageFormat),
+    Svg,
+    Jxl,
+    Heif,
+    /// Shown by streaming its frames from ffmpeg
+    Video,
+    /// Text file with a solid color or a gradient, eg. 3.color
+    Color,
+    /// Wgsl or glsl fragment shader rendered on the GPU
+    Shader,
+}
+
+impl Format {
+    pub fn sniff(path: &Path) -> Result<Option<Format>, String> {
+        // Plain text has no magic bytes
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("color") => return Ok(Some(Format::Color)),
+            Some("wgsl" | "glsl" | "frag") => return Ok(Some(Format::Shader)),
+            _ => {}
+        }
+        let mut header = Vec::with_capacity(SNIFF_LEN);
+        File::open(path)
+            .and_then(|file| file.take(SNIFF_LEN as u64)
+                .read_to_end(&mut header)
+            )
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        Ok(Format::from_header(&header))
+    }
+
+    fn from_header(header: &[u8]) -> Option<Format> {
+        if header.starts_with(&[0xff, 0x0a])
+            || header.starts_with(b"\0\0\0\x0cJXL \r\n\x87\n")
+        {
+            return Some(Format::Jxl);
+        }
+        if header.len() >= 12 && &header[4..8] == b"ftyp" {
+            match &header[8..12] {
+                b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis"
+                | b"mif1" | b"msf1" => return Some(Format::Heif),
+                _ => {}
+            }
+        }
+        if let Ok(image_format) = image::guess_format(header) {
+            return Some(Format::Image(image_format));
+        }
+        // Other iso media files than heif and avif, eg. mp4 and mov,
+        // or matroska, eg. webm and mkv
+        if (header.len() >= 12 && &header[4..8] == b"ftyp")
+            || header.starts_with(&[0x1a, 0x45, 0xdf, 0xa3])
+        {
+            return Some(Format::Video);
+        }
+        let text = String::from_utf8_lossy(header);
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        if text.starts_with("<svg")
+            || (text.starts_with("<?xml") || text.starts_with("<!--"))
+                && text.contains("<svg")
+        {
+            return Some(Format::Svg);
+        }
+        None
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "svg" => Some(Format::Svg),
+            "jxl" => Some(Format::Jxl),
+            "heif" | "heic" => Some(Format::Heif),
+            "video" | "mp4" | "webm" | "mkv" | "mov" => Some(Format::Video),
+            "color" => Some(Format::Color),
+            "shader" | "wgsl" | "glsl" | "frag" => Some(Format::Shader),
+            ext => ImageFormat::from_extension(ext).map(Format::Image),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Image(image_format) => image_format.extensions_str()
+                .first().copied().unwrap_or("unknown"),
+            Format::Svg => "svg",
+            Format::Jxl => "jxl",
+            Format::Heif => "heif",
+            Format::Video => "video",
+            Format::Color => "color",
+            Format::Shader => "shader",
+        }
+    }
+}
+
+/// Images larger than these are decoded at a reduced size where the format
+/// allows, otherwise refused instead of running out of memory
+#[derive(Copy, Clone, Debug)]
+pub struct DecodeLimits {
+    /// Of the width and the height
+    pub max_dimension: u32,
+    /// Of the decoded pixels, of all the frames together for animations
+    pub max_bytes: u64,
+    /// Of animations and frame sequences
+    pub max_frames: usize,
+}
+
+impl DecodeLimits {
+    fn image_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_dimension);
+        limits.max_image_height = Some(self.max_dimension);
+        limits.max_alloc = Some(self.max_bytes);
+        limits
+    }
+
+    fn check(&self, width: u32, height: u32, bytes_per_pixel: u64)
+        -> Result<(), String>
+    {
+        if width > self.max_dimension
+            || height > self.max_dimension
+            || width as u64 * height as u64 * bytes_per_pixel > self.max_bytes
+        {
+            return Err(format!(
+                "Image of {}x{} is larger than allowed by --max-image-size \
+and --max-image-memory",
+                width, height
+            ));
+        }
+        Ok(())
+    }
+
+    /// All the frames of an animation are kept in memory, each of them
+    /// at the size of the output once loaded
+    pub fn check_animation(&self, frames: usize, bytes: u64)
+        -> Result<(), String>
+    {
+        if frames > self.max_frames {
+            return Err(format!(
+                "Animation has more than {} frames", self.max_frames
+            ));
+        }
+        if bytes > self.max_bytes {
+            return Err(format!(
+                "Animation of {} frames takes more memory than allowed by \
+--max-image-memory",
+                frames
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub trait Decoder: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn can_decode(&self, format: Format) -> bool;
+
+    /// The target size is only a hint for formats without a native
+    /// resolution, the result is resized to fill the output anyway
+    fn decode(
+        &self,
+        path: &Path,
+        format: Format,
+        target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String>;
+}
+
+pub struct DecoderRegistry {
+    decoders: Vec<Box<dyn Decoder>>,
+    fallback_command: Option<String>,
+    limits: DecodeLimits,
+}
+
+impl DecoderRegistry {
+    pub fn new(
+        decoder_commands: &[DecoderCommand],
+        fallback_command: Option<String>,
+        limits: DecodeLimits,
+    ) -> DecoderRegistry {
+        let mut decoders: Vec<Box<dyn Decoder>> = Vec::new();
+
+        // User configured decoders take precedence over the built-in ones
+        for decoder_command in decoder_commands {
+            decoders.push(Box::new(CommandDecoder {
+                name: format!("command '{}'", decoder_command.command),
+                format: decoder_command.format,
+                command: decoder_command.command.clone(),
+            }));
+        }
+
+        decoders.push(Box::new(JpegDecoder));
+        decoders.push(Box::new(ImageCrateDecoder));
+        decoders.push(Box::new(VideoDecoder));
+        decoders.push(Box::new(ColorDecoder));
+
+        #[cfg(feature = "heif")]
+        decoders.push(Box::new(LibheifDecoder));
+
+        #[cfg(feature = "jxl")]
+        decoders.push(Box::new(JxlOxideDecoder));
+
+        #[cfg(feature = "svg")]
+        decoders.push(Box::new(SvgDecoder));
+
+        #[cfg(feature = "shader")]
+        decoders.push(Box::new(ShaderDecoder));
+
+        DecoderRegistry { decoders, fallback_command, limits }
+    }
+
+    pub fn limits(&self) -> &DecodeLimits {
+        &self.limits
+    }
+
+    pub fn decode(
+        &self,
+        path: &Path,
+        target_size: (u32, u32),
+    ) -> Result<DynamicImage, String> {
+        let error = match self.decode_builtin(path, target_size) {
+            Ok(image) => return Ok(image),
+            Err(e) => e,
+        };
+
+        let Some(fallback_command) = &self.fallback_command else {
+            return Err(error);
+        };
+
+        debug!(
+            "Decoding image {:?} with the fallback decoder after: {}",
+            path, error
+        );
+
+        decode_with_command(fallback_command, path, &self.limits)
+            .map_err(|fallback_error| format!(
+                "{}, fallback decoder also failed: {}", error, fallback_error
+            ))
+    }
+
+    fn decode_builtin(
+        &self,
+        path: &Path,
+        target_size: (u32, u32),
+    ) -> Result<DynamicImage, String> {
+        let format = Format::sniff(path)?
+            .ok_or("Unrecognized image format")?;
+
+        let decoder = self.decoders.iter()
+            .find(|decoder| decoder.can_decode(format))
+            .ok_or_else(|| format!(
+                "No decoder available for image format '{}'", format.name()
+            ))?;
+
+        debug!(
+            "Decoding {} image {:?} with decoder: {}",
+            format.name(), path, decoder.name()
+        );
+
+        decoder.decode(path, format, target_size, &self.limits)
+    }
+}
+
+/// Browsers show frames with shorter delays than this for 100 ms,
+/// many animations rely on it
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+pub const MAX_ANIMATION_FRAMES: usize = 1000;
+
+/// The frames of an animated gif, png or webp image with the delay after
+/// each of them, None for other formats and single frame images
+pub fn decode_animation(path: &Path, limits: &DecodeLimits)
+    -> Result<Option<Vec<(DynamicImage, Duration)>>, String>
+{
+    let Some(Format::Image(image_format)) = Format::sniff(path)? else {
+        return Ok(None);
+    };
+    if !matches!(
+        image_format,
+        ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP
+    ) {
+        return Ok(None);
+    }
+
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to open file: {}", e))?;
+    let reader = BufReader::new(file);
+    let frames = match image_format {
+        ImageFormat::Gif => GifDecoder::new(reader)
+            .and_then(|mut decoder| {
+                decoder.set_limits(limits.image_limits())?;
+                Ok(Some(decoder.into_frames()))
+            }),
+        ImageFormat::Png => PngDecoder::new(reader)
+            .and_then(|mut decoder| {
+                decoder.set_limits(limits.image_limits())?;
+                match decoder.is_apng()? {
+                    true => decoder.apng()
+                        .map(|apng| Some(apng.into_frames())),
+                    false => Ok(None),
+                }
+            }),
+        ImageFormat::WebP => WebPDecoder::new(reader)
+            .and_then(|mut decoder| {
+                decoder.set_limits(limits.image_limits())?;
+                Ok(decoder.has_animation().then(|| decoder.into_frames()))
+            }),
+        _ => unreachable!(),
+    };
+    let Some(frames): Option<Frames> = frames
+        .map_err(|e| format!("Failed to read image: {}", e))?
+    else {
+        return Ok(None);
+    };
+
+    // One by one to stop at the limits before decoding all of them
+    let mut decoded = Vec::new();
+    let mut bytes = 0;
+    for frame in frames {
+        let frame = frame
+            .map_err(|e| format!("Failed to decode animation: {}", e))?;
+        let (width, height) = frame.buffer().dimensions();
+        bytes += width as u64 * height as u64 * 4;
+        decoded.push(frame);
+        limits.check_animation(decoded.len(), bytes)?;
+    }
+    let frames = decoded;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    debug!(
+        "Decoded {} frames of animated {} image {:?}",
+        frames.len(), Format::Image(image_format).name(), path
+    );
+
+    Ok(Some(frames.into_iter()
+        .map(|frame| {
+            let delay = Duration::from(frame.delay());
+            let delay = if delay < MIN_FRAME_DELAY { DEFAULT_FRAME_DELAY }
+                else { delay };
+            (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+        })
+        .collect()
+    ))
+}
+
+struct ImageCrateDecoder;
+
+impl Decoder for ImageCrateDecoder {
+    fn name(&self) -> &str {
+        "image"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        matches!(format, Format::Image(image_format)
+            if image_format.reading_enabled())
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        format: Format,
+        _target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        let Format::Image(image_format) = format else { unreachable!() };
+
+        let mut icc_profile = None;
+        let image = ImageReader::open(path)
+            .map_err(ImageError::IoError)
+            .and_then(|mut reader| {
+                reader.set_format(image_format);
+                reader.limits(limits.image_limits());
+                let mut decoder = reader.into_decoder()?;
+                icc_profile = decoder.icc_profile().ok().flatten();
+                DynamicImage::from_decoder(decoder)
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(convert_to_srgb(image, icc_profile, path))
+    }
+}
+
+/// Decodes large jpeg images directly at a reduced size of 1/2, 1/4 or 1/8
+/// if that still covers the target size, which is much faster and takes
+/// much less memory than decoding at full size only to downscale afterwards
+struct JpegDecoder;
+
+impl Decoder for JpegDecoder {
+    fn name(&self) -> &str {
+        "jpeg-decoder"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Image(ImageFormat::Jpeg)
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        format: Format,
+        target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut decoder = jpeg_decoder::Decoder::new(BufReader::new(file));
+        decoder.read_info()
+            .map_err(|e| format!("Failed to read jpeg header: {}", e))?;
+        let info = decoder.info().unwrap();
+
+        // Leave the uncommon pixel formats to the image crate
+        if !matches!(
+            info.pixel_format,
+            jpeg_decoder::PixelFormat::RGB24 | jpeg_decoder::PixelFormat::L8
+        ) {
+            return ImageCrateDecoder.decode(
+                path, format, target_size, limits
+            );
+        }
+
+        // The size covering the target with the aspect ratio of the image,
+        // or smaller for images over the limits
+        let (target_width, target_height) = target_size;
+        let cover_scale = f64::max(
+            target_width as f64 / info.width as f64,
+            target_height as f64 / info.height as f64,
+        );
+        let bytes_per_pixel = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => 1,
+            _ => 3,
+        };
+        let pixels = info.width as f64 * info.height as f64;
+        let limit_scale = f64::min(
+            limits.max_dimension as f64 / info.width.max(info.height) as f64,
+            (limits.max_bytes as f64 / bytes_per_pixel as f64 / pixels).sqrt(),
+        );
+        let cover_scale = cover_scale.min(limit_scale);
+        let (width, height) = decoder.scale(
+            (info.width as f64 * cover_scale).ceil()
+                .min(u16::MAX as f64) as u16,
+            (info.height as f64 * cover_scale).ceil()
+                .min(u16::MAX as f64) as u16,
+        ).map_err(|e| format!("Failed to scale jpeg: {}", e))?;
+
+        if (width, height) != (info.width, info.height) {
+            debug!(
+                "Decoding jpeg image {:?} scaled from {}x{} to {}x{}",
+                path, info.width, info.height, width, height
+            );
+        }
+        // Still over the limits when even 1/8 scale is too large
+        limits.check(width as u32, height as u32, bytes_per_pixel)?;
+        decoder.set_max_decoding_buffer_size(limits.max_bytes as usize);
+
+        let pixels = decoder.decode()
+            .map_err(|e| format!("Failed to decode jpeg: {}", e))?;
+
+        let icc_profile = decoder.icc_profile();
+        let (width, height) = (width as u32, height as u32);
+        match info.pixel_format {
+            jpeg_decoder::PixelFormat::RGB24 =>
+                image::RgbImage::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgb8),
+            jpeg_decoder::PixelFormat::L8 =>
+                image::GrayImage::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageLuma8),
+            _ => unreachable!(),
+        }
+        .ok_or_else(|| "Decoded jpeg has an unexpected size".to_string())
+        .map(|image| convert_to_srgb(image, icc_profile, path))
+    }
+}
+
+/// Wide gamut images look washed out or oversaturated
+/// if the colors of their embedded profile are shown as sRGB
+fn convert_to_srgb(
+    image: DynamicImage,
+    icc_profile: Option<Vec<u8>>,
+    path: &Path,
+) -> DynamicImage {
+    let Some(icc_profile) = icc_profile else { return image };
+    match IccTransform::to_srgb(&icc_profile) {
+        Ok(Some(transform)) => {
+            debug!(
+                "Converting image {:?} from its color profile to sRGB", path
+            );
+            transform.apply(image)
+        }
+        Ok(None) => image,
+        Err(e) => {
+            warn!(
+                "Ignoring the color profile of image {:?}: {}", path, e
+            );
+            image
+        }
+    }
+}
+
+/// Generates a solid color or a linear gradient at the target size from
+/// a text file of a color, eg. #282828, or of the word gradient, colors
+/// and optionally the angle, eg. gradient #111 #333 45deg, where 0deg goes
+/// upwards and 90deg to the right like in CSS
+struct ColorDecoder;
+
+impl Decoder for ColorDecoder {
+    fn name(&self) -> &str {
+        "color"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Color
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        (width, height): (u32, u32),
+        _limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        let mut words = text.split_whitespace();
+        let first = words.next().ok_or("Empty color file")?;
+        if first != "gradient" {
+            let [red, green, blue] = parse_color(first)?;
+            return Ok(DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+                width, height, image::Rgb([red, green, blue])
+            )));
+        }
+
+        // Top to bottom by default
+        let mut angle: f32 = 180.0;
+        let mut stops = Vec::new();
+        for word in words {
+            match word.strip_suffix("deg") {
+                Some(degrees) => angle = degrees.parse()
+                    .map_err(|_| format!("invalid angle '{}'", word))?,
+                None => stops.push(parse_color(word)?),
+            }
+        }
+        if stops.len() < 2 {
+            return Err("A gradient needs at least two colors".to_string());
+        }
+
+        // Position along the direction of the gradient from 0 at the
+        // corner it starts at to 1 at the opposite corner
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (dx, dy) = (sin, -cos);
+        let half_length = (width as f32 * dx.abs() + height as f32 * dy.abs())
+            / 2.0;
+        let position = |x: u32, y: u32| {
+            let x = x as f32 + 0.5 - width as f32 / 2.0;
+            let y = y as f32 + 0.5 - height as f32 / 2.0;
+            ((x * dx + y * dy) / half_length.max(1.0) + 1.0) / 2.0
+        };
+        // At 16 bits, so gradients can be dithered with --dither
+        let segments = (stops.len() - 1) as f32;
+        Ok(DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(
+            width, height,
+            |x, y| {
+                let position = position(x, y).clamp(0.0, 1.0) * segments;
+                let index = (position as usize).min(stops.len() - 2);
+                let fraction = position - index as f32;
+                let (from, to) = (stops[index], stops[index + 1]);
+                image::Rgb([0, 1, 2].map(|i| {
+                    let value = from[i] as f32 * (1.0 - fraction)
+                        + to[i] as f32 * fraction;
+                    (value * 257.0).round() as u16
+                }))
+            }
+        )))
+    }
+}
+
+/// Renders a shader once at the target size, animated shaders are rendered
+/// frame by frame on a thread of their own while they are visible
+#[cfg(feature = "shader")]
+struct ShaderDecoder;
+
+#[cfg(feature = "shader")]
+impl Decoder for ShaderDecoder {
+    fn name(&self) -> &str {
+        "wgpu"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Shader
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        (width, height): (u32, u32),
+        _limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        let pixels = crate::shader::ShaderRenderer::new(path, width, height)?
+            .render(0.0)?;
+        image::RgbaImage::from_raw(width, height, pixels)
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| "Rendered shader has an unexpected size".to_string())
+    }
+}
+
+/// The first frame of a video as its still wallpaper,
+/// the rest of the frames are streamed from ffmpeg while it is visible
+struct VideoDecoder;
+
+impl Decoder for VideoDecoder {
+    fn name(&self) -> &str {
+        "ffmpeg"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Video
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        _target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        let output = Command::new("ffmpeg")
+            .args(["-v", "error", "-nostdin", "-i"])
+            .arg(path)
+            .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"])
+            .output()
+            .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "ffmpeg failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        load_from_memory(&output.stdout, limits)
+            .map_err(|e| format!("Failed to read the frame from ffmpeg: {}", e))
+    }
+}
+
+/// Runs a user provided shell command with the image path as $1,
+/// the command should write the converted image to its stdout
+/// in any format the image crate can read, eg. png
+struct CommandDecoder {
+    name: String,
+    format: Format,
+    command: String,
+}
+
+impl Decoder for CommandDecoder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        self.format == format
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        _target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        decode_with_command(&self.command, path, limits)
+    }
+}
+
+fn decode_with_command(
+    command: &str,
+    path: &Path,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg(env!("CARGO_PKG_NAME"))
+        .arg(path)
+        .output()
+        .map_err(|e| format!("Failed to run command '{}': {}", command, e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Command '{}' failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    load_from_memory(&output.stdout, limits)
+        .map_err(|e| format!(
+            "Failed to read the output of command '{}': {}", command, e
+        ))
+}
+
+/// The output of external decoders in any format the image crate reads
+fn load_from_memory(data: &[u8], limits: &DecodeLimits)
+    -> ImageResult<DynamicImage>
+{
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?;
+    reader.limits(limits.image_limits());
+    reader.decode()
+}
+
+/// Decodes heif and heic images, eg. photos from phones, with libheif
+#[cfg(feature = "heif")]
+struct LibheifDecoder;
+
+#[cfg(feature = "heif")]
+impl Decoder for LibheifDecoder {
+    fn name(&self) -> &str {
+        "libheif"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Heif
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        _target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+        let lib_heif = LibHeif::new();
+        let path = path.to_str().ok_or("Path is not valid unicode")?;
+        let context = HeifContext::read_from_file(path)
+            .map_err(|e| format!("Failed to read heif image: {}", e))?;
+        let handle = context.primary_image_handle()
+            .map_err(|e| format!("Failed to read heif image: {}", e))?;
+        let (width, height) = (handle.width(), handle.height());
+        let alpha = handle.has_alpha_channel();
+        let channels = if alpha { 4 } else { 3 };
+        limits.check(width, height, channels)?;
+
+        let chroma = if alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+        let image = lib_heif.decode(&handle, ColorSpace::Rgb(chroma), None)
+            .map_err(|e| format!("Failed to decode heif image: {}", e))?;
+        let plane = image.planes().interleaved
+            .ok_or("Decoded heif image has no interleaved plane")?;
+        // Without the padding at the end of the rows
+        let row_len = plane.width as usize * channels as usize;
+        let pixels = plane.data.chunks(plane.stride)
+            .take(plane.height as usize)
+            .flat_map(|row| &row[..row_len])
+            .copied()
+            .collect::<Vec<_>>();
+        let image = match alpha {
+            true => image::RgbaImage::from_raw(
+                plane.width, plane.height, pixels
+            ).map(DynamicImage::ImageRgba8),
+            false => image::RgbImage::from_raw(
+                plane.width, plane.height, pixels
+            ).map(DynamicImage::ImageRgb8),
+        };
+        image.ok_or_else(|| "Decoded heif image has an unexpected size"
+            .to_string())
+    }
+}
+
+/// Decodes jpeg xl images with jxl-oxide
+#[cfg(feature = "jxl")]
+struct JxlOxideDecoder;
+
+#[cfg(feature = "jxl")]
+impl Decoder for JxlOxideDecoder {
+    fn name(&self) -> &str {
+        "jxl-oxide"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Jxl
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        _target_size: (u32, u32),
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        use jxl_oxide::integration::JxlDecoder;
+
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut decoder = JxlDecoder::new(BufReader::new(file))
+            .map_err(|e| format!("Failed to read jpeg xl image: {}", e))?;
+        let (width, height) = decoder.dimensions();
+        limits.check(
+            width, height, decoder.color_type().bytes_per_pixel() as u64
+        )?;
+        let icc_profile = decoder.icc_profile().ok().flatten();
+        let image = DynamicImage::from_decoder(decoder)
+            .map_err(|e| format!("Failed to decode jpeg xl image: {}", e))?;
+        Ok(convert_to_srgb(image, icc_profile, path))
+    }
+}
+
+#[cfg(feature = "svg")]
+struct SvgDecoder;
+
+#[cfg(feature = "svg")]
+impl Decoder for SvgDecoder {
+    fn name(&self) -> &str {
+        "resvg"
+    }
+
+    fn can_decode(&self, format: Format) -> bool {
+        format == Format::Svg
+    }
+
+    fn decode(
+        &self,
+        path: &Path,
+        _format: Format,
+        target_size: (u32, u32),
+        _limits: &DecodeLimits,
+    ) -> Result<DynamicImage, String> {
+        use resvg::{tiny_skia, usvg};
+
+        let data = std::fs::read(path)
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+
+        let options = usvg::Options {
+            resources_dir: path.parent().map(Path::to_path_buf),
+            ..Default::default()
+        };
+        let tree = usvg::Tree::from_data(&data, &options)
+            .map_err(|e| format!("Failed to parse svg: {}", e))?;
+
+        // Render so the image fills the target size,
+        // the usual crop to the output aspect ratio happens afterwards
+        let (target_width, target_height) = target_size;
+        let svg_size = tree.size();
+        let scale = f32::max(
+            target_width as f32 / svg_size.width(),
+            target_height as f32 / svg_size.height(),
+        );
+        let width = (svg_size.width() * scale).ceil() as u32;
+        let height = (svg_size.height() * scale).ceil() as u32;
+
+        let mut pixmap = tiny_skia::Pixmap::new(width, height)
+            .ok_or_else(|| format!(
+                "Invalid svg render size {}x{}", width, height
+            ))?;
+        resvg::render(
+            &tree,
+            tiny_skia::Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut()
+        );
+
+        let pixels = pixmap.pixels().iter()
+            .flat_map(|pixel| {
+                let color = pixel.demultiply();
+                [color.red(), color.green(), color.blue(), color.alpha()]
+            })
+            .collect();
+
+        image::RgbaImage::from_raw(width, height, pixels)
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| "Failed to convert the rendered svg".to_string())
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    cmp::Reverse,
+    env,
+    fs::read_dir,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use smithay_client_toolkit::reexports::client::Connection;
+
+/// Connect to the wayland compositor of the given display, eg. wayland-1
+/// or an absolute socket path, otherwise the one from the environment
+pub fn connect(display: Option<&str>) -> Result<Connection, String> {
+    if let Some(display) = display {
+        let socket_path = display_socket_path(display)?;
+        let conn = connect_to(&socket_path).map_err(|e| format!(
+            "Failed to connect to wayland display {:?}: {}", socket_path, e
+        ))?;
+        info!("Connected to wayland display {:?}", socket_path);
+        return Ok(conn);
+    }
+
+    let error = match Connection::connect_to_env() {
+        Ok(conn) => {
+            match env::var_os("WAYLAND_DISPLAY") {
+                Some(display) if env::var_os("WAYLAND_SOCKET").is_none() =>
+                    info!("Connected to wayland display {:?}", display),
+                _ => info!("Connected to wayland socket from WAYLAND_SOCKET"),
+            }
+            return Ok(conn);
+        }
+        Err(e) => e,
+    };
+
+    // Without WAYLAND_DISPLAY in the environment
+    // look for the socket of a running compositor
+    if env::var_os("WAYLAND_DISPLAY").is_none() {
+        for socket_path in find_wayland_sockets() {
+            if let Ok(conn) = connect_to(&socket_path) {
+                info!(
+                    "WAYLAND_DISPLAY is not set, connected to wayland \
+display {:?}",
+                    socket_path
+                );
+                return Ok(conn);
+            }
+        }
+    }
+
+    Err(format!("Failed to connect to the wayland compositor: {}", error))
+}
+
+fn connect_to(socket_path: &Path) -> Result<Connection, String> {
+    let stream = UnixStream::connect(socket_path)
+        .map_err(|e| e.to_string())?;
+    Connection::from_socket(stream).map_err(|e| e.to_string())
+}
+
+// Relative display names are in XDG_RUNTIME_DIR like WAYLAND_DISPLAY
+fn display_socket_path(display: &str) -> Result<PathBuf, String> {
+    let path = Path::new(display);
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| format!(
+        "XDG_RUNTIME_DIR is not set, cannot find wayland display '{}'",
+        display
+    ))?;
+    Ok(Path::new(&runtime_dir).join(path))
+}
+
+// Wayland sockets are named wayland-<n> with a wayland-<n>.lock file
+// next to them, try the most recently created first
+fn find_wayland_sockets() -> Vec<PathBuf> {
+    let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") else {
+        return Vec::new();
+    };
+    let Ok(dir) = read_dir(runtime_dir) else {
+        return Vec::new();
+    };
+    let mut sockets = dir.filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name|
+            name.starts_with("wayland-") && !name.ends_with(".lock")
+        ))
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_socket()))
+        .filter_map(|entry| Some((
+            entry.metadata().and_then(|m| m.modified()).ok()?,
+            entry.path()
+        )))
+        .collect::<Vec<_>>();
+    sockets.sort_unstable_by_key(|(modified, _)| Reverse(*modified));
+    sockets.into_iter().map(|(_, path)| path).collect()
+}
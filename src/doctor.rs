@@ -0,0 +1,234 @@
+use std::{
+    env,
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::PathBuf,
+};
+
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    cli::Cli,
+    paths::Paths,
+    probe::probe,
+    sway::{compositor_version, find_sway_sockets},
+};
+
+/// Wayland globals multibg-sway uses, whether it needs them
+/// and what happens without them
+const GLOBALS: &[(&str, bool, &str)] = &[
+    (
+        "zwlr_layer_shell_v1",
+        true,
+        "multibg-sway shows the wallpapers on the background layer of the \
+wlr layer shell, use a compositor that supports it, eg. sway",
+    ),
+    (
+        "wp_viewporter",
+        false,
+        "fractionally scaled outputs get wallpapers at the next integer \
+scale, scaled down by the compositor",
+    ),
+    (
+        "wp_fractional_scale_manager_v1",
+        false,
+        "the compositor does not tell fractional scales, the scale of the \
+output is used",
+    ),
+    (
+        "ext_idle_notifier_v1",
+        false,
+        "--idle-precompute cannot tell when the user is idle",
+    ),
+];
+
+/// Checks the environment multibg-sway runs in and prints what it finds
+/// with advice for the problems, an error if multibg-sway cannot run
+pub fn doctor(cli: &Cli) -> Result<(), String> {
+    let mut doctor = Doctor { problems: 0 };
+
+    println!("Environment:");
+    for var in [
+        "XDG_RUNTIME_DIR", "WAYLAND_DISPLAY", "SWAYSOCK", "XDG_CURRENT_DESKTOP"
+    ] {
+        match env::var(var) {
+            Ok(value) => println!("  {}={}", var, value),
+            Err(_) => println!("  {} is not set", var),
+        }
+    }
+    if env::var_os("XDG_RUNTIME_DIR").is_none() {
+        doctor.problem(
+            "XDG_RUNTIME_DIR is not set",
+            "start multibg-sway in the session of the compositor, eg. with \
+exec in the sway config, or for a systemd user service import the \
+environment with: systemctl --user import-environment",
+        );
+    }
+
+    println!("Sway IPC:");
+    match compositor_version() {
+        Ok(version) => {
+            let swaysock = env::var_os("SWAYSOCK")
+                .filter(|path| UnixStream::connect(path).is_ok());
+            match swaysock {
+                Some(path) =>
+                    println!("  {} at {:?} from SWAYSOCK", version, path),
+                None => println!(
+                    "  {} at {:?} found in XDG_RUNTIME_DIR",
+                    version,
+                    find_sway_sockets().first().cloned().unwrap_or_default()
+                ),
+            }
+        }
+        Err(e) => doctor.problem(
+            &e,
+            "multibg-sway follows the visible workspaces through the sway \
+IPC, it needs sway or a compositor implementing its IPC",
+        ),
+    }
+
+    println!("Wayland:");
+    match probe(cli.display.as_deref()) {
+        Ok(probe) => {
+            for &(interface, needed, consequence) in GLOBALS {
+                let global = probe.globals.iter()
+                    .find(|(global, _)| global == interface);
+                match global {
+                    Some((_, version)) =>
+                        println!("  {} version {}", interface, version),
+                    None if needed => doctor.problem(
+                        &format!("{} is not available", interface),
+                        consequence,
+                    ),
+                    None => doctor.note(
+                        &format!("{} is not available", interface),
+                        consequence,
+                    ),
+                }
+            }
+
+            let formats = probe.formats.iter()
+                .map(|format| format!("{:?}", format))
+                .collect::<Vec<_>>();
+            println!("  shm formats: {}", formats.join(", "));
+            if !probe.formats.contains(&wl_shm::Format::Bgr888) {
+                doctor.note(
+                    "Bgr888 is not offered",
+                    "wallpapers take 4 bytes per pixel instead of 3",
+                );
+            }
+            if !probe.formats.contains(&wl_shm::Format::Rgb565) {
+                doctor.note(
+                    "Rgb565 is not offered",
+                    "--pixelformat=rgb565 falls back to the default",
+                );
+            }
+
+            for info in &probe.outputs {
+                let mode = info.modes.iter().find(|mode| mode.current);
+                println!(
+                    "  output {}: {} {}, {}, scale {}",
+                    info.name.as_deref().unwrap_or("without a name"),
+                    info.make,
+                    info.model,
+                    mode.map_or("no current mode".to_string(), |mode|
+                        format!("{}x{}", mode.dimensions.0, mode.dimensions.1)
+                    ),
+                    info.scale_factor,
+                );
+            }
+            if probe.outputs.is_empty() {
+                doctor.note(
+                    "No outputs",
+                    "wallpapers are shown once outputs are connected",
+                );
+            }
+        }
+        Err(e) => doctor.problem(
+            &e,
+            "run multibg-sway in the session of the compositor, or set \
+WAYLAND_DISPLAY to its display, eg. wayland-1",
+        ),
+    }
+
+    println!("Decoders:");
+    match find_program("ffmpeg") {
+        Some(path) => println!("  ffmpeg at {:?}", path),
+        None => doctor.note(
+            "ffmpeg is not found", "videos cannot be shown as wallpapers"
+        ),
+    }
+
+    if !cfg!(feature = "avif") {
+        doctor.note(
+            "Built without the avif feature",
+            "avif images need a --decoder command",
+        );
+    }
+    if !cfg!(feature = "jxl") {
+        doctor.note(
+            "Built without the jxl feature",
+            "jpeg xl images need a --decoder command, eg. with djxl",
+        );
+    }
+    if !cfg!(feature = "heif") {
+        doctor.note(
+            "Built without the heif feature",
+            "heif and heic images need a --decoder command",
+        );
+    }
+
+    println!("multibg-sway:");
+    match Paths::new(cli) {
+        Ok(paths) => {
+            let control_socket = paths.control_socket();
+            if UnixStream::connect(&control_socket).is_ok() {
+                println!(
+                    "  running, control socket at {:?}", control_socket
+                );
+            } else {
+                println!(
+                    "  not running, no control socket at {:?}",
+                    control_socket
+                );
+            }
+        }
+        Err(e) => doctor.problem(&e, "set XDG_RUNTIME_DIR or USER"),
+    }
+
+    match doctor.problems {
+        0 => Ok(()),
+        problems => Err(format!(
+            "Found {} problems, multibg-sway cannot run", problems
+        )),
+    }
+}
+
+/// The executable in the PATH, like the decoders are run
+fn find_program(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.metadata()
+            .is_ok_and(|metadata| metadata.is_file()
+                && metadata.permissions().mode() & 0o111 != 0
+            )
+        )
+}
+
+struct Doctor {
+    problems: usize,
+}
+
+impl Doctor {
+    /// Stops multibg-sway from running
+    fn problem(&mut self, problem: &str, advice: &str) {
+        println!("  problem: {}", problem);
+        println!("    {}", advice);
+        self.problems += 1;
+    }
+
+    /// Works with limitations
+    fn note(&self, note: &str, advice: &str) {
+        println!("  note: {}", note);
+        println!("    {}", advice);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    slice,
+};
+
+use log::warn;
+
+use crate::{
+    image_settings,
+    cli::Cli,
+    image::{
+        ImageTarget, WallpaperDirs, buffer_stride, global_default_paths,
+        output_image_dir_keys, resolution_dir,
+    },
+    overrides::load_overrides,
+    paths::Paths,
+    prepare::{active_profile_dir, output_jobs},
+    probe::probe,
+    store::WallpaperKey,
+};
+
+/// Prints how much shm memory the wallpapers of the connected outputs
+/// would take if all of them were loaded, without loading any of them
+pub fn estimate(cli: &Cli, wallpaper_dir: &Path) -> Result<(), String> {
+    if !cli.span.is_empty() {
+        warn!("Spanned wallpapers are not estimated");
+    }
+    let probe = probe(cli.display.as_deref())?;
+    let profile_dir = active_profile_dir(cli, wallpaper_dir, &probe.outputs);
+
+    let settings = image_settings(cli);
+    let overrides = load_overrides(&Paths::new(cli)?.overrides_file());
+    let mut all_keys: HashSet<WallpaperKey> = HashSet::new();
+    let mut output_total = 0;
+    let jobs = output_jobs(cli, &probe, slice::from_ref(&profile_dir));
+    if jobs.is_empty() {
+        return Err("No outputs with wallpapers to estimate".to_string());
+    }
+    for job in jobs {
+        let output = job.output.unwrap_or_default();
+        let target = ImageTarget {
+            width: job.width,
+            height: job.height,
+            span_area: None,
+        };
+        let dirs = WallpaperDirs {
+            candidate_sets: job.dir != profile_dir,
+            dir: resolution_dir(&job.dir, job.width, job.height)
+                .unwrap_or(job.dir),
+            fallbacks: global_default_paths(&profile_dir),
+            overrides: overrides.iter()
+                .filter(|wallpaper_override|
+                    wallpaper_override.output == output
+                )
+                .map(|wallpaper_override| (
+                    wallpaper_override.workspace.clone(),
+                    wallpaper_override.path.clone(),
+                ))
+                .collect(),
+            slideshows: cli.slideshow.is_some() || cli.random.is_some(),
+        };
+        // Outputs of the same size share the same wallpapers
+        let keys = output_image_dir_keys(
+            &dirs, &settings, job.format, &target
+        )?.into_iter().collect::<HashSet<_>>();
+        let bytes = keys.iter().map(wallpaper_bytes).sum::<usize>();
+        println!(
+            "{}: {} wallpapers from {:?} at {}x{}, {} MiB",
+            output, keys.len(), dirs.dir, job.width, job.height,
+            mebibytes(bytes)
+        );
+        output_total += bytes;
+        all_keys.extend(keys);
+    }
+
+    let total = all_keys.iter().map(wallpaper_bytes).sum::<usize>();
+    println!(
+        "Total: {} wallpapers, {} MiB, {} MiB saved by sharing them \
+between outputs",
+        all_keys.len(), mebibytes(total), mebibytes(output_total - total)
+    );
+    if let Some(max_memory) = cli.max_memory {
+        if mebibytes(total) > max_memory {
+            println!(
+                "Over --max-memory={}, the least recently shown wallpapers \
+are evicted and loaded again when shown",
+                max_memory
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The size of its buffer, animations counted as a single frame
+fn wallpaper_bytes(key: &WallpaperKey) -> usize {
+    buffer_stride(key.width(), key.format()) * key.height() as usize
+}
+
+/// Rounded up
+fn mebibytes(bytes: usize) -> usize {
+    bytes.div_ceil(1 << 20)
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::Path,
+    sync::{Arc, mpsc::Sender},
+    thread::{sleep, spawn},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info};
+use mio::Waker;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    json::to_json,
+    sway::{ReplayedWorkspaces, WorkspaceVisible},
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LoggedEvent {
+    WorkspaceVisible {
+        output: String,
+        workspace_name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        layout: Option<String>,
+        #[serde(default)]
+        focused: bool,
+    },
+    NewOutput { output: String },
+    UpdateOutput { output: String },
+    OutputDestroyed { output: String },
+}
+
+/// One line of the event log
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// Milliseconds since the unix epoch
+    time: u64,
+    #[serde(flatten)]
+    event: LoggedEvent,
+}
+
+/// Writes events as JSON lines with timestamps for --record-events
+pub struct EventRecorder {
+    writer: LineWriter<File>,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path) -> Result<EventRecorder, String> {
+        let file = File::create(path).map_err(|e| format!(
+            "Failed to create event log file {:?}: {}", path, e
+        ))?;
+        Ok(EventRecorder { writer: LineWriter::new(file) })
+    }
+
+    /// Another recorder appending to the same file,
+    /// used by the state rebuilt after reconnecting to the compositor
+    pub fn try_clone(&self) -> Result<EventRecorder, String> {
+        let file = self.writer.get_ref().try_clone()
+            .map_err(|e| format!("Failed to clone event log file: {}", e))?;
+        Ok(EventRecorder { writer: LineWriter::new(file) })
+    }
+
+    pub fn record(&mut self, event: LoggedEvent) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .unwrap_or_default().as_millis() as u64;
+        let line = to_json(&Record { time, event });
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            error!("Failed to write event log: {}", e);
+        }
+    }
+}
+
+/// Sends the workspace events of a recorded event log to the main event loop
+/// with their original timing instead of the live events from sway,
+/// keeping the visible workspaces in `replayed` to answer the requests
+/// of the main event loop. Output events come from the compositor as usual,
+/// the recorded ones are only logged to help comparing the two
+pub fn spawn_replay(
+    file: File,
+    path: &Path,
+    tx: Sender<WorkspaceVisible>,
+    waker: Arc<Waker>,
+    replayed: ReplayedWorkspaces,
+) {
+    let path = path.to_path_buf();
+    spawn(move || {
+        let mut last_time = None;
+        for (index, line_result) in BufReader::new(file).lines().enumerate() {
+            let line = match line_result {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Failed to read event log {:?}: {}", path, e);
+                    return;
+                }
+            };
+            if line.trim().is_empty() { continue }
+            let record: Record = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    error!(
+                        "Skipping invalid line {} of event log {:?}: {}",
+                        index + 1, path, e
+                    );
+                    continue;
+                }
+            };
+            if let Some(last_time) = last_time {
+                sleep(Duration::from_millis(
+                    record.time.saturating_sub(last_time)
+                ));
+            }
+            last_time = Some(record.time);
+            match record.event {
+                LoggedEvent::WorkspaceVisible {
+                    output, workspace_name, layout, focused
+                } => {
+                    debug!(
+                        "Replaying workspace '{}' on output '{}'",
+                        workspace_name, output
+                    );
+                    let workspace = WorkspaceVisible {
+                        output, workspace_name, layout, focused
+                    };
+                    {
+                        let mut replayed = replayed.lock().unwrap();
+                        if focused {
+                            for other in replayed.values_mut() {
+                                other.focused = false;
+                            }
+                        }
+                        replayed.insert(
+                            workspace.output.clone(), workspace.clone()
+                        );
+                    }
+                    if tx.send(workspace).is_err() {
+                        debug!("Main event loop is gone, stopping replay");
+                        return;
+                    }
+                    waker.wake().unwrap();
+                }
+                LoggedEvent::NewOutput { output } =>
+                    info!("Replay: recorded new output '{}'", output),
+                LoggedEvent::UpdateOutput { output } =>
+                    info!("Replay: recorded updated output '{}'", output),
+                LoggedEvent::OutputDestroyed { output } =>
+                    info!("Replay: recorded destroyed output '{}'", output),
+            }
+        }
+        info!("Replay of event log {:?} finished", path);
+    });
+}
+
+pub fn open_replay(path: &Path) -> Result<File, String> {
+    File::open(path).map_err(|e| format!(
+        "Failed to open event log file {:?}: {}", path, e
+    ))
+}
@@ -0,0 +1,109 @@
+/// Shell style wildcard matching, * matches any characters including none,
+/// ? matches exactly one character, [a-c] one of the characters in the
+/// class and [!a-c] one not in it, everything else matches itself
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Where to continue after the last * if the rest fails to match
+    let mut backtrack = None;
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('[') => match match_class(&pattern[p..], text[t]) {
+                Some((true, len)) => Some(p + len),
+                Some((false, _)) => None,
+                // Without a closing bracket it matches itself
+                None => (text[t] == '[').then_some(p + 1),
+            },
+            Some(&c) if c == '?' || c == text[t] => Some(p + 1),
+            _ => None,
+        };
+        match (next, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            // Let the last * swallow one more character
+            (None, Some((star_p, star_t))) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether the character is in the class at the start of the pattern and
+/// the length of the class, None if the class is not closed
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    // A ] right after the opening bracket is part of the class
+    let mut first = true;
+    loop {
+        match pattern.get(i)? {
+            ']' if !first => return Some((matched != negated, i + 1)),
+            &start => {
+                if pattern.get(i + 1) == Some(&'-')
+                    && pattern.get(i + 2).is_some_and(|&end| end != ']')
+                {
+                    matched |= (start..=pattern[i + 2]).contains(&c);
+                    i += 3;
+                }
+                else {
+                    matched |= start == c;
+                    i += 1;
+                }
+            }
+        }
+        first = false;
+    }
+}
+
+/// Whether the text has any wildcards or classes
+pub fn is_glob(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+/// How specific a pattern is, the number of characters matching
+/// only themselves, a class or ? counts less than a literal character
+pub fn glob_specificity(pattern: &str) -> usize {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let mut specificity = 0;
+    let mut p = 0;
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => p += 1,
+            '?' => {
+                specificity += 1;
+                p += 1;
+            }
+            '[' => match match_class(&pattern[p..], '\0') {
+                Some((_, len)) => {
+                    specificity += 1;
+                    p += len;
+                }
+                None => {
+                    specificity += 2;
+                    p += 1;
+                }
+            },
+            _ => {
+                specificity += 2;
+                p += 1;
+            }
+        }
+    }
+    specificity
+}
@@ -0,0 +1,85 @@
+use std::{
+    process::Command,
+    sync::mpsc::{channel, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::control::WallpaperChange;
+
+/// The hook runs at most this often, the changes meanwhile are coalesced
+/// to the last one of each output
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs the command of --on-change on a thread of its own
+/// for the wallpaper changes sent to it
+pub struct ChangeHook {
+    tx: Sender<WallpaperChange>,
+}
+
+impl ChangeHook {
+    pub fn spawn(command: &str) -> ChangeHook {
+        let (tx, rx) = channel::<WallpaperChange>();
+        let command = command.to_string();
+        thread::spawn(move || {
+            let mut last_run: Option<Instant> = None;
+            // Until the sender is dropped
+            while let Ok(change) = rx.recv() {
+                let mut pending = vec![change];
+                if let Some(last_run) = last_run {
+                    let deadline = last_run + MIN_INTERVAL;
+                    loop {
+                        let timeout =
+                            deadline.saturating_duration_since(Instant::now());
+                        match rx.recv_timeout(timeout) {
+                            Ok(change) => {
+                                pending.retain(|pending|
+                                    pending.output != change.output
+                                );
+                                pending.push(change);
+                            }
+                            // Still run the pending ones when disconnected,
+                            // the loop ends after
+                            Err(_) => break,
+                        }
+                    }
+                }
+                for change in &pending {
+                    run(&command, change);
+                }
+                last_run = Some(Instant::now());
+            }
+        });
+        ChangeHook { tx }
+    }
+
+    pub fn send(&self, changes: Vec<WallpaperChange>) {
+        for change in changes {
+            // The thread only ends with the sender
+            self.tx.send(change).unwrap();
+        }
+    }
+}
+
+fn run(command: &str, change: &WallpaperChange) {
+    debug!(
+        "Running --on-change for workspace '{}' on output '{}'",
+        change.workspace, change.output
+    );
+    let result = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("MULTIBG_OUTPUT", &change.output)
+        .env("MULTIBG_WORKSPACE", &change.workspace)
+        .env("MULTIBG_IMAGE", &change.path)
+        .status();
+    match result {
+        Ok(status) if !status.success() => {
+            warn!("Command '{}' failed with {}", command, status);
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to run command '{}': {}", command, e),
+    }
+}
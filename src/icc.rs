@@ -0,0 +1,261 @@
+use image::DynamicImage;
+
+/// Linear sRGB from the D50 adapted XYZ connection space of ICC profiles
+const XYZ_D50_TO_LINEAR_SRGB: [[f32; 3]; 3] = [
+    [3.133_856, -1.616_867, -0.490_615],
+    [-0.978_768, 1.916_142, 0.033_454],
+    [0.071_945, -0.228_991, 1.405_243],
+];
+
+/// Resolution of the table encoding linear values back to sRGB
+const ENCODE_STEPS: usize = 4096;
+
+/// Conversion of 8 bit pixels from an RGB matrix/TRC profile to sRGB,
+/// the kind of profile used by virtually all exported wide gamut images,
+/// eg. Display P3 or Adobe RGB
+pub struct IccTransform {
+    /// Linear values of 8 bit input for each channel
+    decode: [[f32; 256]; 3],
+    /// From linear profile RGB to linear sRGB
+    matrix: [[f32; 3]; 3],
+    encode: Vec<u8>,
+}
+
+impl IccTransform {
+    /// None if the profile is already sRGB, within rounding
+    pub fn to_srgb(profile: &[u8]) -> Result<Option<IccTransform>, String> {
+        let profile = Profile::parse(profile)?;
+        if profile.header_field(16) != *b"RGB " {
+            return Err("Only RGB profiles are supported".to_string());
+        }
+        if profile.header_field(20) != *b"XYZ " {
+            return Err("Only XYZ connection space is supported".to_string());
+        }
+
+        let columns = [b"rXYZ", b"gXYZ", b"bXYZ"]
+            .map(|signature| profile.xyz(signature));
+        let curves = [b"rTRC", b"gTRC", b"bTRC"]
+            .map(|signature| profile.curve(signature));
+        let mut matrix = [[0.0; 3]; 3];
+        for (column_index, column) in columns.into_iter().enumerate() {
+            let column = column?;
+            for row in 0..3 {
+                matrix[row][column_index] = (0..3)
+                    .map(|i| XYZ_D50_TO_LINEAR_SRGB[row][i] * column[i])
+                    .sum();
+            }
+        }
+        let mut decode = [[0.0; 256]; 3];
+        for (channel, curve) in curves.into_iter().enumerate() {
+            let curve = curve?;
+            for (value, linear) in decode[channel].iter_mut().enumerate() {
+                *linear = curve.eval(value as f32 / 255.0);
+            }
+        }
+
+        let is_identity = matrix.iter().enumerate().all(|(row, values)|
+            values.iter().enumerate().all(|(column, value)|
+                (value - if row == column { 1.0 } else { 0.0 }).abs() < 0.002
+            )
+        );
+        let is_srgb_curve = decode.iter().all(|channel|
+            channel.iter().enumerate().all(|(value, linear)|
+                (linear - srgb_to_linear(value as f32 / 255.0)).abs() < 0.002
+            )
+        );
+        if is_identity && is_srgb_curve {
+            return Ok(None);
+        }
+
+        let encode = (0..ENCODE_STEPS)
+            .map(|step| {
+                let linear = step as f32 / (ENCODE_STEPS - 1) as f32;
+                (linear_to_srgb(linear) * 255.0).round() as u8
+            })
+            .collect();
+        Ok(Some(IccTransform { decode, matrix, encode }))
+    }
+
+    /// Converts the image to 8 bits per channel, keeping its alpha channel
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        if image.color().has_alpha() {
+            let mut image = image.into_rgba8();
+            for pixel in image.pixels_mut() {
+                self.convert(&mut pixel.0[..3]);
+            }
+            DynamicImage::ImageRgba8(image)
+        } else {
+            let mut image = image.into_rgb8();
+            for pixel in image.pixels_mut() {
+                self.convert(&mut pixel.0);
+            }
+            DynamicImage::ImageRgb8(image)
+        }
+    }
+
+    fn convert(&self, rgb: &mut [u8]) {
+        let linear = [0, 1, 2].map(|i| self.decode[i][rgb[i] as usize]);
+        for (value, row) in rgb.iter_mut().zip(&self.matrix) {
+            let linear = row[0] * linear[0] + row[1] * linear[1]
+                + row[2] * linear[2];
+            let step = (linear.clamp(0.0, 1.0) * (ENCODE_STEPS - 1) as f32)
+                .round() as usize;
+            *value = self.encode[step];
+        }
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 { value / 12.92 }
+    else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 { value * 12.92 }
+    else { 1.055 * value.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Tone reproduction curve of a channel
+enum Curve {
+    Gamma(f32),
+    Table(Vec<f32>),
+    /// Parametric curve of ICC function types 0 to 4 as
+    /// g, a, b, c, d, e, f
+    Parametric([f32; 7]),
+}
+
+impl Curve {
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            Curve::Gamma(gamma) => x.powf(*gamma),
+            Curve::Table(table) => {
+                let position = x * (table.len() - 1) as f32;
+                let index = (position.floor() as usize).min(table.len() - 2);
+                let fraction = position - index as f32;
+                table[index] * (1.0 - fraction) + table[index + 1] * fraction
+            }
+            Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= *d { (a * x + b).max(0.0).powf(*g) + e }
+                else { c * x + f }
+            }
+        }
+    }
+}
+
+struct Profile<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Profile<'a> {
+    fn parse(data: &'a [u8]) -> Result<Profile<'a>, String> {
+        if data.len() < 132 || &data[36..40] != b"acsp" {
+            return Err("Not an ICC profile".to_string());
+        }
+        Ok(Profile { data })
+    }
+
+    fn header_field(&self, offset: usize) -> [u8; 4] {
+        self.data[offset..offset + 4].try_into().unwrap()
+    }
+
+    fn tag(&self, signature: &[u8; 4]) -> Result<&'a [u8], String> {
+        let count = read_u32(self.data, 128)
+            .ok_or("Truncated tag table")? as usize;
+        for index in 0..count {
+            let entry = 132 + index * 12;
+            let Some(entry_data) = self.data.get(entry..entry + 12) else {
+                break;
+            };
+            if &entry_data[..4] != signature {
+                continue;
+            }
+            let offset = read_u32(entry_data, 4).unwrap() as usize;
+            let size = read_u32(entry_data, 8).unwrap() as usize;
+            return self.data.get(offset..offset.saturating_add(size))
+                .ok_or_else(|| format!(
+                    "Truncated tag {}", String::from_utf8_lossy(signature)
+                ));
+        }
+        Err(format!(
+            "Missing tag {}, only matrix profiles are supported",
+            String::from_utf8_lossy(signature)
+        ))
+    }
+
+    fn xyz(&self, signature: &[u8; 4]) -> Result<[f32; 3], String> {
+        let tag = self.tag(signature)?;
+        if tag.get(..4) != Some(b"XYZ ") {
+            return Err("Unexpected XYZ tag type".to_string());
+        }
+        let value = |offset| read_s15_fixed16(tag, offset)
+            .ok_or("Truncated XYZ tag");
+        Ok([value(8)?, value(12)?, value(16)?])
+    }
+
+    fn curve(&self, signature: &[u8; 4]) -> Result<Curve, String> {
+        let tag = self.tag(signature)?;
+        let truncated = || "Truncated curve tag".to_string();
+        match tag.get(..4) {
+            Some(b"curv") => {
+                let count = read_u32(tag, 8).ok_or_else(truncated)? as usize;
+                match count {
+                    0 => Ok(Curve::Gamma(1.0)),
+                    1 => {
+                        let gamma = read_u16(tag, 12).ok_or_else(truncated)?;
+                        Ok(Curve::Gamma(gamma as f32 / 256.0))
+                    }
+                    _ => (0..count)
+                        .map(|i| read_u16(tag, 12 + i * 2)
+                            .map(|value| value as f32 / 65535.0)
+                            .ok_or_else(truncated)
+                        )
+                        .collect::<Result<_, _>>()
+                        .map(Curve::Table),
+                }
+            }
+            Some(b"para") => {
+                let function = read_u16(tag, 8).ok_or_else(truncated)?;
+                let param_count = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => return Err(format!(
+                        "Unknown parametric curve type {}", function
+                    )),
+                };
+                let mut params = [0.0; 7];
+                for (i, param) in params.iter_mut().take(param_count)
+                    .enumerate()
+                {
+                    *param = read_s15_fixed16(tag, 12 + i * 4)
+                        .ok_or_else(truncated)?;
+                }
+                let [g, a, b, c, d, e, f] = params;
+                // All types as the most general type 4
+                Ok(Curve::Parametric(match function {
+                    0 => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                    1 => [g, a, b, 0.0, -b / a, 0.0, 0.0],
+                    2 => [g, a, b, 0.0, -b / a, c, c],
+                    3 => [g, a, b, c, d, 0.0, 0.0],
+                    _ => [g, a, b, c, d, e, f],
+                }))
+            }
+            _ => Err("Unexpected curve tag type".to_string()),
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_s15_fixed16(data: &[u8], offset: usize) -> Option<f32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(i32::from_be_bytes(bytes) as f32 / 65536.0)
+}
@@ -1,35 +1,545 @@
 use std::{
-    fs::read_dir,
-    path::Path,
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, read_dir},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
+use clap::ValueEnum;
 use fast_image_resize::{
     FilterType, PixelType, Resizer, ResizeAlg, ResizeOptions,
     images::Image,
 };
-use image::{ImageBuffer, ImageError, ImageReader, Rgb};
+use image::{DynamicImage, ImageFormat, RgbImage, RgbaImage};
 use log::{debug, error};
-use smithay_client_toolkit::shm::slot::{Buffer, SlotPool};
+use smithay_client_toolkit::{
+    shell::wlr_layer::Anchor,
+    shm::slot::{Buffer, SlotPool},
+};
 use smithay_client_toolkit::reexports::client::protocol::wl_shm;
 
-use crate::wayland::WorkspaceBackground;
+use crate::{
+    cache::DiskCache,
+    cli::{
+        BarShade, ColorFilter, Gravity, ResizeFilter, Scaling, ToneMap,
+        WorkspaceFilter, parse_gravity,
+    },
+    decoder::{DecoderRegistry, Format, decode_animation},
+    glob::glob_match,
+    icc::linear_to_srgb,
+    stats::DECODE_COUNTERS,
+    store::{Frame, Wallpaper, WallpaperKey, WallpaperStore},
+    swizzle::bgrx_from_rgb,
+    video::VideoSource,
+    wayland::WorkspaceBackground,
+};
+
+pub struct ColorTransform {
+    pub brightness: i32,
+    pub contrast: f32,
+    /// Percent added to the saturation, -100 makes the image grayscale
+    pub saturation: f32,
+    /// Degrees
+    pub hue_rotate: i32,
+    /// Above 1 brightens the darker tones, below 1 darkens them
+    pub gamma: f32,
+}
+
+impl ColorTransform {
+    fn is_identity(&self) -> bool {
+        self.contrast == 0.0 && self.brightness == 0
+            && self.saturation == 0.0 && self.hue_rotate % 360 == 0
+            && self.gamma == 1.0
+    }
+
+    fn apply(&self, mut image: DynamicImage) -> DynamicImage {
+        if self.contrast != 0.0 {
+            image = image.adjust_contrast(self.contrast)
+        }
+        if self.brightness != 0 {
+            image = image.brighten(self.brightness)
+        }
+        if self.saturation != 0.0 {
+            let factor = (1.0 + self.saturation / 100.0).max(0.0);
+            image = map_rgb(image, |rgb| {
+                let luma = 0.2126 * rgb[0] as f32 + 0.7152 * rgb[1] as f32
+                    + 0.0722 * rgb[2] as f32;
+                for value in rgb {
+                    *value = (luma + (*value as f32 - luma) * factor)
+                        .round().clamp(0.0, 255.0) as u8;
+                }
+            });
+        }
+        if self.hue_rotate % 360 != 0 {
+            image = image.huerotate(self.hue_rotate)
+        }
+        if self.gamma != 1.0 {
+            let table: [u8; 256] = std::array::from_fn(|value|
+                ((value as f32 / 255.0).powf(1.0 / self.gamma) * 255.0)
+                    .round() as u8
+            );
+            image = map_rgb(image, |rgb| {
+                for value in rgb {
+                    *value = table[*value as usize];
+                }
+            });
+        }
+        image
+    }
+}
+
+impl ColorFilter {
+    fn apply(self, image: DynamicImage) -> DynamicImage {
+        match self {
+            ColorFilter::None => image,
+            ColorFilter::Grayscale => map_rgb(image, |rgb| {
+                let luma = 0.2126 * rgb[0] as f32 + 0.7152 * rgb[1] as f32
+                    + 0.0722 * rgb[2] as f32;
+                rgb.fill(luma.round() as u8);
+            }),
+            ColorFilter::Sepia => map_rgb(image, |rgb| {
+                let [red, green, blue] = [rgb[0], rgb[1], rgb[2]]
+                    .map(|value| value as f32);
+                for (value, [r, g, b]) in rgb.iter_mut().zip([
+                    [0.393, 0.769, 0.189],
+                    [0.349, 0.686, 0.168],
+                    [0.272, 0.534, 0.131],
+                ]) {
+                    *value = (r * red + g * green + b * blue)
+                        .round().min(255.0) as u8;
+                }
+            }),
+        }
+    }
+}
+
+/// Converts the image to 8 bits per channel and changes the color
+/// of each pixel, keeping its alpha channel
+fn map_rgb(image: DynamicImage, f: impl Fn(&mut [u8])) -> DynamicImage {
+    if image.color().has_alpha() {
+        let mut image = image.into_rgba8();
+        for pixel in image.pixels_mut() {
+            f(&mut pixel.0[..3]);
+        }
+        DynamicImage::ImageRgba8(image)
+    } else {
+        let mut image = image.into_rgb8();
+        for pixel in image.pixels_mut() {
+            f(&mut pixel.0);
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+}
+
+/// Size of the output surface and the part of the image to show on it
+#[derive(Clone)]
+pub struct ImageTarget {
+    pub width: u32,
+    pub height: u32,
+    pub span_area: Option<SpanArea>,
+}
+
+impl ImageTarget {
+    /// The size the whole image is shown at, larger than the target
+    /// if the image is spanned across multiple outputs
+    pub fn image_size(&self) -> (u32, u32) {
+        match &self.span_area {
+            Some(span_area) => (
+                (self.width as f64 * span_area.total_width / span_area.width)
+                    .ceil() as u32,
+                (self.height as f64 * span_area.total_height
+                    / span_area.height).ceil() as u32,
+            ),
+            None => (self.width, self.height),
+        }
+    }
+}
+
+/// Logical geometry of an output within the area of multiple outputs
+/// that one wallpaper image spans
+#[derive(Clone)]
+pub struct SpanArea {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub total_width: f64,
+    pub total_height: f64,
+}
+
+impl SpanArea {
+    /// The image fills the whole spanned area cropped evenly on both sides,
+    /// returns left, top, width and height of this output's part in pixels
+    fn crop(&self, image_width: u32, image_height: u32)
+        -> (f64, f64, f64, f64)
+    {
+        let image_width = image_width as f64;
+        let image_height = image_height as f64;
+        let scale = f64::min(
+            image_width / self.total_width,
+            image_height / self.total_height,
+        );
+        let offset_x = (image_width - self.total_width * scale) / 2.0;
+        let offset_y = (image_height - self.total_height * scale) / 2.0;
+        (
+            offset_x + self.x * scale,
+            offset_y + self.y * scale,
+            self.width * scale,
+            self.height * scale,
+        )
+    }
+}
+
+// Align Bgr888 buffer stride to both 4 and pixel format block size
+// Not being aligned to 4 caused
+// https://github.com/gergo-salyi/multibg-sway/issues/6
+const BUFFER_STRIDE_ALIGNEMENT: u32 = 4 * 3;
+
+/// The blurred background is made at this fraction of the output size
+const BLUR_FILL_DOWNSCALE: u32 = 16;
+const BLUR_RADIUS: usize = 2;
+
+/// Sidecar files named as the image with this added extension
+/// set the crop gravity of the image
+pub const GRAVITY_EXTENSION: &str = "gravity";
+
+/// Directories with this extension, eg. 3.d, hold the numbered frames
+/// of an animation and optionally an fps file with its frame rate
+pub const FRAME_SEQUENCE_EXTENSION: &str = "d";
+pub const FRAME_SEQUENCE_FPS_FILE: &str = "fps";
+const FRAME_SEQUENCE_DEFAULT_FPS: f64 = 10.0;
+
+pub fn gravity_path(path: &Path) -> PathBuf {
+    let mut gravity_path = path.as_os_str().to_owned();
+    gravity_path.push(".");
+    gravity_path.push(GRAVITY_EXTENSION);
+    PathBuf::from(gravity_path)
+}
+
+/// The gravity from the sidecar file of the image if it has one
+fn image_gravity(path: &Path, settings: &ImageSettings) -> Gravity {
+    let gravity_path = gravity_path(path);
+    let Ok(gravity) = fs::read_to_string(&gravity_path) else {
+        return settings.crop_gravity;
+    };
+    parse_gravity(&gravity).unwrap_or_else(|e| {
+        error!("Ignoring crop gravity file {:?}: {}", gravity_path, e);
+        settings.crop_gravity
+    })
+}
+
+/// Settings applied to every loaded wallpaper image
+pub struct ImageSettings {
+    pub color_transform: ColorTransform,
+    pub keep_alpha: bool,
+    pub dither: bool,
+    /// For floating point images
+    pub tone_map: ToneMap,
+    /// Stops
+    pub exposure: f32,
+    pub resize_filter: ResizeFilter,
+    pub scaling: Scaling,
+    /// Unless set for the image in its sidecar file
+    pub crop_gravity: Gravity,
+    /// Unless set in the file name of the image
+    pub workspace_filters: Vec<WorkspaceFilter>,
+    /// Percent of brightness to take away
+    pub dim: u8,
+    /// Percent of brightness to take away in the corners
+    pub vignette: u8,
+    /// Strips at edges darkened below status bars
+    pub bar_shades: Vec<BarShade>,
+    /// Around images not covering the output
+    pub background_color: [u8; 3],
+    /// Instead of the background color
+    pub blur_fill: bool,
+    /// Shaders are rendered once if 0
+    pub shader_fps: u32,
+    /// Globs of the workspaces to pan and zoom the wallpapers of,
+    /// the last match decides, excluding with a ! prefix
+    pub ken_burns: Vec<String>,
+}
 
+/// Decoded, adjusted and resized wallpaper ready to be copied into a buffer
+pub enum WallpaperImage {
+    Rgb(RgbImage),
+    /// Only for images with transparency if it was requested to be kept
+    Rgba(RgbaImage),
+}
+
+/// Everything loading wallpapers needs besides the store and the target
+pub struct LoadContext<'a> {
+    pub decoders: &'a Arc<DecoderRegistry>,
+    pub disk_cache: Option<&'a DiskCache>,
+    pub settings: &'a Arc<ImageSettings>,
+}
+
+/// Where the wallpapers of an output are found
+pub struct WallpaperDirs {
+    pub dir: PathBuf,
+    /// Used after the wallpapers of the directory, which may be missing
+    pub fallbacks: Vec<PathBuf>,
+    /// Set at runtime by workspace name, used before those of the directory
+    pub overrides: Vec<(String, PathBuf)>,
+    /// Whether its subdirectories are sets of candidate wallpapers,
+    /// not with the flat layout where they are of the outputs
+    pub candidate_sets: bool,
+    /// Whether all the wallpapers of the candidate sets are loaded
+    /// for slideshows or random picks instead of only one
+    pub slideshows: bool,
+}
+
+/// With lazy loading only the _default wallpapers are loaded here,
+/// the others when their workspace is first shown
 pub fn workspace_bgs_from_output_image_dir(
-    dir_path: impl AsRef<Path>,
-    slot_pool: &mut SlotPool,
+    dirs: &WallpaperDirs,
+    store: &mut WallpaperStore,
+    ctx: &LoadContext,
     format: wl_shm::Format,
-    brightness: i32,
-    contrast: f32,
-    surface_width: u32,
-    surface_height: u32,
+    target: &ImageTarget,
+    lazy: bool,
 )
     -> Result<Vec<WorkspaceBackground>, String>
 {
-    let mut buffers = Vec::new();
+    let settings = ctx.settings;
+    let mut buffers: Vec<WorkspaceBackground> = Vec::new();
+
+    let mut entries = Vec::new();
+    for (file_stem, path) in wallpaper_sources(dirs)? {
+        let (workspace_name, filter) = workspace_filter(&file_stem, settings);
+        // The slides of a slideshow follow each other
+        let paths = workspace_paths(&path, dirs.slideshows, target);
+        for (slide, path) in paths.into_iter().enumerate() {
+            let key = WallpaperKey::new(
+                &path,
+                target.width,
+                target.height,
+                format,
+                target.span_area.as_ref(),
+                filter,
+            );
+
+            // Loaded quickly enough even when not needed yet
+            let needs_decoding = !store.is_loaded(&key)
+                && !ctx.disk_cache.is_some_and(|disk_cache|
+                    disk_cache.contains(&key)
+                )
+                // Possibly decoded directly into its buffer
+                && !matches!(
+                    Format::sniff(&path),
+                    Ok(Some(Format::Image(ImageFormat::Qoi)))
+                );
+
+            entries.push(
+                (path, workspace_name.clone(), key, needs_decoding, slide > 0)
+            );
+        }
+    }
+
+    // The default wallpapers are shown until the others are loaded,
+    // in the background unless lazily when first needed
+    let load_now = |workspace_name: &str, needs_decoding: bool|
+        workspace_name.starts_with("_default") || !lazy && !needs_decoding;
+
+    // Decode and resize the wallpapers needed now on all cores,
+    // a batch at a time to limit the memory taken by decoded images
+    let threads = thread::available_parallelism()
+        .map_or(1, |threads| threads.get());
+    for batch in entries.chunks(threads) {
+        let jobs = batch.iter().enumerate()
+            .filter(|(_, (_, workspace_name, _, needs_decoding, _))|
+                *needs_decoding && load_now(workspace_name, *needs_decoding)
+            )
+            .map(|(index, (path, _, key, _, _))| (index, path, key.filter()))
+            .collect::<Vec<_>>();
+        let mut decoded = decode_parallel(
+            jobs, ctx.decoders, settings, target
+        );
+
+        for (
+            index,
+            (path, workspace_name, key, needs_decoding, continues_slideshow),
+        ) in batch.iter().enumerate() {
+            let wallpaper = if load_now(workspace_name, *needs_decoding) {
+                match load_into_store(
+                    path, key, store, ctx, target, decoded.remove(&index)
+                ) {
+                    Ok(wallpaper) => Some(wallpaper),
+                    Err(e) => {
+                        error!(
+                            "Failed to load wallpaper '{:?}': {}",
+                            path, e
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+
+            let video = match Format::sniff(path) {
+                Ok(Some(Format::Video)) =>
+                    Some(VideoSource::File(path.clone())),
+                Ok(Some(Format::Shader)) if settings.shader_fps > 0 => Some(
+                    VideoSource::Shader(path.clone(), settings.shader_fps)
+                ),
+                Ok(Some(Format::Image(_) | Format::Jxl | Format::Heif))
+                    if target.span_area.is_none()
+                    && ken_burns(workspace_name, settings)
+                => Some(VideoSource::KenBurns(path.clone(), key.filter())),
+                _ => None,
+            };
+
+            buffers.push(WorkspaceBackground {
+                workspace_name: workspace_name.clone(),
+                path: path.clone(),
+                key: key.clone(),
+                loading: wallpaper.is_none() && !lazy,
+                failed: false,
+                wallpaper,
+                video,
+                // Unless the slide before it failed to load
+                continues_slideshow: *continues_slideshow
+                    && buffers.last().is_some_and(|last|
+                        last.workspace_name == *workspace_name
+                    ),
+            });
+        }
+    }
+
+    if buffers.is_empty() {
+        Err("Found 0 suitable images in the directory".to_string())
+    }
+    else {
+        Ok(buffers)
+    }
+}
+
+/// The keys of all the wallpapers of an output directory as if they were
+/// loaded, without decoding any of them
+pub fn output_image_dir_keys(
+    dirs: &WallpaperDirs,
+    settings: &ImageSettings,
+    format: wl_shm::Format,
+    target: &ImageTarget,
+)
+    -> Result<Vec<WallpaperKey>, String>
+{
+    let mut keys = Vec::new();
+    for (file_stem, path) in wallpaper_sources(dirs)? {
+        let (_, filter) = workspace_filter(&file_stem, settings);
+        for path in workspace_paths(&path, dirs.slideshows, target) {
+            keys.push(WallpaperKey::new(
+                &path,
+                target.width,
+                target.height,
+                format,
+                target.span_area.as_ref(),
+                filter,
+            ));
+        }
+    }
+    Ok(keys)
+}
+
+/// The file stems and the paths of the wallpapers of an output directory
+/// with its overrides and fallbacks
+fn wallpaper_sources(dirs: &WallpaperDirs)
+    -> Result<Vec<(String, PathBuf)>, String>
+{
+    let fallbacks = &dirs.fallbacks;
+    let mut paths = if fallbacks.is_empty() || dirs.dir.is_dir() {
+        wallpaper_paths(&dirs.dir, dirs.candidate_sets)?
+    } else {
+        Vec::new()
+    };
+    // Not loaded in vain when the directory has its own
+    let own_stems = paths.iter()
+        .map(|path| path.file_stem().map(OsStr::to_os_string))
+        .collect::<Vec<_>>();
+    paths.extend(fallbacks.iter()
+        .filter(|fallback| !own_stems.contains(
+            &fallback.file_stem().map(OsStr::to_os_string)
+        ))
+        .cloned()
+    );
 
-    let dir = read_dir(&dir_path)
+    // Use the file stem as the name of the workspace for this wallpaper
+    Ok(dirs.overrides.iter().cloned()
+        .chain(paths.into_iter().map(|path| (
+            path.file_stem().unwrap().to_string_lossy().into_owned(),
+            path,
+        )))
+        .collect())
+}
+
+/// The _default wallpapers directly in the wallpaper dir, also with
+/// a layout or a filter, the fallbacks of the wallpapers of all outputs
+pub fn global_default_paths(dir_path: &Path) -> Vec<PathBuf> {
+    let Ok(paths) = wallpaper_paths(dir_path, false) else {
+        return Vec::new();
+    };
+    paths.into_iter()
+        .filter(|path| path.file_stem().is_some_and(|stem| {
+            let stem = stem.to_string_lossy();
+            stem.split(['@', '+']).next() == Some("_default")
+        }))
+        .collect()
+}
+
+/// The subdirectory of the wallpapers made for the resolution, named like
+/// 1920x1080, or else for the smallest larger one, or else the largest,
+/// None without such subdirectories
+pub fn resolution_dir(dir_path: &Path, width: u32, height: u32)
+    -> Option<PathBuf>
+{
+    let resolutions = read_dir(dir_path).ok()?
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let resolution = parse_resolution(&entry.file_name()
+                .to_string_lossy())?;
+            Some((resolution, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    let area = |(width, height): (u32, u32)| width as u64 * height as u64;
+    resolutions.iter()
+        .find(|(resolution, _)| *resolution == (width, height))
+        .or_else(|| resolutions.iter()
+            .filter(|((w, h), _)| *w >= width && *h >= height)
+            .min_by_key(|(resolution, _)| area(*resolution))
+        )
+        .or_else(|| resolutions.iter()
+            .max_by_key(|(resolution, _)| area(*resolution))
+        )
+        .map(|(_, path)| path.clone())
+}
+
+/// Width and height from a name like 1920x1080
+pub fn parse_resolution(name: &str) -> Option<(u32, u32)> {
+    let (width, height) = name.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Whether wallpapers are directly in the directory, not only in
+/// the directories of the outputs, with the flat layout
+pub fn has_wallpapers(dir_path: &Path) -> bool {
+    wallpaper_paths(dir_path, false).is_ok_and(|paths| !paths.is_empty())
+}
+
+/// The wallpapers and frame sequence directories in an output directory,
+/// and the directories of candidate sets if they may have them
+fn wallpaper_paths(dir_path: &Path, candidate_sets: bool)
+    -> Result<Vec<PathBuf>, String>
+{
+    let dir = read_dir(dir_path)
         .map_err(|e| format!("Failed to open directory: {}", e))?;
 
+    let mut paths = Vec::new();
     for entry_result in dir {
 
         let entry = match entry_result {
@@ -37,7 +547,7 @@ pub fn workspace_bgs_from_output_image_dir(
             Err(e) => {
                 error!(
                     "Skipping a directory entry in '{:?}' due to an error: {}",
-                    dir_path.as_ref(), e
+                    dir_path, e
                 );
                 continue;
             }
@@ -45,193 +555,1268 @@ pub fn workspace_bgs_from_output_image_dir(
 
         let path = entry.path();
 
-        // Skip dirs
-        if path.is_dir() { continue }
+        // Skip dirs besides frame sequences and candidate sets
+        if path.is_dir() && !path.extension()
+            .is_some_and(|ext| ext == FRAME_SEQUENCE_EXTENSION)
+            && !(candidate_sets && parse_resolution(
+                &entry.file_name().to_string_lossy()
+            ).is_none())
+        {
+            continue
+        }
+
+        // Skip the sidecar files of the wallpapers
+        if path.extension().is_some_and(|ext| ext == GRAVITY_EXTENSION) {
+            continue
+        }
 
-        // Use the file stem as the name of the workspace for this wallpaper
-        let workspace_name = path.file_stem().unwrap()
-            .to_string_lossy().into_owned();
+        paths.push(path);
+    }
+    Ok(paths)
+}
 
-        let raw_image = match ImageReader::open(&path)
-            .map_err(ImageError::IoError)
-            .and_then(|r| r.with_guessed_format()
-                .map_err(ImageError::IoError)
-            )
-            .and_then(|r| r.decode())
-        {
-            Ok(raw_image) => raw_image,
-            Err(e) => {
-                error!(
-                    "Failed to open image '{:?}': {}",
-                    path, e
+/// The wallpapers in the directory of the one at the path, sorted,
+/// to step through them
+pub fn sibling_wallpapers(path: &Path) -> Vec<PathBuf> {
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let mut paths = wallpaper_paths(dir, false).unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+/// The wallpapers shown for a workspace, all the candidates of a set
+/// in order as slides of a slideshow or only the one picked
+fn workspace_paths(
+    path: &Path,
+    slideshows: bool,
+    target: &ImageTarget,
+) -> Vec<PathBuf> {
+    if !slideshows {
+        return vec![candidate_path(path, target)];
+    }
+    match candidates(path) {
+        Some(candidates) if !candidates.is_empty() => candidates,
+        _ => vec![path.to_path_buf()],
+    }
+}
+
+/// The sorted wallpapers of a set of candidates, a directory in place
+/// of a wallpaper, None if it is not one
+fn candidates(path: &Path) -> Option<Vec<PathBuf>> {
+    let is_candidate_set = path.is_dir() && !path.extension()
+        .is_some_and(|ext| ext == FRAME_SEQUENCE_EXTENSION);
+    let mut candidates = is_candidate_set
+        .then(|| wallpaper_paths(path, false).ok())
+        .flatten()?;
+    candidates.sort();
+    Some(candidates)
+}
+
+/// The wallpaper of a set of candidates with the aspect ratio closest
+/// to the target, eg. a portrait one for a rotated monitor, others are
+/// shown as they are
+fn candidate_path(path: &Path, target: &ImageTarget) -> PathBuf {
+    let Some(candidates) = candidates(path) else {
+        return path.to_path_buf();
+    };
+    let (width, height) = target.image_size();
+    let aspect_ratio = |width: u32, height: u32|
+        (width as f64 / height as f64).ln();
+    let target_ratio = aspect_ratio(width, height);
+    candidates.into_iter()
+        .map(|candidate| {
+            // Those that are not images are only picked if nothing else
+            let distance = ::image::image_dimensions(&candidate)
+                .map_or(f64::INFINITY, |(width, height)|
+                    (aspect_ratio(width, height) - target_ratio).abs()
                 );
-                continue;
+            (distance, candidate)
+        })
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map_or_else(|| path.to_path_buf(), |(_, candidate)| {
+            debug!("Picked {:?} for {}x{}", candidate, width, height);
+            candidate
+        })
+}
+
+/// Decodes the wallpapers of an output directory missing from the disk
+/// cache into it, returns how many were added
+pub fn prepare_output_image_dir(
+    dirs: &WallpaperDirs,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    disk_cache: &DiskCache,
+    format: wl_shm::Format,
+    target: &ImageTarget,
+)
+    -> Result<usize, String>
+{
+    let jobs = wallpaper_paths(&dirs.dir, dirs.candidate_sets)?.into_iter()
+        .flat_map(|path| {
+            let (_, filter) = workspace_filter(
+                &path.file_stem().unwrap().to_string_lossy(),
+                settings,
+            );
+            workspace_paths(&path, dirs.slideshows, target).into_iter()
+                .map(move |path| (path, filter))
+        })
+        .filter_map(|(path, filter)| {
+            // Animations are not kept in the disk cache
+            if path.is_dir() {
+                return None;
             }
-        };
+            let key = WallpaperKey::new(
+                &path, target.width, target.height, format, None, filter
+            );
+            (!disk_cache.contains(&key)).then_some((path, key))
+        })
+        .collect::<Vec<_>>();
 
-        // It is possible to adjust the contrast and brightness here
-        let mut image = raw_image;
-        if contrast != 0.0 {
-            image = image.adjust_contrast(contrast)
-        }
-        if brightness != 0 {
-            image = image.brighten(brightness)
+    // A batch at a time to limit the memory taken by decoded images
+    let threads = thread::available_parallelism()
+        .map_or(1, |threads| threads.get());
+    let mut prepared = 0;
+    for batch in jobs.chunks(threads) {
+        let mut decoded = decode_parallel(
+            batch.iter().enumerate()
+                .map(|(index, (path, key))| (index, path, key.filter()))
+                .collect(),
+            decoders,
+            settings,
+            target,
+        );
+        for (index, (path, key)) in batch.iter().enumerate() {
+            match decoded.remove(&index) {
+                Some(Ok(Decoded::Still(image))) => {
+                    let (stride, pixels) = pixels_from_wallpaper(
+                        image, format, settings.dither
+                    );
+                    disk_cache.store_pixels(key, stride as u32, &pixels);
+                    prepared += 1;
+                }
+                Some(Ok(Decoded::Animation(_))) => debug!(
+                    "Not preparing animated wallpaper {:?}", path
+                ),
+                Some(Err(e)) => error!(
+                    "Failed to load wallpaper '{:?}': {}", path, e
+                ),
+                None => (),
+            }
         }
+    }
+    Ok(prepared)
+}
 
-        let mut image = image.into_rgb8();
-        let image_width = image.width();
-        let image_height = image.height();
+/// The wallpaper of a workspace in an output directory, or the _default
+/// one, decoded into a buffer and read back like the compositor would,
+/// the first frame of animations
+pub fn preview_wallpaper(
+    dirs: &WallpaperDirs,
+    workspace: &str,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    format: wl_shm::Format,
+    target: &ImageTarget,
+)
+    -> Result<(PathBuf, RgbaImage), String>
+{
+    let sources = wallpaper_sources(dirs)?.into_iter()
+        .map(|(file_stem, path)| (workspace_filter(&file_stem, settings), path))
+        .collect::<Vec<_>>();
+    let ((_, filter), path) = [workspace, "_default"].iter()
+        .find_map(|name| sources.iter()
+            .find(|((workspace_name, _), _)| workspace_name == name)
+        )
+        .ok_or_else(|| format!(
+            "No wallpaper for workspace '{}' in {:?}", workspace, dirs.dir
+        ))?;
+    let path = workspace_paths(path, dirs.slideshows, target)
+        .into_iter().next()
+        .unwrap_or_else(|| path.clone());
 
-        if image_width == 0 {
-            error!(
-                "Image '{}' has zero width, skipping", workspace_name
-            );
-            continue;
-        };
-        if image_height == 0 {
-            error!(
-                "Image '{}' has zero height, skipping", workspace_name
-            );
-            continue;
+    let image = match decode(&path, decoders, settings, target, *filter)? {
+        Decoded::Still(image) => image,
+        Decoded::Animation(frames) => frames.into_iter().next()
+            .ok_or_else(|| "Animation has no frames".to_string())?.0,
+    };
+    let (width, height) = image.dimensions();
+    let format = image.buffer_format(format);
+    let (stride, pixels) =
+        pixels_from_wallpaper(image, format, settings.dither);
+    Ok((path, image_from_pixels(&pixels, stride, width, height, format)))
+}
+
+/// Returns the wallpaper from the store, or loads it from the disk cache
+/// or decodes it, unless it was decoded already
+pub fn load_into_store(
+    path: &Path,
+    key: &WallpaperKey,
+    store: &mut WallpaperStore,
+    ctx: &LoadContext,
+    target: &ImageTarget,
+    decoded: Option<Result<Decoded, String>>,
+)
+    -> Result<Rc<Wallpaper>, String>
+{
+    let LoadContext { decoders, disk_cache, settings } = *ctx;
+    let format = key.format();
+    let filter = key.filter();
+    store.get_or_load(key.clone(), |slot_pool| {
+        if let Some(disk_cache) = disk_cache {
+            let buffer = disk_cache.load(key, slot_pool);
+            DECODE_COUNTERS.count_disk_cache_lookup(buffer.is_some());
+            if let Some(buffer) = buffer {
+                return Ok(vec![Frame::still(buffer)]);
+            }
+        }
+        if filter == ColorFilter::None {
+            if let Some(buffer) = load_qoi_into_buffer(
+                path, format, settings, target, slot_pool
+            )? {
+                return Ok(vec![Frame::still(buffer)]);
+            }
+        }
+        let decoded = match decoded {
+            Some(decoded) => decoded?,
+            None => decode(path, decoders, settings, target, filter)?,
         };
+        match decoded {
+            // Animations are not kept in the disk cache
+            Decoded::Animation(frames) => Ok(frames.into_iter()
+                .map(|(image, delay)| Frame {
+                    buffer: buffer_from_wallpaper(
+                        image, format, settings.dither, slot_pool
+                    ),
+                    delay,
+                })
+                .collect()
+            ),
+            Decoded::Still(image) => {
+                let buffer = buffer_from_wallpaper(
+                    image, format, settings.dither, slot_pool
+                );
+                if let Some(disk_cache) = disk_cache {
+                    disk_cache.store(key, &buffer, slot_pool);
+                }
+                Ok(vec![Frame::still(buffer)])
+            }
+        }
+    })
+}
+
+/// Solid background color wallpaper shown until the others are loaded
+pub fn placeholder_into_store(
+    store: &mut WallpaperStore,
+    settings: &ImageSettings,
+    format: wl_shm::Format,
+    target: &ImageTarget,
+)
+    -> Result<Rc<Wallpaper>, String>
+{
+    solid_into_store(store, settings.background_color, format, target)
+}
+
+/// Wallpaper of a single color, eg. of a blanked output
+pub fn solid_into_store(
+    store: &mut WallpaperStore,
+    color: [u8; 3],
+    format: wl_shm::Format,
+    target: &ImageTarget,
+)
+    -> Result<Rc<Wallpaper>, String>
+{
+    let key = WallpaperKey::solid(target.width, target.height, format, color);
+    store.get_or_load(key, |slot_pool| {
+        let image = RgbImage::from_pixel(
+            target.width,
+            target.height,
+            image::Rgb(color),
+        );
+        Ok(vec![Frame::still(buffer_from_wallpaper(
+            WallpaperImage::Rgb(image), format, false, slot_pool
+        ))])
+    })
+}
+
+/// A wallpaper decoded and processed, not yet in a buffer
+pub enum Decoded {
+    Still(WallpaperImage),
+    /// With the delay after each frame
+    Animation(Vec<(WallpaperImage, Duration)>),
+}
+
+fn decode(
+    path: &Path,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    filter: ColorFilter,
+)
+    -> Result<Decoded, String>
+{
+    let start = Instant::now();
+    let decoded = decode_file(path, decoders, settings, target, filter);
+    if decoded.is_ok() {
+        DECODE_COUNTERS.count_decode(start.elapsed());
+    }
+    decoded
+}
+
+fn decode_file(
+    path: &Path,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    filter: ColorFilter,
+)
+    -> Result<Decoded, String>
+{
+    if path.is_dir() {
+        return load_frame_sequence(path, decoders, settings, target, filter)
+            .map(Decoded::Animation);
+    }
+    if let Some(frames) = load_animation(
+        path, decoders, settings, target, filter
+    )? {
+        return Ok(Decoded::Animation(frames));
+    }
+    load_wallpaper(path, decoders, settings, target, filter)
+        .map(Decoded::Still)
+}
+
+/// The frames of a frame sequence directory in the order of their names,
+/// each processed like a still wallpaper, decoded on all cores
+fn load_frame_sequence(
+    dir_path: &Path,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    filter: ColorFilter,
+)
+    -> Result<Vec<(WallpaperImage, Duration)>, String>
+{
+    let fps = match fs::read_to_string(
+        dir_path.join(FRAME_SEQUENCE_FPS_FILE)
+    ) {
+        Ok(fps) => fps.trim().parse::<f64>().ok()
+            .filter(|fps| fps.is_finite() && *fps > 0.0)
+            .ok_or_else(|| format!("Invalid frame rate '{}'", fps.trim()))?,
+        Err(_) => FRAME_SEQUENCE_DEFAULT_FPS,
+    };
+    let delay = Duration::from_secs_f64(1.0 / fps);
 
-        if image_width != surface_width || image_height != surface_height
+    let mut paths = read_dir(dir_path)
+        .map_err(|e| format!("Failed to open directory: {}", e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file()
+            && path.file_name().is_some_and(|name|
+                name != FRAME_SEQUENCE_FPS_FILE
+            )
+        )
+        .collect::<Vec<_>>();
+    paths.sort();
+    if paths.is_empty() {
+        return Err("Frame sequence has no frames".to_string());
+    }
+    decoders.limits().check_animation(
+        paths.len(),
+        paths.len() as u64 * target_frame_bytes(target),
+    )?;
+    debug!("Loading {} frames from {:?}", paths.len(), dir_path);
+
+    let threads = thread::available_parallelism()
+        .map_or(1, |threads| threads.get());
+    let mut frames = Vec::with_capacity(paths.len());
+    for batch in paths.chunks(threads) {
+        let mut decoded = decode_parallel(
+            batch.iter().enumerate()
+                .map(|(index, path)| (index, path, filter))
+                .collect(),
+            decoders,
+            settings,
+            target,
+        );
+        for (index, path) in batch.iter().enumerate() {
+            match decoded.remove(&index) {
+                Some(Ok(Decoded::Still(image))) => frames.push((image, delay)),
+                Some(Ok(Decoded::Animation(_))) => return Err(format!(
+                    "Frame {:?} is animated itself", path
+                )),
+                Some(Err(e)) => return Err(format!(
+                    "Failed to load frame {:?}: {}", path, e
+                )),
+                None => {}
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Decodes each job on a thread of its own, keyed by the job index
+pub fn decode_parallel(
+    jobs: Vec<(usize, &PathBuf, ColorFilter)>,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+)
+    -> HashMap<usize, Result<Decoded, String>>
+{
+    if jobs.len() > 1 {
+        debug!("Decoding {} wallpapers in parallel", jobs.len());
+    }
+    thread::scope(|scope| {
+        let handles = jobs.into_iter()
+            .map(|(index, path, filter)| (index, scope.spawn(move ||
+                decode(path, decoders, settings, target, filter)
+            )))
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|(index, handle)| (index, handle.join().unwrap_or_else(|_|
+                Err("Decoding thread panicked".to_string())
+            )))
+            .collect()
+    })
+}
+
+/// The workspace name and the color filter from a file stem with a filter
+/// modifier, eg. 1+sepia, otherwise the last filter given for the workspace
+fn workspace_filter(file_stem: &str, settings: &ImageSettings)
+    -> (String, ColorFilter)
+{
+    if let Some((workspace_name, filter)) = file_stem.rsplit_once('+')
+        .and_then(|(name, modifier)| ColorFilter::from_str(modifier, true)
+            .ok()
+            .map(|filter| (name, filter))
+        )
+    {
+        return (workspace_name.to_string(), filter);
+    }
+    // Ignoring the layout of names like 1@tabbed
+    let workspace = file_stem.rsplit_once('@')
+        .map_or(file_stem, |(workspace, _)| workspace);
+    let filter = settings.workspace_filters.iter().rev()
+        .find(|workspace_filter|
+            glob_match(&workspace_filter.workspace, workspace)
+        )
+        .map(|workspace_filter| workspace_filter.filter)
+        .unwrap_or_default();
+    (file_stem.to_string(), filter)
+}
+
+/// Whether the Ken Burns effect is enabled for the workspace
+fn ken_burns(workspace_name: &str, settings: &ImageSettings) -> bool {
+    // Ignoring the layout of names like 1@tabbed
+    let workspace = workspace_name.rsplit_once('@')
+        .map_or(workspace_name, |(workspace, _)| workspace);
+    settings.ken_burns.iter().rev()
+        .find_map(|pattern| match pattern.strip_prefix('!') {
+            Some(pattern) => glob_match(pattern, workspace).then_some(false),
+            None => glob_match(pattern, workspace).then_some(true),
+        })
+        .unwrap_or(false)
+}
+
+pub fn load_wallpaper(
+    path: &Path,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    filter: ColorFilter,
+)
+    -> Result<WallpaperImage, String>
+{
+    let raw_image = decoders.decode(path, target.image_size())
+        .map_err(|e| format!("Failed to open image: {}", e))?;
+
+    let gravity = image_gravity(path, settings);
+    process_image(raw_image, path, settings, target, gravity, filter)
+}
+
+/// Decodes a qoi image straight into a new Bgr888 buffer, which has the
+/// same byte order, if it needs no resizing, color adjustment or row
+/// padding, eg. for wallpapers converted for the output beforehand.
+/// None if the image has to be loaded the usual way
+fn load_qoi_into_buffer(
+    path: &Path,
+    format: wl_shm::Format,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    slot_pool: &mut SlotPool,
+)
+    -> Result<Option<Buffer>, String>
+{
+    if format != wl_shm::Format::Bgr888
+        || target.span_area.is_some()
+        || !settings.color_transform.is_identity()
+        || settings.dim != 0
+        || settings.vignette != 0
+        || !settings.bar_shades.is_empty()
+        || target.width * 3 % BUFFER_STRIDE_ALIGNEMENT != 0
+        || !matches!(
+            Format::sniff(path),
+            Ok(Some(Format::Image(ImageFormat::Qoi)))
+        )
+    {
+        return Ok(None);
+    }
+
+    let data = fs::read(path)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    let decoder = qoi::Decoder::new(&data)
+        .map_err(|e| format!("Failed to read qoi header: {}", e))?;
+    let header = *decoder.header();
+    if (header.width, header.height) != (target.width, target.height)
+        || (settings.keep_alpha && header.channels.is_rgba())
+    {
+        return Ok(None);
+    }
+
+    let (buffer, canvas) = slot_pool.create_buffer(
+        target.width as i32,
+        target.height as i32,
+        target.width as i32 * 3,
+        wl_shm::Format::Bgr888,
+    ).map_err(|e| format!("Failed to create buffer: {}", e))?;
+    decoder.with_channels(qoi::Channels::Rgb).decode_to_buf(canvas)
+        .map_err(|e| format!("Failed to decode qoi image: {}", e))?;
+    debug!("Decoded qoi image {:?} directly into the buffer", path);
+    Ok(Some(buffer))
+}
+
+/// The frames of animated images processed like still wallpapers,
+/// with the delay after each of them
+pub fn load_animation(
+    path: &Path,
+    decoders: &DecoderRegistry,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    filter: ColorFilter,
+)
+    -> Result<Option<Vec<(WallpaperImage, Duration)>>, String>
+{
+    let Some(frames) = decode_animation(path, decoders.limits())? else {
+        return Ok(None);
+    };
+    decoders.limits().check_animation(
+        frames.len(),
+        frames.len() as u64 * target_frame_bytes(target),
+    )?;
+    let gravity = image_gravity(path, settings);
+    frames.into_iter()
+        .map(|(raw_image, delay)| Ok((
+            process_image(
+                raw_image, path, settings, target, gravity, filter
+            )?,
+            delay
+        )))
+        .collect::<Result<Vec<_>, String>>()
+        .map(Some)
+}
+
+/// At most, with 4 bytes per pixel
+fn target_frame_bytes(target: &ImageTarget) -> u64 {
+    target.width as u64 * target.height as u64 * 4
+}
+
+fn process_image(
+    raw_image: DynamicImage,
+    path: &Path,
+    settings: &ImageSettings,
+    target: &ImageTarget,
+    gravity: Gravity,
+    filter: ColorFilter,
+)
+    -> Result<WallpaperImage, String>
+{
+    let image_width = raw_image.width();
+    let image_height = raw_image.height();
+
+    if image_width == 0 {
+        return Err("Image has zero width".to_string());
+    };
+    if image_height == 0 {
+        return Err("Image has zero height".to_string());
+    };
+
+    let keep_alpha = settings.keep_alpha && raw_image.color().has_alpha();
+
+    let raw_image = tone_map(raw_image, settings);
+
+    let raw_image = if settings.dither {
+        dither_to_8bit(raw_image)
+    } else {
+        raw_image
+    };
+
+    // It is possible to adjust the colors here
+    let image = filter.apply(settings.color_transform.apply(raw_image));
+
+    let (pixels, pixel_type) = if keep_alpha {
+        (image.into_rgba8().into_raw(), PixelType::U8x4)
+    } else {
+        (image.into_rgb8().into_raw(), PixelType::U8x3)
+    };
+
+    let surface_width = target.width;
+    let surface_height = target.height;
+
+    let scaling = match target.span_area {
+        Some(_) => Scaling::Fill,
+        None => settings.scaling,
+    };
+
+    let pixels = match scaling {
+        Scaling::Fill if image_width != surface_width
+            || image_height != surface_height
+            || target.span_area.is_some() =>
         {
-            debug!("Resizing image '{}' from {}x{} to {}x{}",
-                workspace_name,
+            debug!("Resizing image {:?} from {}x{} to {}x{}",
+                path,
                 image_width, image_height,
                 surface_width, surface_height
             );
 
-            let src_image = Image::from_vec_u8(
+            resize(
+                pixels,
+                pixel_type,
                 image_width,
                 image_height,
-                image.into_raw(),
-                PixelType::U8x3,
-            ).unwrap();
-
-            let mut dst_image = Image::new(
-                surface_width,
-                surface_height,
-                PixelType::U8x3,
+                (surface_width, surface_height),
+                match &target.span_area {
+                    Some(span_area) => Crop::Span(span_area),
+                    None => Crop::Gravity(gravity),
+                },
+                settings.resize_filter,
+            )
+        }
+        Scaling::Fill => pixels,
+        Scaling::Fit => {
+            let scale = f64::min(
+                surface_width as f64 / image_width as f64,
+                surface_height as f64 / image_height as f64,
+            );
+            let width = ((image_width as f64 * scale).round() as u32)
+                .clamp(1, surface_width);
+            let height = ((image_height as f64 * scale).round() as u32)
+                .clamp(1, surface_height);
+            let pixels = if (width, height) != (image_width, image_height) {
+                debug!("Resizing image {:?} from {}x{} to fit {}x{}",
+                    path,
+                    image_width, image_height,
+                    width, height
+                );
+                resize(
+                    pixels,
+                    pixel_type,
+                    image_width,
+                    image_height,
+                    (width, height),
+                    Crop::Gravity(gravity),
+                    settings.resize_filter,
+                )
+            } else {
+                pixels
+            };
+            let background = background(
+                &pixels, pixel_type, width, height, target, settings
+            );
+            letterbox(
+                &pixels, pixel_type, width, height, target, gravity,
+                background
+            )
+        }
+        Scaling::Center => {
+            let background = background(
+                &pixels, pixel_type, image_width, image_height, target,
+                settings
             );
+            letterbox(
+                &pixels, pixel_type, image_width, image_height, target,
+                gravity, background
+            )
+        }
+    };
 
-            let mut resizer = Resizer::new();
-            resizer.resize(
-                &src_image,
-                &mut dst_image,
-                &ResizeOptions::new()
-                    .fit_into_destination(None)
-                    .resize_alg(ResizeAlg::Convolution(FilterType::Lanczos3))
-            ).unwrap();
-
-            image = ImageBuffer::from_raw(
-                surface_width,
-                surface_height,
-                dst_image.into_vec()
-            ).unwrap();
-        }
-
-        let buffer = match format {
-            wl_shm::Format::Xrgb8888 =>
-                buffer_xrgb8888_from_image(image, slot_pool),
-            wl_shm::Format::Bgr888 =>
-                buffer_bgr888_from_image(image, slot_pool),
-            _ => unreachable!()
-        };
+    let pixels = darken(
+        pixels, pixel_type, surface_width, surface_height, settings
+    );
+
+    Ok(if keep_alpha {
+        WallpaperImage::Rgba(
+            RgbaImage::from_raw(surface_width, surface_height, pixels)
+                .unwrap()
+        )
+    } else {
+        WallpaperImage::Rgb(
+            RgbImage::from_raw(surface_width, surface_height, pixels)
+                .unwrap()
+        )
+    })
+}
+
+/// Which part of the image is kept if it is cropped when resizing
+enum Crop<'a> {
+    Gravity(Gravity),
+    Span(&'a SpanArea),
+}
+
+fn resize(
+    pixels: Vec<u8>,
+    pixel_type: PixelType,
+    image_width: u32,
+    image_height: u32,
+    (width, height): (u32, u32),
+    crop: Crop,
+    filter: ResizeFilter,
+)
+    -> Vec<u8>
+{
+    let src_image = Image::from_vec_u8(
+        image_width,
+        image_height,
+        pixels,
+        pixel_type,
+    ).unwrap();
+
+    let mut dst_image = Image::new(
+        width,
+        height,
+        pixel_type,
+    );
+
+    let resize_alg = match filter {
+        ResizeFilter::Nearest => ResizeAlg::Nearest,
+        ResizeFilter::Bilinear => ResizeAlg::Convolution(FilterType::Bilinear),
+        ResizeFilter::CatmullRom =>
+            ResizeAlg::Convolution(FilterType::CatmullRom),
+        ResizeFilter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+    };
+    let resize_options = ResizeOptions::new().resize_alg(resize_alg);
+
+    let resize_options = match crop {
+        Crop::Span(span_area) => {
+            let (left, top, width, height) =
+                span_area.crop(image_width, image_height);
+            resize_options.crop(left, top, width, height)
+        },
+        Crop::Gravity(gravity) => resize_options
+            .fit_into_destination(Some((gravity.x, gravity.y))),
+    };
 
-        buffers.push(WorkspaceBackground { workspace_name, buffer });
+    let start = Instant::now();
+    let mut resizer = Resizer::new();
+    resizer.resize(
+        &src_image,
+        &mut dst_image,
+        &resize_options
+    ).unwrap();
+    DECODE_COUNTERS.count_resize(start.elapsed());
+
+    dst_image.into_vec()
+}
+
+/// What is shown around images not covering the output, the background
+/// color or a heavily blurred copy of the image filling the output
+fn background(
+    pixels: &[u8],
+    pixel_type: PixelType,
+    image_width: u32,
+    image_height: u32,
+    target: &ImageTarget,
+    settings: &ImageSettings,
+)
+    -> Vec<u8>
+{
+    let bytes_per_pixel = pixel_type.size();
+    if !settings.blur_fill {
+        let [red, green, blue] = settings.background_color;
+        return [red, green, blue, u8::MAX][..bytes_per_pixel].repeat(
+            target.width as usize * target.height as usize
+        );
     }
 
-    if buffers.is_empty() {
-        Err("Found 0 suitable images in the directory".to_string())
+    // Blurring a much smaller copy is fast, and upscaling it smoothly
+    // blurs it even more
+    let small_width = (target.width / BLUR_FILL_DOWNSCALE).max(1);
+    let small_height = (target.height / BLUR_FILL_DOWNSCALE).max(1);
+    let mut small = resize(
+        pixels.to_vec(),
+        pixel_type,
+        image_width,
+        image_height,
+        (small_width, small_height),
+        Crop::Gravity(Gravity::default()),
+        ResizeFilter::Bilinear,
+    );
+    for _ in 0..3 {
+        box_blur(
+            &mut small,
+            small_width as usize,
+            small_height as usize,
+            bytes_per_pixel,
+        );
     }
-    else {
-        Ok(buffers)
+    resize(
+        small,
+        pixel_type,
+        small_width,
+        small_height,
+        (target.width, target.height),
+        Crop::Gravity(Gravity::default()),
+        ResizeFilter::Bilinear,
+    )
+}
+
+/// One horizontal and one vertical pass of a box blur, repeated passes
+/// approximate a gaussian blur
+fn box_blur(
+    pixels: &mut [u8],
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+) {
+    let mut line = Vec::new();
+    let mut blur_line = |pixels: &mut [u8], start: usize, step: usize,
+        len: usize|
+    {
+        line.clear();
+        for i in 0..len {
+            let offset = start + i * step;
+            line.extend_from_slice(&pixels[offset..offset + bytes_per_pixel]);
+        }
+        for i in 0..len {
+            let first = i.saturating_sub(BLUR_RADIUS);
+            let last = (i + BLUR_RADIUS).min(len - 1);
+            for channel in 0..bytes_per_pixel {
+                let sum = (first..=last)
+                    .map(|j| line[j * bytes_per_pixel + channel] as u32)
+                    .sum::<u32>();
+                pixels[start + i * step + channel] =
+                    (sum / (last - first + 1) as u32) as u8;
+            }
+        }
+    };
+    for row in 0..height {
+        blur_line(
+            pixels,
+            row * width * bytes_per_pixel,
+            bytes_per_pixel,
+            width,
+        );
+    }
+    for column in 0..width {
+        blur_line(
+            pixels,
+            column * bytes_per_pixel,
+            width * bytes_per_pixel,
+            height,
+        );
+    }
+}
+
+/// Darken the whole output uniformly, towards the corners and below
+/// status bars as if covered by a translucent black overlay
+fn darken(
+    mut pixels: Vec<u8>,
+    pixel_type: PixelType,
+    width: u32,
+    height: u32,
+    settings: &ImageSettings,
+)
+    -> Vec<u8>
+{
+    if settings.dim == 0
+        && settings.vignette == 0
+        && settings.bar_shades.is_empty()
+    {
+        return pixels;
+    }
+    let bytes_per_pixel = pixel_type.size();
+    let dim = 1.0 - settings.dim as f32 / 100.0;
+    let vignette = settings.vignette as f32 / 100.0;
+    let center_x = width as f32 / 2.0;
+    let center_y = height as f32 / 2.0;
+    let corner_distance = center_x.hypot(center_y);
+    // Full at the edge, then smoothly fading out over as much again
+    let bar_shade = |column: u32, row: u32| {
+        settings.bar_shades.iter()
+            .map(|bar_shade| {
+                let distance = match bar_shade.edge {
+                    Anchor::TOP => row,
+                    Anchor::BOTTOM => height - 1 - row,
+                    Anchor::LEFT => column,
+                    _ => width - 1 - column,
+                };
+                let t = (2.0 - distance as f32 / bar_shade.pixels as f32)
+                    .clamp(0.0, 1.0);
+                1.0 - bar_shade.percent as f32 / 100.0
+                    * t * t * (3.0 - 2.0 * t)
+            })
+            .product::<f32>()
+    };
+    for (row, line) in pixels
+        .chunks_exact_mut(width as usize * bytes_per_pixel)
+        .enumerate()
+    {
+        let y = row as f32 + 0.5 - center_y;
+        for (column, pixel) in line.chunks_exact_mut(bytes_per_pixel)
+            .enumerate()
+        {
+            let x = column as f32 + 0.5 - center_x;
+            // Darkening starts at a bit less than half way to the corners
+            // and smoothly increases
+            let t = ((x.hypot(y) / corner_distance - 0.4) / 0.6)
+                .clamp(0.0, 1.0);
+            let factor = dim * (1.0 - vignette * t * t * (3.0 - 2.0 * t))
+                * bar_shade(column as u32, row as u32);
+            // Keep the alpha channel
+            for value in &mut pixel[..3] {
+                *value = (*value as f32 * factor).round() as u8;
+            }
+        }
+    }
+    pixels
+}
+
+/// Place the image in the middle of the output over the background,
+/// the image is cropped according to the gravity if it is larger
+fn letterbox(
+    pixels: &[u8],
+    pixel_type: PixelType,
+    image_width: u32,
+    image_height: u32,
+    target: &ImageTarget,
+    gravity: Gravity,
+    mut canvas: Vec<u8>,
+)
+    -> Vec<u8>
+{
+    let bytes_per_pixel = pixel_type.size();
+
+    let width = image_width.min(target.width) as usize;
+    let height = image_height.min(target.height) as usize;
+    let src_x = ((image_width as usize - width) as f64 * gravity.x) as usize;
+    let src_y = ((image_height as usize - height) as f64 * gravity.y)
+        as usize;
+    let dst_x = (target.width as usize - width) / 2;
+    let dst_y = (target.height as usize - height) / 2;
+    let src_stride = image_width as usize * bytes_per_pixel;
+    let dst_stride = target.width as usize * bytes_per_pixel;
+    let row_len = width * bytes_per_pixel;
+    for row in 0..height {
+        let src_start = (src_y + row) * src_stride + src_x * bytes_per_pixel;
+        let dst_start = (dst_y + row) * dst_stride + dst_x * bytes_per_pixel;
+        canvas[dst_start..dst_start + row_len]
+            .copy_from_slice(&pixels[src_start..src_start + row_len]);
     }
+    canvas
 }
 
-fn buffer_xrgb8888_from_image(
-    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+pub fn buffer_from_wallpaper(
+    image: WallpaperImage,
+    format: wl_shm::Format,
+    dither: bool,
     slot_pool: &mut SlotPool,
 )
     -> Buffer
 {
+    let format = image.buffer_format(format);
+    let (width, height) = image.dimensions();
+    let stride = buffer_stride(width, format);
+
     let (buffer, canvas) = slot_pool
         .create_buffer(
-            image.width() as i32,
-            image.height() as i32,
-            image.width() as i32 * 4,
-            wl_shm::Format::Xrgb8888
+            width.try_into().unwrap(),
+            height.try_into().unwrap(),
+            stride.try_into().unwrap(),
+            format
         )
         .unwrap();
 
-    let canvas_len = image.len() / 3 * 4;
-
-    let image_pixels = image.pixels();
-    let canvas_pixels = canvas[..canvas_len].chunks_exact_mut(4);
-
-    for (image_pixel, canvas_pixel) in image_pixels.zip(canvas_pixels) {
-        canvas_pixel[0] = image_pixel.0[2];
-        canvas_pixel[1] = image_pixel.0[1];
-        canvas_pixel[2] = image_pixel.0[0];
-    }
+    write_wallpaper(
+        image, format, dither, &mut canvas[..stride * height as usize], stride
+    );
 
     buffer
 }
 
-fn buffer_bgr888_from_image(
-    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
-    slot_pool: &mut SlotPool,
+/// The stride and the pixels of the buffer of a wallpaper, to write
+/// the disk cache without buffers from a compositor
+pub fn pixels_from_wallpaper(
+    image: WallpaperImage,
+    format: wl_shm::Format,
+    dither: bool,
 )
-    -> Buffer
+    -> (usize, Vec<u8>)
+{
+    let format = image.buffer_format(format);
+    let (width, height) = image.dimensions();
+    let stride = buffer_stride(width, format);
+    let mut pixels = vec![0; stride * height as usize];
+    write_wallpaper(image, format, dither, &mut pixels, stride);
+    (stride, pixels)
+}
+
+/// The colors the pixels of a buffer show, with the premultiplied alpha
+/// of Argb8888 undone
+fn image_from_pixels(
+    pixels: &[u8],
+    stride: usize,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+)
+    -> RgbaImage
 {
-    // Align buffer stride to both 4 and pixel format block size
-    // Not being aligned to 4 caused
-    // https://github.com/gergo-salyi/multibg-sway/issues/6
-    const BUFFER_STRIDE_ALIGNEMENT: u32 = 4 * 3;
+    RgbaImage::from_fn(width, height, |x, y| {
+        let row = &pixels[y as usize * stride..];
+        let x = x as usize;
+        let pixel = match format {
+            wl_shm::Format::Bgr888 => {
+                let [red, green, blue] =
+                    row[x * 3..][..3].try_into().unwrap();
+                [red, green, blue, u8::MAX]
+            }
+            wl_shm::Format::Rgb565 => {
+                let pixel =
+                    u16::from_le_bytes(row[x * 2..][..2].try_into().unwrap());
+                // Repeating the high bits to fill the low ones
+                let expand = |value: u16, bits: u32| {
+                    let value = (value << (8 - bits)) as u8;
+                    value | value >> bits
+                };
+                [
+                    expand(pixel >> 11, 5),
+                    expand(pixel >> 5 & 0x3f, 6),
+                    expand(pixel & 0x1f, 5),
+                    u8::MAX,
+                ]
+            }
+            _ => {
+                let [blue, green, red, alpha] =
+                    row[x * 4..][..4].try_into().unwrap();
+                if format == wl_shm::Format::Argb8888 {
+                    let unpremultiply = |color: u8| match alpha {
+                        0 => 0,
+                        _ => (color as u16 * 255 / alpha as u16).min(255) as u8,
+                    };
+                    [
+                        unpremultiply(red),
+                        unpremultiply(green),
+                        unpremultiply(blue),
+                        alpha,
+                    ]
+                } else {
+                    [red, green, blue, u8::MAX]
+                }
+            }
+        };
+        image::Rgba(pixel)
+    })
+}
 
-    let width = image.width();
-    let height = image.height();
-    let image_stride = width * 3;
+impl WallpaperImage {
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            WallpaperImage::Rgb(image) => image.dimensions(),
+            WallpaperImage::Rgba(image) => image.dimensions(),
+        }
+    }
 
-    let unaligned_bytes = image_stride % BUFFER_STRIDE_ALIGNEMENT;
+    fn buffer_format(&self, format: wl_shm::Format) -> wl_shm::Format {
+        match self {
+            WallpaperImage::Rgba(_) => wl_shm::Format::Argb8888,
+            WallpaperImage::Rgb(_) => format,
+        }
+    }
+}
 
-    let buffer_stride =
-    if unaligned_bytes == 0 {
-        image_stride
-    } else {
-        let padding = BUFFER_STRIDE_ALIGNEMENT - unaligned_bytes;
-        image_stride + padding
+pub fn buffer_stride(width: u32, format: wl_shm::Format) -> usize {
+    let stride = match format {
+        wl_shm::Format::Bgr888 =>
+            (width * 3).next_multiple_of(BUFFER_STRIDE_ALIGNEMENT),
+        // Keep the stride aligned to 4 like with the other formats
+        wl_shm::Format::Rgb565 => (width * 2).next_multiple_of(4),
+        _ => width * 4,
     };
+    stride as usize
+}
 
-    let (buffer, canvas) = slot_pool
-        .create_buffer(
-            width.try_into().unwrap(),
-            height.try_into().unwrap(),
-            buffer_stride.try_into().unwrap(),
-            wl_shm::Format::Bgr888
-        )
-        .unwrap();
+/// Into a canvas of the size and stride of the buffer of the wallpaper
+fn write_wallpaper(
+    image: WallpaperImage,
+    format: wl_shm::Format,
+    dither: bool,
+    canvas: &mut [u8],
+    stride: usize,
+) {
+    match image {
+        WallpaperImage::Rgba(image) => write_argb8888(&image, canvas),
+        WallpaperImage::Rgb(image) => match format {
+            wl_shm::Format::Xrgb8888 => write_xrgb8888(&image, canvas),
+            wl_shm::Format::Bgr888 => write_bgr888(&image, canvas, stride),
+            wl_shm::Format::Rgb565 =>
+                write_rgb565(&image, dither, canvas, stride),
+            _ => unreachable!()
+        },
+    }
+}
+
+fn write_xrgb8888(image: &RgbImage, canvas: &mut [u8]) {
+    bgrx_from_rgb(image, canvas);
+}
+
+// The alpha of wl_shm formats is premultiplied
+fn write_argb8888(image: &RgbaImage, canvas: &mut [u8]) {
+    let image_pixels = image.pixels();
+    let canvas_pixels = canvas.chunks_exact_mut(4);
+
+    for (image_pixel, canvas_pixel) in image_pixels.zip(canvas_pixels) {
+        let [red, green, blue, alpha] = image_pixel.0;
+        let premultiply = |color: u8|
+            ((color as u16 * alpha as u16 + 127) / 255) as u8;
+        canvas_pixel[0] = premultiply(blue);
+        canvas_pixel[1] = premultiply(green);
+        canvas_pixel[2] = premultiply(red);
+        canvas_pixel[3] = alpha;
+    }
+}
+
+fn write_bgr888(image: &RgbImage, canvas: &mut [u8], stride: usize) {
+    let image_stride = image.width() as usize * 3;
 
-    if unaligned_bytes == 0 {
-        canvas[..image.len()].copy_from_slice(&image);
+    if image_stride == stride {
+        canvas.copy_from_slice(image);
     }
     else {
-        let height: usize = height.try_into().unwrap();
-        let buffer_stride: usize = buffer_stride.try_into().unwrap();
-        let image_stride: usize = image_stride.try_into().unwrap();
+        for (canvas_row, image_row) in canvas.chunks_exact_mut(stride)
+            .zip(image.as_raw().chunks_exact(image_stride))
+        {
+            canvas_row[..image_stride].copy_from_slice(image_row);
+        }
+    }
+}
 
-        for row in 0..height {
-            let canvas_start = row * buffer_stride;
-            let image_start = row * image_stride;
-            let len = image_stride;
+// 4x4 Bayer matrix for ordered dithering
+const BAYER_4X4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
 
-            canvas[canvas_start..(canvas_start + len)].copy_from_slice(
-                &image.as_raw()[image_start..(image_start + len)]
-            );
+/// Floating point images, eg. OpenEXR and Radiance HDR, hold linear light
+/// of unbounded brightness, which is mapped to the range of sRGB encoded
+/// values instead of being clipped and shown too dark
+fn tone_map(image: DynamicImage, settings: &ImageSettings) -> DynamicImage {
+    if !matches!(
+        image,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    ) {
+        return image;
+    }
+    let exposure = settings.exposure.exp2();
+    let map = |value: &mut f32| {
+        let x = (*value * exposure).max(0.0);
+        let mapped = match settings.tone_map {
+            ToneMap::Clip => x,
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::Aces =>
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        };
+        *value = linear_to_srgb(mapped.clamp(0.0, 1.0));
+    };
+    match image {
+        DynamicImage::ImageRgba32F(mut image) => {
+            for pixel in image.pixels_mut() {
+                pixel.0[..3].iter_mut().for_each(map);
+            }
+            DynamicImage::ImageRgba32F(image)
+        }
+        DynamicImage::ImageRgb32F(mut image) => {
+            for pixel in image.pixels_mut() {
+                pixel.0.iter_mut().for_each(map);
+            }
+            DynamicImage::ImageRgb32F(image)
         }
+        _ => unreachable!(),
     }
+}
 
-    buffer
+/// Reduce images of more than 8 bits per channel to 8 bits with ordered
+/// dithering instead of truncating them, avoiding banding on gradients,
+/// clearly visible on dark wallpapers
+fn dither_to_8bit(image: DynamicImage) -> DynamicImage {
+    let color = image.color();
+    if color.bytes_per_pixel() == color.channel_count() {
+        return image;
+    }
+    // Round up above a threshold varying by position in the pattern
+    let quantize = |value: f32, x: u32, y: u32| -> u8 {
+        let threshold = (BAYER_4X4[y as usize % 4][x as usize % 4] as f32
+            + 0.5) / 16.0;
+        (value * 255.0 + threshold).floor().clamp(0.0, 255.0) as u8
+    };
+    if color.has_alpha() {
+        let image = image.into_rgba32f();
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(
+            image.width(),
+            image.height(),
+            |x, y| {
+                let [red, green, blue, alpha] = image.get_pixel(x, y).0;
+                image::Rgba([
+                    quantize(red, x, y),
+                    quantize(green, x, y),
+                    quantize(blue, x, y),
+                    (alpha * 255.0).round().clamp(0.0, 255.0) as u8,
+                ])
+            }
+        ))
+    } else {
+        let image = image.into_rgb32f();
+        DynamicImage::ImageRgb8(RgbImage::from_fn(
+            image.width(),
+            image.height(),
+            |x, y| image::Rgb(image.get_pixel(x, y).0
+                .map(|value| quantize(value, x, y))
+            )
+        ))
+    }
+}
+
+fn write_rgb565(
+    image: &RgbImage,
+    dither: bool,
+    canvas: &mut [u8],
+    stride: usize,
+) {
+    let width = image.width() as usize;
+
+    // Quantize a channel to the given bits, with dithering the threshold
+    // of rounding up varies by position in a pattern instead of being 0.5
+    let quantize = |value: u8, bits: u32, x: usize, y: usize| -> u16 {
+        let levels = (1u32 << bits) - 1;
+        let offset = if dither {
+            (BAYER_4X4[y % 4][x % 4] as u32 * 2 + 1) * 255 / 32
+        } else {
+            127
+        };
+        ((value as u32 * levels + offset) / 255).min(levels) as u16
+    };
+
+    for (y, image_row) in image.rows().enumerate() {
+        let canvas_row = &mut canvas[y * stride..][..width * 2];
+        for (x, (image_pixel, canvas_pixel)) in image_row
+            .zip(canvas_row.chunks_exact_mut(2))
+            .enumerate()
+        {
+            let [red, green, blue] = image_pixel.0;
+            let pixel = quantize(red, 5, x, y) << 11
+                | quantize(green, 6, x, y) << 5
+                | quantize(blue, 5, x, y);
+            canvas_pixel.copy_from_slice(&pixel.to_le_bytes());
+        }
+    }
 }
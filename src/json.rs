@@ -0,0 +1,17 @@
+use serde::Serialize;
+
+/// Version of all the JSON printed or sent to clients. Adding fields keeps
+/// the version, removing or changing the meaning of fields increments it
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    schema: u32,
+    #[serde(flatten)]
+    content: &'a T,
+}
+
+pub fn to_json<T: Serialize>(content: &T) -> String {
+    serde_json::to_string(&Versioned { schema: SCHEMA_VERSION, content })
+        .unwrap()
+}
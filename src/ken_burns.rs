@@ -0,0 +1,114 @@
+use std::{
+    f64::consts::TAU,
+    path::Path,
+};
+
+use fast_image_resize::{
+    FilterType, PixelType, Resizer, ResizeAlg, ResizeOptions,
+    images::Image,
+};
+use image::DynamicImage;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    cli::ColorFilter,
+    decoder::DecoderRegistry,
+    image::{ImageSettings, ImageTarget, WallpaperImage, load_wallpaper},
+    swizzle::bgrx_from_rgb,
+};
+
+/// Frames per second, enough for the slow motion
+pub const KEN_BURNS_FPS: u32 = 15;
+/// The wallpaper is decoded this much larger than the output,
+/// at most this much of it is zoomed in
+const ZOOM: f64 = 1.12;
+/// Seconds of zooming in and out again
+const ZOOM_PERIOD: f64 = 60.0;
+/// Seconds of panning around, unrelated to the zoom to not repeat often
+const PAN_PERIOD_X: f64 = 170.0;
+const PAN_PERIOD_Y: f64 = 230.0;
+
+/// Slowly zooms and pans over a wallpaper decoded larger than the output
+pub struct KenBurns {
+    source: Image<'static>,
+    destination: Image<'static>,
+    resizer: Resizer,
+}
+
+impl KenBurns {
+    pub fn new(
+        path: &Path,
+        decoders: &DecoderRegistry,
+        settings: &ImageSettings,
+        width: u32,
+        height: u32,
+        filter: ColorFilter,
+    ) -> Result<KenBurns, String> {
+        let target = ImageTarget {
+            width: (width as f64 * ZOOM).round() as u32,
+            height: (height as f64 * ZOOM).round() as u32,
+            span_area: None,
+        };
+        let image = match load_wallpaper(
+            path, decoders, settings, &target, filter
+        )? {
+            WallpaperImage::Rgb(image) => image,
+            WallpaperImage::Rgba(image) =>
+                DynamicImage::ImageRgba8(image).into_rgb8(),
+        };
+        let source = Image::from_vec_u8(
+            image.width(),
+            image.height(),
+            image.into_raw(),
+            PixelType::U8x3,
+        ).map_err(|e| format!("Failed to zoom image: {}", e))?;
+        Ok(KenBurns {
+            source,
+            destination: Image::new(width, height, PixelType::U8x3),
+            resizer: Resizer::new(),
+        })
+    }
+
+    /// Rgb pixels of the output at the time in seconds, starting with
+    /// the whole image like the still wallpaper
+    pub fn frame(&mut self, time: f64) -> &[u8] {
+        let zoom = (1.0 - (TAU * time / ZOOM_PERIOD).cos()) / 2.0;
+        let pan_x = (1.0 - (TAU * time / PAN_PERIOD_X).cos()) / 2.0;
+        let pan_y = (1.0 - (TAU * time / PAN_PERIOD_Y).sin()) / 2.0;
+
+        let source_width = self.source.width() as f64;
+        let source_height = self.source.height() as f64;
+        let scale = 1.0 - (1.0 - 1.0 / ZOOM) * zoom;
+        let width = source_width * scale;
+        let height = source_height * scale;
+        let left = (source_width - width) * pan_x;
+        let top = (source_height - height) * pan_y;
+
+        let options = ResizeOptions::new()
+            .resize_alg(ResizeAlg::Convolution(FilterType::Bilinear))
+            .crop(left, top, width, height);
+        self.resizer.resize(&self.source, &mut self.destination, &options)
+            .unwrap();
+        self.destination.buffer()
+    }
+}
+
+/// Rgb pixels in the byte order of the buffer format without padding
+pub fn pack_rgb(rgb: &[u8], format: wl_shm::Format) -> Vec<u8> {
+    match format {
+        wl_shm::Format::Bgr888 => rgb.to_vec(),
+        wl_shm::Format::Rgb565 => rgb.chunks_exact(3)
+            .flat_map(|pixel| {
+                let [red, green, blue] = [pixel[0], pixel[1], pixel[2]]
+                    .map(u16::from);
+                ((red >> 3) << 11 | (green >> 2) << 5 | blue >> 3)
+                    .to_le_bytes()
+            })
+            .collect(),
+        _ => {
+            let mut packed = vec![0; rgb.len() / 3 * 4];
+            bgrx_from_rgb(rgb, &mut packed);
+            packed
+        }
+    }
+}
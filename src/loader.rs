@@ -0,0 +1,97 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        mpsc::{channel, Receiver, Sender},
+    },
+    thread,
+};
+
+use log::{debug, error};
+use mio::Waker;
+
+use crate::{
+    decoder::DecoderRegistry,
+    image::{Decoded, ImageSettings, ImageTarget, decode_parallel},
+    store::WallpaperKey,
+};
+
+/// A wallpaper decoded in the background for an output
+pub struct LoadedWallpaper {
+    pub output_name: String,
+    pub key: WallpaperKey,
+    pub decoded: Result<Decoded, String>,
+}
+
+/// Decodes wallpapers on worker threads while the outputs already show
+/// a placeholder, the results are put into buffers on the main thread
+pub struct BackgroundLoader {
+    tx: Sender<LoadedWallpaper>,
+    rx: Receiver<LoadedWallpaper>,
+    /// A poll has only a single waker, shared with the sway connection
+    waker: Arc<Waker>,
+}
+
+impl BackgroundLoader {
+    pub fn new(waker: Arc<Waker>) -> BackgroundLoader {
+        let (tx, rx) = channel();
+        BackgroundLoader { tx, rx, waker }
+    }
+
+    pub fn spawn(
+        &self,
+        output_name: String,
+        jobs: Vec<(PathBuf, WallpaperKey)>,
+        decoders: Arc<DecoderRegistry>,
+        settings: Arc<ImageSettings>,
+        target: ImageTarget,
+    ) {
+        if jobs.is_empty() {
+            return;
+        }
+        debug!(
+            "Loading {} wallpapers in the background on output '{}'",
+            jobs.len(), output_name
+        );
+        let tx = self.tx.clone();
+        let waker = Arc::clone(&self.waker);
+        thread::spawn(move || {
+            // A batch at a time to limit the memory taken by decoded images
+            let threads = thread::available_parallelism()
+                .map_or(1, |threads| threads.get());
+            for batch in jobs.chunks(threads) {
+                let mut decoded = decode_parallel(
+                    batch.iter().enumerate()
+                        .map(|(index, (path, key))|
+                            (index, path, key.filter())
+                        )
+                        .collect(),
+                    &decoders,
+                    &settings,
+                    &target,
+                );
+                for (index, (_, key)) in batch.iter().enumerate() {
+                    let Some(decoded) = decoded.remove(&index) else {
+                        continue;
+                    };
+                    let loaded = LoadedWallpaper {
+                        output_name: output_name.clone(),
+                        key: key.clone(),
+                        decoded,
+                    };
+                    // The receiver is gone when reconnecting
+                    if tx.send(loaded).is_err() {
+                        return;
+                    }
+                }
+                if let Err(e) = waker.wake() {
+                    error!("Failed to wake the main event loop: {}", e);
+                }
+            }
+        });
+    }
+
+    pub fn try_recv(&self) -> Option<LoadedWallpaper> {
+        self.rx.try_recv().ok()
+    }
+}
@@ -1,20 +1,62 @@
+mod cache;
 mod cli;
+mod clock;
+mod config;
+mod control;
+mod daemon;
+mod damage;
+mod decoder;
+mod doctor;
+mod display;
+mod estimate;
+mod event_log;
+mod glob;
+mod hook;
+mod icc;
 mod image;
+mod json;
+mod ken_burns;
+mod loader;
+mod night;
+mod notify;
+mod overlay;
+mod overrides;
+mod palette;
+mod paths;
+mod portal;
+mod power;
+mod prepare;
+mod preview;
+mod probe;
+mod scheduler;
+#[cfg(feature = "shader")]
+mod shader;
+mod signal;
+mod stats;
+mod store;
 mod sway;
+mod swizzle;
+mod sync;
+mod transition;
+mod validate;
+mod video;
 mod wayland;
 
 use std::{
     io,
     os::fd::AsRawFd,
     path::Path,
+    process,
+    rc::Rc,
     sync::{
         Arc,
         mpsc::{channel, Receiver},
-    }
+    },
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use mio::{
     Events, Interest, Poll, Token, Waker,
     unix::SourceFd,
@@ -27,7 +69,7 @@ use smithay_client_toolkit::{
     shm::Shm,
 };
 use smithay_client_toolkit::reexports::client::{
-    Connection, EventQueue,
+    EventQueue,
     backend::{ReadEventsGuard, WaylandError},
     globals::registry_queue_init,
 };
@@ -35,11 +77,42 @@ use smithay_client_toolkit::reexports::protocols
     ::wp::viewporter::client::wp_viewporter::WpViewporter;
 
 use crate::{
-    cli::{Cli, PixelFormat},
-    sway::{SwayConnectionTask, WorkspaceVisible},
-    wayland::State,
+    cache::DiskCache,
+    cli::{Cli, Command, ResizeFilter},
+    decoder::{DecodeLimits, DecoderRegistry, MAX_ANIMATION_FRAMES},
+    event_log::{EventRecorder, LoggedEvent, open_replay, spawn_replay},
+    paths::{Paths, replace_symlink},
+    hook::ChangeHook,
+    image::{ColorTransform, ImageSettings, LoadContext},
+    loader::BackgroundLoader,
+    night::NightMode,
+    notify::ServiceNotifier,
+    overlay::Overlay,
+    overrides::load_overrides,
+    portal::watch_color_scheme,
+    control::{Capabilities, ControlSocket, Request},
+    daemon::{PidFile, daemonize},
+    signal::{Signals, is_termination},
+    store::WallpaperStore,
+    sway::{ReplayedWorkspaces, SwayConnectionTask, WorkspaceVisible},
+    transition::TransitionClock,
+    wayland::{State, active_time_profile, idle_notification},
 };
 
+/// Wait at most this long between attempts to reconnect to the compositor
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(16);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(250);
+/// Tell the service manager the daemon is ready by then at the latest,
+/// even if an output never shows its wallpaper, eg. one that is off
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum RunError {
+    /// Could not connect or initialize the connection
+    Connect(String),
+    /// The established connection was lost
+    Disconnected(String),
+}
+
 fn main()
 {
     #[cfg(debug_assertions)]
@@ -55,29 +128,256 @@ fn main()
     ).init();
 
     let cli = Cli::parse();
-    let wallpaper_dir = Path::new(&cli.wallpaper_dir).canonicalize().unwrap();
 
+    if let Some(command) = &cli.command {
+        let result = match command {
+            Command::Sync { source, wallpaper_dir, rsync_arg } =>
+                Paths::new(&cli).and_then(|paths| sync::sync(
+                    source,
+                    wallpaper_dir,
+                    rsync_arg,
+                    &paths.control_socket(),
+                )),
+            Command::Prepare { wallpaper_dir, size, options } =>
+                prepare::prepare(wallpaper_dir, size, options),
+            Command::Preview {
+                wallpaper_dir, output, workspace, out, size, options
+            } => preview::preview(
+                wallpaper_dir, output, workspace, out, *size, options
+            ),
+            Command::Validate { wallpaper_dir, size } =>
+                validate::validate(wallpaper_dir, size),
+            Command::Doctor => doctor::doctor(&cli),
+            Command::Ctl { command } => Paths::new(&cli).and_then(|paths|
+                control::ctl(&paths.control_socket(), command)
+            ),
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if cli.list_outputs {
+        if let Err(e) = probe::print_outputs(cli.display.as_deref()) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let wallpaper_dir = Path::new(cli.wallpaper_dir.as_ref().unwrap())
+        .canonicalize().unwrap();
+
+    if cli.estimate {
+        if let Err(e) = estimate::estimate(&cli, &wallpaper_dir) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let paths = Paths::new(&cli).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    if cli.print_config {
+        config::print_config(&cli, &wallpaper_dir, &paths);
+        return;
+    }
+    debug!(
+        "Using cache dir {:?}, state dir {:?}, runtime dir {:?}, \
+config dir {:?}",
+        paths.cache_dir, paths.state_dir, paths.runtime_dir, paths.config_dir
+    );
+    if let Err(e) = paths.create_runtime_dir() {
+        error!("{}", e);
+        process::exit(1);
+    }
+
+    // Both before any threads are spawned,
+    // forking and editing the environment
+    let launcher = cli.daemon.then(|| daemonize(&paths.log_file()))
+        .transpose()
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        });
+    let mut service_notifier = ServiceNotifier::from_env(launcher);
+    let _pid_file = cli.daemon.then(|| PidFile::create(&paths.pid_file()))
+        .transpose()
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        });
+
+    if cli.disk_cache {
+        cache::spawn_prune(paths.cache_dir.join("wallpapers"));
+    }
+
+    let event_recorder = cli.record_events.as_ref()
+        .map(|path| EventRecorder::create(Path::new(path)))
+        .transpose()
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        });
+
+    // Reopened on every reconnection, but a missing event log
+    // is not worth waiting for like the compositor
+    if let Some(replay_path) = &cli.replay {
+        if let Err(e) = open_replay(Path::new(replay_path)) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    // Handled in the main event loop to clean up before exiting,
+    // to reload the wallpapers or to advance the slideshows
+    let signals = match Signals::catch(&[
+        libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2
+    ]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // Kept across reconnections, clients wait while disconnected
+    let mut control_socket = ControlSocket::bind(&paths.control_socket())
+        .inspect_err(|e| error!("No control socket: {}", e))
+        .ok();
+
+    let mut ever_connected = false;
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+
+    // Reconnect when the compositor crashes or restarts,
+    // all state is torn down and rebuilt with the new connection
+    loop {
+        match run(
+            &cli,
+            &wallpaper_dir,
+            &paths,
+            event_recorder.as_ref(),
+            &signals,
+            control_socket.as_mut(),
+            &mut service_notifier,
+        ) {
+            Ok(()) => return,
+            Err(RunError::Connect(e)) => {
+                // Eg. started by a service manager before the compositor
+                // or before its environment got imported, keep probing
+                if ever_connected {
+                    error!("{}", e);
+                }
+                else {
+                    warn!("{}, waiting for the compositor to start", e);
+                }
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+            }
+            Err(RunError::Disconnected(e)) => {
+                error!("{}", e);
+                ever_connected = true;
+                backoff = RECONNECT_BACKOFF_MIN;
+            }
+        }
+        warn!(
+            "Reconnecting to the compositor in {} ms", backoff.as_millis()
+        );
+        service_notifier.sleep(backoff);
+        // Nothing to clean up while not connected
+        if signals.received().into_iter()
+            .any(|signal| is_termination(signal, cli.reload_on_sighup))
+        {
+            return;
+        }
+    }
+}
+
+fn run(
+    cli: &Cli,
+    wallpaper_dir: &Path,
+    paths: &Paths,
+    event_recorder: Option<&EventRecorder>,
+    signals: &Signals,
+    mut control_socket: Option<&mut ControlSocket>,
+    service_notifier: &mut ServiceNotifier,
+) -> Result<(), RunError>
+{
     // ********************************
     //     Initialize wayland client
     // ********************************
 
-    let conn = Connection::connect_to_env().unwrap();
-    let (globals, mut event_queue) = registry_queue_init(&conn).unwrap();
+    let conn = display::connect(cli.display.as_deref())
+        .map_err(RunError::Connect)?;
+    let (globals, mut event_queue) = registry_queue_init(&conn)
+        .map_err(|e| RunError::Connect(
+            format!("Failed to initialize the wayland registry: {}", e)
+        ))?;
     let qh = event_queue.handle();
 
-    let compositor_state = CompositorState::bind(&globals, &qh).unwrap();
-    let layer_shell = LayerShell::bind(&globals, &qh).unwrap();
-    let shm = Shm::bind(&globals, &qh).unwrap();
+    let compositor_state = CompositorState::bind(&globals, &qh)
+        .map_err(|e| RunError::Connect(
+            format!("Compositor has no wl_compositor: {}", e)
+        ))?;
+    let layer_shell = LayerShell::bind(&globals, &qh)
+        .map_err(|e| RunError::Connect(format!(
+            "Compositor has no zwlr_layer_shell_v1, \
+it does not support wallpapers: {}", e
+        )))?;
+    let shm = Shm::bind(&globals, &qh)
+        .map_err(|e| RunError::Connect(
+            format!("Compositor has no wl_shm: {}", e)
+        ))?;
+    let wallpaper_store = WallpaperStore::new(&shm, cli.damage_tracking)
+        .map_err(RunError::Connect)?;
 
     let registry_state = RegistryState::new(&globals);
 
-    let viewporter: WpViewporter = registry_state
-        .bind_one(&qh, 1..=1, ()).expect("wp_viewporter not available");
+    let viewporter: Option<WpViewporter> = registry_state
+        .bind_one(&qh, 1..=1, ())
+        .inspect_err(|e| warn!(
+            "wp_viewporter not available, fractional scaling falls back \
+to integer scaling: {}", e
+        ))
+        .ok();
+
+    // Loading all of them would only evict most of them again
+    let lazy = cli.lazy || cli.max_memory.is_some();
+    let idle_notification = cli.idle_precompute.and_then(|seconds| {
+        if !lazy {
+            warn!("--idle-precompute only has an effect with --lazy");
+            return None;
+        }
+        idle_notification(&registry_state, &qh, Duration::from_secs(seconds))
+    });
 
     // Sync tools for sway ipc tasks
-    let mut poll = Poll::new().unwrap();
-    let waker = Arc::new(Waker::new(poll.registry(), SWAY).unwrap());
+    let mut poll = Poll::new().map_err(|e| RunError::Connect(
+        format!("Failed to create the main event loop: {}", e)
+    ))?;
+    let waker = Arc::new(Waker::new(poll.registry(), SWAY)
+        .map_err(|e| RunError::Connect(
+            format!("Failed to create the main event loop waker: {}", e)
+        ))?
+    );
     let (tx, rx) = channel();
+    let (color_scheme_tx, color_scheme_rx) = channel();
+    let change_hook = cli.on_change.as_deref().map(ChangeHook::spawn);
+
+    // Replayed events are diagnosed offline without sway
+    let replayed: Option<ReplayedWorkspaces> = cli.replay.as_ref()
+        .map(|_| Default::default());
+    let sway_connection_task = match &replayed {
+        Some(replayed) => SwayConnectionTask::replay(
+            tx.clone(), Arc::clone(&waker), Arc::clone(replayed)
+        ),
+        None => SwayConnectionTask::new(
+            tx.clone(), Arc::clone(&waker), cli.layout_wallpapers
+        ).map_err(RunError::Connect)?,
+    };
 
     let mut state = State {
         compositor_state,
@@ -86,21 +386,88 @@ fn main()
         shm,
         layer_shell,
         viewporter,
-        wallpaper_dir,
-        force_xrgb8888: cli.pixelformat
-            .is_some_and(|p| p == PixelFormat::Baseline),
+        render_size: cli.render_size,
+        wallpaper_dir: wallpaper_dir.to_path_buf(),
+        profiles: cli.profile.clone(),
+        active_profile: None,
+        time_profiles: cli.time_profile.clone(),
+        active_time_profile: active_time_profile(&cli.time_profile),
+        color_scheme: cli.follow_color_scheme.then(|| watch_color_scheme(
+            color_scheme_tx, Arc::clone(&waker)
+        )),
+        spans: cli.span.clone(),
+        decoders: Arc::new(decoder_registry(cli)),
+        requested_pixel_format: cli.pixelformat,
         pixel_format: None,
         background_layers: Vec::new(),
-        sway_connection_task: SwayConnectionTask::new(
-            tx.clone(), Arc::clone(&waker)
-        ),
-        brightness: cli.brightness.unwrap_or(0),
-        contrast: cli.contrast.unwrap_or(0.0),
+        pending_outputs: Vec::new(),
+        only_outputs: cli.only_output.clone(),
+        skip_outputs: cli.skip_output.clone(),
+        ignored_outputs: Vec::new(),
+        wallpaper_store,
+        sway_connection_task,
+        image_settings: Arc::new(image_settings(cli)),
+        background_loader: BackgroundLoader::new(Arc::clone(&waker)),
+        min_redraw_interval: Duration::from_millis(cli.min_redraw_interval),
+        anchors: cli.anchor.clone(),
+        margins: cli.margin.clone(),
+        exclusive_zones: cli.exclusive_zone.clone(),
+        transitions: cli.transition.clone(),
+        fade_in: Duration::from_millis(cli.fade_in),
+        fade_out: Duration::from_millis(cli.fade_out),
+        fade_out_color: cli.fade_out_color,
+        transition_clock: Rc::new(TransitionClock::new(Duration::from_millis(
+            cli.transition_duration
+        ))),
+        exit_deadline: None,
+        max_fps: (cli.max_fps > 0).then_some(cli.max_fps),
+        adaptive_fps: cli.adaptive_fps,
+        on_battery: false,
+        next_power_check: None,
+        night_mode: cli.night_dim.as_ref().map(|night_dim| NightMode {
+            time_range: night_dim.time_range,
+            dim: night_dim.percent,
+            tint: cli.night_tint,
+        }),
+        night_active: false,
+        lazy,
+        slide_interval: cli.slideshow.map(Duration::from_secs),
+        random: cli.random,
+        overrides: load_overrides(&paths.overrides_file()),
+        overrides_file: paths.overrides_file(),
+        override_history: Vec::new(),
+        max_memory: cli.max_memory.map(|megabytes| megabytes << 20),
+        memory_checked_loads: 0,
+        paused_since: None,
+        palette_dir: cli.palette.then(|| paths.runtime_dir.join("palette")),
+        overlay: cli.overlay.as_ref().and_then(|template| Overlay::new(
+            template.clone(),
+            cli.overlay_font.as_deref().map(Path::new),
+            cli.overlay_size,
+            cli.overlay_corner,
+        ).inspect_err(|e| error!("{}", e)).ok()).map(Rc::new),
+        keep_wallpapers: Duration::from_secs(cli.keep_wallpapers),
+        disk_cache: cli.disk_cache.then(|| DiskCache::new(
+            paths.cache_dir.join("wallpapers"),
+            &cache_settings(cli),
+            cli.verify_cache,
+        )),
+        event_recorder: event_recorder
+            .map(EventRecorder::try_clone)
+            .transpose()
+            .map_err(RunError::Connect)?,
+        idle_notification,
+        idle: false,
     };
 
-    event_queue.roundtrip(&mut state).unwrap();
+    event_queue.roundtrip(&mut state).map_err(|e| RunError::Connect(
+        format!("Initial wayland roundtrip failed: {}", e)
+    ))?;
 
     debug!("Initial wayland roundtrip done. Starting main event loop.");
+    let ready_deadline = Instant::now() + READY_TIMEOUT;
+
+    state.update_night_mode();
 
     // ********************************
     //     Main event loop
@@ -109,29 +476,90 @@ fn main()
     let mut events = Events::with_capacity(16);
 
     const WAYLAND: Token = Token(0);
-    let read_guard = event_queue.prepare_read().unwrap();
+    let read_guard = event_queue.prepare_read().ok_or_else(||
+        RunError::Connect(
+            "Failed to prepare reading Wayland events".to_string()
+        )
+    )?;
     let wayland_socket_fd = read_guard.connection_fd().as_raw_fd();
     poll.registry().register(
         &mut SourceFd(&wayland_socket_fd),
         WAYLAND,
         Interest::READABLE
-    ).unwrap();
+    ).map_err(|e| RunError::Connect(
+        format!("Failed to poll the Wayland connection: {}", e)
+    ))?;
     drop(read_guard);
 
     const SWAY: Token = Token(1);
-    SwayConnectionTask::new(tx, waker).spawn_subscribe_event_loop();
+    if let (Some(replay_path), Some(replayed)) = (&cli.replay, replayed) {
+        let replay_path = Path::new(replay_path);
+        let file = open_replay(replay_path).map_err(RunError::Connect)?;
+        spawn_replay(file, replay_path, tx, waker, replayed);
+    }
+    else {
+        SwayConnectionTask::new(tx, waker, cli.layout_wallpapers)
+            .map_err(RunError::Connect)?
+            .spawn_subscribe_event_loop();
+    }
+
+    const SIGNAL: Token = Token(2);
+    poll.registry().register(
+        &mut SourceFd(&signals.as_raw_fd()),
+        SIGNAL,
+        Interest::READABLE
+    ).map_err(|e| RunError::Connect(
+        format!("Failed to poll for signals: {}", e)
+    ))?;
+
+    const CONTROL: Token = Token(3);
+    if let Some(control_socket) = &control_socket {
+        poll.registry().register(
+            &mut SourceFd(&control_socket.as_raw_fd()),
+            CONTROL,
+            Interest::READABLE
+        ).map_err(|e| RunError::Connect(
+            format!("Failed to poll the control socket: {}", e)
+        ))?;
+    }
 
     loop {
-        event_queue.flush().unwrap();
-        event_queue.dispatch_pending(&mut state).unwrap();
-        let mut read_guard_option = Some(event_queue.prepare_read().unwrap());
+        event_queue.flush().map_err(|e| RunError::Disconnected(
+            format!("Failed to flush Wayland requests: {}", e)
+        ))?;
+        event_queue.dispatch_pending(&mut state).map_err(|e|
+            RunError::Disconnected(
+                format!("Failed to dispatch pending Wayland events: {}", e)
+            )
+        )?;
+        let mut read_guard_option = Some(
+            event_queue.prepare_read().ok_or_else(|| RunError::Disconnected(
+                "Failed to prepare reading Wayland events".to_string()
+            ))?
+        );
+
+        let timeout = [
+            state.next_deferred_draw_time(),
+            state.next_animation_frame_time(),
+            state.next_night_mode_change(),
+            state.next_time_profile_change(),
+            state.next_slide_time(),
+            state.wallpaper_store.next_retained_expiry(),
+            state.exit_deadline,
+            state.next_power_check,
+            service_notifier.next_watchdog_ping(),
+            (!service_notifier.is_ready()).then_some(ready_deadline),
+        ].into_iter().flatten().min()
+            .map(|time| time.saturating_duration_since(Instant::now()));
 
-        if let Err(poll_error) = poll.poll(&mut events, None) {
+        if let Err(poll_error) = poll.poll(&mut events, timeout) {
             if poll_error.kind() == io::ErrorKind::Interrupted {
                 continue;
             }
             else {
-                panic!("Main event loop poll failed: {:?}", poll_error);
+                return Err(RunError::Disconnected(
+                    format!("Main event loop poll failed: {}", poll_error)
+                ));
             }
         }
 
@@ -141,34 +569,235 @@ fn main()
                     &mut state,
                     &mut read_guard_option,
                     &mut event_queue
-                ),
-                SWAY => handle_sway_event(&mut state, &rx),
+                ).map_err(RunError::Disconnected)?,
+                SWAY => {
+                    state.receive_loaded_wallpapers();
+                    for color_scheme in color_scheme_rx.try_iter() {
+                        state.set_color_scheme(color_scheme);
+                    }
+                    handle_sway_event(&mut state, &rx);
+                }
+                SIGNAL => {
+                    let received = signals.received();
+                    if received.iter().any(|&signal|
+                        is_termination(signal, cli.reload_on_sighup)
+                    ) {
+                        if state.exit_deadline.is_some() {
+                            // Asked again, stop waiting for the fade out
+                            state.exit_deadline = Some(Instant::now());
+                        }
+                        else {
+                            state.start_exit();
+                        }
+                    }
+                    else if received.contains(&libc::SIGUSR1)
+                        || received.contains(&libc::SIGHUP)
+                    {
+                        info!("Reloading all wallpapers on signal");
+                        state.reload_all_wallpapers();
+                    }
+                    else if received.contains(&libc::SIGUSR2) {
+                        debug!("Advancing the focused slideshow on SIGUSR2");
+                        state.advance_focused_slideshow();
+                    }
+                }
+                CONTROL => {
+                    let requests = control_socket.as_deref_mut().unwrap()
+                        .requests();
+                    for (stream, request) in requests {
+                        let result = request.and_then(|request|
+                            handle_control_request(&mut state, request)
+                        );
+                        control::reply(stream, result);
+                    }
+                }
                 _ => unreachable!()
             }
         }
+
+        state.update_night_mode();
+        state.update_time_profile();
+        state.update_power_supply();
+        state.update_slideshows();
+        state.expire_kept_wallpapers();
+        state.enforce_memory_limit();
+        state.draw_deferred();
+        state.draw_animation_frames();
+
+        let changes = state.take_wallpaper_changes();
+        for change in &changes {
+            let link = paths.current_wallpaper_link(&change.output);
+            if let Err(e) = replace_symlink(&link, &change.path) {
+                warn!("Failed to link {:?} to {:?}: {}", link, change.path, e);
+            }
+        }
+        if let Some(control_socket) = control_socket.as_deref_mut() {
+            control_socket.notify(&changes);
+        }
+        if let Some(change_hook) = &change_hook {
+            change_hook.send(changes);
+        }
+
+        if !service_notifier.is_ready()
+            && (state.wallpapers_shown() || Instant::now() >= ready_deadline)
+        {
+            service_notifier.ready();
+        }
+        service_notifier.ping_watchdog();
+
+        if state.ready_to_exit() {
+            drop(read_guard_option);
+            state.destroy_layers();
+            if let Err(e) = event_queue.flush() {
+                warn!("Failed to flush Wayland requests before exiting: {}", e);
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn decoder_registry(cli: &Cli) -> DecoderRegistry {
+    DecoderRegistry::new(
+        &cli.decoder,
+        cli.decoder_fallback.clone(),
+        DecodeLimits {
+            max_dimension: cli.max_image_size,
+            max_bytes: cli.max_image_memory << 20,
+            max_frames: MAX_ANIMATION_FRAMES,
+        },
+    )
+}
+
+fn image_settings(cli: &Cli) -> ImageSettings {
+    ImageSettings {
+        color_transform: ColorTransform {
+            brightness: cli.brightness,
+            contrast: cli.contrast,
+            saturation: cli.saturation,
+            hue_rotate: cli.hue_rotate,
+            gamma: cli.gamma,
+        },
+        keep_alpha: cli.alpha,
+        dither: cli.dither,
+        tone_map: cli.tone_map,
+        exposure: cli.exposure,
+        resize_filter: resize_filter(cli),
+        scaling: cli.scaling,
+        crop_gravity: cli.crop_gravity,
+        workspace_filters: cli.workspace_filter.clone(),
+        dim: cli.dim.unwrap_or(0),
+        vignette: cli.vignette.unwrap_or(0),
+        bar_shades: cli.bar_shade.clone(),
+        background_color: cli.background_color,
+        blur_fill: cli.blur_fill,
+        shader_fps: cli.shader_fps,
+        ken_burns: cli.ken_burns.clone(),
     }
 }
 
+fn resize_filter(cli: &Cli) -> ResizeFilter {
+    cli.resize_filter.unwrap_or(
+        if cli.fast_start { ResizeFilter::Bilinear }
+        else { ResizeFilter::Lanczos3 }
+    )
+}
+
+/// Everything besides the wallpaper key that changes the processed pixels,
+/// also the version as changes to the processing may change them
+fn cache_settings(cli: &Cli) -> String {
+    format!(
+        "version={} max_image_size={} max_image_memory={} \
+brightness={:?} contrast={:?} saturation={:?} hue={:?} gamma={:?} \
+dim={:?} vignette={:?} bar_shade={:?} alpha={} dither={} tone_map={:?} \
+exposure={:?} resize={:?} \
+scaling={:?} gravity={:?} background={:?} blur_fill={} decoders={:?} {:?}",
+        env!("CARGO_PKG_VERSION"),
+        cli.max_image_size,
+        cli.max_image_memory,
+        cli.brightness,
+        cli.contrast,
+        cli.saturation,
+        cli.hue_rotate,
+        cli.gamma,
+        cli.dim,
+        cli.vignette,
+        cli.bar_shade,
+        cli.alpha,
+        cli.dither,
+        cli.tone_map,
+        cli.exposure,
+        resize_filter(cli),
+        cli.scaling,
+        cli.crop_gravity,
+        cli.background_color,
+        cli.blur_fill,
+        cli.decoder.iter()
+            .map(|decoder| (decoder.format.name(), &decoder.command))
+            .collect::<Vec<_>>(),
+        cli.decoder_fallback,
+    )
+}
+
 fn handle_wayland_event(
     state: &mut State,
     read_guard_option: &mut Option<ReadEventsGuard>,
     event_queue: &mut EventQueue<State>,
-) {
+) -> Result<(), String> {
     if let Some(read_guard) = read_guard_option.take() {
         if let Err(e) = read_guard.read() {
             // WouldBlock is normal here because of epoll false wakeups
             if let WaylandError::Io(ref io_err) = e {
                 if io_err.kind() == io::ErrorKind::WouldBlock {
-                    return;
+                    return Ok(());
                 }
             }
-            panic!("Failed to read Wayland events: {}", e)
+            return Err(format!("Failed to read Wayland events: {}", e));
         }
 
         if let Err(e) = event_queue.dispatch_pending(state) {
-            panic!("Failed to dispatch pending Wayland events: {}", e);
+            return Err(
+                format!("Failed to dispatch pending Wayland events: {}", e)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The content of the reply if there is any
+fn handle_control_request(
+    state: &mut State,
+    request: Request,
+) -> Result<Option<serde_json::Value>, String> {
+    match request {
+        Request::Reload => {
+            info!("Reloading all wallpapers on request");
+            state.reload_all_wallpapers();
+        }
+        Request::Status => {
+            return Ok(Some(serde_json::to_value(state.status()).unwrap()));
+        }
+        Request::Next => state.cycle_focused_wallpaper(true)?,
+        Request::Prev => state.cycle_focused_wallpaper(false)?,
+        Request::Back => state.undo_wallpaper_change()?,
+        Request::Stats => {
+            return Ok(Some(serde_json::to_value(state.stats()).unwrap()));
         }
+        Request::Pause => state.pause(),
+        Request::Resume => state.resume(),
+        Request::Blank { output, color } => state.blank_output(&output, color)?,
+        Request::Unblank { output } => state.unblank_output(&output)?,
+        Request::Capabilities => {
+            return Ok(Some(
+                serde_json::to_value(Capabilities::new()).unwrap()
+            ));
+        }
+        Request::Set { output, workspace, path, persist } => {
+            state.set_wallpaper(&output, &workspace, &path, persist)?;
+        }
+        // Kept by the control socket
+        Request::Subscribe => unreachable!(),
     }
+    Ok(None)
 }
 
 fn handle_sway_event(
@@ -177,11 +806,47 @@ fn handle_sway_event(
 ) {
     while let Ok(workspace) = rx.try_recv()
     {
+        // Requested again on resuming
+        if state.is_paused() {
+            debug!(
+                "Paused, ignoring workspace '{}' on output '{}'",
+                workspace.workspace_name, workspace.output
+            );
+            continue;
+        }
+
+        if let Some(event_recorder) = &mut state.event_recorder {
+            event_recorder.record(LoggedEvent::WorkspaceVisible {
+                output: workspace.output.clone(),
+                workspace_name: workspace.workspace_name.clone(),
+                layout: workspace.layout.clone(),
+                focused: workspace.focused,
+            });
+        }
+
+        state.set_output_focus(&workspace.output, workspace.focused);
+
+        let ctx = LoadContext {
+            decoders: &state.decoders,
+            disk_cache: state.disk_cache.as_ref(),
+            settings: &state.image_settings,
+        };
         // Find the background layer that of the output where the workspace is
         if let Some(affected_bg_layer) = state.background_layers.iter_mut()
             .find(|bg_layer| bg_layer.output_name == workspace.output)
         {
-            affected_bg_layer.draw_workspace_bg(&workspace.workspace_name);
+            affected_bg_layer.request_workspace_bg(
+                &workspace,
+                state.min_redraw_interval,
+                &mut state.wallpaper_store,
+                &ctx,
+            );
+        }
+        else if state.is_ignored_output(&workspace.output) {
+            debug!(
+                "Workspace '{}' is on ignored output '{}'",
+                workspace.workspace_name, workspace.output
+            );
         }
         else {
             error!(
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::clock::{TimeRange, local_time_of_day};
+
+/// Dim and tint wallpapers during a daily time range
+#[derive(Clone)]
+pub struct NightMode {
+    pub time_range: TimeRange,
+    /// Percent of brightness to take away
+    pub dim: u8,
+    /// Multiplies the colors, eg. ffc080 for a warm tint
+    pub tint: [u8; 3],
+}
+
+impl NightMode {
+    pub fn is_active(&self) -> bool {
+        self.time_range.contains(local_time_of_day())
+    }
+
+    pub fn next_change(&self) -> Instant {
+        // Wake up a bit after the change so it is surely past it
+        Instant::now() + self.time_range.next_change(local_time_of_day())
+            + Duration::from_millis(100)
+    }
+
+    pub fn lut(&self) -> Lut {
+        let factor = |tint: u8|
+            (100 - self.dim.min(100) as u32) * tint as u32;
+        let channel = |tint: u8| {
+            let factor = factor(tint);
+            std::array::from_fn(|value|
+                ((value as u32 * factor + 12750) / 25500) as u8
+            )
+        };
+        Lut {
+            red: channel(self.tint[0]),
+            green: channel(self.tint[1]),
+            blue: channel(self.tint[2]),
+        }
+    }
+}
+
+/// Lookup tables mapping every value of each color channel
+#[derive(Clone)]
+pub struct Lut {
+    red: [u8; 256],
+    green: [u8; 256],
+    blue: [u8; 256],
+}
+
+impl Lut {
+    /// Map the pixels of a buffer in the given format, the alpha of
+    /// Argb8888 is premultiplied so scaling down its colors is still valid
+    pub fn apply(&self, src: &[u8], dst: &mut [u8], format: wl_shm::Format) {
+        match format {
+            wl_shm::Format::Xrgb8888 | wl_shm::Format::Argb8888 => {
+                for (src, dst) in src.chunks_exact(4)
+                    .zip(dst.chunks_exact_mut(4))
+                {
+                    dst[0] = self.blue[src[0] as usize];
+                    dst[1] = self.green[src[1] as usize];
+                    dst[2] = self.red[src[2] as usize];
+                    dst[3] = src[3];
+                }
+            }
+            wl_shm::Format::Bgr888 => {
+                // Padding at the end of rows gets mapped too, harmlessly
+                for (src, dst) in src.chunks_exact(3)
+                    .zip(dst.chunks_exact_mut(3))
+                {
+                    dst[0] = self.red[src[0] as usize];
+                    dst[1] = self.green[src[1] as usize];
+                    dst[2] = self.blue[src[2] as usize];
+                }
+            }
+            wl_shm::Format::Rgb565 => {
+                let map = |lut: &[u8; 256], value: u16, bits: u32| {
+                    let max = (1u32 << bits) - 1;
+                    let value8 = (value as u32 * 255 + max / 2) / max;
+                    ((lut[value8 as usize] as u32 * max + 127) / 255) as u16
+                };
+                for (src, dst) in src.chunks_exact(2)
+                    .zip(dst.chunks_exact_mut(2))
+                {
+                    let pixel = u16::from_le_bytes([src[0], src[1]]);
+                    let pixel = map(&self.red, pixel >> 11, 5) << 11
+                        | map(&self.green, (pixel >> 5) & 0x3f, 6) << 5
+                        | map(&self.blue, pixel & 0x1f, 5);
+                    dst.copy_from_slice(&pixel.to_le_bytes());
+                }
+            }
+            _ => dst[..src.len()].copy_from_slice(src),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+
+use crate::daemon::Launcher;
+
+/// Tells the service manager about the daemon with the sd_notify
+/// protocol, eg. for a systemd service of Type=notify and WatchdogSec,
+/// does nothing if not started by one. Also tells the process that
+/// launched the daemon with --daemon that it is ready
+pub struct ServiceNotifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    launcher: Option<Launcher>,
+    ready: bool,
+    /// Half the watchdog timeout as recommended
+    watchdog_interval: Option<Duration>,
+    next_ping: Option<Instant>,
+}
+
+impl ServiceNotifier {
+    /// Takes the variables from the environment,
+    /// so the commands run by multibg-sway do not inherit them
+    pub fn from_env(launcher: Option<Launcher>) -> ServiceNotifier {
+        let socket = env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            let path = path.to_string_lossy().into_owned();
+            let addr = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            match addr.and_then(|addr|
+                UnixDatagram::unbound().map(|socket| (socket, addr))
+            ) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    error!("Failed to use NOTIFY_SOCKET {:?}: {}", path, e);
+                    None
+                }
+            }
+        });
+        let watchdog_pid = env::var("WATCHDOG_PID").ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let watchdog_interval = env::var("WATCHDOG_USEC").ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            // Meant for another process if not for this one
+            .filter(|_| watchdog_pid.is_none()
+                || watchdog_pid == Some(process::id())
+            )
+            .filter(|_| socket.is_some())
+            .map(|usec| Duration::from_micros(usec) / 2);
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(var);
+        }
+        if let Some(interval) = watchdog_interval {
+            debug!("Pinging the watchdog every {} ms", interval.as_millis());
+        }
+        ServiceNotifier {
+            socket,
+            launcher,
+            ready: false,
+            watchdog_interval,
+            next_ping: None,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Once the wallpapers are shown, the first time only
+    pub fn ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            self.send("READY=1");
+            if let Some(launcher) = self.launcher.take() {
+                launcher.ready();
+            }
+        }
+    }
+
+    pub fn next_watchdog_ping(&self) -> Option<Instant> {
+        self.watchdog_interval?;
+        Some(self.next_ping.unwrap_or_else(Instant::now))
+    }
+
+    /// Tell the watchdog the daemon is alive if it is time to
+    pub fn ping_watchdog(&mut self) {
+        let Some(interval) = self.watchdog_interval else { return };
+        let now = Instant::now();
+        if self.next_ping.is_some_and(|next_ping| now < next_ping) {
+            return;
+        }
+        self.send("WATCHDOG=1");
+        self.next_ping = Some(now + interval);
+    }
+
+    /// While not connected to the compositor, still alive meanwhile
+    pub fn sleep(&mut self, duration: Duration) {
+        let end = Instant::now() + duration;
+        loop {
+            self.ping_watchdog();
+            let now = Instant::now();
+            if now >= end {
+                return;
+            }
+            let wake = self.next_watchdog_ping()
+                .map_or(end, |ping| ping.min(end));
+            thread::sleep(wake.saturating_duration_since(now));
+        }
+    }
+
+    fn send(&self, state: &str) {
+        let Some((socket, addr)) = &self.socket else { return };
+        if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+            error!("Failed to notify the service manager: {}", e);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+};
+
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont, point};
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    cli::Corner,
+    store::bytes_per_pixel,
+};
+
+/// Of the text height, between the text, its box and the output edge
+const PADDING: f32 = 0.4;
+/// Opacity of the black box behind the text
+const BOX_OPACITY: f32 = 0.5;
+
+/// Text drawn into a corner of the wallpapers, from a template with
+/// {workspace}, {output} and {hostname} placeholders
+pub struct Overlay {
+    template: String,
+    font: FontVec,
+    size: f32,
+    corner: Corner,
+    hostname: String,
+}
+
+impl Overlay {
+    /// The font by fontconfig unless given
+    pub fn new(
+        template: String,
+        font_path: Option<&Path>,
+        size: f32,
+        corner: Corner,
+    ) -> Result<Overlay, String> {
+        let font_path = match font_path {
+            Some(font_path) => font_path.to_path_buf(),
+            None => default_font()?.into(),
+        };
+        let data = fs::read(&font_path).map_err(|e|
+            format!("Failed to read font {:?}: {}", font_path, e)
+        )?;
+        let font = FontVec::try_from_vec(data).map_err(|e|
+            format!("Failed to load font {:?}: {}", font_path, e)
+        )?;
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_default();
+        Ok(Overlay { template, font, size, corner, hostname })
+    }
+
+    pub fn text(&self, workspace_name: &str, output_name: &str) -> String {
+        self.template
+            .replace("{workspace}", workspace_name)
+            .replace("{output}", output_name)
+            .replace("{hostname}", &self.hostname)
+    }
+
+    /// Draw white text on a translucent black box into the corner
+    /// of the pixels in the buffer format
+    pub fn draw(
+        &self,
+        text: &str,
+        canvas: &mut [u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: wl_shm::Format,
+    ) {
+        let font = self.font.as_scaled(PxScale::from(self.size));
+        let padding = (self.size * PADDING).round();
+
+        // Coverage of the text, laid out on a single line
+        let mut glyphs = Vec::new();
+        let mut caret = 0.0;
+        let mut previous = None;
+        for c in text.chars() {
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += font.kern(previous, id);
+            }
+            glyphs.push(id.with_scale_and_position(
+                self.size, point(caret, font.ascent())
+            ));
+            caret += font.h_advance(id);
+            previous = Some(id);
+        }
+        let text_width = caret.ceil() as usize;
+        let text_height = font.height().ceil() as usize;
+        let mut coverage = vec![0.0f32; text_width * text_height];
+        for glyph in glyphs {
+            let Some(outlined) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|x, y, value| {
+                let x = bounds.min.x as i64 + x as i64;
+                let y = bounds.min.y as i64 + y as i64;
+                if (0..text_width as i64).contains(&x)
+                    && (0..text_height as i64).contains(&y)
+                {
+                    let index = y as usize * text_width + x as usize;
+                    coverage[index] = (coverage[index] + value).min(1.0);
+                }
+            });
+        }
+
+        let box_width = text_width + 2 * padding as usize;
+        let box_height = text_height + 2 * padding as usize;
+        let margin = padding as usize;
+        let (width, height) = (width as usize, height as usize);
+        if box_width + 2 * margin > width || box_height + 2 * margin > height {
+            return;
+        }
+        let left = match self.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight =>
+                width - margin - box_width,
+        };
+        let top = match self.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight =>
+                height - margin - box_height,
+        };
+
+        let bytes_per_pixel = bytes_per_pixel(format);
+        for y in 0..box_height {
+            let row = &mut canvas[(top + y) * stride..];
+            for x in 0..box_width {
+                let text_x = x.wrapping_sub(padding as usize);
+                let text_y = y.wrapping_sub(padding as usize);
+                let alpha = if text_x < text_width && text_y < text_height {
+                    coverage[text_y * text_width + text_x]
+                } else {
+                    0.0
+                };
+                let pixel = &mut row[(left + x) * bytes_per_pixel..]
+                    [..bytes_per_pixel];
+                let rgb = read_pixel(pixel, format).map(|value|
+                    value as f32 * (1.0 - BOX_OPACITY) * (1.0 - alpha)
+                        + 255.0 * alpha
+                );
+                write_pixel(pixel, format, rgb.map(|value| value as u8));
+                if format == wl_shm::Format::Argb8888 {
+                    // Premultiplied, the box and the text cover the pixel
+                    let box_alpha = 255.0 * BOX_OPACITY
+                        + pixel[3] as f32 * (1.0 - BOX_OPACITY);
+                    pixel[3] = (box_alpha * (1.0 - alpha) + 255.0 * alpha)
+                        as u8;
+                }
+            }
+        }
+    }
+}
+
+/// The file of the default sans-serif font
+fn default_font() -> Result<String, String> {
+    let output = Command::new("fc-match")
+        .args(["--format=%{file}", "sans-serif"])
+        .output()
+        .map_err(|e| format!(
+            "Failed to find a font with fc-match, give one with \
+--overlay-font: {}", e
+        ))?;
+    String::from_utf8(output.stdout).ok()
+        .filter(|path| output.status.success() && !path.is_empty())
+        .ok_or_else(|| "fc-match found no font".to_string())
+}
+
+pub fn read_pixel(pixel: &[u8], format: wl_shm::Format) -> [u8; 3] {
+    match format {
+        wl_shm::Format::Bgr888 => [pixel[0], pixel[1], pixel[2]],
+        wl_shm::Format::Rgb565 => {
+            let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+            let expand = |value: u16, bits: u32|
+                (value as u32 * 255 / ((1 << bits) - 1)) as u8;
+            [
+                expand(value >> 11, 5),
+                expand(value >> 5 & 0x3f, 6),
+                expand(value & 0x1f, 5),
+            ]
+        }
+        // Xrgb8888 and Argb8888 are little endian
+        _ => [pixel[2], pixel[1], pixel[0]],
+    }
+}
+
+pub fn write_pixel(pixel: &mut [u8], format: wl_shm::Format, rgb: [u8; 3]) {
+    let [red, green, blue] = rgb;
+    match format {
+        wl_shm::Format::Bgr888 => pixel.copy_from_slice(&rgb),
+        wl_shm::Format::Rgb565 => {
+            let [red, green, blue] = rgb.map(u16::from);
+            pixel.copy_from_slice(
+                &((red >> 3) << 11 | (green >> 2) << 5 | blue >> 3)
+                    .to_le_bytes()
+            );
+        }
+        _ => {
+            pixel[0] = blue;
+            pixel[1] = green;
+            pixel[2] = red;
+        }
+    }
+}
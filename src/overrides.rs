@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::json::to_json;
+
+/// A wallpaper set at runtime for a workspace of an output
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WallpaperOverride {
+    pub output: String,
+    pub workspace: String,
+    pub path: PathBuf,
+}
+
+/// Made with `ctl set` to undo with `ctl back`
+pub struct OverrideChange {
+    pub output: String,
+    pub workspace: String,
+    /// None if the wallpaper directory decided before
+    pub previous: Option<WallpaperOverride>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OverridesFile {
+    overrides: Vec<WallpaperOverride>,
+}
+
+/// The overrides persisted with `ctl set --persist`,
+/// none if the file does not exist yet
+pub fn load_overrides(path: &Path) -> Vec<WallpaperOverride> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            error!("Failed to read {:?}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    match serde_json::from_str::<OverridesFile>(&content) {
+        Ok(file) => file.overrides,
+        Err(e) => {
+            error!("Invalid wallpaper overrides in {:?}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Add the override to the file, replacing the one
+/// for the same workspace of the output
+pub fn persist_override(
+    path: &Path,
+    wallpaper_override: &WallpaperOverride,
+) -> Result<(), String> {
+    let mut overrides = load_overrides(path);
+    overrides.retain(|persisted| !persisted.is_for(
+        &wallpaper_override.output, &wallpaper_override.workspace
+    ));
+    overrides.push(wallpaper_override.clone());
+    write_overrides(path, overrides)
+        .map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+fn write_overrides(
+    path: &Path,
+    overrides: Vec<WallpaperOverride>,
+) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension(format!("tmp{}", process::id()));
+    let mut file = fs::File::create(&tmp_path)?;
+    writeln!(file, "{}", to_json(&OverridesFile { overrides }))?;
+    fs::rename(&tmp_path, path)
+}
+
+impl WallpaperOverride {
+    pub fn is_for(&self, output: &str, workspace: &str) -> bool {
+        self.output == output && self.workspace == workspace
+    }
+}
+
+impl OverrideChange {
+    pub fn is_for(&self, output: &str, workspace: &str) -> bool {
+        self.output == output && self.workspace == workspace
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+};
+
+use serde::Serialize;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::json::to_json;
+
+/// About this many pixels are looked at, enough for a few colors
+const SAMPLES: usize = 1 << 16;
+const ACCENT_COUNT: usize = 4;
+/// Accents differ at least this much from each other and the dominant
+/// color, in the sum of the channel differences
+const MIN_DISTANCE: u32 = 96;
+
+/// Colors of a wallpaper for other programs to theme themselves after
+#[derive(Clone, Serialize)]
+pub struct Palette {
+    /// The most common color
+    pub dominant: String,
+    /// Other common colors, the more saturated the more preferred
+    pub accents: Vec<String>,
+}
+
+impl Palette {
+    pub fn from_pixels(
+        pixels: &[u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        format: wl_shm::Format,
+    ) -> Palette {
+        // Average color of pixels in each of 16 levels per channel
+        let mut bins = vec![([0u64; 3], 0u64); 1 << 12];
+        let step = ((width * height / SAMPLES) as f64).sqrt().max(1.0)
+            as usize;
+        for y in (0..height).step_by(step) {
+            let row = &pixels[y * stride..];
+            for x in (0..width).step_by(step) {
+                let rgb = read_pixel(row, x, format);
+                let index = (rgb[0] as usize >> 4) << 8
+                    | (rgb[1] as usize >> 4) << 4
+                    | rgb[2] as usize >> 4;
+                let (sums, count) = &mut bins[index];
+                for (sum, value) in sums.iter_mut().zip(rgb) {
+                    *sum += value as u64;
+                }
+                *count += 1;
+            }
+        }
+        let mut colors = bins.into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(sums, count)| (sums.map(|sum| (sum / count) as u8), count))
+            .collect::<Vec<_>>();
+        colors.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+        let dominant = colors.first().map(|(rgb, _)| *rgb).unwrap_or_default();
+        let score = |(rgb, count): &([u8; 3], u64)|
+            *count as f64 * (0.2 + saturation(*rgb));
+        colors.sort_by(|a, b| score(b).total_cmp(&score(a)));
+        let mut chosen = vec![dominant];
+        for (rgb, _) in colors {
+            if chosen.len() > ACCENT_COUNT {
+                break;
+            }
+            if chosen.iter().all(|other| distance(rgb, *other) >= MIN_DISTANCE)
+            {
+                chosen.push(rgb);
+            }
+        }
+
+        Palette {
+            dominant: hex(dominant),
+            accents: chosen[1..].iter().copied().map(hex).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PaletteFile<'a> {
+    output: &'a str,
+    workspace: &'a str,
+    #[serde(flatten)]
+    palette: &'a Palette,
+}
+
+/// Replace dir/OUTPUT.json with the palette of the wallpaper just shown,
+/// through a temporary file so readers never see a partial one
+pub fn write_palette(
+    dir: &Path,
+    output_name: &str,
+    workspace_name: &str,
+    palette: &Palette,
+) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.json", output_name));
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut file = fs::File::create(&tmp_path)?;
+    writeln!(file, "{}", to_json(&PaletteFile {
+        output: output_name,
+        workspace: workspace_name,
+        palette,
+    }))?;
+    fs::rename(&tmp_path, path)
+}
+
+fn read_pixel(row: &[u8], x: usize, format: wl_shm::Format) -> [u8; 3] {
+    match format {
+        wl_shm::Format::Bgr888 => {
+            let pixel = &row[x * 3..];
+            [pixel[0], pixel[1], pixel[2]]
+        }
+        wl_shm::Format::Rgb565 => {
+            let pixel = u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]);
+            let expand = |value: u16, bits: u32|
+                (value as u32 * 255 / ((1 << bits) - 1)) as u8;
+            [
+                expand(pixel >> 11, 5),
+                expand(pixel >> 5 & 0x3f, 6),
+                expand(pixel & 0x1f, 5),
+            ]
+        }
+        // Xrgb8888 and Argb8888 are little endian
+        _ => {
+            let pixel = &row[x * 4..];
+            [pixel[2], pixel[1], pixel[0]]
+        }
+    }
+}
+
+fn saturation(rgb: [u8; 3]) -> f64 {
+    let max = *rgb.iter().max().unwrap();
+    let min = *rgb.iter().min().unwrap();
+    if max == 0 { 0.0 } else { (max - min) as f64 / max as f64 }
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter().zip(b).map(|(a, b)| a.abs_diff(b) as u32).sum()
+}
+
+fn hex([red, green, blue]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", red, green, blue)
+}
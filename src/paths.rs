@@ -0,0 +1,146 @@
+use std::{
+    env, fs, io,
+    os::unix::fs::{DirBuilderExt, MetadataExt, symlink},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::cli::Cli;
+
+/// Where the files generated by multibg-sway go, following the XDG base
+/// directory specification unless overridden on the command line
+pub struct Paths {
+    /// Derived data that can be regenerated, eg. processed wallpapers
+    pub cache_dir: PathBuf,
+    /// Data to keep across restarts, eg. the last shown wallpapers
+    pub state_dir: PathBuf,
+    /// Files only valid while running, eg. sockets and links
+    pub runtime_dir: PathBuf,
+    pub config_dir: PathBuf,
+}
+
+impl Paths {
+    pub fn new(cli: &Cli) -> Result<Paths, String> {
+        let runtime_dir = match &cli.runtime_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => runtime_dir()?,
+        };
+        Ok(Paths {
+            cache_dir: cli.cache_dir.clone().map(PathBuf::from)
+                .unwrap_or_else(|| xdg_dir("XDG_CACHE_HOME", ".cache")),
+            state_dir: cli.state_dir.clone().map(PathBuf::from)
+                .unwrap_or_else(||
+                    xdg_dir("XDG_STATE_HOME", ".local/state")
+                ),
+            runtime_dir,
+            config_dir: xdg_dir("XDG_CONFIG_HOME", ".config"),
+        })
+    }
+
+    /// Create the runtime dir only accessible by the user, refusing an
+    /// existing one anybody else could have made or could write to,
+    /// it decides where the control socket and the links point
+    pub fn create_runtime_dir(&self) -> Result<(), String> {
+        let dir = &self.runtime_dir;
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!(
+                "Failed to create directory {:?}: {}", parent, e
+            ))?;
+        }
+        match fs::DirBuilder::new().mode(0o700).create(dir) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(format!(
+                "Failed to create runtime dir {:?}: {}", dir, e
+            )),
+        }
+        // Not following a link planted in its place
+        let metadata = fs::symlink_metadata(dir).map_err(|e| format!(
+            "Failed to check runtime dir {:?}: {}", dir, e
+        ))?;
+        // SAFETY: getuid has no preconditions and never fails
+        let uid = unsafe { libc::getuid() };
+        if !metadata.is_dir() || metadata.uid() != uid {
+            return Err(format!(
+                "Runtime dir {:?} is not a directory owned by the user",
+                dir
+            ));
+        }
+        if metadata.mode() & 0o077 != 0 {
+            return Err(format!(
+                "Runtime dir {:?} is accessible by other users, \
+its mode should be 700", dir
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where `multibg-sway ctl` finds the running daemon
+    pub fn control_socket(&self) -> PathBuf {
+        self.runtime_dir.join("control.sock")
+    }
+
+    /// Of --daemon
+    pub fn log_file(&self) -> PathBuf {
+        self.state_dir.join("multibg-sway.log")
+    }
+
+    pub fn pid_file(&self) -> PathBuf {
+        self.runtime_dir.join("multibg-sway.pid")
+    }
+
+    /// The wallpapers set with `ctl set --persist`
+    pub fn overrides_file(&self) -> PathBuf {
+        self.state_dir.join("overrides.json")
+    }
+
+    /// Links to the wallpaper shown on the output for other programs
+    pub fn current_wallpaper_link(&self, output_name: &str) -> PathBuf {
+        self.runtime_dir.join(format!("current-{}", output_name))
+    }
+}
+
+/// Point the link to the target, readers never see it missing
+pub fn replace_symlink(link: &Path, target: &Path) -> io::Result<()> {
+    if let Some(dir) = link.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp_name = link.as_os_str().to_owned();
+    tmp_name.push(format!(".tmp{}", process::id()));
+    let tmp_link = PathBuf::from(tmp_name);
+    // Left behind if renaming failed before
+    let _ = fs::remove_file(&tmp_link);
+    symlink(target, &tmp_link)?;
+    fs::rename(&tmp_link, link)
+}
+
+// The spec says relative paths in the variables are invalid and ignored
+fn xdg_env(var: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}
+
+fn xdg_dir(var: &str, home_fallback: &str) -> PathBuf {
+    xdg_env(var)
+        .or_else(|| env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(home_fallback))
+        )
+        .unwrap_or_else(env::temp_dir)
+        .join(env!("CARGO_PKG_NAME"))
+}
+
+fn runtime_dir() -> Result<PathBuf, String> {
+    if let Some(dir) = xdg_env("XDG_RUNTIME_DIR") {
+        return Ok(dir.join(env!("CARGO_PKG_NAME")));
+    }
+    // Keep users apart in the shared temporary directory
+    let user = env::var("USER").unwrap_or_default();
+    if user.is_empty() {
+        return Err(
+            "Neither XDG_RUNTIME_DIR nor USER is set, \
+choose a runtime dir with --runtime-dir".to_string()
+        );
+    }
+    Ok(env::temp_dir().join(format!("{}-{}", env!("CARGO_PKG_NAME"), user)))
+}
@@ -0,0 +1,598 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixStream},
+    },
+    sync::{Arc, mpsc::Sender},
+    thread::spawn,
+    time::Duration,
+};
+
+use log::{debug, error, warn};
+use mio::Waker;
+
+const PORTAL_DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SETTINGS_INTERFACE: &str = "org.freedesktop.portal.Settings";
+const APPEARANCE_NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+/// How long to wait for the bus and the portal at startup,
+/// the portal may be started only by the first call
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Message types of the D-Bus wire protocol
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+// Header field codes
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Messages larger than this are refused by the bus anyway
+const MAX_MESSAGE_SIZE: u32 = 1 << 27;
+
+/// The color scheme preferred by the desktop settings, selecting
+/// the light/ or dark/ variants of the wallpapers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+impl ColorScheme {
+    /// The portal's 1 prefers dark, no preference counts as light
+    fn from_portal(value: u32) -> ColorScheme {
+        if value == 1 { ColorScheme::Dark } else { ColorScheme::Light }
+    }
+
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            ColorScheme::Light => "light",
+            ColorScheme::Dark => "dark",
+        }
+    }
+}
+
+/// Reads the current color scheme and keeps sending its changes
+/// from a new thread, light if the settings portal is not available
+pub fn watch_color_scheme(
+    tx: Sender<ColorScheme>,
+    waker: Arc<Waker>,
+) -> ColorScheme {
+    let mut portal = match SettingsPortal::connect() {
+        Ok(portal) => portal,
+        Err(e) => {
+            error!("Cannot follow the color scheme: {}", e);
+            return ColorScheme::Light;
+        }
+    };
+    let color_scheme = portal.read_color_scheme().unwrap_or_else(|e| {
+        warn!("Failed to read the color scheme, assuming light: {}", e);
+        ColorScheme::Light
+    });
+    debug!("Color scheme is {}", color_scheme.dir_name());
+    spawn(move || portal.watch(tx, waker));
+    color_scheme
+}
+
+/// Reads the current color scheme once, light if the settings portal
+/// is not available
+pub fn current_color_scheme() -> ColorScheme {
+    SettingsPortal::connect()
+        .and_then(|mut portal| portal.read_color_scheme())
+        .unwrap_or_else(|e| {
+            warn!("Failed to read the color scheme, assuming light: {}", e);
+            ColorScheme::Light
+        })
+}
+
+/// Minimal D-Bus session bus client for the settings portal
+struct SettingsPortal {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl SettingsPortal {
+    fn connect() -> Result<SettingsPortal, String> {
+        let stream = connect_session_bus()
+            .map_err(|e| format!("Failed to connect to D-Bus: {}", e))?;
+        stream.set_read_timeout(Some(STARTUP_TIMEOUT)).unwrap();
+        let mut portal = SettingsPortal { stream, serial: 0 };
+        portal.authenticate()
+            .map_err(|e| format!("Failed to authenticate to D-Bus: {}", e))?;
+        portal.call(
+            "org.freedesktop.DBus", "/org/freedesktop/DBus",
+            "org.freedesktop.DBus", "Hello", &[],
+        )?;
+        let rule = format!(
+            "type='signal',interface='{}',member='SettingChanged',\
+path='{}',arg0='{}',arg1='{}'",
+            SETTINGS_INTERFACE, PORTAL_PATH,
+            APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY,
+        );
+        portal.call(
+            "org.freedesktop.DBus", "/org/freedesktop/DBus",
+            "org.freedesktop.DBus", "AddMatch", &[&rule],
+        )?;
+        Ok(portal)
+    }
+
+    fn authenticate(&mut self) -> io::Result<()> {
+        // SAFETY: getuid always succeeds
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex_uid = uid.bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        write!(self.stream, "\0AUTH EXTERNAL {}\r\n", hex_uid)?;
+        let reply = self.read_line()?;
+        if !reply.starts_with("OK ") {
+            return Err(io::Error::other(format!("rejected: {}", reply)));
+        }
+        self.stream.write_all(b"BEGIN\r\n")
+    }
+
+    /// Reads byte by byte so nothing after the line is consumed
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") {
+            self.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    fn read_color_scheme(&mut self) -> Result<ColorScheme, String> {
+        let args = [APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY];
+        // Read is deprecated by ReadOne but older portals only have it,
+        // not tried after timing out to not wait twice
+        let mut reply = self.send(
+            PORTAL_DESTINATION, PORTAL_PATH, SETTINGS_INTERFACE,
+            "ReadOne", &args,
+        )?;
+        if reply.kind == ERROR {
+            reply = self.call(
+                PORTAL_DESTINATION, PORTAL_PATH, SETTINGS_INTERFACE,
+                "Read", &args,
+            )?;
+        }
+        reply.body().variant_u32()
+            .map(ColorScheme::from_portal)
+            .ok_or("unexpected value".to_string())
+    }
+
+    /// Calls the method with string arguments and waits for its reply,
+    /// failing on an error reply
+    fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[&str],
+    ) -> Result<Message, String> {
+        let reply = self.send(destination, path, interface, member, args)?;
+        match reply.kind {
+            ERROR => Err(format!(
+                "{} failed: {}", member, reply.error_name.unwrap_or_default()
+            )),
+            _ => Ok(reply),
+        }
+    }
+
+    /// Calls the method with string arguments and waits for its return
+    /// or error reply, messages arriving meanwhile are dropped
+    fn send(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[&str],
+    ) -> Result<Message, String> {
+        self.serial += 1;
+        let message = method_call(
+            self.serial, destination, path, interface, member, args
+        );
+        self.stream.write_all(&message)
+            .map_err(|e| format!("Failed to call {}: {}", member, e))?;
+        loop {
+            let reply = Message::read(&mut self.stream).map_err(|e|
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut =>
+                        format!(
+                            "No reply to {} in {} seconds",
+                            member, STARTUP_TIMEOUT.as_secs()
+                        ),
+                    _ => format!(
+                        "Failed to get the reply to {}: {}", member, e
+                    ),
+                }
+            )?;
+            if reply.reply_serial == Some(self.serial)
+                && matches!(reply.kind, METHOD_RETURN | ERROR)
+            {
+                return Ok(reply);
+            }
+        }
+    }
+
+    // Returns when the bus goes away or the receiving main event loop
+    // was torn down, a new one is spawned on reconnection
+    fn watch(mut self, tx: Sender<ColorScheme>, waker: Arc<Waker>) {
+        self.stream.set_read_timeout(None).unwrap();
+        loop {
+            let message = match Message::read(&mut self.stream) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Lost the connection to D-Bus: {}", e);
+                    return;
+                }
+            };
+            if message.kind != SIGNAL
+                || message.interface.as_deref() != Some(SETTINGS_INTERFACE)
+                || message.member.as_deref() != Some("SettingChanged")
+            {
+                continue;
+            }
+            let mut body = message.body();
+            if body.string().as_deref() != Some(APPEARANCE_NAMESPACE)
+                || body.string().as_deref() != Some(COLOR_SCHEME_KEY)
+            {
+                continue;
+            }
+            let Some(value) = body.variant_u32() else { continue };
+            let color_scheme = ColorScheme::from_portal(value);
+            debug!("Color scheme changed to {}", color_scheme.dir_name());
+            if tx.send(color_scheme).is_err() {
+                debug!("Main event loop is gone, stopping color scheme events");
+                return;
+            }
+            waker.wake().unwrap();
+        }
+    }
+}
+
+/// From DBUS_SESSION_BUS_ADDRESS, only unix socket addresses are supported
+fn connect_session_bus() -> io::Result<UnixStream> {
+    let Ok(addresses) = env::var("DBUS_SESSION_BUS_ADDRESS") else {
+        // SAFETY: getuid always succeeds
+        let uid = unsafe { libc::getuid() };
+        return UnixStream::connect(format!("/run/user/{}/bus", uid));
+    };
+    let mut last_error = io::Error::other(
+        format!("no supported address in '{}'", addresses)
+    );
+    for address in addresses.split(';') {
+        let Some(params) = address.strip_prefix("unix:") else { continue };
+        for param in params.split(',') {
+            let result = match param.split_once('=') {
+                Some(("path", path)) => UnixStream::connect(path),
+                Some(("abstract", name)) =>
+                    SocketAddr::from_abstract_name(name)
+                        .and_then(|addr| UnixStream::connect_addr(&addr)),
+                _ => continue,
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// A little endian method call message with string arguments
+fn method_call(
+    serial: u32,
+    destination: &str,
+    path: &str,
+    interface: &str,
+    member: &str,
+    args: &[&str],
+) -> Vec<u8> {
+    let mut body = Vec::new();
+    for arg in args {
+        put_string(&mut body, arg);
+    }
+    let signature = "s".repeat(args.len());
+
+    let mut message = vec![b'l', METHOD_CALL, 0, 1];
+    message.extend((body.len() as u32).to_le_bytes());
+    message.extend(serial.to_le_bytes());
+    // Length of the header field array, filled in below
+    message.extend(0u32.to_le_bytes());
+    let mut fields = vec![
+        (FIELD_PATH, 'o', path),
+        (FIELD_DESTINATION, 's', destination),
+        (FIELD_INTERFACE, 's', interface),
+        (FIELD_MEMBER, 's', member),
+    ];
+    if !args.is_empty() {
+        fields.push((FIELD_SIGNATURE, 'g', &signature));
+    }
+    for (code, kind, value) in fields {
+        align(&mut message, 8);
+        message.extend([code, 1, kind as u8, 0]);
+        if kind == 'g' {
+            put_signature(&mut message, value);
+        } else {
+            put_string(&mut message, value);
+        }
+    }
+    let fields_len = (message.len() - 16) as u32;
+    message[12..16].copy_from_slice(&fields_len.to_le_bytes());
+    align(&mut message, 8);
+    message.extend(body);
+    message
+}
+
+fn align(buf: &mut Vec<u8>, alignment: usize) {
+    buf.resize(buf.len().next_multiple_of(alignment), 0);
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    align(buf, 4);
+    buf.extend((s.len() as u32).to_le_bytes());
+    buf.extend(s.as_bytes());
+    buf.push(0);
+}
+
+fn put_signature(buf: &mut Vec<u8>, s: &str) {
+    buf.push(s.len() as u8);
+    buf.extend(s.as_bytes());
+    buf.push(0);
+}
+
+/// A received message with only the header fields used here
+struct Message {
+    kind: u8,
+    big_endian: bool,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn read(stream: &mut impl Read) -> io::Result<Message> {
+        let invalid = || io::Error::new(
+            io::ErrorKind::InvalidData, "malformed message"
+        );
+        let mut fixed = [0u8; 16];
+        stream.read_exact(&mut fixed)?;
+        let big_endian = fixed[0] == b'B';
+        let mut header = Cursor { data: &fixed, pos: 4, big_endian };
+        let body_len = header.u32().ok_or_else(invalid)?;
+        header.u32().ok_or_else(invalid)?;
+        let fields_len = header.u32().ok_or_else(invalid)?;
+        if body_len > MAX_MESSAGE_SIZE || fields_len > MAX_MESSAGE_SIZE {
+            return Err(invalid());
+        }
+        // The header is padded to 8 bytes, the field array starts aligned
+        let padded_len = (fields_len as usize).next_multiple_of(8);
+        let mut fields = vec![0u8; padded_len];
+        stream.read_exact(&mut fields)?;
+        let mut body = vec![0u8; body_len as usize];
+        stream.read_exact(&mut body)?;
+
+        let mut message = Message {
+            kind: fixed[1],
+            big_endian,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            body,
+        };
+        let mut cursor = Cursor {
+            data: &fields[..fields_len as usize],
+            pos: 0,
+            big_endian,
+        };
+        while cursor.pos < cursor.data.len() {
+            cursor.align(8);
+            let code = cursor.u8().ok_or_else(invalid)?;
+            let signature = cursor.signature().ok_or_else(invalid)?;
+            match signature.as_str() {
+                "s" | "o" => {
+                    let value = cursor.string().ok_or_else(invalid)?;
+                    match code {
+                        FIELD_INTERFACE => message.interface = Some(value),
+                        FIELD_MEMBER => message.member = Some(value),
+                        FIELD_ERROR_NAME => message.error_name = Some(value),
+                        _ => {}
+                    }
+                }
+                "g" => {
+                    cursor.signature().ok_or_else(invalid)?;
+                }
+                "u" => {
+                    let value = cursor.u32().ok_or_else(invalid)?;
+                    if code == FIELD_REPLY_SERIAL {
+                        message.reply_serial = Some(value);
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(message)
+    }
+
+    /// The body starts aligned to 8 bytes in the message
+    fn body(&self) -> Cursor<'_> {
+        Cursor { data: &self.body, pos: 0, big_endian: self.big_endian }
+    }
+}
+
+/// Reads values in the D-Bus wire format, None past the end
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Cursor<'_> {
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.next_multiple_of(alignment);
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let big_endian = self.big_endian;
+        let bytes = self.bytes(4)?.try_into().unwrap();
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Also skips the terminating nul byte
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let s = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        self.bytes(1)?;
+        Some(s)
+    }
+
+    fn signature(&mut self) -> Option<String> {
+        let len = self.u8()? as usize;
+        let s = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        self.bytes(1)?;
+        Some(s)
+    }
+
+    /// A variant holding a u32, maybe wrapped in more variants
+    fn variant_u32(&mut self) -> Option<u32> {
+        match self.signature()?.as_str() {
+            "u" => self.u32(),
+            "v" => self.variant_u32(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reply to the deprecated Read method in big endian, its value
+    /// is wrapped in one more variant than with ReadOne
+    fn big_endian_read_reply(reply_serial: u32, value: u32) -> Vec<u8> {
+        let mut body = vec![1, b'v', 0, 1, b'u', 0];
+        align(&mut body, 4);
+        body.extend(value.to_be_bytes());
+
+        let mut message = vec![b'B', METHOD_RETURN, 0, 1];
+        message.extend((body.len() as u32).to_be_bytes());
+        message.extend(9u32.to_be_bytes());
+        message.extend(0u32.to_be_bytes());
+        message.extend([FIELD_REPLY_SERIAL, 1, b'u', 0]);
+        message.extend(reply_serial.to_be_bytes());
+        align(&mut message, 8);
+        message.extend([FIELD_SIGNATURE, 1, b'g', 0]);
+        put_signature(&mut message, "v");
+        let fields_len = (message.len() - 16) as u32;
+        message[12..16].copy_from_slice(&fields_len.to_be_bytes());
+        align(&mut message, 8);
+        message.extend(body);
+        message
+    }
+
+    #[test]
+    fn method_call_round_trip() {
+        let bytes = method_call(
+            3, PORTAL_DESTINATION, PORTAL_PATH, SETTINGS_INTERFACE,
+            "ReadOne", &[APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY],
+        );
+        let mut reader = bytes.as_slice();
+        let message = Message::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(message.kind, METHOD_CALL);
+        assert!(!message.big_endian);
+        assert_eq!(message.interface.as_deref(), Some(SETTINGS_INTERFACE));
+        assert_eq!(message.member.as_deref(), Some("ReadOne"));
+        assert_eq!(message.reply_serial, None);
+        let mut body = message.body();
+        assert_eq!(body.string().as_deref(), Some(APPEARANCE_NAMESPACE));
+        assert_eq!(body.string().as_deref(), Some(COLOR_SCHEME_KEY));
+        assert_eq!(body.string(), None);
+    }
+
+    #[test]
+    fn method_call_without_args() {
+        let bytes = method_call(
+            1, "org.freedesktop.DBus", "/org/freedesktop/DBus",
+            "org.freedesktop.DBus", "Hello", &[],
+        );
+        let message = Message::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(message.member.as_deref(), Some("Hello"));
+        assert!(message.body.is_empty());
+    }
+
+    #[test]
+    fn big_endian_nested_variant() {
+        let bytes = big_endian_read_reply(5, 1);
+        let message = Message::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(message.kind, METHOD_RETURN);
+        assert!(message.big_endian);
+        assert_eq!(message.reply_serial, Some(5));
+        assert_eq!(message.body().variant_u32(), Some(1));
+    }
+
+    #[test]
+    fn truncated_messages() {
+        for bytes in [
+            method_call(1, "a.b", "/", "a.b", "C", &["d"]),
+            big_endian_read_reply(1, 0),
+        ] {
+            for len in 0..bytes.len() {
+                let result = Message::read(&mut &bytes[..len]);
+                assert!(
+                    result.is_err(), "read {} of {} bytes", len, bytes.len()
+                );
+            }
+        }
+        // Cut within the value of the variant
+        let mut message = Message::read(
+            &mut big_endian_read_reply(1, 0).as_slice()
+        ).unwrap();
+        message.body.truncate(message.body.len() - 1);
+        assert_eq!(message.body().variant_u32(), None);
+    }
+
+    #[test]
+    fn oversized_messages() {
+        let bytes = method_call(1, "a.b", "/", "a.b", "C", &["d"]);
+        for offset in [4, 12] {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 4]
+                .copy_from_slice(&(MAX_MESSAGE_SIZE + 1).to_le_bytes());
+            let error = Message::read(&mut bytes.as_slice()).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::fs;
+
+/// Whether the computer runs from its battery, false without one
+pub fn on_battery() -> bool {
+    let Ok(entries) = fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let mut has_mains = false;
+    let mut discharging = false;
+    for entry in entries.flatten() {
+        let read = |name: &str| fs::read_to_string(entry.path().join(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default();
+        // Batteries of wireless mice and keyboards, not of the computer
+        if read("scope") == "Device" {
+            continue;
+        }
+        match read("type").as_str() {
+            "Mains" => {
+                if read("online") == "1" {
+                    return false;
+                }
+                has_mains = true;
+            }
+            "Battery" => discharging |= read("status") == "Discharging",
+            _ => {}
+        }
+    }
+    has_mains || discharging
+}
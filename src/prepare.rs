@@ -0,0 +1,299 @@
+use std::{
+    fs,
+    iter,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use log::{debug, warn};
+use smithay_client_toolkit::output::OutputInfo;
+use smithay_client_toolkit::reexports::client::protocol::{
+    wl_output::Transform,
+    wl_shm,
+};
+
+use crate::{
+    cache::DiskCache,
+    cache_settings, decoder_registry, image_settings,
+    cli::{Cli, RenderSize, output_matches},
+    image::{
+        ImageTarget, WallpaperDirs, has_wallpapers, parse_resolution,
+        prepare_output_image_dir, resolution_dir,
+    },
+    paths::Paths,
+    portal::{ColorScheme, current_color_scheme},
+    probe::{Probe, probe},
+    sway::output_identifiers,
+    wayland::{active_time_profile, select_pixel_format},
+};
+
+/// An output directory to prepare the wallpapers of
+pub struct Job {
+    /// Unless prepared at a size given instead
+    pub output: Option<String>,
+    pub dir: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub format: wl_shm::Format,
+}
+
+/// Writes the disk cache entries multibg-sway would make when started
+/// with the options, for the connected outputs unless sizes are given
+pub fn prepare(
+    wallpaper_dir: &str,
+    sizes: &[(u32, u32)],
+    options: &[String],
+) -> Result<(), String> {
+    let cli = Cli::try_parse_from(
+        iter::once(env!("CARGO_PKG_NAME"))
+            .chain(options.iter().map(String::as_str))
+            .chain([wallpaper_dir])
+    ).map_err(|e| format!("Invalid options: {}", e))?;
+
+    let wallpaper_dir = Path::new(wallpaper_dir).canonicalize()
+        .map_err(|e| format!(
+            "Failed to open wallpaper dir {:?}: {}", wallpaper_dir, e
+        ))?;
+
+    if !cli.disk_cache {
+        warn!("The prepared wallpapers are only used with --disk-cache");
+    }
+    if !cli.span.is_empty() {
+        warn!("Spanned wallpapers are not prepared");
+    }
+
+    let base_dirs = base_dirs(&cli, &wallpaper_dir);
+    let jobs = if sizes.is_empty() {
+        output_jobs(&cli, &probe(cli.display.as_deref())?, &base_dirs)
+    } else {
+        size_jobs(&cli, &wallpaper_dir, sizes)
+    };
+
+    let paths = Paths::new(&cli)?;
+    let disk_cache = DiskCache::new(
+        paths.cache_dir.join("wallpapers"),
+        &cache_settings(&cli),
+        false,
+    );
+    let decoders = decoder_registry(&cli);
+    let settings = image_settings(&cli);
+
+    for mut job in jobs {
+        // Not in the flat layout
+        let candidate_sets = !base_dirs.contains(&job.dir);
+        if let Some(dir) = resolution_dir(&job.dir, job.width, job.height) {
+            job.dir = dir;
+        }
+        let target = ImageTarget {
+            width: job.width,
+            height: job.height,
+            span_area: None,
+        };
+        let dirs = WallpaperDirs {
+            dir: job.dir,
+            fallbacks: Vec::new(),
+            overrides: Vec::new(),
+            candidate_sets,
+            slideshows: cli.slideshow.is_some() || cli.random.is_some(),
+        };
+        let prepared = prepare_output_image_dir(
+            &dirs,
+            &decoders,
+            &settings,
+            &disk_cache,
+            job.format,
+            &target,
+        )?;
+        println!(
+            "Prepared {} wallpapers from {:?} at {}x{}",
+            prepared, dirs.dir, job.width, job.height
+        );
+    }
+    Ok(())
+}
+
+/// Every output directory at every size, assuming the compositor
+/// supports the pixel formats sway does
+fn size_jobs(
+    cli: &Cli,
+    wallpaper_dir: &Path,
+    sizes: &[(u32, u32)],
+) -> Vec<Job> {
+    let format = select_pixel_format(
+        cli.pixelformat,
+        &[wl_shm::Format::Bgr888, wl_shm::Format::Rgb565],
+    );
+    let mut jobs = Vec::new();
+    for base_dir in base_dirs(cli, wallpaper_dir) {
+        // With the flat layout
+        if has_wallpapers(&base_dir) {
+            for &(width, height) in sizes {
+                jobs.push(Job {
+                    output: None,
+                    dir: base_dir.clone(),
+                    width,
+                    height,
+                    format,
+                });
+            }
+        }
+        let Ok(entries) = fs::read_dir(&base_dir) else { continue };
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            let name = entry.file_name();
+            let is_group = cli.profile.iter().chain(&cli.span)
+                .any(|group| *group.name == *name)
+                || cli.time_profile.iter()
+                    .any(|time_profile| *time_profile.name == *name)
+                || (cli.follow_color_scheme
+                    && (name == "light" || name == "dark"));
+            // Of the flat layout
+            let is_resolution =
+                parse_resolution(&name.to_string_lossy()).is_some();
+            if !dir.is_dir() || is_group || is_resolution {
+                continue;
+            }
+            for &(width, height) in sizes {
+                jobs.push(Job {
+                    output: None,
+                    dir: dir.clone(),
+                    width,
+                    height,
+                    format,
+                });
+            }
+        }
+    }
+    jobs
+}
+
+/// The directories of the connected outputs in the base directories
+/// at their current size, the compositor probed only to learn those
+pub fn output_jobs(cli: &Cli, probe: &Probe, base_dirs: &[PathBuf])
+    -> Vec<Job>
+{
+    let format = select_pixel_format(
+        cli.pixelformat,
+        &probe.formats,
+    );
+    let identifiers = output_identifiers();
+
+    let mut jobs = Vec::new();
+    for info in &probe.outputs {
+        let Some(name) = &info.name else { continue };
+        let matches = |pattern: &String|
+            output_matches(pattern, name, &info.make, &info.model);
+        if !cli.only_output.is_empty() && !cli.only_output.iter().any(matches)
+            || cli.skip_output.iter().any(matches)
+            || cli.span.iter()
+                .any(|span| span.contains(name, &info.make, &info.model))
+        {
+            debug!("Skipping output '{}'", name);
+            continue;
+        }
+        let Some((width, height)) = output_size(cli, info) else {
+            warn!("Output '{}' has no size, skipping", name);
+            continue;
+        };
+
+        // Like the wallpaper directories of outputs are found at runtime,
+        // the global _default wallpapers are not prepared
+        let candidates = identifiers.iter()
+            .filter(|(output_name, _)| output_name == name)
+            .map(|(_, identifier)| identifier.clone())
+            .chain([[info.make.as_str(), &info.model].join(" ")])
+            .filter(|candidate|
+                !candidate.trim().is_empty() && !candidate.contains('/')
+            )
+            .chain([name.clone(), "_default".to_string()])
+            .collect::<Vec<_>>();
+        for base_dir in base_dirs {
+            let Some(dir) = candidates.iter()
+                .map(|candidate| base_dir.join(candidate))
+                .find(|dir| dir.is_dir())
+                .or_else(|| has_wallpapers(base_dir).then(|| base_dir.clone()))
+            else {
+                continue;
+            };
+            jobs.push(Job {
+                output: Some(name.clone()),
+                dir,
+                width,
+                height,
+                format,
+            });
+        }
+    }
+    jobs
+}
+
+/// The directory of the profiles active now with the outputs,
+/// like at startup
+pub fn active_profile_dir(
+    cli: &Cli,
+    wallpaper_dir: &Path,
+    outputs: &[OutputInfo],
+) -> PathBuf {
+    let mut dir = wallpaper_dir.to_path_buf();
+    dir.extend(active_time_profile(&cli.time_profile));
+    if cli.follow_color_scheme {
+        dir.push(current_color_scheme().dir_name());
+    }
+    dir.extend(cli.profile.iter()
+        .find(|profile| profile.outputs.iter().all(|profile_output|
+            outputs.iter().any(|info| output_matches(
+                profile_output,
+                info.name.as_deref().unwrap_or_default(),
+                &info.make,
+                &info.model,
+            ))
+        ))
+        .map(|profile| &profile.name)
+    );
+    dir
+}
+
+/// The wallpaper dir and the directories of the time profiles,
+/// in each of them the color schemes, and in those the profiles
+fn base_dirs(cli: &Cli, wallpaper_dir: &Path) -> Vec<PathBuf> {
+    let color_schemes: &[ColorScheme] = if cli.follow_color_scheme {
+        &[ColorScheme::Light, ColorScheme::Dark]
+    } else {
+        &[]
+    };
+    iter::once(wallpaper_dir.to_path_buf())
+        .chain(cli.time_profile.iter()
+            .map(|time_profile| wallpaper_dir.join(&time_profile.name))
+        )
+        .flat_map(|dir| iter::once(dir.clone())
+            .chain(color_schemes.iter()
+                .map(move |color_scheme| dir.join(color_scheme.dir_name()))
+            )
+        )
+        .flat_map(|dir| iter::once(dir.clone())
+            .chain(cli.profile.iter()
+                .map(move |profile| dir.join(&profile.name))
+            )
+        )
+        .collect()
+}
+
+/// The size of the wallpapers of an output at the default scaling,
+/// without margins and exclusive zones
+fn output_size(cli: &Cli, info: &OutputInfo) -> Option<(u32, u32)> {
+    let size = if cli.render_size == RenderSize::Logical {
+        info.logical_size?
+    } else {
+        let (width, height) = info.modes.iter()
+            .find(|mode| mode.current)?
+            .dimensions;
+        match info.transform {
+            Transform::_90
+            | Transform::_270
+            | Transform::Flipped90
+            | Transform::Flipped270 => (height, width),
+            _ => (width, height),
+        }
+    };
+    Some((size.0.try_into().ok()?, size.1.try_into().ok()?))
+}
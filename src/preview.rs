@@ -0,0 +1,102 @@
+use std::{
+    iter,
+    path::Path,
+    slice,
+};
+
+use clap::Parser;
+use image::ImageFormat;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    decoder_registry, image_settings,
+    cli::Cli,
+    image::{ImageTarget, WallpaperDirs, preview_wallpaper, resolution_dir},
+    overrides::load_overrides,
+    paths::Paths,
+    prepare::{active_profile_dir, output_jobs},
+    probe::probe,
+    wayland::select_pixel_format,
+};
+
+/// Writes the wallpaper multibg-sway would show on the workspace of the
+/// output when started with the options to a PNG file, without showing it
+pub fn preview(
+    wallpaper_dir: &str,
+    output: &str,
+    workspace: &str,
+    png_path: &str,
+    size: Option<(u32, u32)>,
+    options: &[String],
+) -> Result<(), String> {
+    let cli = Cli::try_parse_from(
+        iter::once(env!("CARGO_PKG_NAME"))
+            .chain(options.iter().map(String::as_str))
+            .chain([wallpaper_dir])
+    ).map_err(|e| format!("Invalid options: {}", e))?;
+
+    let wallpaper_dir = Path::new(wallpaper_dir).canonicalize()
+        .map_err(|e| format!(
+            "Failed to open wallpaper dir {:?}: {}", wallpaper_dir, e
+        ))?;
+
+    let (profile_dir, dir, width, height, format) = match size {
+        // Without the output identifiers only by its name
+        Some((width, height)) => {
+            let profile_dir = active_profile_dir(&cli, &wallpaper_dir, &[]);
+            let dir = [output, "_default"].iter()
+                .map(|candidate| profile_dir.join(candidate))
+                .find(|dir| dir.is_dir())
+                .unwrap_or_else(|| profile_dir.clone());
+            let format = select_pixel_format(
+                cli.pixelformat,
+                &[wl_shm::Format::Bgr888, wl_shm::Format::Rgb565],
+            );
+            (profile_dir, dir, width, height, format)
+        }
+        None => {
+            let probe = probe(cli.display.as_deref())?;
+            let profile_dir =
+                active_profile_dir(&cli, &wallpaper_dir, &probe.outputs);
+            let job = output_jobs(&cli, &probe, slice::from_ref(&profile_dir))
+                .into_iter()
+                .find(|job| job.output.as_deref() == Some(output))
+                .ok_or_else(|| format!(
+                    "Output '{}' is not connected, skipped or has no \
+wallpapers, try --size",
+                    output
+                ))?;
+            (profile_dir, job.dir, job.width, job.height, job.format)
+        }
+    };
+
+    let target = ImageTarget { width, height, span_area: None };
+    let dirs = WallpaperDirs {
+        candidate_sets: dir != profile_dir,
+        dir: resolution_dir(&dir, width, height).unwrap_or(dir),
+        fallbacks: Vec::new(),
+        overrides: load_overrides(&Paths::new(&cli)?.overrides_file())
+            .into_iter()
+            .filter(|wallpaper_override| wallpaper_override.output == output)
+            .map(|wallpaper_override|
+                (wallpaper_override.workspace, wallpaper_override.path)
+            )
+            .collect(),
+        slideshows: cli.slideshow.is_some() || cli.random.is_some(),
+    };
+    let (path, image) = preview_wallpaper(
+        &dirs,
+        workspace,
+        &decoder_registry(&cli),
+        &image_settings(&cli),
+        format,
+        &target,
+    )?;
+    image.save_with_format(png_path, ImageFormat::Png)
+        .map_err(|e| format!("Failed to write {:?}: {}", png_path, e))?;
+    println!(
+        "Wrote {:?} from {:?} at {}x{} to {:?}",
+        workspace, path, width, height, png_path
+    );
+    Ok(())
+}
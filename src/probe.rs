@@ -0,0 +1,170 @@
+use serde::Serialize;
+use smithay_client_toolkit::{
+    delegate_output, delegate_registry, delegate_shm,
+    output::{OutputHandler, OutputInfo, OutputState},
+    registry::{ProvidesRegistryState, RegistryState},
+    registry_handlers,
+    shm::{Shm, ShmHandler},
+};
+use smithay_client_toolkit::reexports::client::{
+    Connection, QueueHandle,
+    globals::registry_queue_init,
+    protocol::{wl_output::WlOutput, wl_shm},
+};
+
+use crate::{
+    display,
+    json::to_json,
+    sway::output_identifiers,
+};
+
+/// What a short lived wayland connection learned about the compositor
+pub struct Probe {
+    pub outputs: Vec<OutputInfo>,
+    /// Pixel formats supported for shm buffers
+    pub formats: Vec<wl_shm::Format>,
+    /// The interfaces of the globals with their versions
+    pub globals: Vec<(String, u32)>,
+}
+
+pub fn probe(display: Option<&str>) -> Result<Probe, String> {
+    let conn = display::connect(display)?;
+    let (globals, mut event_queue) = registry_queue_init(&conn).map_err(|e|
+        format!("Failed to initialize the wayland registry: {}", e)
+    )?;
+    let qh = event_queue.handle();
+
+    let mut state = ProbeState {
+        registry_state: RegistryState::new(&globals),
+        output_state: OutputState::new(&globals, &qh),
+        shm: Shm::bind(&globals, &qh).ok(),
+    };
+
+    // Second roundtrip for the xdg_output info
+    for _ in 0..2 {
+        event_queue.roundtrip(&mut state).map_err(|e|
+            format!("Wayland roundtrip failed: {}", e)
+        )?;
+    }
+
+    Ok(Probe {
+        outputs: state.output_state.outputs()
+            .filter_map(|output| state.output_state.info(&output))
+            .collect(),
+        formats: state.shm.as_ref()
+            .map(|shm| shm.formats().to_vec())
+            .unwrap_or_default(),
+        globals: globals.contents().with_list(|list| list.iter()
+            .map(|global| (global.interface.clone(), global.version))
+            .collect()
+        ),
+    })
+}
+
+#[derive(Serialize)]
+struct OutputList {
+    outputs: Vec<OutputJson>,
+}
+
+#[derive(Serialize)]
+struct OutputJson {
+    name: Option<String>,
+    identifier: Option<String>,
+    make: String,
+    model: String,
+    description: Option<String>,
+    width: Option<i32>,
+    height: Option<i32>,
+    scale_factor: i32,
+    transform: String,
+    logical_x: Option<i32>,
+    logical_y: Option<i32>,
+    logical_width: Option<i32>,
+    logical_height: Option<i32>,
+}
+
+pub fn print_outputs(display: Option<&str>) -> Result<(), String> {
+    let probe = probe(display)?;
+    let identifiers = output_identifiers();
+
+    let outputs = probe.outputs.into_iter()
+        .map(|info| {
+            let mode = info.modes.iter().find(|mode| mode.current);
+            OutputJson {
+                identifier: info.name.as_ref()
+                    .and_then(|name| identifiers.iter()
+                        .find(|(output_name, _)| output_name == name)
+                    )
+                    .map(|(_, identifier)| identifier.clone()),
+                name: info.name,
+                make: info.make,
+                model: info.model,
+                description: info.description,
+                width: mode.map(|mode| mode.dimensions.0),
+                height: mode.map(|mode| mode.dimensions.1),
+                scale_factor: info.scale_factor,
+                transform: format!("{:?}", info.transform),
+                logical_x: info.logical_position.map(|(x, _)| x),
+                logical_y: info.logical_position.map(|(_, y)| y),
+                logical_width: info.logical_size.map(|(width, _)| width),
+                logical_height: info.logical_size.map(|(_, height)| height),
+            }
+        })
+        .collect();
+
+    println!("{}", to_json(&OutputList { outputs }));
+    Ok(())
+}
+
+struct ProbeState {
+    registry_state: RegistryState,
+    output_state: OutputState,
+    shm: Option<Shm>,
+}
+
+impl OutputHandler for ProbeState {
+    fn output_state(&mut self) -> &mut OutputState {
+        &mut self.output_state
+    }
+
+    fn new_output(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: WlOutput,
+    ) {
+    }
+
+    fn update_output(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: WlOutput,
+    ) {
+    }
+
+    fn output_destroyed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: WlOutput,
+    ) {
+    }
+}
+
+impl ProvidesRegistryState for ProbeState {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+    registry_handlers![OutputState];
+}
+
+impl ShmHandler for ProbeState {
+    fn shm_state(&mut self) -> &mut Shm {
+        self.shm.as_mut().unwrap()
+    }
+}
+
+delegate_output!(ProbeState);
+delegate_registry!(ProbeState);
+delegate_shm!(ProbeState);
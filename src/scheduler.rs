@@ -0,0 +1,120 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use smithay_client_toolkit::reexports::client::{
+    QueueHandle,
+    protocol::wl_surface::WlSurface,
+};
+
+use crate::{
+    transition::Transition,
+    wayland::State,
+};
+
+/// Progress of the animated wallpaper of the visible workspace
+pub struct Animation {
+    pub frame_index: usize,
+    /// The wallpaper of the video player if the frames come from one
+    pub video: Option<PathBuf>,
+}
+
+/// The frame rate allowed for a surface
+#[derive(Copy, Clone)]
+pub struct FrameRate {
+    /// From --max-fps, zero without a limit
+    pub min_interval: Duration,
+    /// The frame rate is halved this many times
+    pub demotion: u32,
+}
+
+impl FrameRate {
+    /// How long a frame of the delay is shown at the allowed frame rate,
+    /// longer delays are kept unless demoted
+    pub fn interval(&self, delay: Duration) -> Duration {
+        delay.max(self.min_interval) * (1 << self.demotion)
+    }
+}
+
+/// Paces the redraws of a surface over time: first the transition between
+/// workspaces, then the animated wallpaper, video or Ken Burns effect of
+/// the workspace. A frame is drawn when its time source says it is due,
+/// the delays of the frames or the clock of the transition, but only after
+/// the compositor signaled that the previous one was shown. It does not
+/// while the output is off or the surface is hidden, pausing everything.
+/// The frame rate may be limited, and lowered further while demoted
+pub struct FrameScheduler {
+    surface: WlSurface,
+    qh: QueueHandle<State>,
+    /// A frame callback was requested and the compositor has not done it
+    waiting: bool,
+    /// When the next frame is due, None while nothing is animated
+    due: Option<Instant>,
+    last_frame: Option<Instant>,
+    pub rate: FrameRate,
+    /// Shown first, the animation continues after it finished
+    pub transition: Option<Transition>,
+    pub animation: Option<Animation>,
+}
+
+impl FrameScheduler {
+    pub fn new(
+        surface: WlSurface,
+        qh: QueueHandle<State>,
+        min_interval: Duration,
+    ) -> FrameScheduler {
+        FrameScheduler {
+            surface,
+            qh,
+            waiting: false,
+            due: None,
+            last_frame: None,
+            rate: FrameRate { min_interval, demotion: 0 },
+            transition: None,
+            animation: None,
+        }
+    }
+
+    /// Ask the compositor to signal with the next commit
+    /// when it is a good time to draw again
+    pub fn request_frame(&mut self) {
+        if !self.waiting {
+            self.surface.frame(&self.qh, self.surface.clone());
+            self.waiting = true;
+        }
+    }
+
+    pub fn frame_done(&mut self) {
+        self.waiting = false;
+    }
+
+    /// Draw the next frame at the time, or once the compositor
+    /// is ready if that is later
+    pub fn schedule(&mut self, due: Instant) {
+        self.due = Some(due);
+    }
+
+    pub fn unschedule(&mut self) {
+        self.due = None;
+    }
+
+    /// None while waiting for the compositor or if nothing is animated
+    pub fn next_frame_time(&self) -> Option<Instant> {
+        let due = self.due.filter(|_| !self.waiting)?;
+        Some(match self.last_frame {
+            Some(last_frame) => due.max(
+                last_frame + self.rate.interval(Duration::ZERO)
+            ),
+            None => due,
+        })
+    }
+
+    /// When the next frame was due if it is due by now,
+    /// it has to be scheduled again after drawing it
+    pub fn take_due(&mut self, now: Instant) -> Option<Instant> {
+        self.next_frame_time().filter(|time| *time <= now)?;
+        self.last_frame = Some(now);
+        self.due.take()
+    }
+}
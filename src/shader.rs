@@ -0,0 +1,306 @@
+use std::{
+    borrow::Cow,
+    path::Path,
+    sync::mpsc::channel,
+};
+
+use log::debug;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+/// Fullscreen triangle, for glsl shaders also a module of its own
+const VERTEX_WGSL: &str = "
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32)
+    -> @builtin(position) vec4<f32>
+{
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+";
+
+/// Wgsl shaders define fn main_image(frag_coord: vec2<f32>) -> vec4<f32>
+/// and can use shadertoy.resolution and shadertoy.time
+const WGSL_PRELUDE: &str = "
+struct Shadertoy {
+    resolution: vec3<f32>,
+    time: f32,
+}
+@group(0) @binding(0) var<uniform> shadertoy: Shadertoy;
+";
+
+const WGSL_MAIN: &str = "
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>)
+    -> @location(0) vec4<f32>
+{
+    return main_image(
+        vec2<f32>(position.x, shadertoy.resolution.y - position.y)
+    );
+}
+";
+
+/// Glsl shaders are written like on shadertoy, defining
+/// void mainImage(out vec4 fragColor, in vec2 fragCoord)
+/// and using iResolution and iTime
+const GLSL_PRELUDE: &str = "#version 450
+layout(set = 0, binding = 0) uniform Shadertoy {
+    vec3 iResolution;
+    float iTime;
+};
+layout(location = 0) out vec4 multibg_frag_color;
+";
+
+const GLSL_MAIN: &str = "
+void main() {
+    mainImage(
+        multibg_frag_color,
+        vec2(gl_FragCoord.x, iResolution.y - gl_FragCoord.y)
+    );
+}
+";
+
+const TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+/// Renders a fragment shader over the whole image on the GPU
+pub struct ShaderRenderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::RenderPipeline,
+    bind_group: wgpu::BindGroup,
+    uniforms: wgpu::Buffer,
+    texture: wgpu::Texture,
+    readback: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    padded_row_len: u32,
+}
+
+impl ShaderRenderer {
+    pub fn new(path: &Path, width: u32, height: u32)
+        -> Result<ShaderRenderer, String>
+    {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read shader: {}", e))?;
+        let glsl = path.extension()
+            .is_some_and(|ext| ext == "glsl" || ext == "frag");
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::LowPower,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            }
+        )).ok_or("No GPU adapter available")?;
+        debug!("Rendering shader {:?} with {:?}", path, adapter.get_info());
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                // For textures as large as the output
+                required_limits: adapter.limits(),
+                memory_hints: wgpu::MemoryHints::MemoryUsage,
+            },
+            None,
+        )).map_err(|e| format!("Failed to open GPU device: {}", e))?;
+
+        // Report invalid shaders as errors instead of panicking
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let vertex_module = device.create_shader_module(
+            wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(VERTEX_WGSL)),
+            }
+        );
+        let fragment_module = device.create_shader_module(
+            wgpu::ShaderModuleDescriptor {
+                label: Some("wallpaper"),
+                source: if glsl {
+                    wgpu::ShaderSource::Glsl {
+                        shader: Cow::Owned(
+                            [GLSL_PRELUDE, &source, GLSL_MAIN].concat()
+                        ),
+                        stage: wgpu::naga::ShaderStage::Fragment,
+                        defines: Default::default(),
+                    }
+                } else {
+                    wgpu::ShaderSource::Wgsl(Cow::Owned(
+                        [WGSL_PRELUDE, &source, WGSL_MAIN].concat()
+                    ))
+                },
+            }
+        );
+        let pipeline = device.create_render_pipeline(
+            &wgpu::RenderPipelineDescriptor {
+                label: None,
+                layout: None,
+                vertex: wgpu::VertexState {
+                    module: &vertex_module,
+                    entry_point: "vs_main",
+                    compilation_options: Default::default(),
+                    buffers: &[],
+                },
+                primitive: Default::default(),
+                depth_stencil: None,
+                multisample: Default::default(),
+                fragment: Some(wgpu::FragmentState {
+                    module: &fragment_module,
+                    entry_point: if glsl { "main" } else { "fs_main" },
+                    compilation_options: Default::default(),
+                    targets: &[Some(TEXTURE_FORMAT.into())],
+                }),
+                multiview: None,
+                cache: None,
+            }
+        );
+        if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+            return Err(format!("Invalid shader: {}", e));
+        }
+
+        let uniforms = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniforms.as_entire_binding(),
+            }],
+        });
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: TEXTURE_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        // Rows copied from textures have to be aligned
+        let padded_row_len = (width * 4)
+            .next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: padded_row_len as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Ok(ShaderRenderer {
+            device,
+            queue,
+            pipeline,
+            bind_group,
+            uniforms,
+            texture,
+            readback,
+            width,
+            height,
+            padded_row_len,
+        })
+    }
+
+    /// Rgba pixels of the shader at the time in seconds
+    pub fn render(&mut self, time: f32) -> Result<Vec<u8>, String> {
+        let uniforms = [self.width as f32, self.height as f32, 1.0, time];
+        self.queue.write_buffer(
+            &self.uniforms,
+            0,
+            &uniforms.map(f32::to_ne_bytes).concat(),
+        );
+
+        let view = self.texture.create_view(&Default::default());
+        let mut encoder = self.device.create_command_encoder(
+            &Default::default()
+        );
+        {
+            let mut pass = encoder.begin_render_pass(
+                &wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[Some(
+                        wgpu::RenderPassColorAttachment {
+                            view: &view,
+                            resolve_target: None,
+                            ops: wgpu::Operations {
+                                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                                store: wgpu::StoreOp::Store,
+                            },
+                        }
+                    )],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                }
+            );
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(self.padded_row_len),
+                    rows_per_image: None,
+                },
+            },
+            self.texture.size(),
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = self.readback.slice(..);
+        let (tx, rx) = channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = tx.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        rx.recv()
+            .map_err(|_| "GPU device lost".to_string())?
+            .map_err(|e| format!("Failed to read rendered image: {}", e))?;
+
+        let row_len = self.width as usize * 4;
+        let mut pixels = Vec::with_capacity(row_len * self.height as usize);
+        for row in slice.get_mapped_range()
+            .chunks_exact(self.padded_row_len as usize)
+        {
+            pixels.extend_from_slice(&row[..row_len]);
+        }
+        self.readback.unmap();
+        Ok(pixels)
+    }
+}
+
+/// Rgba pixels in the byte order of the buffer format without padding
+pub fn pack_pixels(rgba: &[u8], format: wl_shm::Format) -> Vec<u8> {
+    let pixels = rgba.chunks_exact(4);
+    match format {
+        wl_shm::Format::Bgr888 => pixels
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect(),
+        wl_shm::Format::Rgb565 => pixels
+            .flat_map(|pixel| {
+                let [red, green, blue] = [pixel[0], pixel[1], pixel[2]]
+                    .map(u16::from);
+                ((red >> 3) << 11 | (green >> 2) << 5 | blue >> 3)
+                    .to_le_bytes()
+            })
+            .collect(),
+        // Xrgb8888 and Argb8888 are little endian
+        _ => pixels
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+            .collect(),
+    }
+}
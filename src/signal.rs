@@ -0,0 +1,97 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use libc::c_int;
+
+/// Write end of the pipe the signal handler reports to
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_signal(signal: c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    let byte = signal as u8;
+    // SAFETY: write is async-signal-safe, a full pipe drops the signal
+    // but then one is already waiting to be read
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+/// Signals caught and queued to be handled in the main event loop,
+/// readable when one arrived
+pub struct Signals {
+    read: OwnedFd,
+}
+
+impl Signals {
+    /// Replaces the default actions of the signals,
+    /// only one instance may exist
+    pub fn catch(signals: &[c_int]) -> Result<Signals, String> {
+        let mut fds = [0; 2];
+        // SAFETY: pipe2 only writes the two fds
+        if unsafe {
+            libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC)
+        } != 0 {
+            return Err(format!(
+                "Failed to create signal pipe: {}", io::Error::last_os_error()
+            ));
+        }
+        // SAFETY: both were just created and are owned by nothing else,
+        // the write end is kept open for the handler
+        let read = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+
+        for &signal in signals {
+            // SAFETY: the handler only does async-signal-safe calls
+            unsafe {
+                let mut action = std::mem::zeroed::<libc::sigaction>();
+                action.sa_sigaction =
+                    handle_signal as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0
+                {
+                    return Err(format!(
+                        "Failed to catch signal {}: {}",
+                        signal, io::Error::last_os_error()
+                    ));
+                }
+            }
+        }
+        Ok(Signals { read })
+    }
+
+    /// The signals arrived since the last call, in order
+    pub fn received(&self) -> Vec<c_int> {
+        let mut signals = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            // SAFETY: reads at most the length of the buffer into it
+            let len = unsafe {
+                libc::read(
+                    self.read.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len <= 0 {
+                return signals;
+            }
+            signals.extend(buf[..len as usize].iter().map(|&b| b as c_int));
+        }
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+}
+
+/// Whether the signal asks to exit, SIGHUP does unless it reloads
+pub fn is_termination(signal: c_int, hup_reloads: bool) -> bool {
+    matches!(signal, libc::SIGTERM | libc::SIGINT)
+        || signal == libc::SIGHUP && !hup_reloads
+}
@@ -0,0 +1,65 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Counted since startup on all the threads decoding wallpapers
+pub static DECODE_COUNTERS: DecodeCounters = DecodeCounters {
+    decoded: AtomicU64::new(0),
+    decode_micros: AtomicU64::new(0),
+    resize_micros: AtomicU64::new(0),
+    disk_cache_hits: AtomicU64::new(0),
+    disk_cache_misses: AtomicU64::new(0),
+};
+
+pub struct DecodeCounters {
+    decoded: AtomicU64,
+    decode_micros: AtomicU64,
+    resize_micros: AtomicU64,
+    disk_cache_hits: AtomicU64,
+    disk_cache_misses: AtomicU64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DecodeStats {
+    pub decoded: u64,
+    /// Including resizing and the other processing
+    pub decode_ms: u64,
+    pub resize_ms: u64,
+    pub disk_cache_hits: u64,
+    pub disk_cache_misses: u64,
+}
+
+impl DecodeCounters {
+    pub fn count_decode(&self, time: Duration) {
+        self.decoded.fetch_add(1, Ordering::Relaxed);
+        self.decode_micros
+            .fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_resize(&self, time: Duration) {
+        self.resize_micros
+            .fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_disk_cache_lookup(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.disk_cache_hits,
+            false => &self.disk_cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> DecodeStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        DecodeStats {
+            decoded: load(&self.decoded),
+            decode_ms: load(&self.decode_micros) / 1000,
+            resize_ms: load(&self.resize_micros) / 1000,
+            disk_cache_hits: load(&self.disk_cache_hits),
+            disk_cache_misses: load(&self.disk_cache_misses),
+        }
+    }
+}
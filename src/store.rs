@@ -0,0 +1,560 @@
+use std::{
+    cell::{Cell, OnceCell},
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::{Rc, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use smithay_client_toolkit::shm::{
+    Shm,
+    slot::{Buffer, SlotPool},
+};
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    cli::ColorFilter,
+    damage::TileHashes,
+    image::{SpanArea, buffer_stride, gravity_path},
+    night::Lut,
+    overlay::Overlay,
+    palette::Palette,
+};
+
+/// Frames being shown and the one being written, a few more
+/// in case the compositor holds on to them longer
+const MAX_FRAME_BUFFERS: usize = 4;
+
+/// Identifies wallpapers that come out the same when loaded,
+/// so outputs of the same size can share one buffer
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct WallpaperKey {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    span_area: Option<[u64; 6]>,
+    filter: ColorFilter,
+    night: bool,
+    /// The text drawn over the wallpaper
+    overlay: Option<String>,
+}
+
+impl WallpaperKey {
+    pub fn new(
+        path: &Path,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+        span_area: Option<&SpanArea>,
+        filter: ColorFilter,
+    ) -> WallpaperKey {
+        // Editing the crop gravity of the image changes the wallpaper too
+        let gravity_path = gravity_path(path);
+        // Symlinked wallpapers are the same wallpaper
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let modified = [&path, &gravity_path].iter()
+            .filter_map(|path| path.metadata().and_then(|m| m.modified()).ok())
+            .max();
+        let span_area = span_area.map(|area| [
+            area.x, area.y, area.width, area.height,
+            area.total_width, area.total_height,
+        ].map(f64::to_bits));
+        WallpaperKey {
+            path, modified, width, height, format, span_area, filter,
+            night: false,
+            overlay: None,
+        }
+    }
+
+    /// For solid colors, eg. shown before any wallpaper is loaded,
+    /// by a relative path no canonicalized wallpaper file has
+    pub fn solid(
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+        color: [u8; 3],
+    ) -> WallpaperKey {
+        let [red, green, blue] = color;
+        WallpaperKey {
+            path: PathBuf::from(
+                format!("#{:02x}{:02x}{:02x}", red, green, blue)
+            ),
+            modified: None,
+            width,
+            height,
+            format,
+            span_area: None,
+            filter: ColorFilter::None,
+            night: false,
+            overlay: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> wl_shm::Format {
+        self.format
+    }
+
+    pub fn filter(&self) -> ColorFilter {
+        self.filter
+    }
+
+    /// The key of the wallpaper a night mode or overlay variant
+    /// was made from
+    pub fn original(&self) -> WallpaperKey {
+        WallpaperKey { night: false, overlay: None, ..self.clone() }
+    }
+
+    /// Feed the key to a hasher the same way on every platform and version,
+    /// unlike the Hash implementation
+    pub fn hash_stable(&self, update: &mut impl FnMut(&[u8])) {
+        update(self.path.as_os_str().as_encoded_bytes());
+        let modified = self.modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        update(&modified.as_secs().to_le_bytes());
+        update(&modified.subsec_nanos().to_le_bytes());
+        update(&self.width.to_le_bytes());
+        update(&self.height.to_le_bytes());
+        update(&(self.format as u32).to_le_bytes());
+        for value in self.span_area.iter().flatten() {
+            update(&value.to_le_bytes());
+        }
+        update(&[self.filter as u8]);
+        update(&[self.night as u8]);
+        if let Some(overlay) = &self.overlay {
+            update(overlay.as_bytes());
+        }
+    }
+}
+
+pub struct Wallpaper {
+    pub key: WallpaperKey,
+    /// One for still images, all of them for animated ones
+    pub frames: Vec<Frame>,
+    /// Of the first frame, only with damage tracking
+    pub tile_hashes: Option<TileHashes>,
+    /// Of the first frame, made when first needed
+    palette: OnceCell<Palette>,
+    /// For evicting the least recently shown wallpapers over --max-memory
+    last_shown: Cell<Option<Instant>>,
+}
+
+impl Wallpaper {
+    /// The first frame, the only one of still images
+    pub fn buffer(&self) -> &Buffer {
+        &self.frames[0].buffer
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    pub fn mark_shown(&self) {
+        self.last_shown.set(Some(Instant::now()));
+    }
+
+    /// None if never shown
+    pub fn last_shown(&self) -> Option<Instant> {
+        self.last_shown.get()
+    }
+
+    /// Bytes of the buffers of all frames
+    pub fn memory(&self) -> usize {
+        self.frames.iter()
+            .map(|frame|
+                frame.buffer.stride() as usize * frame.buffer.height() as usize
+            )
+            .sum()
+    }
+}
+
+pub struct Frame {
+    pub buffer: Buffer,
+    /// How long the frame is shown before the next one
+    pub delay: Duration,
+}
+
+impl Frame {
+    pub fn still(buffer: Buffer) -> Frame {
+        Frame { buffer, delay: Duration::ZERO }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// Wallpapers currently held by at least one output
+    pub wallpapers: usize,
+    /// Buffers attached to a surface not yet released by the compositor
+    pub busy: usize,
+    /// Buffers of outputs using the same wallpaper as an other output
+    pub shared: usize,
+    /// Memory of the shm pool backing all the wallpaper buffers
+    pub pool_bytes: usize,
+    /// Memory of the buffers of the wallpapers held, the rest of the pool
+    /// is free or waiting for the compositor to release it
+    pub used_bytes: usize,
+    /// Wallpapers loaded since startup
+    pub loaded: u64,
+    /// Times an already loaded wallpaper was used instead of loading it
+    pub reused: u64,
+    /// Wallpapers released since startup after no output used them
+    pub released: u64,
+}
+
+/// Owns the buffers of all the wallpapers of all the outputs.
+/// Background layers hold reference counted wallpapers from here,
+/// a wallpaper and its buffer is released when the last layer drops it.
+/// Buffers are marked busy when attached, sctk defers destroying a busy
+/// buffer and reusing its memory until the compositor releases it
+pub struct WallpaperStore {
+    slot_pool: SlotPool,
+    wallpapers: HashMap<WallpaperKey, Weak<Wallpaper>>,
+    loaded: u64,
+    reused: u64,
+    released: u64,
+    damage_tracking: bool,
+    night_lut: Option<Lut>,
+    /// Wallpapers of destroyed outputs kept until the deadline
+    /// in case the output comes back, eg. when redocking
+    retained: Vec<(Instant, Vec<Rc<Wallpaper>>)>,
+}
+
+impl WallpaperStore {
+    pub fn new(shm: &Shm, damage_tracking: bool)
+        -> Result<WallpaperStore, String>
+    {
+        Ok(WallpaperStore {
+            slot_pool: new_slot_pool(shm)?,
+            wallpapers: HashMap::new(),
+            loaded: 0,
+            reused: 0,
+            released: 0,
+            damage_tracking,
+            night_lut: None,
+            retained: Vec::new(),
+        })
+    }
+
+    pub fn is_loaded(&self, key: &WallpaperKey) -> bool {
+        self.wallpapers.get(key)
+            .is_some_and(|wallpaper| wallpaper.strong_count() > 0)
+    }
+
+    /// Returns the already loaded wallpaper with the same key if any,
+    /// otherwise creates it with the load function
+    pub fn get_or_load(
+        &mut self,
+        key: WallpaperKey,
+        load: impl FnOnce(&mut SlotPool) -> Result<Vec<Frame>, String>,
+    ) -> Result<Rc<Wallpaper>, String> {
+        if let Some(wallpaper) = self.wallpapers.get(&key)
+            .and_then(Weak::upgrade)
+        {
+            debug!("Sharing already loaded wallpaper {:?}", key.path);
+            self.reused += 1;
+            return Ok(wallpaper);
+        }
+
+        let frames = load(&mut self.slot_pool)?;
+        let Some(buffer) = frames.first().map(|frame| &frame.buffer) else {
+            return Err("Image has no frames".to_string());
+        };
+        let tile_hashes = self.damage_tracking
+            .then(|| pixels(buffer, &mut self.slot_pool))
+            .map(|canvas| TileHashes::new(
+                canvas,
+                buffer.height() as usize,
+                buffer.stride() as usize,
+                bytes_per_pixel(key.format),
+            ));
+        let wallpaper = Rc::new(Wallpaper {
+            key: key.clone(),
+            frames,
+            tile_hashes,
+            palette: OnceCell::new(),
+            last_shown: Cell::new(None),
+        });
+        self.wallpapers.insert(key, Rc::downgrade(&wallpaper));
+        self.loaded += 1;
+        Ok(wallpaper)
+    }
+
+    /// Dominant and accent colors of the wallpaper
+    pub fn palette(&mut self, wallpaper: &Wallpaper) -> Palette {
+        wallpaper.palette.get_or_init(|| {
+            let buffer = wallpaper.buffer();
+            Palette::from_pixels(
+                pixels(buffer, &mut self.slot_pool),
+                wallpaper.key.width as usize,
+                wallpaper.key.height as usize,
+                buffer.stride() as usize,
+                wallpaper.key.format,
+            )
+        }).clone()
+    }
+
+    /// Night mode variants of wallpapers are made while this is set
+    pub fn set_night_lut(&mut self, night_lut: Option<Lut>) {
+        self.night_lut = night_lut;
+    }
+
+    /// The wallpaper itself, or while night mode is on, a copy of it
+    /// with the colors mapped, made on first use. It is only kept while
+    /// shown, making it again is much cheaper than decoding
+    pub fn night_variant(&mut self, wallpaper: &Rc<Wallpaper>)
+        -> Rc<Wallpaper>
+    {
+        let Some(night_lut) = &self.night_lut else {
+            return Rc::clone(wallpaper);
+        };
+        let key = WallpaperKey { night: true, ..wallpaper.key.clone() };
+        let night_lut = night_lut.clone();
+        let format = key.format;
+        self.variant(wallpaper, key, |src, canvas|
+            night_lut.apply(src, canvas, format)
+        ).unwrap_or_else(|e| {
+            error!("Failed to make night mode wallpaper: {}", e);
+            Rc::clone(wallpaper)
+        })
+    }
+
+    /// A copy of the wallpaper with the text drawn over it, made on first
+    /// use and only kept while shown like the night mode variants
+    pub fn overlay_variant(
+        &mut self,
+        wallpaper: &Rc<Wallpaper>,
+        overlay: &Overlay,
+        text: &str,
+    ) -> Rc<Wallpaper> {
+        let key = WallpaperKey {
+            overlay: Some(text.to_string()),
+            ..wallpaper.key.clone()
+        };
+        let (width, height, format) = (key.width, key.height, key.format);
+        let stride = wallpaper.buffer().stride() as usize;
+        self.variant(wallpaper, key, |src, canvas| {
+            canvas.copy_from_slice(src);
+            overlay.draw(text, canvas, width, height, stride, format);
+        }).unwrap_or_else(|e| {
+            error!("Failed to draw overlay over wallpaper: {}", e);
+            Rc::clone(wallpaper)
+        })
+    }
+
+    /// The wallpaper under the key if loaded already, otherwise made
+    /// from the pixels of each frame of the wallpaper by the map function
+    fn variant(
+        &mut self,
+        wallpaper: &Wallpaper,
+        key: WallpaperKey,
+        map: impl Fn(&[u8], &mut [u8]),
+    ) -> Result<Rc<Wallpaper>, String> {
+        if let Some(variant) = self.wallpapers.get(&key)
+            .and_then(Weak::upgrade)
+        {
+            return Ok(variant);
+        }
+        let (width, height, format) = (key.width, key.height, key.format);
+        self.get_or_load(key, |slot_pool| {
+            wallpaper.frames.iter()
+                .map(|frame| {
+                    // Copy out the source, the canvas of the new buffer
+                    // borrows the same pool
+                    let src = pixels(&frame.buffer, slot_pool).to_vec();
+                    if src.is_empty() {
+                        return Err("Source buffer is gone".to_string());
+                    }
+                    let (buffer, canvas) = slot_pool.create_buffer(
+                        width as i32,
+                        height as i32,
+                        frame.buffer.stride(),
+                        format,
+                    ).map_err(|e| format!("Failed to create buffer: {}", e))?;
+                    map(&src, canvas);
+                    Ok(Frame { buffer, delay: frame.delay })
+                })
+                .collect()
+        })
+    }
+
+    /// Copy a frame into the first of the buffers the compositor does not
+    /// use, adding one if all of them are busy, with night mode applied.
+    /// None if the frame has to be dropped
+    pub fn write_frame<'a>(
+        &mut self,
+        buffers: &'a mut Vec<Buffer>,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+        pixels: &[u8],
+    ) -> Option<&'a Buffer> {
+        let night_lut = self.night_lut.clone();
+        let stride = buffer_stride(width, format);
+        let row_len = width as usize * bytes_per_pixel(format);
+        self.draw_frame(buffers, width, height, format, |canvas| {
+            // The frames are packed, the rows of the buffer padded
+            let rows = canvas.chunks_mut(stride).zip(pixels.chunks(row_len));
+            for (row, pixels) in rows {
+                let row = &mut row[..pixels.len()];
+                match &night_lut {
+                    Some(night_lut) => night_lut.apply(pixels, row, format),
+                    None => row.copy_from_slice(pixels),
+                }
+            }
+        })
+    }
+
+    /// Like write_frame but the frame is drawn into the canvas as is,
+    /// its rows are buffer_stride apart
+    pub fn draw_frame<'a>(
+        &mut self,
+        buffers: &'a mut Vec<Buffer>,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+        draw: impl FnOnce(&mut [u8]),
+    ) -> Option<&'a Buffer> {
+        let index = match buffers.iter()
+            .position(|buffer| buffer.canvas(&mut self.slot_pool).is_some())
+        {
+            Some(index) => index,
+            None if buffers.len() >= MAX_FRAME_BUFFERS => {
+                debug!("All frame buffers are busy, dropping a frame");
+                return None;
+            }
+            None => {
+                let stride = buffer_stride(width, format);
+                match self.slot_pool.create_buffer(
+                    width as i32, height as i32, stride as i32, format
+                ) {
+                    Ok((buffer, _)) => buffers.push(buffer),
+                    Err(e) => {
+                        error!("Failed to create frame buffer: {}", e);
+                        return None;
+                    }
+                }
+                buffers.len() - 1
+            }
+        };
+        let buffer = &buffers[index];
+        let canvas = buffer.canvas(&mut self.slot_pool)?;
+        draw(canvas);
+        Some(buffer)
+    }
+
+    /// A copy of the pixels of the first frame of a wallpaper
+    pub fn copy_pixels(&mut self, wallpaper: &Wallpaper) -> Vec<u8> {
+        pixels(wallpaper.buffer(), &mut self.slot_pool).to_vec()
+    }
+
+    /// Keep the wallpapers for a while after no output uses them,
+    /// an output with the same wallpapers and size gets them back
+    pub fn retain(&mut self, wallpapers: Vec<Rc<Wallpaper>>, time: Duration) {
+        if wallpapers.is_empty() || time.is_zero() {
+            return;
+        }
+        self.retained.push((Instant::now() + time, wallpapers));
+    }
+
+    pub fn next_retained_expiry(&self) -> Option<Instant> {
+        self.retained.iter().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Drop the retained wallpapers past their deadline,
+    /// returns whether there were any
+    pub fn expire_retained(&mut self) -> bool {
+        let now = Instant::now();
+        let count_before = self.retained.len();
+        self.retained.retain(|(deadline, _)| *deadline > now);
+        self.retained.len() != count_before
+    }
+
+    /// Forget the wallpapers no output uses anymore, their buffers were
+    /// already released when the last reference was dropped
+    pub fn evict_unused(&mut self, shm: &Shm) {
+        let count_before = self.wallpapers.len();
+        self.wallpapers.retain(|_, wallpaper| wallpaper.strong_count() > 0);
+        let evicted = count_before - self.wallpapers.len();
+        self.released += evicted as u64;
+
+        // The pool never shrinks by itself, give back its memory
+        // when it has no buffers left
+        if evicted > 0 && self.wallpapers.is_empty() {
+            match new_slot_pool(shm) {
+                Ok(slot_pool) => self.slot_pool = slot_pool,
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+
+    /// Wallpapers loaded since starting, only grows
+    pub fn loaded(&self) -> u64 {
+        self.loaded
+    }
+
+    /// Bytes of the buffers of the wallpapers held by outputs
+    pub fn memory_used(&self) -> usize {
+        self.wallpapers.values()
+            .filter_map(Weak::upgrade)
+            .map(|wallpaper| wallpaper.memory())
+            .sum()
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let live = self.wallpapers.values()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        StoreStats {
+            wallpapers: live.len(),
+            busy: live.iter()
+                .filter(|wallpaper|
+                    wallpaper.buffer().slot().has_active_buffers()
+                )
+                .count(),
+            shared: live.iter()
+                .map(|wallpaper| Rc::strong_count(wallpaper) - 2)
+                .sum(),
+            pool_bytes: self.slot_pool.len(),
+            used_bytes: live.iter()
+                .map(|wallpaper| wallpaper.memory())
+                .sum(),
+            loaded: self.loaded,
+            reused: self.reused,
+            released: self.released,
+        }
+    }
+}
+
+/// Pixels of a buffer for reading, even while the compositor uses it,
+/// wallpaper buffers are never written after they are loaded
+fn pixels<'a>(buffer: &Buffer, slot_pool: &'a mut SlotPool) -> &'a [u8] {
+    let len = buffer.stride() as usize * buffer.height() as usize;
+    slot_pool.raw_data_mut(&buffer.slot()).get(..len).unwrap_or_default()
+}
+
+pub fn bytes_per_pixel(format: wl_shm::Format) -> usize {
+    match format {
+        wl_shm::Format::Rgb565 => 2,
+        wl_shm::Format::Bgr888 => 3,
+        _ => 4,
+    }
+}
+
+fn new_slot_pool(shm: &Shm) -> Result<SlotPool, String> {
+    // Initialize slot pool with a minimum size (0 is not allowed)
+    // it will be automatically resized later
+    SlotPool::new(1, shm)
+        .map_err(|e| format!("Failed to create shm slot pool: {}", e))
+}
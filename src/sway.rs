@@ -1,80 +1,338 @@
 use std::{
-    sync::{Arc, mpsc::Sender},
+    cmp::Reverse,
+    collections::HashMap,
+    env,
+    fs::read_dir,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{Arc, Mutex, mpsc::Sender},
     thread::spawn,
 };
 
+use log::{debug, error};
 use mio::Waker;
-use swayipc::{Connection, Event, EventType, WorkspaceChange};
+use swayipc::{
+    Connection, Event, EventType, NodeLayout, Output, Workspace,
+    WorkspaceChange,
+};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct WorkspaceVisible {
     pub output: String,
-    pub workspace_name: String
+    pub workspace_name: String,
+    /// Only if wallpapers by layout were requested
+    pub layout: Option<String>,
+    /// Whether the workspace has the focus
+    pub focused: bool,
+}
+
+/// The visible workspace of each output as of the last replayed event
+pub type ReplayedWorkspaces = Arc<Mutex<HashMap<String, WorkspaceVisible>>>;
+
+enum WorkspaceSource {
+    Sway(Connection),
+    /// Answers from the replayed events of --replay without asking sway
+    Replay(ReplayedWorkspaces),
 }
 
 pub struct SwayConnectionTask {
-    sway_conn: Connection,
+    source: WorkspaceSource,
     tx: Sender<WorkspaceVisible>,
     waker: Arc<Waker>,
+    track_layouts: bool,
 }
 impl SwayConnectionTask
 {
-    pub fn new(tx: Sender<WorkspaceVisible>, waker: Arc<Waker>) -> Self {
+    pub fn new(
+        tx: Sender<WorkspaceVisible>,
+        waker: Arc<Waker>,
+        track_layouts: bool,
+    ) -> Result<Self, String> {
+        Ok(SwayConnectionTask {
+            source: WorkspaceSource::Sway(connect()?),
+            tx,
+            waker,
+            track_layouts,
+        })
+    }
+
+    /// Without a sway connection, the visible workspaces are
+    /// the ones shown by the replayed events so far
+    pub fn replay(
+        tx: Sender<WorkspaceVisible>,
+        waker: Arc<Waker>,
+        replayed: ReplayedWorkspaces,
+    ) -> Self {
         SwayConnectionTask {
-            sway_conn: Connection::new()
-                .expect("Failed to connect to sway socket"),
+            source: WorkspaceSource::Replay(replayed),
             tx,
-            waker
+            waker,
+            track_layouts: false,
+        }
+    }
+
+    fn sway_conn(&mut self) -> Option<&mut Connection> {
+        match &mut self.source {
+            WorkspaceSource::Sway(sway_conn) => Some(sway_conn),
+            WorkspaceSource::Replay(_) => None,
+        }
+    }
+
+    fn visible_workspaces(&mut self) -> Vec<WorkspaceVisible> {
+        let workspaces = match &mut self.source {
+            WorkspaceSource::Sway(sway_conn) => sway_conn.get_workspaces(),
+            WorkspaceSource::Replay(replayed) => return replayed.lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect(),
+        };
+        match workspaces {
+            Ok(workspaces) => workspaces.into_iter()
+                .filter(|w| w.visible)
+                .map(|w| self.workspace_visible(w))
+                .collect(),
+            Err(e) => {
+                error!("Failed to get workspaces from sway: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn workspace_visible(&self, workspace: Workspace) -> WorkspaceVisible {
+        WorkspaceVisible {
+            output: workspace.output,
+            workspace_name: workspace.name,
+            layout: self.track_layouts.then_some(workspace.layout),
+            focused: workspace.focused,
         }
     }
 
     pub fn request_visible_workspace(&mut self, output: &str) {
-        if let Some(workspace) = self.sway_conn.get_workspaces().unwrap()
-            .into_iter()
-            .filter(|w| w.visible)
+        if let Some(workspace) = self.visible_workspaces().into_iter()
             .find(|w| w.output == output)
         {
-            self.tx.send(WorkspaceVisible {
-                output: workspace.output,
-                workspace_name: workspace.name,
-            }).unwrap();
+            self.tx.send(workspace).unwrap();
 
             self.waker.wake().unwrap();
         }
     }
 
     pub fn request_visible_workspaces(&mut self) {
-        for workspace in self.sway_conn.get_workspaces().unwrap()
-            .into_iter().filter(|w| w.visible)
-        {
-            self.tx.send(WorkspaceVisible {
-                output: workspace.output,
-                workspace_name: workspace.name,
-            }).unwrap();
+        for workspace in self.visible_workspaces() {
+            self.tx.send(workspace).unwrap();
         }
         self.waker.wake().unwrap();
     }
 
+    /// The output identifier also used in the sway config:
+    /// make, model and serial separated by spaces
+    pub fn output_identifier(&mut self, output_name: &str) -> Option<String> {
+        let outputs = self.get_outputs()?;
+        outputs.into_iter()
+            .find(|output| output.name == output_name)
+            .map(|output| [output.make, output.model, output.serial].join(" "))
+    }
+
+    pub fn output_name_at(&mut self, position: (i32, i32)) -> Option<String> {
+        let outputs = self.get_outputs()?;
+        outputs.into_iter()
+            .filter(|output| output.active)
+            .find(|output| (output.rect.x, output.rect.y) == position)
+            .map(|output| output.name)
+    }
+
+    fn get_outputs(&mut self) -> Option<Vec<Output>> {
+        self.sway_conn()?.get_outputs()
+            .map_err(|e| error!("Failed to get outputs from sway: {}", e))
+            .ok()
+    }
+
     pub fn spawn_subscribe_event_loop(self) {
         spawn(|| self.subscribe_event_loop());
     }
 
+    // Returns when sway goes away or the receiving main event loop
+    // was torn down, a new task is spawned on reconnection
     fn subscribe_event_loop(self) {
-        let event_stream = self.sway_conn.subscribe([EventType::Workspace])
-            .unwrap();
+        let WorkspaceSource::Sway(sway_conn) = self.source else {
+            return;
+        };
+        // Sway has no event for layout changes, check the layouts
+        // after anything that may have changed them
+        let event_types: &[EventType] = if self.track_layouts {
+            &[EventType::Workspace, EventType::Window, EventType::Binding]
+        } else {
+            &[EventType::Workspace]
+        };
+
+        // The subscribed connection can only receive events,
+        // the layouts are queried on a new one
+        let mut layout_task = None;
+        if self.track_layouts {
+            match connect() {
+                Ok(sway_conn) => layout_task = Some(SwayConnectionTask {
+                    source: WorkspaceSource::Sway(sway_conn),
+                    tx: self.tx.clone(),
+                    waker: Arc::clone(&self.waker),
+                    track_layouts: true,
+                }),
+                Err(e) => error!("Cannot follow workspace layouts: {}", e),
+            }
+        }
+        let mut layouts = HashMap::new();
+
+        let event_stream = match sway_conn
+            .subscribe(event_types)
+        {
+            Ok(event_stream) => event_stream,
+            Err(e) => {
+                error!("Failed to subscribe to sway events: {}", e);
+                return;
+            }
+        };
         for event_result in event_stream {
-            let event = event_result.unwrap();
-            let Event::Workspace(workspace_event) = event else {continue};
+            let event = match event_result {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Sway event stream ended: {}", e);
+                    return;
+                }
+            };
+            let Event::Workspace(workspace_event) = event else {
+                if let Some(layout_task) = &mut layout_task {
+                    if !layout_task.send_layout_changes(&mut layouts) {
+                        return;
+                    }
+                }
+                continue;
+            };
             if let WorkspaceChange::Focus = workspace_event.change {
                 let current_workspace = workspace_event.current.unwrap();
 
-                self.tx.send(WorkspaceVisible {
+                let workspace = WorkspaceVisible {
                     output: current_workspace.output.unwrap(),
                     workspace_name: current_workspace.name.unwrap(),
-                }).unwrap();
+                    layout: self.track_layouts
+                        .then(|| layout_name(current_workspace.layout))
+                        .flatten()
+                        .map(str::to_string),
+                    focused: true,
+                };
+                layouts.insert(workspace.output.clone(), workspace.clone());
+
+                if self.tx.send(workspace).is_err() {
+                    debug!("Main event loop is gone, stopping sway events");
+                    return;
+                }
 
                 self.waker.wake().unwrap();
             }
         }
     }
+
+    /// Send the visible workspaces whose layout changed since last sent,
+    /// returns false if the main event loop is gone
+    fn send_layout_changes(
+        &mut self,
+        layouts: &mut HashMap<String, WorkspaceVisible>,
+    ) -> bool {
+        for workspace in self.visible_workspaces() {
+            if layouts.get(&workspace.output).is_some_and(|last|
+                last.workspace_name == workspace.workspace_name
+                    && last.layout == workspace.layout
+                    && last.focused == workspace.focused
+            ) {
+                continue;
+            }
+            debug!(
+                "Workspace '{}' has layout {:?}",
+                workspace.workspace_name, workspace.layout
+            );
+            layouts.insert(workspace.output.clone(), workspace.clone());
+            if self.tx.send(workspace).is_err() {
+                debug!("Main event loop is gone, stopping sway events");
+                return false;
+            }
+            self.waker.wake().unwrap();
+        }
+        true
+    }
+}
+
+// Same as the layout names in the reply of get_workspaces
+fn layout_name(layout: NodeLayout) -> Option<&'static str> {
+    match layout {
+        NodeLayout::SplitH => Some("splith"),
+        NodeLayout::SplitV => Some("splitv"),
+        NodeLayout::Stacked => Some("stacked"),
+        NodeLayout::Tabbed => Some("tabbed"),
+        _ => None,
+    }
+}
+
+/// Output names with the identifiers also used in the sway config
+pub fn output_identifiers() -> Vec<(String, String)> {
+    let outputs = match connect()
+        .and_then(|mut conn| conn.get_outputs().map_err(|e| e.to_string()))
+    {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            error!("Failed to get outputs from sway: {}", e);
+            return Vec::new();
+        }
+    };
+    outputs.into_iter()
+        .map(|output| (
+            output.name,
+            [output.make, output.model, output.serial].join(" ")
+        ))
+        .collect()
+}
+
+/// The version of sway, or of another compositor with its IPC
+pub fn compositor_version() -> Result<String, String> {
+    connect()?.get_version()
+        .map(|version| version.human_readable)
+        .map_err(|e| format!("Failed to get the version: {}", e))
+}
+
+fn connect() -> Result<Connection, String> {
+    let error = match Connection::new() {
+        Ok(conn) => return Ok(conn),
+        Err(e) => e,
+    };
+
+    // A restarted sway listens on a new socket while our SWAYSOCK
+    // still points to the old one, look for the new one
+    for socket_path in find_sway_sockets() {
+        if let Ok(stream) = UnixStream::connect(&socket_path) {
+            debug!("Connected to sway socket: {:?}", socket_path);
+            return Ok(Connection::from(stream));
+        }
+    }
+
+    Err(format!("Failed to connect to sway socket: {}", error))
+}
+
+// Sway sockets are named sway-ipc.<uid>.<pid>.sock,
+// try the most recently created first
+pub fn find_sway_sockets() -> Vec<PathBuf> {
+    let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") else {
+        return Vec::new();
+    };
+    let Ok(dir) = read_dir(runtime_dir) else {
+        return Vec::new();
+    };
+    let mut sockets = dir.filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name|
+            name.starts_with("sway-ipc.") && name.ends_with(".sock")
+        ))
+        .filter_map(|entry| Some((
+            entry.metadata().and_then(|m| m.modified()).ok()?,
+            entry.path()
+        )))
+        .collect::<Vec<_>>();
+    sockets.sort_unstable_by_key(|(modified, _)| Reverse(*modified));
+    sockets.into_iter().map(|(_, path)| path).collect()
 }
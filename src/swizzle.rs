@@ -0,0 +1,106 @@
+//! Conversion of packed rgb pixels to the little endian Xrgb8888 buffer
+//! format, the bytes of each pixel in blue, green, red, unused order.
+//! Runs over every pixel of every wallpaper on compositors without Bgr888
+
+/// Converts as many pixels as both fit, with SIMD where available
+pub fn bgrx_from_rgb(src: &[u8], dst: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        // SAFETY: avx2 is available
+        return unsafe { bgrx_from_rgb_avx2(src, dst) };
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // SAFETY: neon is always available on aarch64
+        return unsafe { bgrx_from_rgb_neon(src, dst) };
+    }
+    #[allow(unreachable_code)]
+    bgrx_from_rgb_scalar(src, dst)
+}
+
+pub fn bgrx_from_rgb_scalar(src: &[u8], dst: &mut [u8]) {
+    for (rgb, bgrx) in src.chunks_exact(3).zip(dst.chunks_exact_mut(4)) {
+        bgrx[0] = rgb[2];
+        bgrx[1] = rgb[1];
+        bgrx[2] = rgb[0];
+        bgrx[3] = 0xff;
+    }
+}
+
+/// 8 pixels at a time, each 128 bit lane shuffles 4 of them in place
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn bgrx_from_rgb_avx2(src: &[u8], dst: &mut [u8]) {
+    use std::arch::x86_64::*;
+
+    let pixels = (src.len() / 3).min(dst.len() / 4);
+    let shuffle = _mm256_setr_epi8(
+        2, 1, 0, -1, 5, 4, 3, -1, 8, 7, 6, -1, 11, 10, 9, -1,
+        2, 1, 0, -1, 5, 4, 3, -1, 8, 7, 6, -1, 11, 10, 9, -1,
+    );
+    let opaque = _mm256_set1_epi32(0xff000000u32 as i32);
+    let mut done = 0;
+    // The upper lane loads 4 bytes past the 8 pixels, stop before the end
+    while done + 8 <= pixels && (done + 8) * 3 + 4 <= src.len() {
+        let src_ptr = src.as_ptr().add(done * 3);
+        let rgb = _mm256_loadu2_m128i(
+            src_ptr.add(12) as *const __m128i,
+            src_ptr as *const __m128i,
+        );
+        let bgrx = _mm256_or_si256(_mm256_shuffle_epi8(rgb, shuffle), opaque);
+        _mm256_storeu_si256(
+            dst.as_mut_ptr().add(done * 4) as *mut __m256i,
+            bgrx,
+        );
+        done += 8;
+    }
+    bgrx_from_rgb_scalar(&src[done * 3..], &mut dst[done * 4..]);
+}
+
+/// 16 pixels at a time, loaded and stored deinterleaved by channel
+#[cfg(target_arch = "aarch64")]
+unsafe fn bgrx_from_rgb_neon(src: &[u8], dst: &mut [u8]) {
+    use std::arch::aarch64::*;
+
+    let pixels = (src.len() / 3).min(dst.len() / 4);
+    let opaque = vdupq_n_u8(0xff);
+    let mut done = 0;
+    while done + 16 <= pixels {
+        let rgb = vld3q_u8(src.as_ptr().add(done * 3));
+        vst4q_u8(
+            dst.as_mut_ptr().add(done * 4),
+            uint8x16x4_t(rgb.2, rgb.1, rgb.0, opaque),
+        );
+        done += 16;
+    }
+    bgrx_from_rgb_scalar(&src[done * 3..], &mut dst[done * 4..]);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn same_as_scalar() {
+        let rgb = |pixels: usize| -> Vec<u8> {
+            (0..pixels * 3).map(|i| (i * 7 + 1) as u8).collect()
+        };
+        let assert_same = |src: &[u8], dst_pixels: usize| {
+            // Bytes not written keep the filler in both
+            let mut simd = vec![0x55; dst_pixels * 4];
+            let mut scalar = simd.clone();
+            super::bgrx_from_rgb(src, &mut simd);
+            super::bgrx_from_rgb_scalar(src, &mut scalar);
+            assert_eq!(
+                simd, scalar,
+                "{} pixels into {} pixels", src.len() / 3, dst_pixels
+            );
+        };
+        for pixels in 0..=40 {
+            assert_same(&rgb(pixels), pixels);
+        }
+        // Only as many pixels as the shorter dst fits
+        let src = rgb(40);
+        for dst_pixels in 0..40 {
+            assert_same(&src, dst_pixels);
+        }
+    }
+}
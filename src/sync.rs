@@ -0,0 +1,49 @@
+use std::{path::Path, process::Command};
+
+use log::debug;
+
+use crate::control::request_reload;
+
+/// Mirror the source into the wallpaper dir, rsync only transfers the
+/// changed parts of changed files and works over ssh for remote sources.
+/// Then the running daemon reloads the wallpapers
+pub fn sync(
+    source: &str,
+    wallpaper_dir: &str,
+    rsync_args: &[String],
+    control_socket: &Path,
+) -> Result<(), String> {
+    // Trailing slashes make rsync copy the contents of the directories
+    // instead of the source directory itself
+    let source = format!("{}/", source.trim_end_matches('/'));
+    let destination = format!("{}/", wallpaper_dir.trim_end_matches('/'));
+
+    let mut command = Command::new("rsync");
+    command
+        .arg("--recursive")
+        .arg("--links")
+        .arg("--times")
+        .arg("--compress")
+        .arg("--partial")
+        .arg("--delete-delay")
+        .args(rsync_args)
+        .arg(&source)
+        .arg(&destination);
+
+    debug!("Running: {:?}", command);
+
+    let status = command.status()
+        .map_err(|e| format!("Failed to run rsync: {}", e))?;
+    if !status.success() {
+        return Err(format!("rsync failed with {}", status));
+    }
+
+    println!("Synced {} into {}", source, destination);
+    if request_reload(control_socket)? {
+        println!("Reloaded the wallpapers of the running multibg-sway");
+    }
+    else {
+        println!("multibg-sway is not running, it loads them when started");
+    }
+    Ok(())
+}
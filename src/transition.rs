@@ -0,0 +1,438 @@
+use std::{
+    cell::Cell,
+    f64::consts::PI,
+    time::{Duration, Instant},
+};
+
+use smithay_client_toolkit::shm::slot::Buffer;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    cli::TransitionStyle,
+    image::buffer_stride,
+    overlay::{read_pixel, write_pixel},
+    store::{Wallpaper, WallpaperStore, bytes_per_pixel},
+};
+
+/// How much larger the old wallpaper grows by the end of a zoom
+const ZOOM: f64 = 0.1;
+
+/// Transitions starting this close to each other share their start,
+/// eg. those of a single switch changing the workspaces of several outputs
+const SYNC_WINDOW: Duration = Duration::from_millis(100);
+
+/// The clock shared by the transitions of all outputs, so a workspace
+/// switch changing several of them animates them in lockstep
+/// instead of one after the other as their events arrive
+pub struct TransitionClock {
+    duration: Duration,
+    start: Cell<Option<Instant>>,
+}
+
+impl TransitionClock {
+    pub fn new(duration: Duration) -> TransitionClock {
+        TransitionClock { duration, start: Cell::new(None) }
+    }
+
+    /// The start of a transition beginning now
+    fn start(&self) -> Instant {
+        let now = Instant::now();
+        match self.start.get()
+            .filter(|start| now < *start + SYNC_WINDOW)
+        {
+            Some(start) => start,
+            None => {
+                self.start.set(Some(now));
+                now
+            }
+        }
+    }
+}
+
+/// Where the wallpapers move, towards the left when switching
+/// to a workspace with a higher number
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// The animation from the wallpaper of the previous workspace to the one
+/// of the new workspace, each frame composed of their pixels into one of
+/// its own buffers
+pub struct Transition {
+    style: TransitionStyle,
+    direction: Direction,
+    start: Instant,
+    duration: Duration,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    /// Copies of the first frames of the wallpapers, as the frame buffers
+    /// are written in the same pool
+    from: Vec<u8>,
+    to: Vec<u8>,
+    /// Of both wallpapers
+    stride: usize,
+    buffers: Vec<Buffer>,
+}
+
+impl Transition {
+    /// None if no transition is shown between the wallpapers,
+    /// eg. because one of them has transparency
+    pub fn new(
+        style: TransitionStyle,
+        clock: &TransitionClock,
+        from_workspace: &str,
+        to_workspace: &str,
+        from: &Wallpaper,
+        to: &Wallpaper,
+        store: &mut WallpaperStore,
+    ) -> Option<Transition> {
+        if style == TransitionStyle::None || clock.duration.is_zero() {
+            return None;
+        }
+        let (width, height, format) =
+            (to.key.width(), to.key.height(), to.key.format());
+        let stride = buffer_stride(width, format);
+        if (from.key.width(), from.key.height(), from.key.format())
+            != (width, height, format)
+            || from.buffer().stride() as usize != stride
+            || to.buffer().stride() as usize != stride
+        {
+            return None;
+        }
+
+        let forward = match (
+            workspace_number(from_workspace),
+            workspace_number(to_workspace),
+        ) {
+            (Some(from), Some(to)) => to >= from,
+            _ => true,
+        };
+        let vertical = matches!(
+            style,
+            TransitionStyle::SlideVertical | TransitionStyle::WipeVertical
+        );
+        let direction = match (vertical, forward) {
+            (false, true) => Direction::Left,
+            (false, false) => Direction::Right,
+            (true, true) => Direction::Up,
+            (true, false) => Direction::Down,
+        };
+
+        let from = store.copy_pixels(from);
+        let to = store.copy_pixels(to);
+        if from.len() != stride * height as usize || from.len() != to.len() {
+            return None;
+        }
+
+        Some(Transition {
+            style,
+            direction,
+            start: clock.start(),
+            duration: clock.duration,
+            width,
+            height,
+            format,
+            from,
+            to,
+            stride,
+            buffers: Vec::new(),
+        })
+    }
+
+    /// From black to the wallpaper, None if it is not faded in
+    pub fn fade_in(
+        duration: Duration,
+        clock: &TransitionClock,
+        wallpaper: &Wallpaper,
+        store: &mut WallpaperStore,
+    ) -> Option<Transition> {
+        Transition::fade(duration, clock, wallpaper, [0; 3], false, store)
+    }
+
+    /// From the wallpaper to the color, None if it is not faded out
+    pub fn fade_out(
+        duration: Duration,
+        color: [u8; 3],
+        clock: &TransitionClock,
+        wallpaper: &Wallpaper,
+        store: &mut WallpaperStore,
+    ) -> Option<Transition> {
+        Transition::fade(duration, clock, wallpaper, color, true, store)
+    }
+
+    fn fade(
+        duration: Duration,
+        clock: &TransitionClock,
+        wallpaper: &Wallpaper,
+        color: [u8; 3],
+        out: bool,
+        store: &mut WallpaperStore,
+    ) -> Option<Transition> {
+        if duration.is_zero() {
+            return None;
+        }
+        let (width, height, format) = (
+            wallpaper.key.width(),
+            wallpaper.key.height(),
+            wallpaper.key.format(),
+        );
+        let stride = buffer_stride(width, format);
+        if wallpaper.buffer().stride() as usize != stride {
+            return None;
+        }
+        let pixels = store.copy_pixels(wallpaper);
+        if pixels.len() != stride * height as usize {
+            return None;
+        }
+        let solid = solid_pixels(color, width, height, stride, format);
+        let (from, to) = if out { (pixels, solid) } else { (solid, pixels) };
+
+        Some(Transition {
+            style: TransitionStyle::Crossfade,
+            direction: Direction::Left,
+            start: clock.start(),
+            duration,
+            width,
+            height,
+            format,
+            from,
+            to,
+            stride,
+            buffers: Vec::new(),
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.start.elapsed() >= self.duration
+    }
+
+    /// None if the frame has to be dropped
+    pub fn draw_frame(&mut self, store: &mut WallpaperStore)
+        -> Option<&Buffer>
+    {
+        let elapsed = self.start.elapsed().as_secs_f64()
+            / self.duration.as_secs_f64();
+        // Ease in and out
+        let progress = (1.0 - (PI * elapsed.min(1.0)).cos()) / 2.0;
+
+        let Transition {
+            style, direction, width, height, format, from, to, stride,
+            buffers, ..
+        } = self;
+        let bytes_per_pixel = bytes_per_pixel(*format);
+        let row_len = *width as usize * bytes_per_pixel;
+        let slide = matches!(
+            style,
+            TransitionStyle::Slide | TransitionStyle::SlideVertical
+        );
+        store.draw_frame(buffers, *width, *height, *format, |canvas| {
+            // The canvas has the padded rows of the wallpapers too
+            let rows = canvas.chunks_mut(*stride)
+                .map(|row| &mut row[..row_len])
+                .take(*height as usize)
+                .enumerate();
+            match (*style, *direction) {
+                (TransitionStyle::Crossfade, _) => {
+                    for (y, row) in rows {
+                        row.copy_from_slice(&from[y * *stride..][..row_len]);
+                        mix_row(
+                            row,
+                            Some(&to[y * *stride..][..row_len]),
+                            progress,
+                            *format,
+                        );
+                    }
+                }
+                (TransitionStyle::FadeThroughBlack, _) => {
+                    // Black halfway through
+                    let (source, darkness) = if progress < 0.5 {
+                        (&from, progress * 2.0)
+                    } else {
+                        (&to, 2.0 - progress * 2.0)
+                    };
+                    for (y, row) in rows {
+                        row.copy_from_slice(
+                            &source[y * *stride..][..row_len]
+                        );
+                        mix_row(row, None, darkness, *format);
+                    }
+                }
+                (TransitionStyle::Zoom, _) => {
+                    // Nearest neighbor around the center, the rows and
+                    // columns of the old wallpaper shown in the frame
+                    let scale = 1.0 + ZOOM * progress;
+                    let zoomed = |index: usize, len: u32| {
+                        let center = len as f64 / 2.0;
+                        let source = center
+                            + (index as f64 + 0.5 - center) / scale;
+                        (source as usize).min(len as usize - 1)
+                    };
+                    let columns = (0..*width as usize)
+                        .map(|x| zoomed(x, *width) * bytes_per_pixel)
+                        .collect::<Vec<_>>();
+                    for (y, row) in rows {
+                        let source =
+                            &from[zoomed(y, *height) * *stride..][..row_len];
+                        let pixels = row.chunks_exact_mut(bytes_per_pixel);
+                        for (pixel, &x) in pixels.zip(&columns) {
+                            pixel.copy_from_slice(
+                                &source[x..][..bytes_per_pixel]
+                            );
+                        }
+                        mix_row(
+                            row,
+                            Some(&to[y * *stride..][..row_len]),
+                            progress,
+                            *format,
+                        );
+                    }
+                }
+                (_, Direction::Left | Direction::Right) => {
+                    let offset = (progress * *width as f64).round() as usize
+                        * bytes_per_pixel;
+                    for (y, row) in rows {
+                        compose_row(
+                            row,
+                            &from[y * *stride..][..row_len],
+                            &to[y * *stride..][..row_len],
+                            offset,
+                            slide,
+                            *direction,
+                        );
+                    }
+                }
+                (_, Direction::Up | Direction::Down) => {
+                    let offset = (progress * *height as f64).round() as usize;
+                    for (y, row) in rows {
+                        let (new, y) = source_row(
+                            y, *height as usize, offset, slide, *direction
+                        );
+                        let source = if new { &to } else { &from };
+                        row.copy_from_slice(&source[y * *stride..][..row_len]);
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// The row of the canvas with the new wallpaper moved in by offset bytes
+fn compose_row(
+    row: &mut [u8],
+    from: &[u8],
+    to: &[u8],
+    offset: usize,
+    slide: bool,
+    direction: Direction,
+) {
+    let len = row.len();
+    match (slide, direction) {
+        (true, Direction::Left) => {
+            row[..len - offset].copy_from_slice(&from[offset..]);
+            row[len - offset..].copy_from_slice(&to[..offset]);
+        }
+        (true, _) => {
+            row[..offset].copy_from_slice(&to[len - offset..]);
+            row[offset..].copy_from_slice(&from[..len - offset]);
+        }
+        (false, Direction::Left) => {
+            row[..len - offset].copy_from_slice(&from[..len - offset]);
+            row[len - offset..].copy_from_slice(&to[len - offset..]);
+        }
+        (false, _) => {
+            row[..offset].copy_from_slice(&to[..offset]);
+            row[offset..].copy_from_slice(&from[offset..]);
+        }
+    }
+}
+
+/// Of the color in the format, opaque
+fn solid_pixels(
+    color: [u8; 3],
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: wl_shm::Format,
+) -> Vec<u8> {
+    let bytes_per_pixel = bytes_per_pixel(format);
+    let mut pixel = vec![0xff; bytes_per_pixel];
+    write_pixel(&mut pixel, format, color);
+    let mut row = pixel.repeat(width as usize);
+    row.resize(stride, 0);
+    row.repeat(height as usize)
+}
+
+/// Blend the row of pixels towards the other row, or towards black
+/// if there is none, by the amount from 0 to 1
+fn mix_row(
+    row: &mut [u8],
+    other: Option<&[u8]>,
+    amount: f64,
+    format: wl_shm::Format,
+) {
+    let weight = (amount.clamp(0.0, 1.0) * 256.0) as u32;
+    let mix = |value: u8, other: u8|
+        ((value as u32 * (256 - weight) + other as u32 * weight) >> 8) as u8;
+    match format {
+        wl_shm::Format::Rgb565 => {
+            for (index, pixel) in row.chunks_exact_mut(2).enumerate() {
+                let other = other.map_or([0; 3], |other|
+                    read_pixel(&other[index * 2..][..2], format)
+                );
+                let rgb = read_pixel(pixel, format);
+                write_pixel(
+                    pixel,
+                    format,
+                    [0, 1, 2].map(|channel| mix(rgb[channel], other[channel])),
+                );
+            }
+        }
+        _ => match other {
+            Some(other) => {
+                for (value, other) in row.iter_mut().zip(other) {
+                    *value = mix(*value, *other);
+                }
+            }
+            None => {
+                let has_alpha = format == wl_shm::Format::Argb8888;
+                for (index, value) in row.iter_mut().enumerate() {
+                    // Keep the coverage of translucent wallpapers
+                    if !(has_alpha && index % 4 == 3) {
+                        *value = mix(*value, 0);
+                    }
+                }
+            }
+        },
+    }
+}
+
+/// Whether the row y of the canvas comes from the new wallpaper,
+/// and which row of it, with the new wallpaper moved in by offset rows
+fn source_row(
+    y: usize,
+    height: usize,
+    offset: usize,
+    slide: bool,
+    direction: Direction,
+) -> (bool, usize) {
+    match (slide, direction) {
+        (true, Direction::Up) if y < height - offset => (false, y + offset),
+        (true, Direction::Up) => (true, y + offset - height),
+        (true, _) if y < offset => (true, y + height - offset),
+        (true, _) => (false, y - offset),
+        (false, Direction::Up) => (y >= height - offset, y),
+        (false, _) => (y < offset, y),
+    }
+}
+
+/// The number sway sorts workspaces by, eg. 3 of "3:mail"
+fn workspace_number(name: &str) -> Option<i64> {
+    let digits = name.find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(name.len());
+    name[..digits].parse().ok()
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    cli::ColorFilter,
+    decoder::Format,
+    image::{
+        FRAME_SEQUENCE_EXTENSION, FRAME_SEQUENCE_FPS_FILE, GRAVITY_EXTENSION,
+        parse_resolution,
+    },
+};
+
+/// Deeper directories are not a layout multibg-sway uses, also stops
+/// at symlink loops
+const MAX_DEPTH: usize = 8;
+
+/// Checks the wallpaper directory without connecting to the compositor
+/// and prints the problems found, an error if there are any
+pub fn validate(wallpaper_dir: &str, sizes: &[(u32, u32)])
+    -> Result<(), String>
+{
+    let wallpaper_dir = Path::new(wallpaper_dir);
+    if !wallpaper_dir.is_dir() {
+        return Err(format!("{:?} is not a directory", wallpaper_dir));
+    }
+    let mut validator = Validator { sizes, problems: 0, wallpapers: 0 };
+    validator.dir(wallpaper_dir, None, 0);
+    match validator.problems {
+        0 => {
+            println!(
+                "{} wallpapers in {:?}, no problems found",
+                validator.wallpapers, wallpaper_dir
+            );
+            Ok(())
+        }
+        problems => Err(format!("Found {} problems", problems)),
+    }
+}
+
+struct Validator<'a> {
+    /// The images must cover
+    sizes: &'a [(u32, u32)],
+    problems: usize,
+    wallpapers: usize,
+}
+
+impl Validator<'_> {
+    fn problem(&mut self, path: &Path, problem: impl AsRef<str>) {
+        println!("{}: {}", path.display(), problem.as_ref());
+        self.problems += 1;
+    }
+
+    /// The resolution is of the directory or the one it is in
+    fn dir(
+        &mut self,
+        dir: &Path,
+        resolution: Option<(u32, u32)>,
+        depth: usize,
+    ) {
+        if depth > MAX_DEPTH {
+            self.problem(dir, "nested too deep, maybe a symlink loop");
+            return;
+        }
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return self.problem(dir, format!("unreadable: {}", e)),
+        };
+        let mut paths = entries
+            .filter_map(|entry| entry.map(|entry| entry.path()).ok())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut workspaces: HashMap<String, PathBuf> = HashMap::new();
+        for path in paths {
+            if path.is_symlink() && !path.exists() {
+                self.problem(&path, "broken symlink");
+                continue;
+            }
+            if path.extension().is_some_and(|ext| ext == GRAVITY_EXTENSION) {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy();
+            let is_frame_sequence = path.extension()
+                .is_some_and(|ext| ext == FRAME_SEQUENCE_EXTENSION);
+            if path.is_dir() && !is_frame_sequence {
+                let resolution = parse_resolution(&name).or(resolution);
+                self.dir(&path, resolution, depth + 1);
+                continue;
+            }
+
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            if let Some(problem) = unknown_name(&stem) {
+                self.problem(&path, problem);
+            }
+            let other = workspaces.insert(stem.to_string(), path.clone());
+            if let Some(other) = other {
+                self.problem(&path, format!(
+                    "same workspace as {}, only one of them is shown",
+                    other.display()
+                ));
+            }
+            self.wallpapers += 1;
+            if is_frame_sequence {
+                self.frame_sequence(&path, resolution);
+            }
+            else {
+                self.wallpaper(&path, resolution);
+            }
+        }
+    }
+
+    fn frame_sequence(&mut self, dir: &Path, resolution: Option<(u32, u32)>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return self.problem(dir, "unreadable");
+        };
+        let frames = entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.file_name()
+                .is_some_and(|name| name != FRAME_SEQUENCE_FPS_FILE)
+            )
+            .collect::<Vec<_>>();
+        if frames.is_empty() {
+            return self.problem(dir, "frame sequence without frames");
+        }
+        for frame in frames {
+            self.wallpaper(&frame, resolution);
+        }
+    }
+
+    fn wallpaper(&mut self, path: &Path, resolution: Option<(u32, u32)>) {
+        let format = match Format::sniff(path) {
+            Ok(Some(format)) => format,
+            Ok(None) => return self.problem(path, "unsupported format"),
+            Err(e) => return self.problem(path, e),
+        };
+        // The others are not checked without decoding them
+        let Format::Image(_) = format else { return };
+        let (width, height) = match ::image::image_dimensions(path) {
+            Ok(dimensions) => dimensions,
+            Err(e) => return self.problem(path, format!("unreadable: {}", e)),
+        };
+        if let Some(resolution) = resolution {
+            if (width, height) != resolution {
+                self.problem(path, format!(
+                    "{}x{} in the directory of {}x{} wallpapers",
+                    width, height, resolution.0, resolution.1
+                ));
+            }
+        }
+        else if let Some(&(size_width, size_height)) = self.sizes.iter()
+            .find(|(size_width, size_height)|
+                width < *size_width && height < *size_height
+            )
+        {
+            self.problem(path, format!(
+                "{}x{} is upscaled to cover {}x{}",
+                width, height, size_width, size_height
+            ));
+        }
+    }
+}
+
+/// A problem with the file stem as a workspace name
+fn unknown_name(stem: &str) -> Option<String> {
+    let mut name = stem;
+    if let Some((workspace, modifier)) = stem.rsplit_once('+') {
+        if ColorFilter::from_str(modifier, true).is_err() {
+            return Some(format!(
+                "unknown filter '{}', it is part of the workspace name",
+                modifier
+            ));
+        }
+        name = workspace;
+    }
+    let name = name.split_once('@').map_or(name, |(name, _)| name);
+    (name.starts_with('_') && name != "_default")
+        .then(|| format!("unknown special name '{}'", name))
+}
@@ -0,0 +1,280 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc,
+        mpsc::{Receiver, TryRecvError, sync_channel},
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+use log::{debug, error};
+use smithay_client_toolkit::shm::slot::Buffer;
+use smithay_client_toolkit::reexports::client::protocol::wl_shm;
+
+use crate::{
+    cli::ColorFilter,
+    image::LoadContext,
+    ken_burns::{KEN_BURNS_FPS, KenBurns, pack_rgb},
+    store::{WallpaperStore, bytes_per_pixel},
+};
+
+/// Videos are resampled by ffmpeg to this frame rate
+const VIDEO_FPS: u32 = 30;
+
+/// Where the frames of a wallpaper are streamed from while it is visible
+#[derive(Clone)]
+pub enum VideoSource {
+    /// A video file decoded by ffmpeg
+    File(PathBuf),
+    /// A shader rendered on the GPU at the frame rate
+    Shader(PathBuf, u32),
+    /// A still image slowly panned and zoomed over
+    KenBurns(PathBuf, ColorFilter),
+}
+
+/// Streams the frames of a looping video from ffmpeg, converted to the
+/// size and pixel format of the output. Only one decoded frame is waiting
+/// at a time, ffmpeg blocks while no frames are taken, so a video
+/// not shown is paused without using any CPU. Animated shaders and
+/// Ken Burns effects are rendered the same way by a thread instead of ffmpeg
+pub struct VideoPlayer {
+    /// The ffmpeg process of video files
+    child: Option<Child>,
+    frames: Receiver<Vec<u8>>,
+    frame_delay: Duration,
+    width: u32,
+    height: u32,
+    format: wl_shm::Format,
+    /// The frames are copied into whichever of these the compositor
+    /// does not use at the moment
+    buffers: Vec<Buffer>,
+}
+
+impl VideoPlayer {
+    /// Rendered at most at max_fps frames per second,
+    /// the frames keep their pace
+    pub fn spawn(
+        source: &VideoSource,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+        max_fps: Option<u32>,
+        ctx: &LoadContext,
+    ) -> Result<VideoPlayer, String> {
+        let fps = |fps: u32| max_fps.map_or(fps, |max_fps| fps.min(max_fps));
+        match source {
+            VideoSource::File(path) => VideoPlayer::spawn_ffmpeg(
+                path, fps(VIDEO_FPS), width, height, format
+            ),
+            #[cfg(feature = "shader")]
+            VideoSource::Shader(path, shader_fps) => VideoPlayer::spawn_shader(
+                path, fps(*shader_fps), width, height, format
+            ),
+            #[cfg(not(feature = "shader"))]
+            VideoSource::Shader(path, fps) => Err(format!(
+                "Cannot render shader {:?} at {} fps, \
+built without the shader feature",
+                path, fps
+            )),
+            VideoSource::KenBurns(path, filter) => Ok(
+                VideoPlayer::spawn_ken_burns(
+                    path, *filter, fps(KEN_BURNS_FPS), width, height, format,
+                    ctx,
+                )
+            ),
+        }
+    }
+
+    fn spawn_ffmpeg(
+        path: &Path,
+        fps: u32,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+    ) -> Result<VideoPlayer, String> {
+        let pixel_format = match format {
+            wl_shm::Format::Bgr888 => "rgb24",
+            wl_shm::Format::Rgb565 => "rgb565le",
+            _ => "bgr0",
+        };
+        let filter = format!(
+            "scale={w}:{h}:force_original_aspect_ratio=increase,\
+crop={w}:{h},fps={fps}",
+            w = width, h = height, fps = fps
+        );
+        let mut child = Command::new("ffmpeg")
+            .args(["-v", "error", "-nostdin", "-stream_loop", "-1", "-i"])
+            .arg(path)
+            .args(["-an", "-vf", &filter, "-pix_fmt", pixel_format])
+            .args(["-f", "rawvideo", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+        debug!("Started ffmpeg for video {:?}", path);
+
+        let mut stdout = child.stdout.take().unwrap();
+        let frame_len = width as usize * height as usize
+            * bytes_per_pixel(format);
+        let (tx, frames) = sync_channel(0);
+        let path = path.to_path_buf();
+        spawn(move || loop {
+            let mut frame = vec![0; frame_len];
+            if let Err(e) = stdout.read_exact(&mut frame) {
+                debug!("Stopped reading video {:?}: {}", path, e);
+                return;
+            }
+            if tx.send(frame).is_err() {
+                return;
+            }
+        });
+
+        Ok(VideoPlayer {
+            child: Some(child),
+            frames,
+            frame_delay: Duration::from_secs(1) / fps,
+            width,
+            height,
+            format,
+            buffers: Vec::new(),
+        })
+    }
+
+    #[cfg(feature = "shader")]
+    fn spawn_shader(
+        path: &Path,
+        fps: u32,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+    ) -> Result<VideoPlayer, String> {
+        use crate::shader::{ShaderRenderer, pack_pixels};
+
+        let (tx, frames) = sync_channel(0);
+        let path = path.to_path_buf();
+        spawn(move || {
+            let mut renderer = match ShaderRenderer::new(&path, width, height)
+            {
+                Ok(renderer) => renderer,
+                Err(e) => {
+                    error!("Failed to render shader {:?}: {}", path, e);
+                    return;
+                }
+            };
+            debug!("Started rendering shader {:?}", path);
+            // The time only passes while frames are shown
+            for frame_count in 0u64.. {
+                let time = frame_count as f32 / fps as f32;
+                let pixels = match renderer.render(time) {
+                    Ok(pixels) => pixels,
+                    Err(e) => {
+                        error!("Failed to render shader {:?}: {}", path, e);
+                        return;
+                    }
+                };
+                if tx.send(pack_pixels(&pixels, format)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(VideoPlayer {
+            child: None,
+            frames,
+            frame_delay: Duration::from_secs(1) / fps,
+            width,
+            height,
+            format,
+            buffers: Vec::new(),
+        })
+    }
+
+    fn spawn_ken_burns(
+        path: &Path,
+        filter: ColorFilter,
+        fps: u32,
+        width: u32,
+        height: u32,
+        format: wl_shm::Format,
+        ctx: &LoadContext,
+    ) -> VideoPlayer {
+        let (tx, frames) = sync_channel(0);
+        let path = path.to_path_buf();
+        let decoders = Arc::clone(ctx.decoders);
+        let settings = Arc::clone(ctx.settings);
+        spawn(move || {
+            let mut ken_burns = match KenBurns::new(
+                &path, &decoders, &settings, width, height, filter
+            ) {
+                Ok(ken_burns) => ken_burns,
+                Err(e) => {
+                    error!("Failed to zoom wallpaper {:?}: {}", path, e);
+                    return;
+                }
+            };
+            debug!("Started panning and zooming wallpaper {:?}", path);
+            // The time only passes while frames are shown
+            for frame_count in 0u64.. {
+                let time = frame_count as f64 / fps as f64;
+                let pixels = pack_rgb(ken_burns.frame(time), format);
+                if tx.send(pixels).is_err() {
+                    return;
+                }
+            }
+        });
+
+        VideoPlayer {
+            child: None,
+            frames,
+            frame_delay: Duration::from_secs(1) / fps,
+            width,
+            height,
+            format,
+            buffers: Vec::new(),
+        }
+    }
+
+    /// How long each frame is shown
+    pub fn frame_delay(&self) -> Duration {
+        self.frame_delay
+    }
+
+    /// The next frame if it was decoded already,
+    /// fails if ffmpeg stopped, eg. because the file is not a video
+    pub fn next_frame(&mut self, store: &mut WallpaperStore)
+        -> Result<Option<&Buffer>, String>
+    {
+        let pixels = match self.frames.try_recv() {
+            Ok(pixels) => pixels,
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(TryRecvError::Disconnected) => return Err(
+                match self.child.as_mut().map(Child::try_wait) {
+                    Some(Ok(Some(status))) =>
+                        format!("ffmpeg exited with {}", status),
+                    Some(_) => "ffmpeg stopped sending frames".to_string(),
+                    None => "Stopped rendering frames".to_string(),
+                }
+            ),
+        };
+        Ok(store.write_frame(
+            &mut self.buffers,
+            self.width,
+            self.height,
+            self.format,
+            &pixels,
+        ))
+    }
+}
+
+impl Drop for VideoPlayer {
+    fn drop(&mut self) {
+        // Rendering threads stop when the frames are not received
+        let Some(child) = &mut self.child else { return };
+        if let Err(e) = child.kill().and_then(|_| child.wait()) {
+            error!("Failed to stop ffmpeg: {}", e);
+        }
+    }
+}
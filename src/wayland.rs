@@ -1,11 +1,22 @@
-use std::path::PathBuf;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    hash::{BuildHasher, Hasher, RandomState},
+    ops::Range,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use smithay_client_toolkit::{
     delegate_compositor, delegate_layer, delegate_output, delegate_registry,
     delegate_shm,
     compositor::{CompositorHandler, CompositorState, Region},
-    output::{OutputHandler, OutputState},
+    output::{OutputHandler, OutputInfo, OutputState},
     registry::{ProvidesRegistryState, RegistryState},
     registry_handlers,
     shell::{
@@ -17,248 +28,1620 @@ use smithay_client_toolkit::{
     },
     shm::{
         Shm, ShmHandler,
-        slot::{Buffer, SlotPool},
+        slot::Buffer,
     },
 };
 use smithay_client_toolkit::reexports::client::{
     Connection, Dispatch, Proxy, QueueHandle,
     protocol::{
         wl_output::{self, Transform, WlOutput},
+        wl_seat::WlSeat,
         wl_shm,
         wl_surface::WlSurface
     },
 };
-use smithay_client_toolkit::reexports::protocols::wp::viewporter::client::{
-    wp_viewport::WpViewport,
-    wp_viewporter::WpViewporter
+use smithay_client_toolkit::reexports::protocols::{
+    ext::idle_notify::v1::client::{
+        ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+        ext_idle_notifier_v1::ExtIdleNotifierV1,
+    },
+    wp::viewporter::client::{
+        wp_viewport::WpViewport,
+        wp_viewporter::WpViewporter
+    },
 };
 
 use crate::{
-    image::workspace_bgs_from_output_image_dir,
-    sway::SwayConnectionTask,
+    cache::DiskCache,
+    clock::local_time_of_day,
+    cli::{
+        ExclusiveZone, Margin, OutputGroup, PerOutput, PixelFormat,
+        RandomPick, RenderSize, TimeProfile, TransitionStyle,
+        output_matches, per_output,
+    },
+    control::{
+        OutputStats, OutputStatus, Stats, Status, WallpaperChange,
+        WorkspaceStatus,
+    },
+    decoder::DecoderRegistry,
+    event_log::{EventRecorder, LoggedEvent},
+    glob::{glob_match, glob_specificity, is_glob},
+    night::NightMode,
+    overlay::Overlay,
+    overrides::{OverrideChange, WallpaperOverride, persist_override},
+    palette::write_palette,
+    portal::ColorScheme,
+    power::on_battery,
+    image::{
+        ImageSettings, ImageTarget, LoadContext, SpanArea, WallpaperDirs,
+        global_default_paths, has_wallpapers, load_into_store,
+        placeholder_into_store, resolution_dir, sibling_wallpapers,
+        solid_into_store,
+        workspace_bgs_from_output_image_dir
+    },
+    loader::BackgroundLoader,
+    store::{Wallpaper, WallpaperKey, WallpaperStore},
+    scheduler::{Animation, FrameScheduler},
+    stats::DECODE_COUNTERS,
+    sway::{SwayConnectionTask, WorkspaceVisible},
+    transition::{Transition, TransitionClock},
+    video::{VideoPlayer, VideoSource},
 };
 
+/// How soon to check again for a video frame not decoded in time
+const VIDEO_RETRY_DELAY: Duration = Duration::from_millis(5);
+/// How often the power supply is checked with --adaptive-fps
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How long after its end to wait for the compositor to show the fade out
+/// before exiting anyway, eg. while an output is off
+const EXIT_GRACE: Duration = Duration::from_secs(1);
+/// How many wallpaper changes of a workspace `ctl back` can undo
+const HISTORY_LEN: usize = 20;
+
 pub struct State {
     pub compositor_state: CompositorState,
     pub registry_state: RegistryState,
     pub output_state: OutputState,
     pub shm: Shm,
     pub layer_shell: LayerShell,
-    pub viewporter: WpViewporter,
+    /// Fractional scaling is not possible without it
+    pub viewporter: Option<WpViewporter>,
+    pub render_size: RenderSize,
     pub wallpaper_dir: PathBuf,
-    pub force_xrgb8888: bool,
+    pub profiles: Vec<OutputGroup>,
+    pub active_profile: Option<String>,
+    pub time_profiles: Vec<TimeProfile>,
+    pub active_time_profile: Option<String>,
+    /// Only while following the color scheme
+    pub color_scheme: Option<ColorScheme>,
+    pub spans: Vec<OutputGroup>,
+    pub decoders: Arc<DecoderRegistry>,
+    pub requested_pixel_format: PixelFormat,
     pub pixel_format: Option<wl_shm::Format>,
     pub background_layers: Vec<BackgroundLayer>,
+    /// Outputs without a usable mode or geometry yet,
+    /// their layers are created when an update makes them usable
+    pub pending_outputs: Vec<WlOutput>,
+    /// From --only-output and --skip-output
+    pub only_outputs: Vec<String>,
+    pub skip_outputs: Vec<String>,
+    /// Ids and names of the outputs left alone because of the above
+    pub ignored_outputs: Vec<(u32, String)>,
+    pub wallpaper_store: WallpaperStore,
     pub sway_connection_task: SwayConnectionTask,
-    pub brightness: i32,
-    pub contrast: f32,
+    pub image_settings: Arc<ImageSettings>,
+    pub background_loader: BackgroundLoader,
+    pub min_redraw_interval: Duration,
+    pub anchors: Vec<PerOutput<Anchor>>,
+    pub margins: Vec<PerOutput<Margin>>,
+    pub exclusive_zones: Vec<PerOutput<ExclusiveZone>>,
+    pub transitions: Vec<PerOutput<TransitionStyle>>,
+    pub fade_in: Duration,
+    pub fade_out: Duration,
+    pub fade_out_color: [u8; 3],
+    pub transition_clock: Rc<TransitionClock>,
+    /// Exiting once the wallpapers faded out or at the latest by then
+    pub exit_deadline: Option<Instant>,
+    pub max_fps: Option<u32>,
+    /// Lower the frame rate on battery and without focus
+    pub adaptive_fps: bool,
+    pub on_battery: bool,
+    pub next_power_check: Option<Instant>,
+    pub event_recorder: Option<EventRecorder>,
+    pub night_mode: Option<NightMode>,
+    pub night_active: bool,
+    pub keep_wallpapers: Duration,
+    pub disk_cache: Option<DiskCache>,
+    /// Load wallpapers when their workspace is first shown
+    pub lazy: bool,
+    /// How long a slide of a slideshow is shown
+    pub slide_interval: Option<Duration>,
+    pub random: Option<RandomPick>,
+    /// Set with `multibg-sway ctl set`, kept until exiting
+    /// unless persisted
+    pub overrides: Vec<WallpaperOverride>,
+    /// Where the persisted overrides are kept
+    pub overrides_file: PathBuf,
+    /// Of the overrides, the most recent last
+    pub override_history: Vec<OverrideChange>,
+    /// Bytes the wallpapers may take before the least recently shown
+    /// ones are evicted
+    pub max_memory: Option<usize>,
+    /// Wallpapers loaded by the store when the memory limit was last
+    /// enforced, only loading can take more memory
+    pub memory_checked_loads: u64,
+    /// Set with `multibg-sway ctl pause`, workspace changes are ignored
+    /// and slideshows and animations stopped meanwhile
+    pub paused_since: Option<Instant>,
+    /// Where the colors of the shown wallpapers are written if requested
+    pub palette_dir: Option<PathBuf>,
+    pub overlay: Option<Rc<Overlay>>,
+    /// Tells when the user went idle with --idle-precompute
+    pub idle_notification: Option<ExtIdleNotificationV1>,
+    /// The wallpapers not loaded yet are loaded in the background meanwhile
+    pub idle: bool,
 }
 
 impl State {
-    fn pixel_format(&mut self) -> wl_shm::Format
-    {
-        *self.pixel_format.get_or_insert_with(|| {
-
-            if !self.force_xrgb8888 {
-                // Consume less gpu memory by using Bgr888 if available,
-                // fall back to the always supported Xrgb8888 otherwise
-                for format in self.shm.formats() {
-                    if let wl_shm::Format::Bgr888 = format {
-                        debug!("Using pixel format: {:?}", format);
-                        return *format
-                    }
-                    // XXX: One may add Rgb888 and HDR support here
-                }
-            }
+    /// Fade the wallpapers out if requested,
+    /// then the layers are destroyed to exit
+    pub fn start_exit(&mut self) {
+        debug!("Exiting");
+        self.exit_deadline = Some(Instant::now() + self.fade_out + EXIT_GRACE);
+        // Nothing more to load
+        if let Some(idle_notification) = self.idle_notification.take() {
+            idle_notification.destroy();
+        }
+        self.idle = false;
+        for bg_layer in self.background_layers.iter_mut() {
+            bg_layer.fade_out(
+                self.fade_out, self.fade_out_color, &mut self.wallpaper_store
+            );
+        }
+    }
 
-            debug!("Using default pixel format: Xrgb8888");
-            wl_shm::Format::Xrgb8888
-        })
+    pub fn ready_to_exit(&self) -> bool {
+        self.exit_deadline.is_some_and(|deadline|
+            Instant::now() >= deadline
+                || self.background_layers.iter()
+                    .all(|bg_layer| bg_layer.scheduler.transition.is_none())
+        )
     }
-}
 
-impl CompositorHandler for State
-{
-    fn scale_factor_changed(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &WlSurface,
-        _new_factor: i32,
-    ) {
+    /// Explicitly, so the compositor does not keep showing their buffers
+    pub fn destroy_layers(&mut self) {
+        debug!("Destroying {} layers", self.background_layers.len());
+        self.background_layers.clear();
     }
 
-    fn frame(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &WlSurface,
-        _time: u32,
-    ) {
+    pub fn next_deferred_draw_time(&self) -> Option<Instant> {
+        self.background_layers.iter()
+            .filter_map(|bg_layer|
+                bg_layer.deferred_draw_time(self.min_redraw_interval)
+            )
+            .min()
     }
 
-    fn transform_changed(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &WlSurface,
-        _new_transform: wl_output::Transform,
-    ) {
+    pub fn draw_deferred(&mut self) {
+        let now = Instant::now();
+        let ctx = LoadContext {
+            decoders: &self.decoders,
+            disk_cache: self.disk_cache.as_ref(),
+            settings: &self.image_settings,
+        };
+        for bg_layer in self.background_layers.iter_mut() {
+            if bg_layer.deferred_draw_time(self.min_redraw_interval)
+                .is_some_and(|draw_time| draw_time <= now)
+            {
+                let workspace = bg_layer.deferred_workspace.take()
+                    .unwrap();
+                bg_layer.request_workspace_bg(
+                    &workspace,
+                    self.min_redraw_interval,
+                    &mut self.wallpaper_store,
+                    &ctx,
+                );
+            }
+        }
     }
 
-    fn surface_enter(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &WlSurface,
-        _output: &wl_output::WlOutput,
-    ) {
+    /// Put the wallpapers decoded in the background into buffers,
+    /// redrawing the outputs that showed a placeholder meanwhile
+    pub fn receive_loaded_wallpapers(&mut self) {
+        let ctx = LoadContext {
+            decoders: &self.decoders,
+            disk_cache: self.disk_cache.as_ref(),
+            settings: &self.image_settings,
+        };
+        let mut redraw = false;
+        while let Some(loaded) = self.background_loader.try_recv() {
+            // Gone or reloaded since
+            let Some(bg_layer) = self.background_layers.iter_mut()
+                .find(|bg_layer| bg_layer.output_name == loaded.output_name)
+            else {
+                continue;
+            };
+            let Some(workspace_bg) = bg_layer.workspace_backgrounds
+                .iter_mut()
+                .find(|workspace_bg|
+                    workspace_bg.loading && workspace_bg.key == loaded.key
+                )
+            else {
+                continue;
+            };
+            workspace_bg.loading = false;
+            match load_into_store(
+                &workspace_bg.path,
+                &workspace_bg.key,
+                &mut self.wallpaper_store,
+                &ctx,
+                &bg_layer.target,
+                Some(loaded.decoded),
+            ) {
+                Ok(wallpaper) => {
+                    debug!(
+                        "Loaded wallpaper '{}' on output '{}'",
+                        workspace_bg.workspace_name, bg_layer.output_name
+                    );
+                    workspace_bg.wallpaper = Some(wallpaper);
+                    redraw |= bg_layer.placeholder_shown;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to load wallpaper '{:?}': {}",
+                        workspace_bg.path, e
+                    );
+                    workspace_bg.failed = true;
+                    redraw |= bg_layer.placeholder_shown;
+                }
+            }
+        }
+        if redraw {
+            self.sway_connection_task.request_visible_workspaces();
+        }
+        self.precompute_idle();
     }
 
-    fn surface_leave(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &WlSurface,
-        _output: &wl_output::WlOutput,
-    ) {
+    /// Load the wallpapers not loaded yet in the background while the user
+    /// is idle, so first switching to them does not stutter. A batch at a
+    /// time, continued when it is loaded, to stop soon after resuming
+    pub fn precompute_idle(&mut self) {
+        if !self.idle {
+            return;
+        }
+        let in_flight = self.background_layers.iter()
+            .flat_map(|bg_layer| &bg_layer.workspace_backgrounds)
+            .any(|workspace_bg| workspace_bg.loading);
+        if in_flight {
+            return;
+        }
+        // Would only be evicted again
+        if self.max_memory
+            .is_some_and(|max| self.wallpaper_store.memory_used() >= max)
+        {
+            debug!("Not loading more wallpapers at idle over --max-memory");
+            return;
+        }
+        let mut batch = thread::available_parallelism()
+            .map_or(1, |threads| threads.get());
+        for bg_layer in self.background_layers.iter_mut() {
+            let jobs = bg_layer.workspace_backgrounds.iter_mut()
+                .filter(|workspace_bg| workspace_bg.wallpaper.is_none()
+                    && !workspace_bg.loading
+                    && !workspace_bg.failed
+                )
+                .take(batch)
+                .map(|workspace_bg| {
+                    workspace_bg.loading = true;
+                    (workspace_bg.path.clone(), workspace_bg.key.clone())
+                })
+                .collect::<Vec<_>>();
+            batch -= jobs.len();
+            self.background_loader.spawn(
+                bg_layer.output_name.clone(),
+                jobs,
+                Arc::clone(&self.decoders),
+                Arc::clone(&self.image_settings),
+                bg_layer.target.clone(),
+            );
+            if batch == 0 {
+                break;
+            }
+        }
     }
-}
 
-impl LayerShellHandler for State
-{
-    fn closed(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _layer: &LayerSurface
-    ) {
+    pub fn pause(&mut self) {
+        if self.paused_since.is_none() {
+            info!("Pausing");
+            self.paused_since = Some(Instant::now());
+        }
     }
 
-    fn configure(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        layer: &LayerSurface,
-        configure: LayerSurfaceConfigure,
-        _serial: u32,
-    ) {
-        // The new layer is ready: request all the visible workspace from sway,
-        // it will get picked up by the main event loop and be drawn from there
+    /// Continue the slideshows where they were paused
+    /// and show the wallpapers of the workspaces visible now
+    pub fn resume(&mut self) {
+        let Some(paused_since) = self.paused_since.take() else { return };
+        info!("Resuming");
+        let paused = paused_since.elapsed();
+        for bg_layer in self.background_layers.iter_mut() {
+            if let Some(slideshow) = &mut bg_layer.slideshow {
+                slideshow.next_slide = slideshow.next_slide
+                    .map(|next_slide| next_slide + paused);
+            }
+        }
+        self.sway_connection_task.request_visible_workspaces();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    /// Show a solid color on the output instead of its wallpapers
+    /// until unblanked
+    pub fn blank_output(&mut self, output: &str, color: [u8; 3])
+        -> Result<(), String>
+    {
         let bg_layer = self.background_layers.iter_mut()
-            .find(|bg_layer| &bg_layer.layer == layer).unwrap();
+            .find(|bg_layer| bg_layer.output_name == output)
+            .ok_or_else(|| format!("No output named '{}'", output))?;
+        info!("Blanking output '{}'", output);
+        bg_layer.blank = Some(color);
+        bg_layer.draw_blank(color, &mut self.wallpaper_store);
+        Ok(())
+    }
 
-        if !bg_layer.configured {
-            bg_layer.configured = true;
+    /// Show the wallpaper of the visible workspace on the output again
+    pub fn unblank_output(&mut self, output: &str) -> Result<(), String> {
+        let bg_layer = self.background_layers.iter_mut()
+            .find(|bg_layer| bg_layer.output_name == output)
+            .ok_or_else(|| format!("No output named '{}'", output))?;
+        if bg_layer.blank.take().is_some() {
+            info!("Unblanking output '{}'", output);
+            self.sway_connection_task.request_visible_workspace(output);
+        }
+        Ok(())
+    }
+
+    pub fn next_slide_time(&self) -> Option<Instant> {
+        if self.is_paused() {
+            return None;
+        }
+        self.background_layers.iter()
+            .filter_map(|bg_layer| bg_layer.slideshow.as_ref())
+            .filter_map(|slideshow| slideshow.next_slide)
+            .min()
+    }
+
+    /// Step the wallpaper of the focused workspace forward or back through
+    /// its slideshow, otherwise through the files next to the one shown
+    pub fn cycle_focused_wallpaper(&mut self, forward: bool)
+        -> Result<(), String>
+    {
+        let now = Instant::now();
+        let mut shown = false;
+        let mut siblings = Vec::new();
+        for bg_layer in self.background_layers.iter_mut()
+            .filter(|bg_layer| bg_layer.focused)
+        {
+            let (Some(workspace), Some(path)) =
+                (&bg_layer.shown_workspace, &bg_layer.shown_path)
+            else {
+                continue;
+            };
+            shown = true;
+            if bg_layer.slideshow.is_none() {
+                siblings.push((
+                    bg_layer.output_name.clone(),
+                    workspace.clone(),
+                    path.clone(),
+                ));
+                continue;
+            }
+            if forward {
+                bg_layer.advance_slideshow(now);
+            }
+            else {
+                bg_layer.rewind_slideshow(now);
+            }
             self.sway_connection_task
                 .request_visible_workspace(&bg_layer.output_name);
-
-            debug!(
-                "Configured layer on output: {}, new surface size {}x{}",
-                bg_layer.output_name,
-                configure.new_size.0, configure.new_size.1
-            );
         }
-        else {
-            debug!(
-"Ignoring configure for already configured layer on output: {}, \
-new surface size {}x{}",
-                bg_layer.output_name,
-                configure.new_size.0, configure.new_size.1
-            );
+        if !shown {
+            return Err("No wallpaper is shown on the focused output".into());
+        }
+        for (output, workspace, path) in siblings {
+            let paths = sibling_wallpapers(&path);
+            let Some(index) = paths.iter().position(|sibling| *sibling == path)
+            else {
+                continue;
+            };
+            let index = if forward {
+                (index + 1) % paths.len()
+            } else {
+                (index + paths.len() - 1) % paths.len()
+            };
+            self.set_wallpaper(&output, &workspace, &paths[index], false)?;
         }
+        Ok(())
     }
-}
 
-impl OutputHandler for State {
-    fn output_state(&mut self) -> &mut OutputState {
-        &mut self.output_state
+    /// Show the next slide of the slideshow or another random wallpaper
+    /// on the output with the focused workspace, returns whether it has one
+    pub fn advance_focused_slideshow(&mut self) -> bool {
+        let now = Instant::now();
+        let mut advanced = false;
+        for bg_layer in self.background_layers.iter_mut()
+            .filter(|bg_layer| bg_layer.focused && bg_layer.slideshow.is_some())
+        {
+            bg_layer.advance_slideshow(now);
+            self.sway_connection_task
+                .request_visible_workspace(&bg_layer.output_name);
+            advanced = true;
+        }
+        advanced
     }
 
-    fn new_output(
+    /// Show the file as the wallpaper of the workspace on the output
+    /// instead of the one in the wallpaper directory
+    pub fn set_wallpaper(
         &mut self,
-        _conn: &Connection,
-        qh: &QueueHandle<Self>,
-        output: WlOutput,
-    ) {
-        let Some(info) = self.output_state.info(&output)
+        output: &str,
+        workspace: &str,
+        path: &Path,
+        persist: bool,
+    ) -> Result<(), String> {
+        let Some(bg_layer) = self.background_layers.iter()
+            .find(|bg_layer| bg_layer.output_name == output)
         else {
-            error!("New output has no output info, skipping");
-            return;
+            return Err(format!("There is no output named '{}'", output));
         };
-
-        let Some(output_name) = info.name
-        else {
-            error!("New output has no name, skipping");
-            return;
+        if !path.exists() {
+            return Err(format!("There is no file {:?}", path));
+        }
+        let output_id = bg_layer.output_id;
+        debug!(
+            "Setting wallpaper {:?} for workspace '{}' on output '{}'",
+            path, workspace, output
+        );
+        let wallpaper_override = WallpaperOverride {
+            output: output.to_string(),
+            workspace: workspace.to_string(),
+            path: path.to_path_buf(),
         };
+        let previous = self.replace_override(
+            output, workspace, Some(wallpaper_override.clone())
+        );
+        self.record_override_change(OverrideChange {
+            output: output.to_string(),
+            workspace: workspace.to_string(),
+            previous,
+        });
+        self.reload_wallpapers(&[output_id]);
+        if persist {
+            persist_override(&self.overrides_file, &wallpaper_override)?;
+        }
+        Ok(())
+    }
 
-        let Some((width, height)) = info.modes.iter()
-            .find(|mode| mode.current)
-            .map(|mode| mode.dimensions)
+    /// Revert the most recent change of `ctl set`, `ctl next` or
+    /// `ctl prev`, not the persisted overrides
+    pub fn undo_wallpaper_change(&mut self) -> Result<(), String> {
+        let change = self.override_history.pop()
+            .ok_or("There is no wallpaper change to undo")?;
+        let Some(output_id) = self.background_layers.iter()
+            .find(|bg_layer| bg_layer.output_name == change.output)
+            .map(|bg_layer| bg_layer.output_id)
         else {
-            error!(
-                "New output '{}' has no current mode set, skipping",
-                output_name
-            );
-            return;
+            return Err(format!("Output '{}' is gone", change.output));
         };
+        debug!(
+            "Reverting the wallpaper of workspace '{}' on output '{}' to {}",
+            change.workspace, change.output,
+            change.previous.as_ref().map_or(
+                "the one in the wallpaper directory".into(),
+                |previous| format!("{:?}", previous.path)
+            )
+        );
+        self.replace_override(
+            &change.output, &change.workspace, change.previous
+        );
+        self.reload_wallpapers(&[output_id]);
+        Ok(())
+    }
 
-        if !width.is_positive() || !height.is_positive() {
-            error!(
-            "New output '{}' has non-positive resolution: {} x {}, skipping",
-                output_name, width, height
-            );
+    /// Returns the one replaced
+    fn replace_override(
+        &mut self,
+        output: &str,
+        workspace: &str,
+        wallpaper_override: Option<WallpaperOverride>,
+    ) -> Option<WallpaperOverride> {
+        let index = self.overrides.iter()
+            .position(|replaced| replaced.is_for(output, workspace));
+        let replaced = index.map(|index| self.overrides.remove(index));
+        self.overrides.extend(wallpaper_override);
+        replaced
+    }
+
+    fn record_override_change(&mut self, change: OverrideChange) {
+        let same_workspace = |recorded: &OverrideChange|
+            recorded.is_for(&change.output, &change.workspace);
+        let count = self.override_history.iter()
+            .filter(|recorded| same_workspace(recorded))
+            .count();
+        if count >= HISTORY_LEN {
+            let oldest = self.override_history.iter()
+                .position(same_workspace)
+                .unwrap();
+            self.override_history.remove(oldest);
+        }
+        self.override_history.push(change);
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            pid: process::id(),
+            paused: self.is_paused(),
+            outputs: self.background_layers.iter()
+                .map(|bg_layer| OutputStatus {
+                    name: bg_layer.output_name.clone(),
+                    width: bg_layer.width,
+                    height: bg_layer.height,
+                    logical_width: bg_layer.logical_width,
+                    logical_height: bg_layer.logical_height,
+                    scale: bg_layer.scale(),
+                    workspace: bg_layer.shown_workspace.clone(),
+                    wallpaper: bg_layer.shown_path.clone(),
+                    blank: bg_layer.blank.map(|[red, green, blue]|
+                        format!("{:02x}{:02x}{:02x}", red, green, blue)
+                    ),
+                    workspaces: bg_layer.workspace_backgrounds.iter()
+                        .map(|workspace_bg| WorkspaceStatus {
+                            name: workspace_bg.workspace_name.clone(),
+                            path: workspace_bg.path.clone(),
+                            loaded: workspace_bg.wallpaper.is_some(),
+                            memory_bytes: workspace_bg.wallpaper.as_ref()
+                                .map_or(0, |wallpaper| wallpaper.memory()),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            memory: self.wallpaper_store.stats(),
+        }
+    }
+
+    /// Whether every output shows its wallpaper, not a placeholder
+    pub fn wallpapers_shown(&self) -> bool {
+        self.background_layers.iter().all(|bg_layer|
+            bg_layer.current_wallpaper.is_some() && !bg_layer.placeholder_shown
+        )
+    }
+
+    /// The wallpapers shown since the last call where they changed
+    pub fn take_wallpaper_changes(&mut self) -> Vec<WallpaperChange> {
+        self.background_layers.iter_mut()
+            .filter(|bg_layer| bg_layer.shown_changed)
+            .filter_map(|bg_layer| {
+                bg_layer.shown_changed = false;
+                Some(WallpaperChange {
+                    output: bg_layer.output_name.clone(),
+                    workspace: bg_layer.shown_workspace.clone()?,
+                    path: bg_layer.shown_path.clone()?,
+                })
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            decoding: DECODE_COUNTERS.stats(),
+            memory: self.wallpaper_store.stats(),
+            outputs: self.background_layers.iter()
+                .map(|bg_layer| {
+                    let mut wallpapers = bg_layer.workspace_backgrounds.iter()
+                        .filter_map(|workspace_bg|
+                            workspace_bg.wallpaper.as_ref()
+                        )
+                        .collect::<Vec<_>>();
+                    // Slides and workspaces may share them
+                    wallpapers.sort_by_key(|wallpaper| Rc::as_ptr(wallpaper));
+                    wallpapers.dedup_by(|a, b| Rc::ptr_eq(a, b));
+                    OutputStats {
+                        name: bg_layer.output_name.clone(),
+                        switches: bg_layer.switches,
+                        wallpapers: wallpapers.len(),
+                        memory_bytes: wallpapers.iter()
+                            .map(|wallpaper| wallpaper.memory())
+                            .sum(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Show the next slide of the slideshows due, and load the slides
+    /// following the shown ones in the background
+    pub fn update_slideshows(&mut self) {
+        if self.is_paused() {
             return;
         }
+        let now = Instant::now();
+        for bg_layer in self.background_layers.iter_mut() {
+            if bg_layer.slideshow.as_ref()
+                .and_then(|slideshow| slideshow.next_slide)
+                .is_some_and(|next_slide| next_slide <= now)
+            {
+                bg_layer.advance_slideshow(now);
+                self.sway_connection_task
+                    .request_visible_workspace(&bg_layer.output_name);
+            }
+            let Some(index) = bg_layer.upcoming_slide() else { continue };
+            let workspace_bg = &mut bg_layer.workspace_backgrounds[index];
+            if workspace_bg.wallpaper.is_some()
+                || workspace_bg.loading
+                || workspace_bg.failed
+            {
+                continue;
+            }
+            workspace_bg.loading = true;
+            self.background_loader.spawn(
+                bg_layer.output_name.clone(),
+                vec![(workspace_bg.path.clone(), workspace_bg.key.clone())],
+                Arc::clone(&self.decoders),
+                Arc::clone(&self.image_settings),
+                bg_layer.target.clone(),
+            );
+        }
+    }
 
-        let (width, height) = {
-            match info.transform {
-                Transform::Normal
-                | Transform::_180
-                | Transform::Flipped
-                | Transform::Flipped180 => (width, height),
-                Transform::_90
-                | Transform::_270
-                | Transform::Flipped90
-                | Transform::Flipped270 => (height, width),
-                _ => {
-                    warn!(
-                        "New output '{}' has unsupported transform",
-                        output_name
-                    );
-                    (width, height)
-                }
+    pub fn next_animation_frame_time(&self) -> Option<Instant> {
+        self.background_layers.iter()
+            .filter(|bg_layer| !self.is_paused() || bg_layer.in_transition())
+            .filter_map(BackgroundLayer::next_frame_time)
+            .min()
+    }
+
+    pub fn draw_animation_frames(&mut self) {
+        let now = Instant::now();
+        let paused = self.is_paused();
+        for bg_layer in self.background_layers.iter_mut() {
+            // Transitions started before pausing still finish
+            if paused && !bg_layer.in_transition() {
+                continue;
             }
-        };
+            if bg_layer.next_frame_time()
+                .is_some_and(|frame_time| frame_time <= now)
+            {
+                bg_layer.draw_next_frame(&mut self.wallpaper_store);
+            }
+        }
+    }
 
-        let integer_scale_factor = info.scale_factor;
+    pub fn next_time_profile_change(&self) -> Option<Instant> {
+        let time_of_day = local_time_of_day();
+        self.time_profiles.iter()
+            .map(|time_profile|
+                time_profile.time_range.next_change(time_of_day)
+            )
+            .min()
+            // Wake up a bit after the change so it is surely past it
+            .map(|until| Instant::now() + until + Duration::from_millis(100))
+    }
 
-        let Some((logical_width, logical_height)) = info.logical_size
-        else {
-            error!(
-                "New output '{}' has no logical_size, skipping",
-                output_name
-            );
+    /// Switch to the time profile of the time of day, reloading
+    /// the wallpapers of all outputs if it changed
+    pub fn update_time_profile(&mut self) {
+        let active = active_time_profile(&self.time_profiles);
+        if active == self.active_time_profile {
             return;
-        };
+        }
+        debug!(
+            "Switching time profile from {:?} to {:?}",
+            self.active_time_profile, active
+        );
+        self.active_time_profile = active;
+        self.reload_all_wallpapers();
+    }
 
-        if !logical_width.is_positive() || !logical_height.is_positive() {
-            error!(
-            "New output '{}' has non-positive logical size: {} x {}, skipping",
-                output_name, logical_width, logical_height
-            );
+    /// Switch to the light or dark wallpapers, reloading
+    /// the wallpapers of all outputs if it changed
+    pub fn set_color_scheme(&mut self, color_scheme: ColorScheme) {
+        if self.color_scheme.is_none()
+            || self.color_scheme == Some(color_scheme)
+        {
             return;
         }
+        debug!("Switching to the {} wallpapers", color_scheme.dir_name());
+        self.color_scheme = Some(color_scheme);
+        self.reload_all_wallpapers();
+    }
 
-        debug!(
-"New output, name: {}, resolution: {}x{}, integer scale factor: {}, \
-logical size: {}x{}, transform: {:?}",
-            output_name, width, height, integer_scale_factor,
-            logical_width, logical_height, info.transform
-        );
+    pub fn next_night_mode_change(&self) -> Option<Instant> {
+        self.night_mode.as_ref().map(NightMode::next_change)
+    }
 
-        let layer = self.layer_shell.create_layer_surface(
+    /// Switch night mode on or off if its time range started or ended
+    pub fn update_night_mode(&mut self) {
+        let Some(night_mode) = &self.night_mode else { return };
+        let active = night_mode.is_active();
+        if active == self.night_active {
+            return;
+        }
+        debug!("Night mode {}", if active { "on" } else { "off" });
+        self.night_active = active;
+        self.wallpaper_store.set_night_lut(active.then(|| night_mode.lut()));
+        self.sway_connection_task.request_visible_workspaces();
+    }
+
+    /// Forget the least recently shown wallpapers while the wallpapers
+    /// take more memory than allowed, they are loaded again when shown
+    pub fn enforce_memory_limit(&mut self) {
+        let Some(max_memory) = self.max_memory else { return };
+        let loaded = self.wallpaper_store.loaded();
+        if loaded == self.memory_checked_loads {
+            return;
+        }
+        self.memory_checked_loads = loaded;
+        let mut memory_used = self.wallpaper_store.memory_used();
+        if memory_used <= max_memory {
+            return;
+        }
+        // The shown wallpapers are kept anyway
+        let shown = self.background_layers.iter()
+            .filter_map(|bg_layer| bg_layer.current_wallpaper.as_ref())
+            .map(|wallpaper| wallpaper.key.original())
+            .collect::<Vec<_>>();
+        let mut candidates = HashMap::new();
+        for wallpaper in self.background_layers.iter()
+            .flat_map(|bg_layer| &bg_layer.workspace_backgrounds)
+            .filter_map(|workspace_bg| workspace_bg.wallpaper.as_ref())
+            .filter(|wallpaper| !shown.contains(&wallpaper.key))
+        {
+            candidates.entry(wallpaper.key.clone())
+                .or_insert((wallpaper.last_shown(), wallpaper.memory()));
+        }
+        // The shown and the kept wallpapers of destroyed outputs
+        // take the rest, evicting nothing while only they are over it
+        let evictable = candidates.values()
+            .map(|(_, memory)| memory)
+            .sum::<usize>();
+        if evictable == 0 {
+            return;
+        }
+        let mut candidates = candidates.into_iter()
+            .map(|(key, (last_shown, _))| (last_shown, key))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(last_shown, _)| *last_shown);
+        let mut evicted = 0;
+        for (_, key) in candidates {
+            if memory_used <= max_memory {
+                break;
+            }
+            // Listed once for each workspace using it
+            for workspace_bg in self.background_layers.iter_mut()
+                .flat_map(|bg_layer| &mut bg_layer.workspace_backgrounds)
+                .filter(|workspace_bg| workspace_bg.key == key)
+            {
+                workspace_bg.wallpaper = None;
+            }
+            memory_used = self.wallpaper_store.memory_used();
+            evicted += 1;
+        }
+        if evicted == 0 {
+            return;
+        }
+        debug!(
+            "Evicted {} wallpapers over the memory limit, using {} KiB",
+            evicted, memory_used / 1024
+        );
+        self.wallpaper_store.evict_unused(&self.shm);
+        self.log_store_stats();
+    }
+
+    pub fn expire_kept_wallpapers(&mut self) {
+        if self.wallpaper_store.expire_retained() {
+            debug!("Dropping the kept wallpapers of destroyed outputs");
+            self.wallpaper_store.evict_unused(&self.shm);
+            self.log_store_stats();
+        }
+    }
+
+    fn pixel_format(&mut self) -> wl_shm::Format
+    {
+        *self.pixel_format.get_or_insert_with(|| select_pixel_format(
+            self.requested_pixel_format, self.shm.formats()
+        ))
+    }
+
+    // The name is missing if the compositor supports neither wl_output v4
+    // nor zxdg_output_v1 v2, then try to get the name by other means
+    fn output_name(&mut self, info: &OutputInfo) -> Option<String> {
+        if let Some(name) = &info.name {
+            return Some(name.clone());
+        }
+
+        // Sway describes outputs as: make model serial (name)
+        if let Some(name) = info.description.as_deref()
+            .and_then(|description| description.strip_suffix(')'))
+            .and_then(|description| description.rsplit_once(" ("))
+            .map(|(_, name)| name)
+            .filter(|name| !name.is_empty())
+        {
+            debug!("Using output name '{}' from its description", name);
+            return Some(name.to_string());
+        }
+
+        let position = info.logical_position.unwrap_or(info.location);
+        let name = self.sway_connection_task.output_name_at(position)?;
+        debug!(
+            "Using output name '{}' from sway by its position {:?}",
+            name, position
+        );
+        Some(name)
+    }
+
+    /// Only one output has the focus
+    pub fn set_output_focus(&mut self, output_name: &str, focused: bool) {
+        for bg_layer in self.background_layers.iter_mut() {
+            if bg_layer.output_name == output_name {
+                bg_layer.focused = focused;
+            }
+            else if focused {
+                bg_layer.focused = false;
+            }
+            bg_layer.update_demotion(self.adaptive_fps, self.on_battery);
+        }
+    }
+
+    /// Check whether the computer runs from its battery from time to time
+    pub fn update_power_supply(&mut self) {
+        if !self.adaptive_fps {
+            return;
+        }
+        let now = Instant::now();
+        if self.next_power_check.is_some_and(|time| now < time) {
+            return;
+        }
+        self.next_power_check = Some(now + POWER_CHECK_INTERVAL);
+        let on_battery = on_battery();
+        if on_battery == self.on_battery {
+            return;
+        }
+        debug!("Running on {}", if on_battery { "battery" } else { "AC" });
+        self.on_battery = on_battery;
+        for bg_layer in self.background_layers.iter_mut() {
+            bg_layer.update_demotion(self.adaptive_fps, self.on_battery);
+        }
+    }
+
+    pub fn is_ignored_output(&self, output_name: &str) -> bool {
+        self.ignored_outputs.iter().any(|(_, name)| name == output_name)
+    }
+
+    fn output_filtered_out(&self, output_name: &str, info: &OutputInfo)
+        -> bool
+    {
+        let matches = |pattern: &String| output_matches(
+            pattern, output_name, &info.make, &info.model
+        );
+        let only = self.only_outputs.is_empty()
+            || self.only_outputs.iter().any(matches);
+        !only || self.skip_outputs.iter().any(matches)
+    }
+
+    /// With --render-size=logical wallpapers are made at the logical size.
+    /// Without wp_viewporter fractionally scaled outputs get wallpapers
+    /// resized to their logical size times the integer scale factor,
+    /// which the compositor scales down
+    fn buffer_resolution(
+        &self,
+        output_name: &str,
+        (width, height): (i32, i32),
+        (logical_width, logical_height): (i32, i32),
+        integer_scale_factor: i32,
+    ) -> (i32, i32) {
+        if self.render_size == RenderSize::Logical {
+            debug!(
+                "Output '{}' gets wallpapers at logical size {}x{}",
+                output_name, logical_width, logical_height
+            );
+            return (logical_width, logical_height);
+        }
+        if self.viewporter.is_some()
+            || width == logical_width
+            || height == logical_height
+            || (width == logical_width * integer_scale_factor
+                && height == logical_height * integer_scale_factor)
+        {
+            return (width, height);
+        }
+        let resolution = (
+            logical_width * integer_scale_factor,
+            logical_height * integer_scale_factor,
+        );
+        warn!(
+            "Output '{}' is fractionally scaled but the compositor has no \
+wp_viewporter, using {}x{} wallpapers at integer scale {} instead",
+            output_name, resolution.0, resolution.1, integer_scale_factor
+        );
+        resolution
+    }
+
+    fn log_store_stats(&self) {
+        let stats = self.wallpaper_store.stats();
+        debug!(
+            "Holding {} wallpapers for all outputs, {} of them shared, \
+{} busy, shm slot pool size: {} KiB, {} KiB of it used, \
+loaded {} and released {} since startup",
+            stats.wallpapers, stats.shared, stats.busy,
+            stats.pool_bytes / 1024, stats.used_bytes / 1024,
+            stats.loaded, stats.released
+        );
+    }
+
+    fn record_event(&mut self, event: LoggedEvent) {
+        if let Some(event_recorder) = &mut self.event_recorder {
+            event_recorder.record(event);
+        }
+    }
+
+    fn output_info(&self, output_id: u32) -> Option<OutputInfo> {
+        self.output_state.outputs()
+            .filter_map(|output| self.output_state.info(&output))
+            .find(|info| info.id == output_id)
+    }
+
+    fn span_group(&self, output_name: &str, info: &OutputInfo)
+        -> Option<&OutputGroup>
+    {
+        self.spans.iter()
+            .find(|span| span.contains(output_name, &info.make, &info.model))
+    }
+
+    // The spanned area is the bounding box of the outputs in the span group
+    // with background layers, plus the output being loaded
+    fn span_area(&self, span: &OutputGroup, info: &OutputInfo)
+        -> Option<SpanArea>
+    {
+        let rect = |info: &OutputInfo| -> Option<(i32, i32, i32, i32)> {
+            let (x, y) = info.logical_position?;
+            let (width, height) = info.logical_size?;
+            Some((x, y, width, height))
+        };
+
+        let output_rect = rect(info)?;
+        let rects = self.background_layers.iter()
+            .filter(|bg_layer| bg_layer.output_id != info.id)
+            .filter_map(|bg_layer| {
+                let info = self.output_info(bg_layer.output_id)?;
+                span.contains(&bg_layer.output_name, &info.make, &info.model)
+                    .then(|| rect(&info))?
+            })
+            .chain([output_rect]);
+
+        let (mut left, mut top) = (i32::MAX, i32::MAX);
+        let (mut right, mut bottom) = (i32::MIN, i32::MIN);
+        for (x, y, width, height) in rects {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + width);
+            bottom = bottom.max(y + height);
+        }
+
+        let (x, y, width, height) = output_rect;
+        Some(SpanArea {
+            x: (x - left) as f64,
+            y: (y - top) as f64,
+            width: width as f64,
+            height: height as f64,
+            total_width: (right - left) as f64,
+            total_height: (bottom - top) as f64,
+        })
+    }
+
+    fn load_wallpapers(
+        &mut self,
+        output_name: &str,
+        info: &OutputInfo,
+        width: i32,
+        height: i32,
+    ) -> Option<(Vec<WorkspaceBackground>, ImageTarget)> {
+        let pixel_format = self.pixel_format();
+
+        let (output_wallpaper_dir, span_area) =
+            match self.span_group(output_name, info) {
+                Some(span) => {
+                    let span_area = self.span_area(span, info);
+                    if span_area.is_none() {
+                        error!(
+            "Output '{}' has no logical geometry, cannot span wallpapers on it",
+                            output_name
+                        );
+                    }
+                    (self.profile_dir().join(&span.name), span_area)
+                },
+                None => (
+                    self.output_wallpaper_dir(
+                        output_name, &info.make, &info.model
+                    ),
+                    None
+                ),
+            };
+
+        if let Some(span_area) = &span_area {
+            debug!(
+                "Output '{}' shows {}x{} at {},{} of the spanned {}x{}",
+                output_name,
+                span_area.width, span_area.height,
+                span_area.x, span_area.y,
+                span_area.total_width, span_area.total_height
+            );
+        }
+
+        let target = ImageTarget {
+            width: width.try_into().unwrap(),
+            height: height.try_into().unwrap(),
+            span_area,
+        };
+
+        // Made for the resolution, resized only if none match exactly
+        let output_wallpaper_dir = match resolution_dir(
+            &output_wallpaper_dir, target.width, target.height
+        ) {
+            Some(dir) => {
+                debug!(
+                    "Using wallpaper directory {:?} for output '{}' at {}x{}",
+                    dir, output_name, target.width, target.height
+                );
+                dir
+            }
+            None => output_wallpaper_dir,
+        };
+
+        let ctx = LoadContext {
+            decoders: &self.decoders,
+            disk_cache: self.disk_cache.as_ref(),
+            settings: &self.image_settings,
+        };
+        let dirs = WallpaperDirs {
+            candidate_sets: output_wallpaper_dir != self.profile_dir(),
+            slideshows: self.slide_interval.is_some() || self.random.is_some(),
+            fallbacks: global_default_paths(&self.profile_dir()),
+            overrides: self.overrides.iter()
+                .filter(|wallpaper_override|
+                    wallpaper_override.output == output_name
+                )
+                .map(|wallpaper_override| (
+                    wallpaper_override.workspace.clone(),
+                    wallpaper_override.path.clone(),
+                ))
+                .collect(),
+            dir: output_wallpaper_dir,
+        };
+        let workspace_backgrounds = match workspace_bgs_from_output_image_dir(
+            &dirs,
+            &mut self.wallpaper_store,
+            &ctx,
+            pixel_format,
+            &target,
+            self.lazy,
+        ) {
+            Ok(workspace_bgs) => {
+                self.background_loader.spawn(
+                    output_name.to_string(),
+                    workspace_bgs.iter()
+                        .filter(|workspace_bg| workspace_bg.loading)
+                        .map(|workspace_bg| (
+                            workspace_bg.path.clone(),
+                            workspace_bg.key.clone(),
+                        ))
+                        .collect(),
+                    Arc::clone(&self.decoders),
+                    Arc::clone(&self.image_settings),
+                    target.clone(),
+                );
+                debug!(
+                    "Loaded {} wallpapers on output '{}' for workspaces: {}",
+                    workspace_bgs.iter()
+                        .filter(|workspace_bg| workspace_bg.wallpaper.is_some())
+                        .count(),
+                    output_name,
+                    workspace_bgs.iter()
+                        .map(|workspace_bg| workspace_bg.workspace_name.as_str())
+                        .collect::<Vec<_>>().join(", ")
+                );
+                workspace_bgs
+            },
+            Err(e) => {
+                error!(
+                "Failed to get wallpapers for output '{}' form '{:?}': {}",
+                    output_name, dirs.dir, e
+                );
+                return None;
+            }
+        };
+
+        Some((workspace_backgrounds, target))
+    }
+
+    fn reload_wallpapers(&mut self, output_ids: &[u32]) {
+        for index in 0..self.background_layers.len() {
+            let bg_layer = &self.background_layers[index];
+            if !output_ids.contains(&bg_layer.output_id) {
+                continue;
+            }
+            let output_name = bg_layer.output_name.clone();
+            let (width, height) = (bg_layer.width, bg_layer.height);
+            let Some(info) = self.output_info(bg_layer.output_id) else {
+                continue;
+            };
+
+            if let Some((workspace_backgrounds, target)) = self
+                .load_wallpapers(&output_name, &info, width, height)
+            {
+                let bg_layer = &mut self.background_layers[index];
+                bg_layer.workspace_backgrounds = workspace_backgrounds;
+                bg_layer.target = target;
+                // The videos may have changed too
+                bg_layer.videos.clear();
+                bg_layer.slideshow = None;
+            }
+        }
+
+        self.wallpaper_store.evict_unused(&self.shm);
+        self.log_store_stats();
+
+        self.sway_connection_task.request_visible_workspaces();
+    }
+
+    pub fn reload_all_wallpapers(&mut self) {
+        let output_ids = self.background_layers.iter()
+            .map(|bg_layer| bg_layer.output_id)
+            .collect::<Vec<_>>();
+        self.reload_wallpapers(&output_ids);
+    }
+
+    // The spanned area changes when an output of a span group
+    // is added or removed, so reload the other outputs of the group
+    fn reload_span_group(&mut self, output_name: &str, info: &OutputInfo) {
+        let Some(span) = self.span_group(output_name, info) else {
+            return;
+        };
+        let output_ids = self.background_layers.iter()
+            .filter(|bg_layer| bg_layer.output_id != info.id)
+            .filter(|bg_layer| self.output_info(bg_layer.output_id)
+                .is_some_and(|info| span.contains(
+                    &bg_layer.output_name, &info.make, &info.model
+                ))
+            )
+            .map(|bg_layer| bg_layer.output_id)
+            .collect::<Vec<_>>();
+        if !output_ids.is_empty() {
+            debug!(
+                "Reloading wallpapers spanned with output '{}'", output_name
+            );
+            self.reload_wallpapers(&output_ids);
+        }
+    }
+
+    /// Select the first profile with all of its outputs connected,
+    /// returns whether the active profile changed
+    fn update_active_profile(
+        &mut self,
+        removed_output_id: Option<u32>
+    ) -> bool {
+        let outputs = self.output_state.outputs()
+            .filter_map(|output| self.output_state.info(&output))
+            .filter(|info| Some(info.id) != removed_output_id)
+            .collect::<Vec<_>>();
+
+        let active_profile = self.profiles.iter()
+            .find(|profile| profile.outputs.iter().all(|profile_output|
+                outputs.iter().any(|info|
+                    output_matches(
+                        profile_output,
+                        info.name.as_deref().unwrap_or_default(),
+                        &info.make,
+                        &info.model
+                    )
+                )
+            ))
+            .map(|profile| profile.name.clone());
+
+        if active_profile == self.active_profile {
+            return false;
+        }
+
+        debug!(
+            "Switching wallpaper profile from {:?} to {:?}",
+            self.active_profile, active_profile
+        );
+        self.active_profile = active_profile;
+        true
+    }
+
+    /// The wallpaper dir, in the time profile, in the color scheme,
+    /// in the output profile
+    fn profile_dir(&self) -> PathBuf {
+        let mut dir = self.wallpaper_dir.clone();
+        dir.extend(&self.active_time_profile);
+        dir.extend(self.color_scheme.map(ColorScheme::dir_name));
+        dir.extend(&self.active_profile);
+        dir
+    }
+
+    // Connector names may change between docks and ports so prefer
+    // directories named by the output identifier like in the sway config:
+    // "make model serial", then "make model", then the connector name,
+    // outputs without a directory of their own use the _default directory
+    // or the wallpapers directly in the wallpaper dir
+    fn output_wallpaper_dir(
+        &mut self,
+        output_name: &str,
+        make: &str,
+        model: &str,
+    ) -> PathBuf {
+        let candidates = self.sway_connection_task
+            .output_identifier(output_name)
+            .into_iter()
+            .chain([[make, model].join(" ")]);
+
+        for candidate in candidates {
+            if candidate.trim().is_empty() || candidate.contains('/') {
+                continue;
+            }
+            let dir = self.profile_dir().join(&candidate);
+            if dir.is_dir() {
+                debug!(
+                    "Using wallpaper directory {:?} for output '{}'",
+                    dir, output_name
+                );
+                return dir;
+            }
+        }
+
+        let dir = self.profile_dir().join(output_name);
+        if dir.is_dir() {
+            return dir;
+        }
+        let default_dir = self.profile_dir().join("_default");
+        if default_dir.is_dir() {
+            debug!(
+                "Using the default wallpaper directory for output '{}'",
+                output_name
+            );
+            return default_dir;
+        }
+        if has_wallpapers(&self.profile_dir()) {
+            debug!("Using the flat layout for output '{}'", output_name);
+            return self.profile_dir();
+        }
+        dir
+    }
+}
+
+impl CompositorHandler for State
+{
+    fn scale_factor_changed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &WlSurface,
+        _new_factor: i32,
+    ) {
+    }
+
+    fn frame(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        surface: &WlSurface,
+        _time: u32,
+    ) {
+        if let Some(bg_layer) = self.background_layers.iter_mut()
+            .find(|bg_layer| bg_layer.layer.wl_surface() == surface)
+        {
+            bg_layer.frame_done();
+        }
+    }
+
+    fn transform_changed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &WlSurface,
+        _new_transform: wl_output::Transform,
+    ) {
+    }
+
+    fn surface_enter(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &WlSurface,
+        _output: &wl_output::WlOutput,
+    ) {
+    }
+
+    fn surface_leave(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &WlSurface,
+        _output: &wl_output::WlOutput,
+    ) {
+    }
+}
+
+impl LayerShellHandler for State
+{
+    fn closed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _layer: &LayerSurface
+    ) {
+    }
+
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        layer: &LayerSurface,
+        configure: LayerSurfaceConfigure,
+        _serial: u32,
+    ) {
+        let bg_layer_index = self.background_layers.iter()
+            .position(|bg_layer| &bg_layer.layer == layer).unwrap();
+        let output_logical_size = self
+            .output_info(self.background_layers[bg_layer_index].output_id)
+            .and_then(|info| info.logical_size);
+        let bg_layer = &mut self.background_layers[bg_layer_index];
+
+        // Zero is only valid on an axis where we have set the size ourselves,
+        // where we asked to stretch between two edges it is a compositor bug,
+        // drawing would hit a protocol error, wait for a valid configure
+        let (new_width, new_height) = configure.new_size;
+        let stretch_width = bg_layer.anchor
+            .contains(Anchor::LEFT | Anchor::RIGHT);
+        let stretch_height = bg_layer.anchor
+            .contains(Anchor::TOP | Anchor::BOTTOM);
+        if (new_width == 0 && stretch_width)
+            || (new_height == 0 && stretch_height)
+        {
+            warn!(
+                "Invalid layer configure: output={} width={} height={} \
+anchor={:?}, deferring drawing until a valid configure",
+                bg_layer.output_name, new_width, new_height, bg_layer.anchor
+            );
+            return;
+        }
+        let new_width = match new_width {
+            0 => bg_layer.logical_width,
+            new_width => i32::try_from(new_width).unwrap_or(i32::MAX),
+        };
+        let new_height = match new_height {
+            0 => bg_layer.logical_height,
+            new_height => i32::try_from(new_height).unwrap_or(i32::MAX),
+        };
+        if output_logical_size.is_some_and(|(output_width, output_height)|
+            new_width > output_width || new_height > output_height
+        ) {
+            warn!(
+                "Invalid layer configure: output={} width={} height={} \
+output_logical_size={:?}, deferring drawing until a valid configure",
+                bg_layer.output_name, new_width, new_height,
+                output_logical_size.unwrap()
+            );
+            return;
+        }
+
+        let mut needs_reload = false;
+
+        // Respected exclusive zones change the size of the surface
+        if (new_width, new_height)
+            != (bg_layer.logical_width, bg_layer.logical_height)
+        {
+            let width = scale_to_pixels(
+                new_width, bg_layer.width, bg_layer.logical_width
+            );
+            let height = scale_to_pixels(
+                new_height, bg_layer.height, bg_layer.logical_height
+            );
+            debug!(
+                "Layer on output '{}' resized to {}x{}, {}x{} pixels",
+                bg_layer.output_name, new_width, new_height, width, height
+            );
+            needs_reload = (width, height) != (bg_layer.width, bg_layer.height);
+            bg_layer.width = width;
+            bg_layer.height = height;
+            bg_layer.logical_width = new_width;
+            bg_layer.logical_height = new_height;
+            bg_layer.apply_scaling(self.viewporter.as_ref(), qh);
+        }
+
+        if !bg_layer.configured {
+            bg_layer.configured = true;
+
+            debug!(
+                "Configured layer on output: {}, new surface size {}x{}",
+                bg_layer.output_name,
+                configure.new_size.0, configure.new_size.1
+            );
+        }
+        else if !needs_reload {
+            debug!(
+"Ignoring configure for already configured layer on output: {}, \
+new surface size {}x{}",
+                bg_layer.output_name,
+                configure.new_size.0, configure.new_size.1
+            );
+            return;
+        }
+
+        // The layer is ready: request all the visible workspace from sway,
+        // it will get picked up by the main event loop and be drawn from there
+        if needs_reload {
+            let output_id = bg_layer.output_id;
+            self.reload_wallpapers(&[output_id]);
+        }
+        else {
+            self.sway_connection_task
+                .request_visible_workspace(&bg_layer.output_name);
+        }
+    }
+}
+
+impl OutputHandler for State {
+    fn output_state(&mut self) -> &mut OutputState {
+        &mut self.output_state
+    }
+
+    fn new_output(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        output: WlOutput,
+    ) {
+        let Some(info) = self.output_state.info(&output)
+        else {
+            error!("New output has no output info, skipping");
+            return;
+        };
+
+        let Some(output_name) = self.output_name(&info)
+        else {
+            error!("New output has no name, skipping");
+            return;
+        };
+
+        self.record_event(LoggedEvent::NewOutput {
+            output: output_name.clone()
+        });
+
+        if self.output_filtered_out(&output_name, &info) {
+            info!(
+                "Ignoring new output '{}' because of --only-output \
+or --skip-output",
+                output_name
+            );
+            self.ignored_outputs.push((info.id, output_name));
+            return;
+        }
+
+        let Some((width, height)) = info.modes.iter()
+            .find(|mode| mode.current)
+            .map(|mode| mode.dimensions)
+        else {
+            warn!(
+                "New output '{}' has no current mode set, deferring",
+                output_name
+            );
+            self.pending_outputs.push(output);
+            return;
+        };
+
+        if !width.is_positive() || !height.is_positive() {
+            warn!(
+            "New output '{}' has non-positive resolution: {} x {}, deferring",
+                output_name, width, height
+            );
+            self.pending_outputs.push(output);
+            return;
+        }
+
+        let (width, height) = {
+            match info.transform {
+                Transform::Normal
+                | Transform::_180
+                | Transform::Flipped
+                | Transform::Flipped180 => (width, height),
+                Transform::_90
+                | Transform::_270
+                | Transform::Flipped90
+                | Transform::Flipped270 => (height, width),
+                _ => {
+                    warn!(
+                        "New output '{}' has unsupported transform",
+                        output_name
+                    );
+                    (width, height)
+                }
+            }
+        };
+
+        let integer_scale_factor = info.scale_factor;
+
+        let Some((logical_width, logical_height)) = info.logical_size
+        else {
+            warn!(
+                "New output '{}' has no logical_size, deferring",
+                output_name
+            );
+            self.pending_outputs.push(output);
+            return;
+        };
+
+        if !logical_width.is_positive() || !logical_height.is_positive() {
+            warn!(
+        "New output '{}' has non-positive logical size: {} x {}, deferring",
+                output_name, logical_width, logical_height
+            );
+            self.pending_outputs.push(output);
+            return;
+        }
+
+        debug!(
+"New output, name: {}, resolution: {}x{}, integer scale factor: {}, \
+logical size: {}x{}, transform: {:?}",
+            output_name, width, height, integer_scale_factor,
+            logical_width, logical_height, info.transform
+        );
+
+        let (width, height) = self.buffer_resolution(
+            &output_name,
+            (width, height),
+            (logical_width, logical_height),
+            integer_scale_factor,
+        );
+
+        let anchor = per_output(
+            &self.anchors, &output_name, &info.make, &info.model
+        ).copied().unwrap_or(Anchor::all());
+        let margin = per_output(
+            &self.margins, &output_name, &info.make, &info.model
+        ).copied().unwrap_or_default();
+        let exclusive_zone = per_output(
+            &self.exclusive_zones, &output_name, &info.make, &info.model
+        ).copied().unwrap_or(ExclusiveZone::Ignore);
+        let transition_style = per_output(
+            &self.transitions, &output_name, &info.make, &info.model
+        ).copied().unwrap_or(TransitionStyle::None);
+
+        // The wallpaper covers the output without the margins,
+        // if it respects exclusive zones configure may make it even smaller
+        let surface_logical_width =
+            logical_width - margin.left - margin.right;
+        let surface_logical_height =
+            logical_height - margin.top - margin.bottom;
+        if !surface_logical_width.is_positive()
+            || !surface_logical_height.is_positive()
+        {
+            error!(
+                "New output '{}' has no space left for the wallpaper \
+inside the margins, skipping",
+                output_name
+            );
+            return;
+        }
+        let width = scale_to_pixels(
+            surface_logical_width, width, logical_width
+        );
+        let height = scale_to_pixels(
+            surface_logical_height, height, logical_height
+        );
+
+        let layer = self.layer_shell.create_layer_surface(
             qh,
             self.compositor_state.create_surface(qh),
             Layer::Background,
@@ -266,10 +1649,20 @@ logical size: {}x{}, transform: {:?}",
             Some(&output)
         );
 
-        layer.set_anchor(
-            Anchor::TOP | Anchor::BOTTOM | Anchor::LEFT | Anchor::RIGHT
+        layer.set_anchor(anchor);
+        layer.set_margin(margin.top, margin.right, margin.bottom, margin.left);
+        // Size 0 means stretching between the anchored opposite edges
+        layer.set_size(
+            if anchor.contains(Anchor::LEFT | Anchor::RIGHT) { 0 }
+            else { surface_logical_width as u32 },
+            if anchor.contains(Anchor::TOP | Anchor::BOTTOM) { 0 }
+            else { surface_logical_height as u32 },
         );
-        layer.set_exclusive_zone(-1); // Don't let the status bar push it around
+        match exclusive_zone {
+            // Don't let the status bar push it around
+            ExclusiveZone::Ignore => layer.set_exclusive_zone(-1),
+            ExclusiveZone::Respect => layer.set_exclusive_zone(0),
+        }
         layer.set_keyboard_interactivity(KeyboardInteractivity::None);
 
         let surface = layer.wl_surface();
@@ -286,105 +1679,116 @@ logical size: {}x{}, transform: {:?}",
             )
         };
 
-        let mut viewport = None;
-
-        if width == logical_width || height == logical_height {
-            debug!("Output '{}' needs no scaling", output_name);
-        }
-        else if width == logical_width * integer_scale_factor
-            && height == logical_height * integer_scale_factor
-        {
-            debug!("Output '{}' needs integer scaling", output_name);
-            surface.set_buffer_scale(integer_scale_factor);
+        // The new output may change which profile is active
+        if self.update_active_profile(None) {
+            self.reload_all_wallpapers();
         }
-        else {
-            debug!("Output '{}' needs fractional scaling", output_name);
-            let new_viewport = self.viewporter.get_viewport(surface, qh, ());
-            new_viewport.set_destination(logical_width, logical_height);
-            viewport = Some(new_viewport);
-        }
-
-        layer.commit();
-
-        let pixel_format = self.pixel_format();
-
-        let output_wallpaper_dir = self.wallpaper_dir.join(&output_name);
 
-        // Initialize slot pool with a minimum size (0 is not allowed)
-        // it will be automatically resized later
-        let mut shm_slot_pool = SlotPool::new(1, &self.shm).unwrap();
-
-        let workspace_backgrounds = match workspace_bgs_from_output_image_dir(
-            &output_wallpaper_dir,
-            &mut shm_slot_pool,
-            pixel_format,
-            self.brightness,
-            self.contrast,
-            width.try_into().unwrap(),
-            height.try_into().unwrap()
-        ) {
-            Ok(workspace_bgs) => {
-                debug!(
-                    "Loaded {} wallpapers on new output for workspaces: {}",
-                    workspace_bgs.len(),
-                    workspace_bgs.iter()
-                        .map(|workspace_bg| workspace_bg.workspace_name.as_str())
-                        .collect::<Vec<_>>().join(", ")
-                );
-                workspace_bgs
-            },
-            Err(e) => {
-                error!(
-            "Failed to get wallpapers for new output '{}' form '{:?}': {}",
-                    output_name, output_wallpaper_dir, e
-                );
-                return;
-            }
+        let Some((workspace_backgrounds, target)) = self.load_wallpapers(
+            &output_name, &info, width, height
+        ) else {
+            return;
         };
 
-        debug!(
-        "Shm slot pool size for output '{}' after loading wallpapers: {} KiB",
-            output_name,
-            shm_slot_pool.len() / 1024
+        let mut scheduler = FrameScheduler::new(
+            layer.wl_surface().clone(),
+            qh.clone(),
+            self.max_fps.map_or(Duration::ZERO, |max_fps|
+                Duration::from_secs(1) / max_fps
+            ),
         );
+        scheduler.rate.demotion = (self.adaptive_fps && self.on_battery)
+            as u32;
 
-        self.background_layers.push(BackgroundLayer {
-            output_name,
+        let mut bg_layer = BackgroundLayer {
+            output_name: output_name.clone(),
+            output_id: info.id,
             width,
             height,
+            logical_width: surface_logical_width,
+            logical_height: surface_logical_height,
+            integer_scale_factor,
+            anchor,
+            margin,
             layer,
             configured: false,
             workspace_backgrounds,
-            shm_slot_pool,
-            viewport,
-        });
+            target,
+            viewport: None,
+            last_draw: None,
+            deferred_workspace: None,
+            current_wallpaper: None,
+            attached_first_frame: false,
+            shown_workspace: None,
+            shown_path: None,
+            shown_changed: false,
+            switches: 0,
+            placeholder_shown: false,
+            blank: None,
+            scheduler,
+            transition_style,
+            fade_in: self.fade_in,
+            fading_out: false,
+            transition_clock: Rc::clone(&self.transition_clock),
+            focused: true,
+            max_fps: self.max_fps,
+            videos: HashMap::new(),
+            slide_interval: self.slide_interval,
+            random: self.random,
+            slideshow: None,
+            slide_positions: HashMap::new(),
+            palette_dir: self.palette_dir.clone(),
+            overlay: self.overlay.clone(),
+        };
 
-        debug!(
-            "New sum of shm slot pool sizes for all outputs: {} KiB",
-            self.background_layers.iter()
-                .map(|bg_layer| bg_layer.shm_slot_pool.len())
-                .sum::<usize>() / 1024
-        );
+        bg_layer.apply_scaling(self.viewporter.as_ref(), qh);
+        bg_layer.layer.commit();
+
+        self.background_layers.push(bg_layer);
+
+        self.reload_span_group(&output_name, &info);
+
+        self.log_store_stats();
     }
 
     fn update_output(
         &mut self,
-        _conn: &Connection,
+        conn: &Connection,
         qh: &QueueHandle<Self>,
         output: WlOutput,
     ) {
+        // Try again to set up a deferred output, it is deferred again
+        // if it still has no usable mode or geometry
+        if let Some(pending_index) = self.pending_outputs.iter()
+            .position(|pending_output| pending_output == &output)
+        {
+            self.pending_outputs.swap_remove(pending_index);
+            debug!("Retrying to set up deferred output on update");
+            self.new_output(conn, qh, output);
+            return;
+        }
+
         let Some(info) = self.output_state.info(&output)
         else {
             error!("Updated output has no output info, skipping");
             return;
         };
 
-        let Some(output_name) = info.name
+        let Some(output_name) = self.background_layers.iter()
+            .find(|bg_layer| bg_layer.output_id == info.id)
+            .map(|bg_layer| bg_layer.output_name.clone())
         else {
-            error!("Updated output has no name, skipping");
+            if self.ignored_outputs.iter().any(|(id, _)| *id == info.id) {
+                return;
+            }
+            error!("Updated output has no background layer, skipping");
             return;
         };
 
+        self.record_event(LoggedEvent::UpdateOutput {
+            output: output_name.clone()
+        });
+
         let Some((width, height)) = info.modes.iter()
             .find(|mode| mode.current)
             .map(|mode| mode.dimensions)
@@ -450,8 +1854,15 @@ logical size: {}x{}, transform: {:?}",
             logical_width, logical_height, info.transform
         );
 
+        let (width, height) = self.buffer_resolution(
+            &output_name,
+            (width, height),
+            (logical_width, logical_height),
+            integer_scale_factor,
+        );
+
         let Some(bg_layer) = self.background_layers.iter_mut()
-            .find(|bg_layers| bg_layers.output_name == output_name)
+            .find(|bg_layer| bg_layer.output_id == info.id)
         else {
             error!(
                 "Updated output '{}' has no background layer, skipping",
@@ -460,175 +1871,722 @@ logical size: {}x{}, transform: {:?}",
             return;
         };
 
-        if bg_layer.width != width || bg_layer.height != height {
-            warn!(
-"Handling of output mode or transform changes are not yet implemented. \
-Restart multibg-sway or expect broken wallpapers or low quality due to scaling"
+        let surface_logical_width =
+            logical_width - bg_layer.margin.left - bg_layer.margin.right;
+        let surface_logical_height =
+            logical_height - bg_layer.margin.top - bg_layer.margin.bottom;
+        let width = scale_to_pixels(
+            surface_logical_width, width, logical_width
+        );
+        let height = scale_to_pixels(
+            surface_logical_height, height, logical_height
+        );
+
+        let resized = bg_layer.width != width || bg_layer.height != height;
+        if resized {
+            debug!(
+                "Output '{}' changed from {}x{} to {}x{}",
+                output_name, bg_layer.width, bg_layer.height, width, height
+            );
+            bg_layer.width = width;
+            bg_layer.height = height;
+        }
+
+        bg_layer.logical_width = surface_logical_width;
+        bg_layer.logical_height = surface_logical_height;
+        bg_layer.integer_scale_factor = integer_scale_factor;
+        bg_layer.apply_scaling(self.viewporter.as_ref(), qh);
+        bg_layer.layer.commit();
+
+        if resized {
+            // Kept for a while like those of destroyed outputs,
+            // the mode may change back, eg. when docking again
+            let old_wallpapers = bg_layer.workspace_backgrounds.iter()
+                .filter_map(|workspace_bg| workspace_bg.wallpaper.clone())
+                .collect();
+            self.wallpaper_store.retain(old_wallpapers, self.keep_wallpapers);
+            // Picks the resolution directory for the new size too
+            self.reload_wallpapers(&[info.id]);
+        }
+    }
+
+    fn output_destroyed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        output: WlOutput,
+    ) {
+        let pending_index = self.pending_outputs.iter()
+            .position(|pending_output| pending_output == &output);
+        let was_pending = pending_index
+            .map(|index| self.pending_outputs.swap_remove(index))
+            .is_some();
+
+        let Some(info) = self.output_state.info(&output)
+        else {
+            error!("Destroyed output has no output info, skipping");
+            return;
+        };
+
+        if let Some(bg_layer_index) = self.background_layers.iter()
+            .position(|bg_layer| bg_layer.output_id == info.id)
+        {
+            let removed_bg_layer = self.background_layers
+                .swap_remove(bg_layer_index);
+            let output_name = &removed_bg_layer.output_name;
+
+            debug!("Output destroyed: {}", output_name);
+
+            if let Some(event_recorder) = &mut self.event_recorder {
+                event_recorder.record(LoggedEvent::OutputDestroyed {
+                    output: output_name.clone()
+                });
+            }
+
+            // Workspaces on the destroyed output may have been moved anywhere
+            // so reset the wallpaper on all the visible workspaces
+            self.sway_connection_task.request_visible_workspaces();
+
+            debug!(
+                "Dropping {} wallpapers on destroyed output for workspaces: {}",
+                removed_bg_layer.workspace_backgrounds.len(),
+                removed_bg_layer.workspace_backgrounds.iter()
+                    .map(|workspace_bg| workspace_bg.workspace_name.as_str())
+                    .collect::<Vec<_>>().join(", ")
+            );
+
+            self.reload_span_group(&removed_bg_layer.output_name, &info);
+
+            // The output may come back soon, eg. when redocking,
+            // then it can use its wallpapers without loading them again
+            self.wallpaper_store.retain(
+                removed_bg_layer.workspace_backgrounds.into_iter()
+                    .filter_map(|workspace_bg| workspace_bg.wallpaper)
+                    .collect(),
+                self.keep_wallpapers,
+            );
+        }
+        else if was_pending {
+            debug!(
+                "Deferred output destroyed: {}",
+                info.name.as_deref().unwrap_or_default()
+            );
+        }
+        else if let Some(ignored_index) = self.ignored_outputs.iter()
+            .position(|(id, _)| *id == info.id)
+        {
+            let (_, output_name) = self.ignored_outputs
+                .swap_remove(ignored_index);
+            debug!("Ignored output destroyed: {}", output_name);
+        }
+        else {
+            error!(
+    "Ignoring destroyed output '{}' without background layer, \
+known outputs were: {}",
+                info.name.as_deref().unwrap_or_default(),
+                self.background_layers.iter()
+                    .map(|bg_layer| bg_layer.output_name.as_str())
+                    .collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        // The destroyed output may change which profile is active
+        if self.update_active_profile(Some(info.id)) {
+            self.reload_all_wallpapers();
+        }
+
+        self.wallpaper_store.evict_unused(&self.shm);
+        self.log_store_stats();
+    }
+}
+
+impl ProvidesRegistryState for State {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+    registry_handlers![OutputState];
+}
+
+impl ShmHandler for State {
+    fn shm_state(&mut self) -> &mut Shm {
+        &mut self.shm
+    }
+}
+
+delegate_compositor!(State);
+delegate_layer!(State);
+delegate_output!(State);
+delegate_registry!(State);
+delegate_shm!(State);
+
+impl Dispatch<WpViewporter, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewporter,
+        _event: <WpViewporter as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        unreachable!("wp_viewporter has no events");
+    }
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtIdleNotifierV1,
+        _event: <ExtIdleNotifierV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        unreachable!("ext_idle_notifier_v1 has no events");
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => {
+                debug!("Idle, loading the wallpapers not loaded yet");
+                state.idle = true;
+                state.precompute_idle();
+            }
+            ext_idle_notification_v1::Event::Resumed => {
+                debug!("No longer idle");
+                state.idle = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Only needed for the idle notification
+impl Dispatch<WlSeat, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSeat,
+        _event: <WlSeat as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {}
+}
+
+/// Notifies once the user was idle for the timeout, on the first seat,
+/// None if the compositor does not support it
+pub fn idle_notification(
+    registry_state: &RegistryState,
+    qh: &QueueHandle<State>,
+    timeout: Duration,
+) -> Option<ExtIdleNotificationV1> {
+    let notifier: ExtIdleNotifierV1 = registry_state
+        .bind_one(qh, 1..=1, ())
+        .inspect_err(|e| warn!(
+            "ext_idle_notifier_v1 not available, wallpapers are not loaded \
+at idle: {}", e
+        ))
+        .ok()?;
+    let seat: WlSeat = registry_state
+        .bind_one(qh, 1..=1, ())
+        .inspect_err(|e| warn!(
+            "No seat, wallpapers are not loaded at idle: {}", e
+        ))
+        .ok()?;
+    let timeout_ms = timeout.as_millis().try_into().unwrap_or(u32::MAX);
+    Some(notifier.get_idle_notification(timeout_ms, &seat, qh, ()))
+}
+
+impl Dispatch<WpViewport, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewport,
+        _event: <WpViewport as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        unreachable!("wp_viewport has no events");
+    }
+}
+
+pub struct BackgroundLayer {
+    pub output_name: String,
+    pub output_id: u32,
+    pub width: i32,
+    pub height: i32,
+    pub logical_width: i32,
+    pub logical_height: i32,
+    pub integer_scale_factor: i32,
+    pub anchor: Anchor,
+    pub margin: Margin,
+    pub layer: LayerSurface,
+    pub configured: bool,
+    pub workspace_backgrounds: Vec<WorkspaceBackground>,
+    /// What the wallpapers are loaded for
+    pub target: ImageTarget,
+    pub viewport: Option<WpViewport>,
+    pub last_draw: Option<Instant>,
+    pub deferred_workspace: Option<WorkspaceVisible>,
+    /// The wallpaper attached to the surface
+    pub current_wallpaper: Option<Rc<Wallpaper>>,
+    /// Whether the first frame of the current wallpaper is attached,
+    /// not another frame of its animation, video or transition,
+    /// the damage is only tracked from there
+    pub attached_first_frame: bool,
+    /// The workspace the current wallpaper is shown for
+    pub shown_workspace: Option<String>,
+    /// The file of the current wallpaper, maybe still loading
+    pub shown_path: Option<PathBuf>,
+    /// The shown workspace or file changed since the last notification
+    pub shown_changed: bool,
+    /// Times the shown workspace or file changed
+    pub switches: u64,
+    /// Whether the wallpaper of the visible workspace is still loading
+    pub placeholder_shown: bool,
+    /// Set with `multibg-sway ctl blank`, shown instead of the wallpapers
+    pub blank: Option<[u8; 3]>,
+    /// Transitions, animated wallpapers and videos
+    pub scheduler: FrameScheduler,
+    pub transition_style: TransitionStyle,
+    /// Of the first wallpaper shown
+    pub fade_in: Duration,
+    /// Nothing is drawn anymore before exiting
+    pub fading_out: bool,
+    pub transition_clock: Rc<TransitionClock>,
+    /// Whether the visible workspace has the focus, assumed until known
+    pub focused: bool,
+    pub max_fps: Option<u32>,
+    /// Players of the videos shown since the wallpapers were loaded
+    /// by their path, paused while their workspace is not visible
+    pub videos: HashMap<PathBuf, VideoPlayer>,
+    pub slide_interval: Option<Duration>,
+    pub random: Option<RandomPick>,
+    /// Of the visible workspace
+    pub slideshow: Option<Slideshow>,
+    /// By the name of the workspace showing a slideshow or random pool,
+    /// its slide shown is this modulo the number of slides, kept while
+    /// the workspace is not visible
+    pub slide_positions: HashMap<String, usize>,
+    pub palette_dir: Option<PathBuf>,
+    pub overlay: Option<Rc<Overlay>>,
+}
+
+impl BackgroundLayer
+{
+    /// Of the wallpapers, fractional if drawn through the viewport
+    pub fn scale(&self) -> f64 {
+        if self.logical_width > 0 {
+            self.width as f64 / self.logical_width as f64
+        }
+        else {
+            self.integer_scale_factor as f64
+        }
+    }
+
+    /// Draw now unless the previous draw was less than min_interval ago,
+    /// then only the last requested workspace gets drawn once it elapsed
+    pub fn request_workspace_bg(
+        &mut self,
+        workspace: &WorkspaceVisible,
+        min_interval: Duration,
+        store: &mut WallpaperStore,
+        ctx: &LoadContext,
+    ) {
+        let now = Instant::now();
+        if self.last_draw.is_some_and(|last| now < last + min_interval) {
+            debug!(
+                "Deferring draw on output '{}' for workspace: {}",
+                self.output_name, workspace.workspace_name
             );
+            self.deferred_workspace = Some(workspace.clone());
+            return;
         }
+        self.deferred_workspace = None;
+        self.last_draw = Some(now);
+        self.draw_workspace_bg(
+            &workspace.workspace_name, workspace.layout.as_deref(), store, ctx
+        );
+    }
 
-        let surface = bg_layer.layer.wl_surface();
+    fn apply_scaling(
+        &mut self,
+        viewporter: Option<&WpViewporter>,
+        qh: &QueueHandle<State>,
+    ) {
+        let surface = self.layer.wl_surface();
 
-        if width == logical_width || height == logical_height {
-            debug!("Output '{}' needs no scaling", output_name);
+        if self.width == self.logical_width
+            || self.height == self.logical_height
+        {
+            debug!("Output '{}' needs no scaling", self.output_name);
             surface.set_buffer_scale(1);
-            if let Some(old_viewport) = bg_layer.viewport.take() {
+            if let Some(old_viewport) = self.viewport.take() {
                 old_viewport.destroy();
             };
         }
-        else if width == logical_width * integer_scale_factor
-            && height == logical_height * integer_scale_factor
+        else if self.width == self.logical_width * self.integer_scale_factor
+            && self.height == self.logical_height * self.integer_scale_factor
         {
-            debug!("Output '{}' needs integer scaling", output_name);
-            surface.set_buffer_scale(integer_scale_factor);
-            if let Some(old_viewport) = bg_layer.viewport.take() {
+            debug!("Output '{}' needs integer scaling", self.output_name);
+            surface.set_buffer_scale(self.integer_scale_factor);
+            if let Some(old_viewport) = self.viewport.take() {
                 old_viewport.destroy();
             };
         }
-        else {
-            debug!("Output '{}' needs fractional scaling", output_name);
+        else if let Some(viewporter) = viewporter {
+            debug!("Output '{}' needs fractional scaling", self.output_name);
             surface.set_buffer_scale(1);
-            bg_layer.viewport
+            self.viewport
                 .get_or_insert_with(||
-                    self.viewporter.get_viewport(surface, qh, ())
+                    viewporter.get_viewport(surface, qh, ())
                 )
-                .set_destination(logical_width, logical_height);
+                .set_destination(self.logical_width, self.logical_height);
+        }
+        else {
+            warn!(
+                "Output '{}' needs fractional scaling without wp_viewporter, \
+the wallpaper will not fit",
+                self.output_name
+            );
+            surface.set_buffer_scale(1);
         }
+    }
 
-        surface.commit();
+    fn update_demotion(&mut self, adaptive_fps: bool, on_battery: bool) {
+        let demotion = if adaptive_fps {
+            on_battery as u32 + !self.focused as u32
+        } else {
+            0
+        };
+        if demotion != self.scheduler.rate.demotion {
+            debug!(
+                "Frame rate halved {} times on output '{}'",
+                demotion, self.output_name
+            );
+            self.scheduler.rate.demotion = demotion;
+        }
     }
 
-    fn output_destroyed(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        output: WlOutput,
-    ) {
-        let Some(info) = self.output_state.info(&output)
+    fn frame_done(&mut self) {
+        self.scheduler.frame_done();
+    }
+
+    fn next_frame_time(&self) -> Option<Instant> {
+        self.scheduler.next_frame_time()
+    }
+
+    fn in_transition(&self) -> bool {
+        self.scheduler.transition.is_some()
+    }
+
+    /// A solid color instead of the wallpapers, stopping their animations
+    fn draw_blank(&mut self, color: [u8; 3], store: &mut WallpaperStore) {
+        if self.fading_out || !self.configured {
+            return;
+        }
+        // The pixel format of all the wallpapers of the output
+        let Some(format) = self.workspace_backgrounds.first()
+            .map(|workspace_bg| workspace_bg.key.format())
         else {
-            error!("Destroyed output has no output info, skipping");
             return;
         };
+        let wallpaper = match solid_into_store(
+            store, color, format, &self.target
+        ) {
+            Ok(wallpaper) => wallpaper,
+            Err(e) => {
+                error!(
+                    "Failed to blank output '{}': {}", self.output_name, e
+                );
+                return;
+            }
+        };
+        self.deferred_workspace = None;
+        self.scheduler.animation = None;
+        self.scheduler.transition = None;
+        self.scheduler.unschedule();
+        attach(self.layer.wl_surface(), wallpaper.buffer(), &self.output_name);
+        self.layer.wl_surface().damage_buffer(0, 0, self.width, self.height);
+        self.layer.commit();
+        self.current_wallpaper = Some(wallpaper);
+        self.attached_first_frame = true;
+    }
+
+    fn draw_next_frame(&mut self, store: &mut WallpaperStore) {
+        let Some(due) = self.scheduler.take_due(Instant::now()) else {
+            return;
+        };
+        if self.scheduler.transition.is_some() {
+            self.draw_transition_frame(store);
+        }
+        else {
+            self.draw_animation_frame(due, store);
+        }
+        self.layer.commit();
+    }
 
-        let Some(output_name) = info.name
+    fn draw_animation_frame(
+        &mut self,
+        due: Instant,
+        store: &mut WallpaperStore,
+    ) {
+        let rate = self.scheduler.rate;
+        let (Some(wallpaper), Some(animation)) =
+            (&self.current_wallpaper, &mut self.scheduler.animation)
         else {
-            error!("Destroyed output has no name, skipping");
             return;
         };
+        let now = Instant::now();
+
+        let (buffer, delay) = match animation.video.clone() {
+            Some(path) => {
+                let Some(player) = self.videos.get_mut(&path) else {
+                    self.scheduler.animation = None;
+                    return;
+                };
+                // Slower when demoted
+                let frame_delay = rate.interval(player.frame_delay());
+                match player.next_frame(store) {
+                    Ok(Some(buffer)) => (buffer, frame_delay),
+                    // Not decoded yet or all buffers are busy
+                    Ok(None) => {
+                        self.scheduler.schedule(now + VIDEO_RETRY_DELAY);
+                        return;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Stopping video on output '{}': {}",
+                            self.output_name, e
+                        );
+                        self.scheduler.animation = None;
+                        self.videos.remove(&path);
+                        return;
+                    }
+                }
+            }
+            None => {
+                let len = wallpaper.frames.len();
+                let shown = (animation.frame_index + 1) % len;
+                // Skip the frames following it while they would be shown
+                // faster than the frame rate allows
+                let interval = rate.interval(wallpaper.frames[shown].delay);
+                let mut frame_index = shown;
+                let mut delay = wallpaper.frames[shown].delay;
+                for _ in 1..len {
+                    if delay >= interval {
+                        break;
+                    }
+                    frame_index = (frame_index + 1) % len;
+                    delay += wallpaper.frames[frame_index].delay;
+                }
+                animation.frame_index = frame_index;
+                (&wallpaper.frames[shown].buffer, delay)
+            }
+        };
 
-        debug!(
-            "Output destroyed: {}",
-            output_name,
-        );
-
-        if let Some(bg_layer_index) = self.background_layers.iter()
-            .position(|bg_layers| bg_layers.output_name == output_name)
-        {
-            let removed_bg_layer = self.background_layers
-                .swap_remove(bg_layer_index);
-
-            // Workspaces on the destroyed output may have been moved anywhere
-            // so reset the wallpaper on all the visible workspaces
-            self.sway_connection_task.request_visible_workspaces();
+        // Keep the pace of the frame delays,
+        // but don't try to catch up after being paused
+        let frame_start = if now > due + delay { now } else { due };
+        self.scheduler.schedule(frame_start + delay);
 
-            debug!(
-                "Dropping {} wallpapers on destroyed output for workspaces: {}",
-                removed_bg_layer.workspace_backgrounds.len(),
-                removed_bg_layer.workspace_backgrounds.iter()
-                    .map(|workspace_bg| workspace_bg.workspace_name.as_str())
-                    .collect::<Vec<_>>().join(", ")
-            );
+        let surface = self.layer.wl_surface();
+        attach(surface, buffer, &self.output_name);
+        surface.damage_buffer(0, 0, self.width, self.height);
+        self.attached_first_frame = false;
+        self.scheduler.request_frame();
+    }
 
-            for workspace_bg in removed_bg_layer.workspace_backgrounds.iter() {
-                if workspace_bg.buffer.slot().has_active_buffers() {
-                    warn!(
-"On destroyed output '{}' workspace background '{}' will be dropped while its shm slot still has active buffers",
-                        output_name,
-                        workspace_bg.workspace_name,
-                    );
+    /// The next frame of the transition, or the current wallpaper
+    /// once it finished
+    fn draw_transition_frame(&mut self, store: &mut WallpaperStore) {
+        let Some(transition) = &mut self.scheduler.transition else {
+            return;
+        };
+        let surface = self.layer.wl_surface();
+        if transition.is_finished() {
+            // Stays on the color faded out to
+            if self.fading_out {
+                if let Some(buffer) = transition.draw_frame(store) {
+                    attach(surface, buffer, &self.output_name);
+                    surface.damage_buffer(0, 0, self.width, self.height);
+                    self.attached_first_frame = false;
                 }
+                self.scheduler.transition = None;
+                return;
             }
-
-            drop(removed_bg_layer);
+            self.scheduler.transition = None;
+            let Some(wallpaper) = &self.current_wallpaper else { return };
+            let frame_index = self.scheduler.animation.as_ref()
+                .map_or(0, |animation| animation.frame_index);
+            let frame = &wallpaper.frames[frame_index];
+            attach(surface, &frame.buffer, &self.output_name);
+            surface.damage_buffer(0, 0, self.width, self.height);
+            self.attached_first_frame = frame_index == 0;
+            // Animations continue from here
+            if self.scheduler.animation.is_some() {
+                self.scheduler.schedule(Instant::now() + frame.delay);
+                self.scheduler.request_frame();
+            }
+            return;
         }
-        else {
-            error!(
-    "Ignoring destroyed output with unknown name '{}', known outputs were: {}",
-                output_name,
-                self.background_layers.iter()
-                    .map(|bg_layer| bg_layer.output_name.as_str())
-                    .collect::<Vec<_>>().join(", ")
-            );
+        // Dropped while all buffers are busy, retried on the next callback
+        if let Some(buffer) = transition.draw_frame(store) {
+            attach(surface, buffer, &self.output_name);
+            surface.damage_buffer(0, 0, self.width, self.height);
+            self.attached_first_frame = false;
         }
+        // As soon as the compositor is ready
+        self.scheduler.schedule(Instant::now());
+        self.scheduler.request_frame();
+    }
 
-        debug!(
-            "New sum of shm slot pool sizes for all outputs: {} KiB",
-            self.background_layers.iter()
-                .map(|bg_layer| bg_layer.shm_slot_pool.len())
-                .sum::<usize>() / 1024
-        );
+    fn fade_out(
+        &mut self,
+        duration: Duration,
+        color: [u8; 3],
+        store: &mut WallpaperStore,
+    ) {
+        self.fading_out = true;
+        self.deferred_workspace = None;
+        self.scheduler.animation = None;
+        self.scheduler.unschedule();
+        self.scheduler.transition = self.current_wallpaper.as_ref()
+            .and_then(|current| Transition::fade_out(
+                duration, color, &self.transition_clock, current, store
+            ));
+        self.draw_transition_frame(store);
+        self.layer.commit();
     }
-}
 
-impl ProvidesRegistryState for State {
-    fn registry(&mut self) -> &mut RegistryState {
-        &mut self.registry_state
+    /// The wallpaper named exactly like the workspace, or else the one named
+    /// by the most specific glob pattern matching it, the first one of those
+    /// equally specific. Patterns match workspaces with a layout only
+    /// if they also have one
+    fn find_workspace_bg(&self, name: &str) -> Option<usize> {
+        let workspace_bgs = &self.workspace_backgrounds;
+        workspace_bgs.iter()
+            .position(|workspace_bg| workspace_bg.workspace_name == name)
+            .or_else(|| workspace_bgs.iter().enumerate()
+                .map(|(index, workspace_bg)|
+                    (index, workspace_bg.workspace_name.as_str())
+                )
+                .filter(|(_, pattern)| is_glob(pattern)
+                    && pattern.contains('@') == name.contains('@')
+                    && glob_match(pattern, name)
+                )
+                .max_by_key(|(index, pattern)|
+                    (glob_specificity(pattern), Reverse(*index))
+                )
+                .map(|(index, _)| index)
+            )
     }
-    registry_handlers![OutputState];
-}
 
-impl ShmHandler for State {
-    fn shm_state(&mut self) -> &mut Shm {
-        &mut self.shm
+    /// The slide of the slideshow or random pool at the index
+    /// to show on the workspace
+    fn current_slide(&self, index: usize, workspace_name: &str) -> usize {
+        let slides = self.slides(index);
+        let position = self.slide_positions.get(workspace_name)
+            .map_or(0, |position| position % slides.len());
+        slides.start + position
     }
-}
 
-delegate_compositor!(State);
-delegate_layer!(State);
-delegate_output!(State);
-delegate_registry!(State);
-delegate_shm!(State);
+    /// The workspace backgrounds of the slideshow the one at the index
+    /// belongs to, only itself if it is not in one
+    fn slides(&self, index: usize) -> Range<usize> {
+        let workspace_bgs = &self.workspace_backgrounds;
+        let start = index - workspace_bgs[..=index].iter().rev()
+            .take_while(|workspace_bg| workspace_bg.continues_slideshow)
+            .count();
+        let end = index + 1 + workspace_bgs[index + 1..].iter()
+            .take_while(|workspace_bg| workspace_bg.continues_slideshow)
+            .count();
+        start..end
+    }
 
-impl Dispatch<WpViewporter, ()> for State {
-    fn event(
-        _state: &mut Self,
-        _proxy: &WpViewporter,
-        _event: <WpViewporter as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-        unreachable!("wp_viewporter has no events");
+    /// The position of the slide after the one at the position,
+    /// a different random one when picking randomly
+    fn next_position(&self, first: usize, position: usize) -> usize {
+        let len = self.slides(first).len();
+        match self.random {
+            Some(_) if len > 1 =>
+                position.wrapping_add(1 + random_number() % (len - 1)),
+            _ => position.wrapping_add(1),
+        }
     }
-}
 
-impl Dispatch<WpViewport, ()> for State {
-    fn event(
-        _state: &mut Self,
-        _proxy: &WpViewport,
-        _event: <WpViewport as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-        unreachable!("wp_viewport has no events");
+    fn advance_slideshow(&mut self, now: Instant) {
+        if let Some(slideshow) = &self.slideshow {
+            self.show_slide(now, slideshow.upcoming);
+        }
     }
-}
 
-pub struct BackgroundLayer {
-    pub output_name: String,
-    pub width: i32,
-    pub height: i32,
-    pub layer: LayerSurface,
-    pub configured: bool,
-    pub workspace_backgrounds: Vec<WorkspaceBackground>,
-    pub shm_slot_pool: SlotPool,
-    pub viewport: Option<WpViewport>,
-}
-impl BackgroundLayer
-{
-    pub fn draw_workspace_bg(&mut self, workspace_name: &str)
-    {
+    /// Back to the slide shown before the current one in order
+    fn rewind_slideshow(&mut self, now: Instant) {
+        let (Some(slideshow), Some(shown)) =
+            (&self.slideshow, &self.shown_workspace)
+        else {
+            return;
+        };
+        let len = self.slides(slideshow.first).len();
+        let position = self.slide_positions.get(shown)
+            .map_or(0, |position| position % len);
+        self.show_slide(now, (position + len - 1) % len);
+    }
+
+    /// Show the slide at the position of the visible slideshow
+    /// when the workspace is drawn next
+    fn show_slide(&mut self, now: Instant, position: usize) {
+        let (Some(slideshow), Some(shown)) =
+            (&self.slideshow, &self.shown_workspace)
+        else {
+            return;
+        };
+        let first = slideshow.first;
+        debug!(
+            "Advancing to slide {} of workspace '{}' on output '{}'",
+            position % self.slides(first).len(), shown, self.output_name
+        );
+        self.slide_positions.insert(shown.clone(), position);
+        self.slideshow = Some(Slideshow {
+            first,
+            next_slide: self.slide_interval.map(|interval| now + interval),
+            upcoming: self.next_position(first, position),
+        });
+    }
+
+    /// Shown after the current slide of the visible slideshow
+    fn upcoming_slide(&self) -> Option<usize> {
+        let slideshow = self.slideshow.as_ref()?;
+        let slides = self.slides(slideshow.first);
+        Some(slides.start + slideshow.upcoming % slides.len())
+    }
+
+    fn deferred_draw_time(&self, min_interval: Duration) -> Option<Instant> {
+        self.deferred_workspace.as_ref()?;
+        Some(self.last_draw? + min_interval)
+    }
+
+    /// With a layout, the wallpaper named workspace_name@layout
+    /// is preferred, eg. 1@tabbed.jpg over 1.jpg
+    pub fn draw_workspace_bg(
+        &mut self,
+        workspace_name: &str,
+        layout: Option<&str>,
+        store: &mut WallpaperStore,
+        ctx: &LoadContext,
+    ) {
+        if self.fading_out {
+            return;
+        }
         if !self.configured {
             error!(
 "Cannot draw wallpaper image on the not yet configured layer for output: {}",
@@ -636,12 +2594,57 @@ impl BackgroundLayer
             );
             return;
         }
+        // Drawn again in case the size changed,
+        // the workspace is requested again when unblanked
+        if let Some(color) = self.blank {
+            self.draw_blank(color, store);
+            return;
+        }
 
-        let Some(workspace_bg) = self.workspace_backgrounds.iter()
-            .find(|workspace_bg| workspace_bg.workspace_name == workspace_name)
-            .or_else(|| self.workspace_backgrounds.iter()
-                .find(|workspace_bg| workspace_bg.workspace_name == "_default")
-            )
+        // Sway workspaces like 3:mail also by their number, then their name
+        let numbered = numbered_workspace(workspace_name);
+        let candidates = [
+            Some(workspace_name),
+            numbered.map(|(number, _)| number),
+            numbered.map(|(_, name)| name),
+            Some("_default"),
+        ].into_iter()
+            .flatten()
+            .flat_map(|name| [
+                layout.map(|layout| format!("{}@{}", name, layout)),
+                Some(name.to_string()),
+            ])
+            .flatten();
+
+        let newly_shown =
+            self.shown_workspace.as_deref() != Some(workspace_name);
+        match self.random {
+            Some(RandomPick::Show) if newly_shown => {
+                self.slide_positions
+                    .insert(workspace_name.to_string(), random_number());
+            }
+            Some(_) => {
+                self.slide_positions.entry(workspace_name.to_string())
+                    .or_insert_with(random_number);
+            }
+            None => {}
+        }
+
+        let mut loading = None;
+        let Some(workspace_bg_index) = candidates
+            .filter_map(|candidate| self.find_workspace_bg(&candidate))
+            .map(|index| self.current_slide(index, workspace_name))
+            // Not tried again on every switch
+            .filter(|&index| !self.workspace_backgrounds[index].failed)
+            // Fall back to the next candidate while loading in the background
+            .find(|&index| {
+                let loaded = !self.workspace_backgrounds[index].loading;
+                if !loaded {
+                    loading.get_or_insert(index);
+                }
+                loaded
+            })
+            .or(loading)
         else {
             error!(
 "There is no wallpaper image on output '{}' for workspace '{}', only for: {}",
@@ -654,43 +2657,309 @@ impl BackgroundLayer
             return;
         };
 
-        if workspace_bg.buffer.slot().has_active_buffers() {
-            debug!(
-"Skipping draw on output '{}' for workspace '{}' because its buffer already active",
-                self.output_name,
-                workspace_name,
-            );
-            return;
+        self.placeholder_shown = loading.is_some();
+
+        // Advances only while the workspace is visible
+        let slides = self.slides(workspace_bg_index);
+        if slides.len() < 2 {
+            self.slideshow = None;
+        }
+        else if self.slideshow.is_none() || newly_shown {
+            let position = self.slide_positions.get(workspace_name)
+                .copied()
+                .unwrap_or(0);
+            self.slideshow = Some(Slideshow {
+                first: slides.start,
+                next_slide: self.slide_interval
+                    .map(|interval| Instant::now() + interval),
+                upcoming: self.next_position(slides.start, position),
+            });
         }
 
-        // Attach and commit to new workspace background
-        if let Err(e) = workspace_bg.buffer.attach_to(self.layer.wl_surface()) {
-            error!(
-            "Error attaching buffer of workspace '{}' on output '{}': {:#?}",
-                workspace_name,
-                self.output_name,
-                e
+        let workspace_bg = &mut self.workspace_backgrounds[workspace_bg_index];
+        let wallpaper = match &workspace_bg.wallpaper {
+            Some(wallpaper) => wallpaper.clone(),
+            // Not even a default wallpaper is loaded yet
+            None if workspace_bg.loading => match placeholder_into_store(
+                store, ctx.settings, workspace_bg.key.format(), &self.target
+            ) {
+                Ok(wallpaper) => wallpaper,
+                Err(e) => {
+                    error!("Failed to create placeholder wallpaper: {}", e);
+                    return;
+                }
+            },
+            // Not loaded yet with lazy loading
+            None => match load_into_store(
+                &workspace_bg.path,
+                &workspace_bg.key,
+                store,
+                ctx,
+                &self.target,
+                None,
+            ) {
+                Ok(wallpaper) => {
+                    debug!(
+                        "Loaded wallpaper '{}' on output '{}'",
+                        workspace_bg.workspace_name, self.output_name
+                    );
+                    workspace_bg.wallpaper.insert(wallpaper).clone()
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to load wallpaper '{:?}': {}",
+                        workspace_bg.path, e
+                    );
+                    // Show the next candidate instead
+                    workspace_bg.failed = true;
+                    return self.draw_workspace_bg(
+                        workspace_name, layout, store, ctx
+                    );
+                }
+            },
+        };
+        let workspace_bg = &self.workspace_backgrounds[workspace_bg_index];
+        wallpaper.mark_shown();
+        let wallpaper = match &self.overlay {
+            Some(overlay) => store.overlay_variant(
+                &wallpaper,
+                overlay,
+                &overlay.text(workspace_name, &self.output_name),
+            ),
+            None => wallpaper,
+        };
+        let wallpaper = store.night_variant(&wallpaper);
+
+        // Only when switching workspaces, not when the wallpaper
+        // of the same workspace changes
+        self.scheduler.transition = if self.current_wallpaper.is_none() {
+            Transition::fade_in(
+                self.fade_in, &self.transition_clock, &wallpaper, store
+            )
+        } else {
+            self.shown_workspace.as_deref()
+                .filter(|shown| *shown != workspace_name)
+                .zip(self.current_wallpaper.as_ref())
+                .filter(|(_, current)| !Rc::ptr_eq(current, &wallpaper))
+                .and_then(|(shown, current)| Transition::new(
+                    self.transition_style,
+                    &self.transition_clock,
+                    shown,
+                    workspace_name,
+                    current,
+                    &wallpaper,
+                    store,
+                ))
+        };
+        if newly_shown
+            || self.shown_path.as_ref() != Some(&workspace_bg.path)
+        {
+            self.shown_changed = true;
+            self.switches += 1;
+        }
+        self.shown_workspace = Some(workspace_name.to_string());
+        self.shown_path = Some(workspace_bg.path.clone());
+
+        // Attach and commit to new workspace background,
+        // the transition attaches its first frame instead
+        if self.scheduler.transition.is_none() {
+            attach(
+                self.layer.wl_surface(), wallpaper.buffer(), &self.output_name
             );
-            return;
+
+            // Anything but the first frame of the current wallpaper
+            // may differ anywhere
+            let damage = self.current_wallpaper.as_ref()
+                .filter(|_| self.attached_first_frame)
+                .and_then(|current| current.tile_hashes.as_ref())
+                .zip(wallpaper.tile_hashes.as_ref())
+                .and_then(|(current, new)| current.damage(new));
+            match damage {
+                Some(rects) => {
+                    debug!(
+                        "Damaging {} rectangles on output '{}'",
+                        rects.len(), self.output_name
+                    );
+                    for (x, y, width, height) in rects {
+                        self.layer.wl_surface()
+                            .damage_buffer(x, y, width, height);
+                    }
+                }
+                // Damage the entire surface
+                None => self.layer.wl_surface()
+                    .damage_buffer(0, 0, self.width, self.height),
+            }
+            self.attached_first_frame = true;
+        }
+        let video = workspace_bg.video.as_ref().and_then(|source| {
+            // Animated images are shown as they are
+            if matches!(source, VideoSource::KenBurns(..))
+                && wallpaper.is_animated()
+            {
+                return None;
+            }
+            let path = &workspace_bg.path;
+            if !self.videos.contains_key(path) {
+                match VideoPlayer::spawn(
+                    source,
+                    wallpaper.key.width(),
+                    wallpaper.key.height(),
+                    wallpaper.key.format(),
+                    self.max_fps,
+                    ctx,
+                ) {
+                    Ok(player) => {
+                        self.videos.insert(path.clone(), player);
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to play video of workspace '{}' \
+on output '{}': {}",
+                            workspace_bg.workspace_name, self.output_name, e
+                        );
+                        return None;
+                    }
+                }
+            }
+            Some(path.clone())
+        });
+        if wallpaper.is_animated() || video.is_some() {
+            let delay = match &video {
+                Some(path) => self.videos[path].frame_delay(),
+                None => wallpaper.frames[0].delay,
+            };
+            self.scheduler.animation = Some(Animation {
+                frame_index: 0,
+                video,
+            });
+            self.scheduler.schedule(Instant::now() + delay);
+            self.scheduler.request_frame();
+        }
+        else {
+            self.scheduler.animation = None;
+            self.scheduler.unschedule();
+        }
+        if let Some(palette_dir) = &self.palette_dir {
+            let palette = store.palette(&wallpaper);
+            if let Err(e) = write_palette(
+                palette_dir, &self.output_name, workspace_name, &palette
+            ) {
+                error!(
+                    "Failed to write palette of output '{}': {}",
+                    self.output_name, e
+                );
+            }
         }
+        self.current_wallpaper = Some(wallpaper);
 
-        // Damage the entire surface
-        self.layer.wl_surface().damage_buffer(0, 0, self.width, self.height);
+        debug!(
+            "Setting wallpaper '{}' on output '{}' for workspace: {}",
+            workspace_bg.workspace_name, self.output_name, workspace_name
+        );
+
+        self.draw_transition_frame(store);
 
         self.layer.commit();
+    }
+}
+
+pub struct WorkspaceBackground {
+    pub workspace_name: String,
+    pub path: PathBuf,
+    pub key: WallpaperKey,
+    /// For videos the first frame, None until the workspace is first shown
+    /// with lazy loading
+    pub wallpaper: Option<Rc<Wallpaper>>,
+    /// Being decoded in the background, others are shown until then
+    pub loading: bool,
+    /// Failed to load, others are shown instead until reloaded
+    pub failed: bool,
+    pub video: Option<VideoSource>,
+    /// Shown after the one before it as the next slide of a slideshow
+    pub continues_slideshow: bool,
+}
+
+/// The slideshow of the visible workspace
+pub struct Slideshow {
+    /// Index of its first slide in the workspace backgrounds
+    pub first: usize,
+    /// None without --slideshow, a random pool advances only on SIGUSR2
+    pub next_slide: Option<Instant>,
+    /// The position of the slide shown next
+    pub upcoming: usize,
+}
+
+pub fn select_pixel_format(
+    requested: PixelFormat,
+    supported: &[wl_shm::Format],
+) -> wl_shm::Format {
+    if requested == PixelFormat::Rgb565 {
+        if supported.contains(&wl_shm::Format::Rgb565) {
+            debug!("Using pixel format: Rgb565");
+            return wl_shm::Format::Rgb565
+        }
+        warn!("Pixel format Rgb565 is not supported by the compositor");
+    }
+
+    if requested != PixelFormat::Baseline {
+        // Consume less gpu memory by using Bgr888 if available,
+        // fall back to the always supported Xrgb8888 otherwise
+        for format in supported {
+            if let wl_shm::Format::Bgr888 = format {
+                debug!("Using pixel format: {:?}", format);
+                return *format
+            }
+            // XXX: One may add Rgb888 and HDR support here
+        }
+    }
+
+    debug!("Using default pixel format: Xrgb8888");
+    wl_shm::Format::Xrgb8888
+}
 
+/// Mark the buffer busy until the compositor releases it, so it is neither
+/// destroyed nor its memory reused while it may be scanned out. A buffer
+/// shared with other outputs may already be busy, attaching it again is
+/// fine as wallpaper buffers are never written after loading
+fn attach(surface: &WlSurface, buffer: &Buffer, output_name: &str) {
+    if buffer.activate().is_err() {
         debug!(
-            "Setting wallpaper on output '{}' for workspace: {}",
-            self.output_name, workspace_name
+            "Attaching already busy buffer on output '{}'", output_name
         );
     }
+    surface.attach(Some(buffer.wl_buffer()), 0, 0);
 }
 
-pub struct WorkspaceBackground {
-    pub workspace_name: String,
-    pub buffer: Buffer,
+/// Pixel size of a logical length on an output with the given pixel
+/// and logical size, also handles fractional scale factors
+fn scale_to_pixels(logical: i32, output_pixels: i32, output_logical: i32)
+    -> i32
+{
+    (logical as f64 * output_pixels as f64 / output_logical as f64).round()
+        as i32
 }
 
 fn layer_surface_name(output_name: &str) -> Option<String> {
     Some([env!("CARGO_PKG_NAME"), "_wallpaper_", output_name].concat())
 }
+
+/// The number and the name of sway workspaces named like 3:mail
+fn numbered_workspace(workspace_name: &str) -> Option<(&str, &str)> {
+    let (number, name) = workspace_name.split_once(':')?;
+    (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+        && !name.is_empty()
+    ).then_some((number, name))
+}
+
+/// The first time profile of the time of day
+pub fn active_time_profile(time_profiles: &[TimeProfile]) -> Option<String> {
+    let time_of_day = local_time_of_day();
+    time_profiles.iter()
+        .find(|time_profile| time_profile.time_range.contains(time_of_day))
+        .map(|time_profile| time_profile.name.clone())
+}
+
+/// A random number from the randomly seeded hasher of the standard library
+fn random_number() -> usize {
+    RandomState::new().build_hasher().finish() as usize
+}